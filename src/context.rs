@@ -1,32 +1,79 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, rc::Rc, sync::Arc};
 
 use crate::{
-    pipeline::{GpuVertex, Primitive},
-    svg::Svg,
+    geometry::{geometry_key, GeometryKey},
+    gradient::{piet_stops, ExtendMode, Gradient},
+    image::{to_rgba, ImageTexture, MipmapGenerator},
+    layer::Layer,
+    pipeline::{BlendMode, DrawBatch, GpuGradientStop, GpuVertex, Primitive},
+    recording::FrameRecording,
+    render_pass::{create_depth, CustomPass, RenderPassCtx},
+    svg::{dash_path, Svg, SvgFit, SvgStyle},
     text::{WgpuText, WgpuTextLayout},
-    WgpuRenderer,
+    CustomShader, Frame, RenderTexture, WgpuRenderer,
 };
-use futures::task::SpawnExt;
 use lyon::lyon_tessellation::{
     BuffersBuilder, FillOptions, FillTessellator, FillVertex, StrokeOptions, StrokeTessellator,
     StrokeVertex, VertexBuffers,
 };
 use lyon::tessellation;
 use piet::{
-    kurbo::{Affine, Point, Rect, Shape, Size, Vec2},
-    Color, FontFamily, Image, IntoBrush, RenderContext,
+    kurbo::{
+        Affine, BezPath, Circle, Ellipse, Line, PathEl, Point, Rect, RoundedRect, RoundedRectRadii,
+        Shape, Size, Vec2,
+    },
+    Color, Image, InterpolationMode, IntoBrush, LineCap, LineJoin, RenderContext, StrokeStyle,
 };
 
 pub struct WgpuRenderContext<'a> {
-    pub(crate) renderer: &'a mut WgpuRenderer,
+    /// `None` for a context that only records geometry, as tests use
+    /// without a device.
+    renderer: Option<&'a mut WgpuRenderer>,
+    /// The target's size in physical pixels.
+    size: Size,
+    /// Physical pixels per logical pixel.
+    scale: f64,
+    target: Option<&'a wgpu::TextureView>,
+    /// Whether `target` is a frame other contexts may draw into too.
+    shares_frame: bool,
     pub(crate) fill_tess: FillTessellator,
     pub(crate) stroke_tess: StrokeTessellator,
     pub(crate) geometry: VertexBuffers<GpuVertex, u32>,
     inner_text: WgpuText,
     pub(crate) cur_transform: Affine,
     state_stack: Vec<State>,
-    clip_stack: Vec<Rect>,
+    /// Clip rects in logical target coordinates, with how far inside them
+    /// their edges fade out, in logical pixels.
+    clip_stack: Vec<(Rect, f64)>,
+    blend: BlendMode,
+    pixel_snap: bool,
+    z: f32,
+    alpha: f32,
+    blur_quality: BlurQuality,
+    /// How far flattened curves may stray from the true ones, in logical
+    /// pixels; `None` picks it from the DPI scale.
+    tolerance: Option<f64>,
+    image_mipmaps: bool,
+    #[cfg(feature = "debug-wireframe")]
+    debug_wireframe: bool,
+    /// The areas to redraw, in device pixels, if only some are.
+    damage: Option<Vec<Rect>>,
     pub(crate) primitives: Vec<Primitive>,
+    /// The stops of every gradient primitive.
+    gradient_stops: Vec<GpuGradientStop>,
+    /// Index runs that share a scissor rect, in draw order.
+    batches: Vec<DrawBatch>,
+    /// The batches of each open layer's parent, with the layer's alpha,
+    /// blur and bounds.
+    layer_stack: Vec<(Vec<DrawBatch>, f32, f32, Rect)>,
+    /// Layers that have been restored, innermost first.
+    layers: Vec<Layer>,
+    custom_passes: Vec<Option<CustomPass<'a>>>,
+    /// The first error hit since the last `status` call.
+    error: Option<piet::Error>,
+    /// Whether `finish` has been called, so dropping the context doesn't
+    /// finish the frame again.
+    finished: bool,
 }
 
 #[derive(Default)]
@@ -38,15 +85,52 @@ struct State {
     /// This invariant should hold: transform * rel_transform = cur_transform
     transform: Affine,
     n_clip: usize,
+    /// Whether this state was pushed by `save_layer`.
+    layer: bool,
+    /// The blend mode at the parent state.
+    blend: BlendMode,
+    /// Whether pixel snapping was on at the parent state.
+    pixel_snap: bool,
+    /// The depth at the parent state.
+    z: f32,
+    /// The alpha at the parent state.
+    alpha: f32,
 }
 
 impl<'a> WgpuRenderContext<'a> {
     pub fn new(renderer: &'a mut WgpuRenderer) -> Self {
         let text = renderer.text();
-        let geometry: VertexBuffers<GpuVertex, u32> = VertexBuffers::new();
+        let mut geometry = std::mem::take(&mut renderer.frame_geometry);
+        geometry.vertices.clear();
+        geometry.indices.clear();
+        let mut primitives = std::mem::take(&mut renderer.frame_primitives);
+        primitives.clear();
+        let (size, scale) = (renderer.size, renderer.pipeline.scale);
+        Self::with_parts(Some(renderer), text, geometry, primitives, size, scale)
+    }
+
+    /// A context that records geometry, primitives and batches like any
+    /// other, for tests to check without a device; it can't draw images or
+    /// SVGs, and `finish` only closes its layers.
+    #[cfg(test)]
+    pub(crate) fn recording(text: WgpuText, size: Size, scale: f64) -> Self {
+        Self::with_parts(None, text, VertexBuffers::new(), Vec::new(), size, scale)
+    }
 
-        Self {
+    fn with_parts(
+        renderer: Option<&'a mut WgpuRenderer>,
+        text: WgpuText,
+        geometry: VertexBuffers<GpuVertex, u32>,
+        primitives: Vec<Primitive>,
+        size: Size,
+        scale: f64,
+    ) -> Self {
+        let mut ctx = Self {
             renderer,
+            size,
+            scale,
+            target: None,
+            shares_frame: false,
             fill_tess: FillTessellator::new(),
             stroke_tess: StrokeTessellator::new(),
             geometry,
@@ -54,88 +138,2056 @@ impl<'a> WgpuRenderContext<'a> {
             cur_transform: Affine::default(),
             state_stack: Vec::new(),
             clip_stack: Vec::new(),
-            primitives: Vec::new(),
-        }
+            blend: BlendMode::default(),
+            pixel_snap: false,
+            z: 0.0,
+            alpha: 1.0,
+            blur_quality: BlurQuality::default(),
+            tolerance: None,
+            image_mipmaps: true,
+            #[cfg(feature = "debug-wireframe")]
+            debug_wireframe: false,
+            damage: None,
+            primitives,
+            gradient_stops: Vec::new(),
+            batches: vec![DrawBatch {
+                start: 0,
+                end: 0,
+                scissor: None,
+                blend: BlendMode::default(),
+                layer: None,
+                custom: None,
+                image: None,
+                shader: None,
+            }],
+            layer_stack: Vec::new(),
+            layers: Vec::new(),
+            custom_passes: Vec::new(),
+            error: None,
+            finished: false,
+        };
+        // Drawing uses the last primitive, so there has to be one before
+        // anything changes the transform.
+        ctx.add_primitive();
+        ctx
+    }
+
+    /// Create a context that resolves into `target` on `finish` instead of
+    /// presenting to the renderer's surface.
+    ///
+    /// `target` must have the renderer's [`format`](WgpuRenderer::format) and
+    /// the size last passed to [`set_size`](WgpuRenderer::set_size).
+    pub fn new_with_target(renderer: &'a mut WgpuRenderer, target: &'a wgpu::TextureView) -> Self {
+        let mut ctx = Self::new(renderer);
+        ctx.target = Some(target);
+        ctx
+    }
+
+    /// Create a context that draws into `texture` on `finish`, starting from
+    /// the clear color, to draw it as an image afterwards with
+    /// [`image_from_render_texture`](Self::image_from_render_texture).
+    pub fn new_for_texture(renderer: &'a mut WgpuRenderer, texture: &'a RenderTexture) -> Self {
+        Self::new_with_target(renderer, texture.view())
+    }
+
+    /// Create a context that draws into `frame` on `finish`, over whatever
+    /// the contexts finished in it before drew.
+    pub fn new_for_frame(renderer: &'a mut WgpuRenderer, frame: &'a Frame) -> Self {
+        let mut ctx = Self::new_with_target(renderer, frame.view());
+        ctx.shares_frame = true;
+        ctx
     }
 
     fn pop_clip(&mut self) {
         self.clip_stack.pop();
+        self.start_batch();
+    }
+
+    /// Start a new draw batch scissored to the current clip.
+    ///
+    /// Clips are axis-aligned rects, so the render pass scissor rect does the
+    /// clipping instead of a per-fragment test in the shader, which only
+    /// fades out the edges of feathered clips.
+    fn start_batch(&mut self) {
+        let start = self.geometry.indices.len() as u32;
+        let scissor = self.current_clip().copied();
+        let blend = self.blend;
+        match self.batches.last_mut() {
+            Some(batch) if batch.is_draw() && batch.start == start => {
+                batch.scissor = scissor;
+                batch.blend = blend;
+            }
+            _ => {
+                self.close_batch();
+                self.batches.push(DrawBatch {
+                    start,
+                    end: start,
+                    scissor,
+                    blend,
+                    layer: None,
+                    custom: None,
+                    image: None,
+                    shader: None,
+                });
+            }
+        }
+    }
+
+    fn close_batch(&mut self) {
+        let end = self.geometry.indices.len() as u32;
+        if let Some(batch) = self.batches.last_mut() {
+            if batch.is_draw() {
+                batch.end = end;
+            }
+        }
+    }
+
+    /// The size of the target in logical pixels; see
+    /// [`WgpuRenderer::size`].
+    pub fn size(&self) -> Size {
+        Size::new(self.size.width / self.scale, self.size.height / self.scale)
+    }
+
+    /// The size of the target in physical pixels.
+    pub fn physical_size(&self) -> Size {
+        self.size
+    }
+
+    /// Physical pixels per logical pixel.
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    /// The renderer, which only contexts made to record geometry in tests
+    /// lack; they never draw anything that needs it.
+    fn renderer(&mut self) -> &mut WgpuRenderer {
+        self.renderer
+            .as_deref_mut()
+            .expect("recording contexts have no renderer")
+    }
+
+    /// Set how everything drawn from now until the next `restore` is blended
+    /// with what's already been drawn.
+    ///
+    /// A layer opened with [`save_layer`](Self::save_layer) in a blend mode
+    /// is composited as a whole in that mode when it's restored, while
+    /// what's drawn in it starts out blended normally.
+    pub fn set_blend_mode(&mut self, mode: BlendMode) {
+        if self.blend != mode {
+            self.blend = mode;
+            self.start_batch();
+        }
+    }
+
+    /// Draw at depth `z` from now on, clamped to 0, the default, through 1.
+    /// Drawing at a higher z stays in front of drawing at a lower one
+    /// whatever order they're drawn in; at the same z, later drawing is in
+    /// front as usual.
+    ///
+    /// Depth only orders drawing between custom render passes, and layers
+    /// are composited in drawing order. Translucent drawing behind something
+    /// drawn earlier is hidden rather than blended.
+    pub fn set_z(&mut self, z: f32) {
+        self.z = z.clamp(0.0, 1.0);
+        self.add_primitive();
+    }
+
+    /// Fade everything drawn from now until the next `restore` by `alpha`,
+    /// clamped to 0 through 1, on top of the alpha in effect at the last
+    /// `save`, so alphas set in nested saves multiply.
+    ///
+    /// Unlike [`save_layer`](Self::save_layer), drawing isn't grouped first:
+    /// each fill, stroke, text run and image is faded on its own, so where
+    /// they overlap they show through each other. Custom render passes
+    /// aren't faded.
+    pub fn set_alpha(&mut self, alpha: f32) {
+        let parent = self.state_stack.last().map_or(1.0, |state| state.alpha);
+        self.alpha = parent * alpha.clamp(0.0, 1.0);
+        self.add_primitive();
+    }
+
+    /// What the frame has recorded so far. The totals for a whole frame are
+    /// kept by the renderer after `finish`; see
+    /// [`WgpuRenderer::last_frame_stats`].
+    pub fn stats(&self) -> FrameStats {
+        let layer_batches: usize = self
+            .layers
+            .iter()
+            .map(|layer| layer.batches.len())
+            .chain(self.layer_stack.iter().map(|(batches, ..)| batches.len()))
+            .sum();
+        FrameStats {
+            vertices: self.geometry.vertices.len(),
+            indices: self.geometry.indices.len(),
+            primitives: self.primitives.len(),
+            draw_batches: self.batches.len() + layer_batches,
+            layers: self.layers.len() + self.layer_stack.len(),
+            custom_passes: self.custom_passes.len(),
+        }
+    }
+
+    /// Run `f` clipped to `shape`, like `with_save`: the clip is popped when
+    /// `f` returns, even with an error, and `f`'s result is passed on.
+    pub fn with_clip(
+        &mut self,
+        shape: impl Shape,
+        f: impl FnOnce(&mut Self) -> Result<(), piet::Error>,
+    ) -> Result<(), piet::Error> {
+        self.with_save(|ctx| {
+            ctx.clip(shape);
+            f(ctx)
+        })
+    }
+
+    /// Run `f` with `transform` applied on top of the current transform,
+    /// restoring it when `f` returns, like [`with_clip`](Self::with_clip).
+    pub fn with_transform(
+        &mut self,
+        transform: Affine,
+        f: impl FnOnce(&mut Self) -> Result<(), piet::Error>,
+    ) -> Result<(), piet::Error> {
+        self.with_save(|ctx| {
+            ctx.transform(transform);
+            f(ctx)
+        })
+    }
+
+    /// Save the state and apply `transform` on top of the current
+    /// transform, until the matching [`pop_transform`](Self::pop_transform).
+    pub fn push_transform(&mut self, transform: Affine) {
+        let _ = self.save();
+        self.transform(transform);
+    }
+
+    /// Restore the state saved by the matching
+    /// [`push_transform`](Self::push_transform), including clips added since.
+    pub fn pop_transform(&mut self) -> Result<(), piet::Error> {
+        self.restore()
+    }
+
+    /// Round the edges of rects filled or stroked from now until the next
+    /// `restore` to device pixel boundaries, so thin rects and borders stay
+    /// crisp instead of blurring over fractional pixels.
+    ///
+    /// Stroke widths are rounded to whole device pixels too. Off by default.
+    pub fn set_pixel_snap(&mut self, snap: bool) {
+        self.pixel_snap = snap;
+    }
+
+    /// Record a custom render pass at this point in the drawing order.
+    ///
+    /// `f` runs during `finish`, after everything drawn before this call
+    /// and before everything drawn after it, including text; see
+    /// [`RenderPassCtx`] for how the order is kept. It draws into the current
+    /// target: the frame, or the offscreen texture of the layer being
+    /// recorded.
+    pub fn custom_render_pass(&mut self, f: impl FnOnce(&mut RenderPassCtx) + 'a) {
+        self.close_batch();
+        let start = self.geometry.indices.len() as u32;
+        self.custom_passes.push(Some(Box::new(f)));
+        self.batches.push(DrawBatch {
+            start,
+            end: start,
+            scissor: None,
+            blend: self.blend,
+            layer: None,
+            custom: Some(self.custom_passes.len() - 1),
+            image: None,
+            shader: None,
+        });
+        self.start_batch();
+    }
+
+    /// Wrap a texture that's already on the GPU, such as a video frame or a
+    /// render target, as an image for `draw_image` without copying it.
+    ///
+    /// The image holds a clone of `texture`, keeping it alive until the last
+    /// clone of the image is dropped. The texture must have been created on
+    /// the renderer's [`device`](WgpuRenderer::device) with
+    /// `TEXTURE_BINDING` usage and a filterable float format; `size` is in
+    /// pixels. Colors are sampled as stored, so use an sRGB format for sRGB
+    /// content.
+    pub fn image_from_texture(&mut self, texture: Arc<wgpu::Texture>, size: Size) -> WgpuImage {
+        let renderer = self.renderer();
+        let texture = ImageTexture::import(&renderer.device, &renderer.pipeline, texture);
+        WgpuImage {
+            width: size.width as u32,
+            height: size.height as u32,
+            pixels: Rc::new(Vec::new()),
+            texture: Rc::new(texture),
+            uv: WgpuImage::WHOLE_TEXTURE,
+            premultiplied: false,
+        }
+    }
+
+    /// Wrap what a context made with [`new_for_texture`](Self::new_for_texture)
+    /// drew into `texture` as an image, without copying it.
+    ///
+    /// The image is `texture`'s size in physical pixels. It's sampled as the
+    /// premultiplied colors the renderer draws, so translucent drawing comes
+    /// out as it was drawn, except as a pattern brush, which samples it as
+    /// straight alpha.
+    pub fn image_from_render_texture(&mut self, texture: &RenderTexture) -> WgpuImage {
+        WgpuImage {
+            premultiplied: true,
+            ..self.image_from_texture(texture.texture().clone(), texture.size())
+        }
+    }
+
+    /// Copy `images` into the shared image atlas, so drawing them together
+    /// takes as few batches as possible, and return the packed copies.
+    ///
+    /// Small images made with `make_image` are packed as they're made while
+    /// there's room; this is for packing a set, like a toolbar's icons, in
+    /// one go, tallest first. Images that are already packed, came from a
+    /// texture, or are larger than 128 pixels either way are returned as
+    /// they are.
+    pub fn pack_images(&mut self, images: &[WgpuImage]) -> Vec<WgpuImage> {
+        let mut order: Vec<usize> = (0..images.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(images[i].height));
+        let mut packed = images.to_vec();
+        for i in order {
+            let image = &images[i];
+            if image.uv != WgpuImage::WHOLE_TEXTURE || image.pixels.is_empty() {
+                continue;
+            }
+            if let Some((texture, uv)) = self.pack_image(image.width, image.height, &image.pixels) {
+                packed[i].texture = texture;
+                packed[i].uv = uv;
+            }
+        }
+        packed
+    }
+
+    fn pack_image(
+        &mut self,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+    ) -> Option<(Rc<ImageTexture>, Rect)> {
+        let renderer = self.renderer.as_deref_mut()?;
+        renderer.image_atlas.insert(
+            &renderer.device,
+            &renderer.queue,
+            &renderer.pipeline,
+            width,
+            height,
+            pixels,
+        )
+    }
+
+    fn upload_image(&mut self, width: u32, height: u32, pixels: Vec<u8>) -> WgpuImage {
+        if let Some((texture, uv)) = self.pack_image(width, height, &pixels) {
+            return WgpuImage {
+                width,
+                height,
+                pixels: Rc::new(pixels),
+                texture,
+                uv,
+                premultiplied: false,
+            };
+        }
+        let image_mipmaps = self.image_mipmaps;
+        let renderer = self.renderer();
+        let device = &renderer.device;
+        let mipmaps = if image_mipmaps {
+            Some(
+                &*renderer
+                    .mipmaps
+                    .get_or_insert_with(|| MipmapGenerator::new(device)),
+            )
+        } else {
+            None
+        };
+        let texture = ImageTexture::upload(
+            &renderer.device,
+            &renderer.queue,
+            &renderer.pipeline,
+            mipmaps,
+            width,
+            height,
+            &pixels,
+        );
+        WgpuImage {
+            width,
+            height,
+            pixels: Rc::new(pixels),
+            texture: Rc::new(texture),
+            uv: WgpuImage::WHOLE_TEXTURE,
+            premultiplied: false,
+        }
+    }
+
+    /// Draw what's added from now on with custom shader `shader`, or the
+    /// built-in one for `None`, starting a new batch if that changes it.
+    fn set_shader(&mut self, shader: Option<usize>) {
+        let start = self.geometry.indices.len() as u32;
+        let needs_batch = match self.batches.last() {
+            Some(batch) if batch.is_draw() => batch.shader != shader && batch.start != start,
+            _ => true,
+        };
+        if needs_batch {
+            self.start_batch();
+        }
+        if let Some(batch) = self.batches.last_mut() {
+            batch.shader = shader;
+        }
+    }
+
+    /// Have the current batch sample `image`, starting a new one if it
+    /// already has vertices sampling another.
+    ///
+    /// Untextured vertices ignore the image, so they can share its batch.
+    fn set_image(&mut self, image: &Rc<wgpu::BindGroup>) {
+        let start = self.geometry.indices.len() as u32;
+        let needs_batch = match self.batches.last() {
+            Some(batch) if batch.is_draw() => match batch.image {
+                Some(ref current) => !Rc::ptr_eq(current, image) && batch.start != start,
+                None => false,
+            },
+            _ => true,
+        };
+        if needs_batch {
+            self.start_batch();
+        }
+        if let Some(batch) = self.batches.last_mut() {
+            batch.image = Some(image.clone());
+        }
+    }
+
+    /// How far curves flattened from now on may stray from the true ones,
+    /// in logical pixels. Smaller values keep large curves smooth at the
+    /// cost of more vertices.
+    ///
+    /// `None`, the default, keeps them within a fiftieth of a device pixel
+    /// whatever the DPI scale. Shapes only follow the translation of the
+    /// transform, so that's the only scale that applies.
+    pub fn set_tolerance(&mut self, tolerance: Option<f64>) {
+        self.tolerance = tolerance.filter(|t| *t > 0.0 && t.is_finite());
+    }
+
+    fn tolerance(&self) -> f64 {
+        const DEVICE_TOLERANCE: f64 = 0.02;
+        self.tolerance
+            .unwrap_or(DEVICE_TOLERANCE / self.scale.max(0.01))
+    }
+
+    /// Choose how `blurred_rect` blurs from now on.
+    pub fn set_blur_quality(&mut self, quality: BlurQuality) {
+        self.blur_quality = quality;
+    }
+
+    /// Declare `rect`, in the current coordinate space, as changed since the
+    /// last frame. Once any area is declared, `finish` only redraws the
+    /// declared areas and keeps the last frame everywhere else.
+    ///
+    /// Everything overlapping the areas still has to be drawn, starting
+    /// with the background, since they aren't cleared. Custom render passes
+    /// aren't limited to them. The whole frame is redrawn when there's no
+    /// last frame to keep: on the first frame, after a resize, for surfaces
+    /// without [`set_partial_redraw`](WgpuRenderer::set_partial_redraw), and
+    /// for contexts drawing to their own target.
+    pub fn add_damage(&mut self, rect: Rect) {
+        let scale = self.scale;
+        let mut rect = self
+            .cur_transform
+            .transform_rect_bbox(rect)
+            .scale_from_origin(scale)
+            .expand();
+        let damage = self.damage.get_or_insert_with(Vec::new);
+        // Areas drawn twice would blend translucent drawing twice, so
+        // overlapping ones are merged.
+        while let Some(i) = damage.iter().position(|r| r.intersect(rect).area() > 0.0) {
+            rect = rect.union(damage.swap_remove(i));
+        }
+        damage.push(rect);
+    }
+
+    /// Whether images made from now on get mipmaps, on by default. They keep
+    /// detailed images from aliasing when drawn smaller than their size;
+    /// turn them off for images only ever drawn at their own size to save a
+    /// third of their memory. Images small enough for the image atlas never
+    /// get them.
+    pub fn set_image_mipmaps(&mut self, enabled: bool) {
+        self.image_mipmaps = enabled;
+    }
+
+    /// Whether the edges of every triangle drawn this frame are outlined in
+    /// magenta over it, off by default. Shapes drawn as signed distance
+    /// fields show as the two triangles of their quad.
+    #[cfg(feature = "debug-wireframe")]
+    pub fn set_debug_wireframe(&mut self, enabled: bool) {
+        self.debug_wireframe = enabled;
+    }
+
+    /// Replace everything drawn so far with a recorded frame, to draw it
+    /// again when the context is finished. Call it on a fresh context; layers
+    /// opened before it are dropped.
+    pub fn replay(&mut self, recording: &FrameRecording) {
+        self.geometry.vertices.clone_from(&recording.vertices);
+        self.geometry.indices.clone_from(&recording.indices);
+        self.primitives.clone_from(&recording.primitives);
+        self.gradient_stops.clone_from(&recording.gradient_stops);
+        self.layer_stack.clear();
+        self.layers.clear();
+        self.custom_passes.clear();
+        self.batches = recording.draw_batches();
+        // Indices past the last recorded batch belonged to layers, so
+        // anything drawn after this starts a batch of its own.
+        let end = self.geometry.indices.len() as u32;
+        self.batches.push(DrawBatch {
+            start: end,
+            end,
+            scissor: self.current_clip().copied(),
+            blend: self.blend,
+            layer: None,
+            custom: None,
+            image: None,
+            shader: None,
+        });
+    }
+
+    /// Like `save`, but everything drawn until the matching `restore` is
+    /// rendered to an offscreen layer first and then composited with `alpha`
+    /// as a group. The layer's texture covers only the current clip, so
+    /// clipping to the group first keeps small groups cheap.
+    pub fn save_layer(&mut self, alpha: f64) -> Result<(), piet::Error> {
+        self.push_layer(alpha.clamp(0.0, 1.0) as f32, 0.0, None)
+    }
+
+    /// Open a layer. Only what's drawn within `bounds`, in user space, and
+    /// the current clip reaches it; without `bounds` it covers the clip.
+    fn push_layer(
+        &mut self,
+        alpha: f32,
+        blur: f32,
+        bounds: Option<Rect>,
+    ) -> Result<(), piet::Error> {
+        let bounds = self.layer_bounds(bounds);
+        self.save()?;
+        if let Some(state) = self.state_stack.last_mut() {
+            state.layer = true;
+        }
+        self.close_batch();
+        // The layer is composited with the blend mode it was opened in, which
+        // `restore` brings back; its content is drawn normally.
+        self.blend = BlendMode::SourceOver;
+        let start = self.geometry.indices.len() as u32;
+        let scissor = self.current_clip().copied();
+        let parent = std::mem::replace(
+            &mut self.batches,
+            vec![DrawBatch {
+                start,
+                end: start,
+                scissor,
+                blend: self.blend,
+                layer: None,
+                custom: None,
+                image: None,
+                shader: None,
+            }],
+        );
+        self.layer_stack.push((parent, alpha, blur, bounds));
+        Ok(())
+    }
+
+    /// The device pixels of the frame a layer covering `bounds`, in user
+    /// space, draws to, within the current clip.
+    fn layer_bounds(&self, bounds: Option<Rect>) -> Rect {
+        let affine = self.cur_transform.as_coeffs();
+        let frame = Rect::from_origin_size(Point::ZERO, self.size);
+        // A pixel of slack for anti-aliasing and snapping to pixels.
+        let bounds = bounds
+            .map(|rect| rect.inflate(1.0, 1.0) + Vec2::new(affine[4], affine[5]))
+            .into_iter()
+            .chain(self.current_clip().copied())
+            .fold(None, |acc: Option<Rect>, rect| {
+                Some(acc.map_or(rect, |acc| acc.intersect(rect)))
+            });
+        match bounds {
+            Some(bounds) if bounds.is_finite() => bounds
+                .scale_from_origin(self.scale)
+                .expand()
+                .intersect(frame),
+            _ => frame,
+        }
+    }
+
+    /// Finish the innermost layer and composite it into its parent.
+    fn end_layer(&mut self) {
+        let (parent, alpha, blur, bounds) = match self.layer_stack.pop() {
+            Some(layer) => layer,
+            None => return,
+        };
+        self.close_batch();
+        let batches = std::mem::replace(&mut self.batches, parent);
+        // Custom passes draw to the whole target, and get the frame's depth
+        // buffer, so their layers cover the frame.
+        let bounds = match batches.iter().any(|batch| batch.custom.is_some()) {
+            true => Rect::from_origin_size(Point::ZERO, self.size),
+            false => bounds,
+        };
+        self.layers.push(Layer {
+            batches,
+            alpha,
+            blur,
+            bounds,
+        });
+        let start = self.geometry.indices.len() as u32;
+        self.batches.push(DrawBatch {
+            start,
+            end: start,
+            scissor: self.current_clip().copied(),
+            blend: self.blend,
+            layer: Some(self.layers.len() - 1),
+            custom: None,
+            image: None,
+            shader: None,
+        });
+        self.start_batch();
     }
 
     pub(crate) fn current_clip(&self) -> Option<&Rect> {
-        self.clip_stack.last()
+        self.clip_stack.last().map(|(rect, _)| rect)
+    }
+
+    /// Like `clip`, but with anti-aliased edges: coverage falls off from
+    /// `feather` logical pixels inside the clip to nothing at its edge, so
+    /// clipped content doesn't end in a hard pixel step. A `feather` of 0 is
+    /// the same as `clip`.
+    ///
+    /// Only rects clip, as with `clip`. Clipping inside a feathered clip
+    /// feathers every edge of the intersection by the larger of the two.
+    pub fn clip_feathered(&mut self, shape: impl Shape, feather: f64) {
+        if let Some(rect) = shape.as_rect() {
+            let affine = self.cur_transform.as_coeffs();
+            let rect = rect + Vec2::new(affine[4], affine[5]);
+            let feather = feather.max(0.0);
+            let clip = match self.clip_stack.last() {
+                Some(&(clip, clip_feather)) => (clip.intersect(rect), clip_feather.max(feather)),
+                None => (rect, feather),
+            };
+            self.clip_stack.push(clip);
+            if let Some(state) = self.state_stack.last_mut() {
+                state.n_clip += 1;
+            }
+            self.start_batch();
+            self.add_primitive();
+        }
+    }
+
+    /// Set `primitive` to fade out at the edges of the current clip, if it's
+    /// feathered; hard clips are left to the scissor rect.
+    fn feather_clip(&self, primitive: &mut Primitive) {
+        if let Some(&(clip, feather)) = self.clip_stack.last() {
+            if feather > 0.0 {
+                primitive.clip = 1.0;
+                primitive.clip_rect = [
+                    clip.x0 as f32,
+                    clip.y0 as f32,
+                    clip.x1 as f32,
+                    clip.y1 as f32,
+                ];
+                primitive.clip_feather = feather as f32;
+            }
+        }
+    }
+
+    /// The active clip in the context's current coordinate space, or `None`
+    /// when nothing is clipped. Drawing entirely outside it can be skipped.
+    ///
+    /// Clips only follow the translation of the transform, so the rect is
+    /// mapped back by the current translation alone.
+    pub fn current_clip_rect(&self) -> Option<Rect> {
+        let affine = self.cur_transform.as_coeffs();
+        self.current_clip()
+            .map(|clip| *clip - Vec2::new(affine[4], affine[5]))
     }
 
     fn add_primitive(&mut self) {
         let affine = self.cur_transform.as_coeffs();
-        let translate = [affine[4] as f32, affine[5] as f32];
-        let (clip, clip_rect) = self
-            .current_clip()
-            .map(|r| (1.0, [r.x0 as f32, r.y0 as f32, r.x1 as f32, r.y1 as f32]))
-            .unwrap_or((0.0, [0.0, 0.0, 0.0, 0.0]));
-        self.primitives.push(Primitive {
+        let mut translate = [affine[4] as f32, affine[5] as f32];
+        if !translate.iter().all(|t| t.is_finite()) {
+            self.record_error(piet::Error::InvalidInput);
+            translate = [0.0, 0.0];
+        }
+        let mut primitive = Primitive {
             translate,
-            clip,
-            clip_rect,
+            z: self.z,
+            alpha: self.alpha,
+            ..Default::default()
+        };
+        self.feather_clip(&mut primitive);
+        self.primitives.push(primitive);
+    }
+
+    fn fill_with_rule(
+        &mut self,
+        shape: impl Shape,
+        brush: &impl IntoBrush<Self>,
+        fill_rule: tessellation::FillRule,
+    ) {
+        match self.snap_rect(&shape, 0.0) {
+            Some((rect, _)) => self.fill_shape(rect, brush, fill_rule),
+            None => self.fill_shape(shape, brush, fill_rule),
+        }
+    }
+
+    fn fill_shape(
+        &mut self,
+        shape: impl Shape,
+        brush: &impl IntoBrush<Self>,
+        fill_rule: tessellation::FillRule,
+    ) {
+        if let Some(rounded_rect) = shape.as_rounded_rect() {
+            self.fill_rounded_rect(rounded_rect.rect(), rounded_rect.radii(), brush);
+            return;
+        }
+        if let Some(circle) = shape.as_circle() {
+            self.fill_rounded_rect(circle.bounding_box(), circle.radius, brush);
+            return;
+        }
+        let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
+        let color = self.fill_color(&brush);
+        let primitive_id = self.primitives.len() as u32 - 1;
+        let vertex_start = self.geometry.vertices.len();
+        self.fill_tessellated(shape, color, primitive_id, fill_rule);
+        self.end_brush(&brush, vertex_start);
+    }
+
+    /// The vertex color to fill with `brush`.
+    ///
+    /// Gradients and patterns get a primitive of their own, with the vertex
+    /// color left white so they show through unchanged; call `end_brush`
+    /// after drawing with one.
+    fn fill_color(&mut self, brush: &Brush) -> [f32; 4] {
+        match (brush, brush.gradient()) {
+            (Brush::Solid(color), _) => format_color(color),
+            (Brush::Pattern { image, extend, .. }, _) => {
+                self.set_image(image.texture.bind_group(InterpolationMode::Bilinear));
+                self.add_primitive();
+                let primitive = self.primitives.last_mut().unwrap();
+                let uv = image.uv;
+                primitive.gradient = [uv.x0 as f32, uv.y0 as f32, uv.x1 as f32, uv.y1 as f32];
+                primitive.gradient_extend = extend.to_f32();
+                [1.0, 1.0, 1.0, 1.0]
+            }
+            (_, Some(gradient)) => {
+                self.add_primitive();
+                gradient.apply(
+                    self.primitives.last_mut().unwrap(),
+                    &mut self.gradient_stops,
+                );
+                [1.0, 1.0, 1.0, 1.0]
+            }
+            (_, None) => format_color(&brush.solid_color()),
+        }
+    }
+
+    /// Finish drawing the vertices from `vertex_start` on with `brush`,
+    /// pointing them at the pattern's image if it's a pattern, and end the
+    /// primitive `fill_color` started for it.
+    fn end_brush(&mut self, brush: &Brush, vertex_start: usize) {
+        match brush {
+            Brush::Solid(_) => return,
+            Brush::Pattern {
+                image, transform, ..
+            } => {
+                // `tex_pos` counts images across and down the pattern, so
+                // the shader wraps it with `fract`.
+                let empty = image.width == 0 || image.height == 0;
+                let to_pattern = if transform.determinant() != 0.0 && !empty {
+                    Affine::scale_non_uniform(1.0 / image.width as f64, 1.0 / image.height as f64)
+                        * transform.inverse()
+                } else {
+                    Affine::scale(0.0)
+                };
+                for vertex in &mut self.geometry.vertices[vertex_start..] {
+                    let p = to_pattern * Point::new(vertex.pos[0] as f64, vertex.pos[1] as f64);
+                    vertex.tex = 6.0;
+                    vertex.tex_pos = [p.x as f32, p.y as f32];
+                }
+            }
+            _ => {}
+        }
+        self.add_primitive();
+    }
+
+    fn fill_tessellated(
+        &mut self,
+        shape: impl Shape,
+        color: [f32; 4],
+        primitive_id: u32,
+        fill_rule: tessellation::FillRule,
+    ) {
+        if let Some(rect) = shape.as_rect() {
+            self.fill_quad(rect, color, primitive_id);
+            return;
+        }
+        let cache_key = match self.cached_geometry(
+            &shape,
+            ("fill", fill_rule == tessellation::FillRule::EvenOdd),
+            color,
+            primitive_id,
+        ) {
+            Ok(()) => return,
+            Err(key) => key,
+        };
+        let vertex_start = self.geometry.vertices.len();
+        let index_start = self.geometry.indices.len();
+        let tolerance = self.tolerance();
+        let options = FillOptions::tolerance(tolerance as f32).with_fill_rule(fill_rule);
+        let mut builder = BuffersBuilder::new(&mut self.geometry, |vertex: FillVertex| GpuVertex {
+            pos: vertex.position().to_array(),
+            color,
+            primitive_id,
             ..Default::default()
         });
+
+        let path = shape_to_path(&shape, tolerance / 2.0);
+        let result = self
+            .fill_tess
+            .tessellate_path(&path, &options, &mut builder);
+        if self.check_tessellation(result) {
+            self.cache_geometry(cache_key, vertex_start, index_start);
+        }
+    }
+
+    /// Fill `rect` with two triangles, written straight into the geometry.
+    ///
+    /// Rects are most of what UIs draw, so this skips the tessellator and
+    /// the geometry cache, emitting the same vertices and indices lyon's
+    /// `tessellate_rectangle` would.
+    fn fill_quad(&mut self, rect: Rect, color: [f32; 4], primitive_id: u32) {
+        let (x0, y0) = (rect.x0 as f32, rect.y0 as f32);
+        let (x1, y1) = (x0 + rect.width() as f32, y0 + rect.height() as f32);
+        let offset = self.geometry.vertices.len() as u32;
+        self.geometry
+            .vertices
+            .extend(
+                [[x0, y0], [x0, y1], [x1, y1], [x1, y0]].map(|pos| GpuVertex {
+                    pos,
+                    color,
+                    primitive_id,
+                    ..Default::default()
+                }),
+            );
+        self.geometry
+            .indices
+            .extend([0, 1, 2, 0, 2, 3].map(|i| offset + i));
+    }
+
+    /// Fill `rect` with each corner rounded by its own radius.
+    ///
+    /// This draws a single quad whose edges are anti-aliased from a distance
+    /// field in the shader, which is cheaper than tessellating the shape and
+    /// stays smooth without MSAA. Radii are clamped to half the rect's
+    /// shorter side.
+    pub fn fill_rounded_rect(
+        &mut self,
+        rect: Rect,
+        radii: impl Into<RoundedRectRadii>,
+        brush: &impl IntoBrush<Self>,
+    ) {
+        let rect = rect.abs();
+        let max_radius = rect.width().min(rect.height()) / 2.0;
+        let radii = radii.into();
+        let radii = [
+            radii.top_left,
+            radii.top_right,
+            radii.bottom_right,
+            radii.bottom_left,
+        ]
+        .map(|radius| radius.clamp(0.0, max_radius) as f32);
+        let shape = SdfShape {
+            center: rect.center(),
+            axis: Vec2::new(1.0, 0.0),
+            size: [rect.width() as f32 / 2.0, rect.height() as f32 / 2.0],
+            radii,
+            kind: SdfKind::RoundedBox,
+        };
+        self.fill_sdf(shape, rect, brush);
+    }
+
+    /// Fill a circle as a single anti-aliased quad, like
+    /// [`fill_rounded_rect`](Self::fill_rounded_rect).
+    pub fn fill_circle(
+        &mut self,
+        center: impl Into<Point>,
+        radius: f64,
+        brush: &impl IntoBrush<Self>,
+    ) {
+        let circle = Circle::new(center, radius.abs());
+        self.fill_rounded_rect(circle.bounding_box(), circle.radius, brush);
+    }
+
+    /// Fill an ellipse, rotated or not, as a single anti-aliased quad, like
+    /// [`fill_rounded_rect`](Self::fill_rounded_rect).
+    ///
+    /// `fill` tessellates ellipses, since they can't be told apart from
+    /// other paths there.
+    pub fn fill_ellipse(&mut self, ellipse: Ellipse, brush: &impl IntoBrush<Self>) {
+        let radii = ellipse.radii();
+        if radii.x == 0.0 || radii.y == 0.0 {
+            return;
+        }
+        let rotation = ellipse.rotation();
+        let shape = SdfShape {
+            center: ellipse.center(),
+            axis: Vec2::new(rotation.cos(), rotation.sin()),
+            size: [radii.x.abs() as f32, radii.y.abs() as f32],
+            radii: [0.0; 4],
+            kind: SdfKind::Ellipse,
+        };
+        self.fill_sdf(shape, ellipse.bounding_box(), brush);
+    }
+
+    /// Fill the slice of an ellipse swept from `start_angle` by
+    /// `sweep_angle`, in radians as for a `kurbo::Arc`: the arc closed by
+    /// lines to and from `center`. `fill` closes an arc with a chord instead.
+    ///
+    /// Slices of circles are drawn as a single anti-aliased quad, like
+    /// [`fill_ellipse`](Self::fill_ellipse).
+    pub fn fill_arc(
+        &mut self,
+        center: impl Into<Point>,
+        radii: impl Into<Vec2>,
+        start_angle: f64,
+        sweep_angle: f64,
+        brush: &impl IntoBrush<Self>,
+    ) {
+        let (center, radii) = (center.into(), radii.into());
+        let radii = Vec2::new(radii.x.abs(), radii.y.abs());
+        if radii.x == 0.0 || radii.y == 0.0 || sweep_angle == 0.0 || !sweep_angle.is_finite() {
+            return;
+        }
+        if sweep_angle.abs() >= 2.0 * std::f64::consts::PI {
+            self.fill_ellipse(Ellipse::new(center, radii, 0.0), brush);
+            return;
+        }
+        let arc = piet::kurbo::Arc {
+            center,
+            radii,
+            start_angle,
+            sweep_angle,
+            x_rotation: 0.0,
+        };
+        if radii.x != radii.y {
+            let path = pie_path(&arc, self.tolerance() / 2.0);
+            self.fill_shape(path, brush, tessellation::FillRule::NonZero);
+            return;
+        }
+        let half_sweep = sweep_angle.abs() / 2.0;
+        let shape = SdfShape {
+            center,
+            axis: Vec2::from_angle(start_angle + sweep_angle / 2.0),
+            size: [radii.x as f32; 2],
+            radii: [half_sweep.sin() as f32, half_sweep.cos() as f32, 0.0, 0.0],
+            kind: SdfKind::Pie,
+        };
+        let bounds = pie_path(&arc, self.tolerance() / 2.0).bounding_box();
+        self.fill_sdf(shape, bounds, brush);
+    }
+
+    fn fill_sdf(&mut self, shape: SdfShape, bounds: Rect, brush: &impl IntoBrush<Self>) {
+        let brush = brush.make_brush(self, || bounds).into_owned();
+        if let Brush::Pattern { .. } = brush {
+            // Patterns need `tex_pos` for the image, which the quad uses
+            // for its distance field, so tessellate the shape instead.
+            let path = shape.to_path(self.tolerance() / 2.0);
+            self.fill_shape(path, &brush, tessellation::FillRule::NonZero);
+            return;
+        }
+        let color = self.fill_color(&brush);
+        let primitive_id = self.primitives.len() as u32 - 1;
+        let vertex_start = self.geometry.vertices.len();
+        self.push_sdf_quad(shape, color, primitive_id);
+        self.end_brush(&brush, vertex_start);
+    }
+
+    /// Stroke `line` with round caps as a single anti-aliased capsule.
+    fn stroke_line_sdf(&mut self, line: Line, color: [f32; 4], primitive_id: u32, width: f64) {
+        let length = line.length();
+        let axis = if length > 0.0 {
+            (line.p1 - line.p0) / length
+        } else {
+            Vec2::new(1.0, 0.0)
+        };
+        let radius = width as f32 / 2.0;
+        let shape = SdfShape {
+            center: line.p0.midpoint(line.p1),
+            axis,
+            size: [length as f32 / 2.0 + radius, radius],
+            radii: [radius; 4],
+            kind: SdfKind::RoundedBox,
+        };
+        self.push_sdf_quad(shape, color, primitive_id);
+    }
+
+    /// Emit a quad covering `shape`, with room around it for the
+    /// anti-aliased edge.
+    fn push_sdf_quad(&mut self, shape: SdfShape, color: [f32; 4], primitive_id: u32) {
+        let (axis, normal) = (shape.axis, Vec2::new(-shape.axis.y, shape.axis.x));
+        let [w, h] = [shape.size[0] + 1.0, shape.size[1] + 1.0];
+        let offset = self.geometry.vertices.len() as u32;
+        for local in [[-w, -h], [-w, h], [w, h], [w, -h]] {
+            let pos = shape.center + axis * local[0] as f64 + normal * local[1] as f64;
+            self.geometry.vertices.push(GpuVertex {
+                pos: [pos.x as f32, pos.y as f32],
+                color,
+                tex: shape.kind.tex(),
+                tex_pos: local,
+                primitive_id,
+                sdf_size: shape.size,
+                sdf_radii: shape.radii,
+                ..Default::default()
+            });
+        }
+        self.geometry.indices.extend_from_slice(&[
+            offset,
+            offset + 1,
+            offset + 2,
+            offset,
+            offset + 2,
+            offset + 3,
+        ]);
+    }
+
+    /// Draw a blurred rounded rect, e.g. the shadow of a card.
+    ///
+    /// `radius` is the corner radius of the unblurred rect and is independent
+    /// of `blur_radius`.
+    pub fn blurred_rounded_rect(
+        &mut self,
+        rect: Rect,
+        radius: f64,
+        blur_radius: f64,
+        brush: &impl IntoBrush<Self>,
+    ) {
+        if radius > 0.0 && blur_radius <= 0.0 {
+            self.fill(rect.to_rounded_rect(radius), brush);
+            return;
+        }
+        // The analytic blur of a sharp rect is exact, so only rounded corners
+        // need the offscreen blur.
+        if self.blur_quality == BlurQuality::Gaussian && blur_radius > 0.0 && radius > 0.0 {
+            let brush = brush.make_brush(self, || rect).into_owned();
+            let bounds = rect.inflate(3.0 * blur_radius, 3.0 * blur_radius);
+            if self
+                .push_layer(1.0, blur_radius as f32, Some(bounds))
+                .is_ok()
+            {
+                self.fill(rect.to_rounded_rect(radius), &brush);
+                let _ = self.restore();
+            }
+            return;
+        }
+        let blur_rect = rect;
+        let rect = rect.inflate(3.0 * blur_radius, 3.0 * blur_radius);
+        let brush = brush.make_brush(self, || rect).into_owned();
+        let (color, primitive_id) =
+            self.blur_primitive(&brush, blur_rect, radius, blur_radius as f32);
+        self.fill_quad(rect, color, primitive_id);
+        self.add_primitive();
+    }
+
+    /// Draw a soft shadow of `shape` in `color`, moved by `offset` and
+    /// blurred by `blur_radius`, e.g. under a round floating button. Draw
+    /// the shape itself over it afterwards.
+    ///
+    /// Rects, circles and rounded rects with equal corners are shadowed
+    /// like [`blurred_rounded_rect`](Self::blurred_rounded_rect). Other
+    /// shapes are filled into an offscreen layer the size of the shadow and
+    /// blurred with the separable Gaussian of [`BlurQuality::Gaussian`],
+    /// whatever the blur quality, so the shadow follows any silhouette.
+    pub fn shape_shadow(
+        &mut self,
+        shape: impl Shape,
+        offset: Vec2,
+        blur_radius: f64,
+        color: Color,
+    ) {
+        if blur_radius <= 0.0 {
+            let _ = self.with_transform(Affine::translate(offset), |ctx| {
+                ctx.fill(shape, &color);
+                Ok(())
+            });
+            return;
+        }
+        let rounded = match (shape.as_rect(), shape.as_rounded_rect(), shape.as_circle()) {
+            (Some(rect), ..) => Some((rect, 0.0)),
+            (_, Some(rect), _) => {
+                let radii = rect.radii();
+                let equal = [radii.top_right, radii.bottom_right, radii.bottom_left]
+                    .iter()
+                    .all(|radius| *radius == radii.top_left);
+                match equal {
+                    true => Some((rect.rect(), radii.top_left)),
+                    false => None,
+                }
+            }
+            (_, _, Some(circle)) => Some((circle.bounding_box(), circle.radius)),
+            _ => None,
+        };
+        if let Some((rect, radius)) = rounded {
+            self.blurred_rounded_rect(rect + offset, radius, blur_radius, &color);
+            return;
+        }
+
+        let bounds = (shape.bounding_box() + offset).inflate(3.0 * blur_radius, 3.0 * blur_radius);
+        if self
+            .push_layer(1.0, blur_radius as f32, Some(bounds))
+            .is_ok()
+        {
+            self.transform(Affine::translate(offset));
+            self.fill(shape, &color);
+            let _ = self.restore();
+        }
+    }
+
+    /// Draw an inset shadow inside `rect`, as of a pressed button: `brush`
+    /// darkens the rect near its edges, fading out inwards over
+    /// `blur_radius`, like CSS's `inset` box shadow.
+    ///
+    /// `radius` rounds the corners of the rect, which the shadow is clipped
+    /// to, and of the unshadowed area in the middle. `offset` moves that
+    /// area, so the shadow is deeper on the opposite side. The blur is always
+    /// the fast approximation, whatever the
+    /// [blur quality](Self::set_blur_quality).
+    pub fn inset_blurred_rounded_rect(
+        &mut self,
+        rect: Rect,
+        radius: f64,
+        blur_radius: f64,
+        offset: Vec2,
+        brush: &impl IntoBrush<Self>,
+    ) {
+        let rect = rect.abs();
+        let radius = radius.clamp(0.0, rect.width().min(rect.height()) / 2.0);
+        let brush = brush.make_brush(self, || rect).into_owned();
+        // A radius of 0 has no falloff to compute, so it's kept just above.
+        let blur_radius = blur_radius.max(1e-3) as f32;
+        let (color, primitive_id) =
+            self.blur_primitive(&brush, rect + offset, radius, -blur_radius);
+        let shape = SdfShape {
+            center: rect.center(),
+            axis: Vec2::new(1.0, 0.0),
+            size: [rect.width() as f32 / 2.0, rect.height() as f32 / 2.0],
+            radii: [radius as f32; 4],
+            kind: SdfKind::RoundedBox,
+        };
+        self.push_sdf_quad(shape, color, primitive_id);
+        self.add_primitive();
+    }
+
+    /// Start a primitive that fades what's drawn with it by the blurred
+    /// coverage of `blur_rect` with corners of `radius`, inverted for a
+    /// negative `blur_radius`, and return the vertex color to draw `brush`
+    /// with and the primitive.
+    fn blur_primitive(
+        &mut self,
+        brush: &Brush,
+        blur_rect: Rect,
+        radius: f64,
+        blur_radius: f32,
+    ) -> ([f32; 4], u32) {
+        let radius = radius
+            .min(blur_rect.width() / 2.0)
+            .min(blur_rect.height() / 2.0)
+            .max(0.0);
+        // The blur falloff multiplies the gradient per fragment, so gradients
+        // share the blur's primitive.
+        let gradient = brush.gradient();
+        let color = match (brush, &gradient) {
+            (Brush::Solid(color), _) => format_color(color),
+            (_, Some(_)) => [1.0, 1.0, 1.0, 1.0],
+            (_, None) => format_color(&brush.solid_color()),
+        };
+
+        self.add_primitive();
+        let primitive = self.primitives.last_mut().unwrap();
+        if let Some(gradient) = gradient {
+            gradient.apply(primitive, &mut self.gradient_stops);
+        }
+        primitive.blur_radius = blur_radius;
+        primitive.blur_corner_radius = radius as f32;
+        primitive.blur_rect = [
+            blur_rect.x0 as f32,
+            blur_rect.y0 as f32,
+            blur_rect.x1 as f32,
+            blur_rect.y1 as f32,
+        ];
+        (color, self.primitives.len() as u32 - 1)
+    }
+
+    /// Draw `layout` at `pos` with its glyphs outlined by a stroke of
+    /// `width` in `outline`, e.g. light text with a dark outline over imagery.
+    ///
+    /// The stroke is drawn under the fill, so only its outer half shows.
+    pub fn draw_text_outlined(
+        &mut self,
+        layout: &WgpuTextLayout,
+        pos: impl Into<Point>,
+        outline: &impl IntoBrush<Self>,
+        width: f64,
+    ) {
+        let pos = pos.into();
+        let path = Affine::translate(pos.to_vec2()) * layout.outline();
+        self.stroke(path, outline, width);
+        self.draw_text(layout, pos);
+    }
+
+    /// Fill `shape` with the colors `shader` computes, passing it `params`.
+    /// See [`CustomShader`] for what the shader is given.
+    pub fn fill_with_shader(&mut self, shape: impl Shape, shader: CustomShader, params: [f32; 4]) {
+        let bounds = shape.bounding_box();
+        let (primitive_id, vertex_start) = self.begin_shader(shader, params);
+        self.fill_tessellated(
+            shape,
+            [1.0; 4],
+            primitive_id,
+            tessellation::FillRule::NonZero,
+        );
+        self.end_shader(bounds, vertex_start);
+    }
+
+    /// Stroke `shape` with the colors `shader` computes, like
+    /// [`fill_with_shader`](Self::fill_with_shader).
+    pub fn stroke_with_shader(
+        &mut self,
+        shape: impl Shape,
+        shader: CustomShader,
+        width: f64,
+        style: &StrokeStyle,
+        params: [f32; 4],
+    ) {
+        let bounds = shape.bounding_box().inflate(width / 2.0, width / 2.0);
+        let (primitive_id, vertex_start) = self.begin_shader(shader, params);
+        self.stroke_tessellated(shape, [1.0; 4], primitive_id, width, style);
+        self.end_shader(bounds, vertex_start);
+    }
+
+    /// Start drawing with `shader` in a primitive of its own holding
+    /// `params`, returning the primitive and where its vertices start.
+    fn begin_shader(&mut self, shader: CustomShader, params: [f32; 4]) -> (u32, usize) {
+        self.add_primitive();
+        self.primitives.last_mut().unwrap().gradient = params;
+        self.set_shader(Some(shader.index));
+        (
+            self.primitives.len() as u32 - 1,
+            self.geometry.vertices.len(),
+        )
+    }
+
+    /// Give the vertices drawn with a custom shader their position across
+    /// `bounds` and go back to the built-in shader.
+    fn end_shader(&mut self, bounds: Rect, vertex_start: usize) {
+        let size = Vec2::new(bounds.width().max(1e-6), bounds.height().max(1e-6));
+        for vertex in &mut self.geometry.vertices[vertex_start..] {
+            vertex.tex = 0.0;
+            vertex.tex_pos = [
+                ((vertex.pos[0] as f64 - bounds.x0) / size.x) as f32,
+                ((vertex.pos[1] as f64 - bounds.y0) / size.y) as f32,
+            ];
+        }
+        self.set_shader(None);
+        self.add_primitive();
+    }
+
+    /// Draw `layout` with `transform` applied on top of the current
+    /// transform, e.g. rotated a quarter turn for a chart's vertical axis
+    /// label.
+    ///
+    /// Unlike the current transform, which only moves text, `transform` may
+    /// rotate, scale and skew it. Glyphs are rasterized upright at the
+    /// renderer's scale and their quads transformed, so text scaled up
+    /// comes out softer than text laid out at the larger size.
+    pub fn draw_text_transformed(&mut self, layout: &WgpuTextLayout, transform: Affine) {
+        let [a, b, c, d, _, _] = transform.as_coeffs();
+        if [a, b, c, d] == [1.0, 0.0, 0.0, 1.0] {
+            layout.draw_text(self, transform);
+            return;
+        }
+        self.add_primitive();
+        self.primitives.last_mut().unwrap().transform_1 = [a as f32, b as f32, c as f32, d as f32];
+        layout.draw_text(self, transform);
+        self.add_primitive();
+    }
+
+    /// Keep tessellated shapes across frames and reuse them when the same
+    /// shape is filled or stroked again with the same parameters.
+    ///
+    /// Entries that go unused for 60 frames are evicted, as are the least
+    /// recently used ones once the cache holds 32 MiB. Disabling the cache
+    /// drops everything in it.
+    pub fn enable_geometry_cache(&mut self, enabled: bool) {
+        if let Some(renderer) = self.renderer.as_deref_mut() {
+            let cache = &mut renderer.geometry_cache;
+            cache.enabled = enabled;
+            if !enabled {
+                cache.clear();
+            }
+        }
+    }
+
+    /// Emit cached geometry for `shape`, or return the key to cache it under
+    /// once it's been tessellated.
+    fn cached_geometry(
+        &mut self,
+        shape: &impl Shape,
+        params: impl std::hash::Hash,
+        color: [f32; 4],
+        primitive_id: u32,
+    ) -> Result<(), Option<GeometryKey>> {
+        let tolerance = self.tolerance();
+        let cache = match self.renderer.as_deref_mut() {
+            Some(renderer) if renderer.geometry_cache.enabled => &mut renderer.geometry_cache,
+            _ => return Err(None),
+        };
+        let key = geometry_key(shape, (params, tolerance.to_bits()));
+        if cache.emit(&key, &mut self.geometry, color, primitive_id) {
+            Ok(())
+        } else {
+            Err(Some(key))
+        }
+    }
+
+    fn cache_geometry(
+        &mut self,
+        key: Option<GeometryKey>,
+        vertex_start: usize,
+        index_start: usize,
+    ) {
+        if let (Some(key), Some(renderer)) = (key, self.renderer.as_deref_mut()) {
+            renderer
+                .geometry_cache
+                .insert(key, &self.geometry, vertex_start, index_start);
+        }
+    }
+
+    /// Remember the first error hit while drawing so `status` can report it.
+    fn record_error(&mut self, error: piet::Error) {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+    }
+
+    /// Draw the recorded frame with `renderer` and submit it.
+    fn submit(&mut self, renderer: &mut WgpuRenderer) -> Result<(), piet::Error> {
+        renderer.last_frame_stats = self.stats();
+        if renderer.record_frames {
+            renderer.frame_recording = Some(FrameRecording::new(
+                renderer.size,
+                &self.geometry,
+                &self.primitives,
+                &self.gradient_stops,
+                &self.batches,
+            ));
+        }
+
+        renderer.ensure_encoder();
+        let mut encoder = renderer.take_encoder();
+
+        #[cfg(feature = "debug-wireframe")]
+        {
+            renderer.pipeline.debug_wireframe = self.debug_wireframe;
+        }
+        let uploaded = renderer.pipeline.upload_data(
+            &renderer.device,
+            &mut renderer.staging_belt.lock().unwrap(),
+            &mut encoder,
+            &self.geometry,
+            &self.primitives,
+            &self.gradient_stops,
+            &self.layers,
+        );
+        if let Err(e) = uploaded {
+            self.record_error(e);
+        }
+
+        let surface_texture = match self.target {
+            Some(_) => None,
+            None => match renderer.acquire_surface_texture() {
+                Some(Ok(texture)) => Some(texture),
+                Some(Err(e)) => {
+                    self.record_error(piet::Error::BackendError(Box::new(e.clone())));
+                    return Err(piet::Error::BackendError(Box::new(e)));
+                }
+                None => None,
+            },
+        };
+        let surface_view = surface_texture.as_ref().map(|texture| {
+            texture
+                .texture
+                .create_view(&wgpu::TextureViewDescriptor::default())
+        });
+        // With partial redraws on, surface frames are drawn into a texture
+        // that's kept, then copied onto the surface.
+        let retained_view = match surface_view {
+            Some(_) if renderer.keeps_surface_frames() => Some(renderer.retained_view()),
+            _ => None,
+        };
+        let keeps_frame =
+            self.target.is_none() && (surface_view.is_none() || retained_view.is_some());
+        let damage = if self.shares_frame && renderer.frame_drawn {
+            // Keeps what earlier contexts drew by damaging the whole frame.
+            let size = renderer.size;
+            Some(vec![Rect::new(0.0, 0.0, size.width, size.height)])
+        } else {
+            self.damage
+                .take()
+                .filter(|_| keeps_frame && renderer.frame_kept)
+        };
+        renderer.frame_drawn |= self.shares_frame;
+        let owned_view;
+        let frame_view = retained_view.as_ref().or(surface_view.as_ref());
+        let view = match (self.target, frame_view, &renderer.offscreen) {
+            (Some(target), _, _) => target,
+            (None, Some(view), _) => view,
+            (None, None, Some(texture)) => {
+                owned_view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+                &owned_view
+            }
+            (None, None, None) => return Err(piet::Error::InvalidInput),
+        };
+
+        if renderer.depth.is_none() {
+            renderer.depth = Some(create_depth(
+                &renderer.device,
+                renderer.sample_count,
+                (renderer.size.width as u32).max(1),
+                (renderer.size.height as u32).max(1),
+            ));
+        }
+        #[cfg(feature = "timestamps")]
+        let timer = &mut renderer.timer;
+        #[cfg(feature = "timestamps")]
+        if let Some(timer) = timer {
+            renderer.device.poll(wgpu::Maintain::Poll);
+            renderer.local_pool.run_until_stalled();
+            timer.collect();
+            timer.begin_frame(&mut encoder);
+        }
+        let (device, queue) = (&renderer.device, &renderer.queue);
+        let (format, sample_count, size) = (renderer.format, renderer.sample_count, renderer.size);
+        let depth = renderer.depth.as_ref().expect("depth buffer created above");
+        let custom_passes = &mut self.custom_passes;
+        renderer.pipeline.draw(
+            device,
+            &mut encoder,
+            view,
+            renderer.msaa.as_ref(),
+            depth,
+            damage.as_deref(),
+            &self.batches,
+            &self.layers,
+            &mut |index, encoder, view, msaa| {
+                if let Some(f) = custom_passes[index].take() {
+                    #[cfg(feature = "timestamps")]
+                    if let Some(timer) = timer {
+                        timer.begin_custom_pass(encoder);
+                    }
+                    f(&mut RenderPassCtx {
+                        device,
+                        queue,
+                        encoder,
+                        view,
+                        msaa,
+                        depth,
+                        format,
+                        sample_count,
+                        size,
+                        load: wgpu::LoadOp::Load,
+                        depth_ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(1.0),
+                            store: false,
+                        },
+                    });
+                    #[cfg(feature = "timestamps")]
+                    if let Some(timer) = timer {
+                        timer.end_custom_pass(encoder);
+                    }
+                }
+            },
+        );
+        if let (Some(retained), Some(surface), Some((_, blitter))) =
+            (&retained_view, &surface_view, &renderer.retained)
+        {
+            blitter.blit(device, &mut encoder, retained, surface);
+        }
+        #[cfg(feature = "timestamps")]
+        if let Some(timer) = timer {
+            timer.end_frame(&mut encoder);
+        }
+
+        renderer.staging_belt.lock().unwrap().finish();
+        renderer.queue.submit(Some(encoder.finish()));
+        if let Some(texture) = surface_texture {
+            texture.present();
+        }
+
+        renderer.frame_kept = keeps_frame;
+        renderer.geometry_cache.end_frame();
+        renderer.text.cache.lock().unwrap().end_frame();
+        renderer.frame_geometry = std::mem::take(&mut self.geometry);
+        renderer.frame_primitives = std::mem::take(&mut self.primitives);
+
+        renderer.start_recall();
+        #[cfg(feature = "timestamps")]
+        if let Some(timer) = &mut renderer.timer {
+            timer.after_submit(&renderer.local_pool.spawner());
+        }
+        if renderer.blocking_finish {
+            renderer.local_pool.run_until_stalled();
+        }
+
+        Ok(())
+    }
+
+    /// Strokes take gradients and patterns the way fills do: the shader
+    /// evaluates them at each fragment's position, so they follow the stroke
+    /// around corners without needing anything from the tessellator.
+    fn stroke_shape(
+        &mut self,
+        shape: impl Shape,
+        brush: &impl IntoBrush<Self>,
+        width: f64,
+        style: &StrokeStyle,
+    ) {
+        let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
+        let color = self.fill_color(&brush);
+        let primitive_id = self.primitives.len() as u32 - 1;
+        let vertex_start = self.geometry.vertices.len();
+        // Patterns need `tex_pos`, which the capsule uses for its distance
+        // field.
+        let capsule = style.line_cap == LineCap::Round
+            && style.dash_pattern.is_empty()
+            && !matches!(brush, Brush::Pattern { .. });
+        match shape.as_line() {
+            Some(line) if capsule => self.stroke_line_sdf(line, color, primitive_id, width),
+            _ => self.stroke_tessellated(shape, color, primitive_id, width, style),
+        }
+        self.end_brush(&brush, vertex_start);
+    }
+
+    fn stroke_tessellated(
+        &mut self,
+        shape: impl Shape,
+        color: [f32; 4],
+        primitive_id: u32,
+        width: f64,
+        style: &StrokeStyle,
+    ) {
+        let (join, miter_limit) = match style.line_join {
+            LineJoin::Miter { limit } => (0u8, limit),
+            LineJoin::Round => (1, 0.0),
+            LineJoin::Bevel => (2, 0.0),
+        };
+        let params = (
+            "stroke",
+            width.to_bits(),
+            style.line_cap as u8,
+            join,
+            miter_limit.to_bits(),
+            style
+                .dash_pattern
+                .iter()
+                .map(|d| d.to_bits())
+                .collect::<Vec<_>>(),
+            style.dash_offset.to_bits(),
+        );
+        let cache_key = match self.cached_geometry(&shape, params, color, primitive_id) {
+            Ok(()) => return,
+            Err(key) => key,
+        };
+        let vertex_start = self.geometry.vertices.len();
+        let index_start = self.geometry.indices.len();
+
+        let tolerance = self.tolerance();
+        let options = StrokeOptions::tolerance(tolerance as f32)
+            .with_line_width(width as f32)
+            .with_line_cap(match style.line_cap {
+                LineCap::Butt => tessellation::LineCap::Butt,
+                LineCap::Round => tessellation::LineCap::Round,
+                LineCap::Square => tessellation::LineCap::Square,
+            })
+            .with_line_join(match style.line_join {
+                LineJoin::Miter { .. } => tessellation::LineJoin::Miter,
+                LineJoin::Round => tessellation::LineJoin::Round,
+                LineJoin::Bevel => tessellation::LineJoin::Bevel,
+            })
+            .with_miter_limit((miter_limit as f32).max(StrokeOptions::MINIMUM_MITER_LIMIT));
+        let mut builder = BuffersBuilder::new(&mut self.geometry, |vertex: StrokeVertex| {
+            let mut pos = vertex.position_on_path().to_array();
+            let normal = vertex.normal().to_array();
+            pos[0] += normal[0] * width as f32 / 2.0;
+            pos[1] += normal[1] * width as f32 / 2.0;
+            GpuVertex {
+                pos,
+                color,
+                primitive_id,
+                ..Default::default()
+            }
+        });
+
+        let result = match shape.as_rect() {
+            Some(rect) if style.dash_pattern.is_empty() => self.stroke_tess.tessellate_rectangle(
+                &lyon::geom::Rect::new(
+                    lyon::geom::Point::new(rect.x0 as f32, rect.y0 as f32),
+                    lyon::geom::Size::new(rect.width() as f32, rect.height() as f32),
+                ),
+                &options,
+                &mut builder,
+            ),
+            _ => {
+                let path = shape_to_path(&shape, tolerance / 2.0);
+                let path = if style.dash_pattern.is_empty() {
+                    path
+                } else {
+                    dash_path(
+                        path.iter(),
+                        &style.dash_pattern,
+                        style.dash_offset,
+                        options.tolerance,
+                    )
+                };
+                self.stroke_tess
+                    .tessellate_path(&path, &options, &mut builder)
+            }
+        };
+        if self.check_tessellation(result) {
+            self.cache_geometry(cache_key, vertex_start, index_start);
+        }
+    }
+
+    /// With pixel snapping on, `shape` as a rect whose edges, or the edges of
+    /// its stroke `width` wide, lie on device pixel boundaries, and the
+    /// stroke width rounded to whole device pixels.
+    fn snap_rect(&self, shape: &impl Shape, width: f64) -> Option<(Rect, f64)> {
+        if !self.pixel_snap {
+            return None;
+        }
+        let rect = shape.as_rect()?.abs();
+        let scale = self.scale;
+        let affine = self.cur_transform.as_coeffs();
+        let width = if width > 0.0 {
+            (width * scale).round().max(1.0) / scale
+        } else {
+            0.0
+        };
+        let outer = rect.inflate(width / 2.0, width / 2.0);
+        let snap = |v: f64, offset: f64| ((v + offset) * scale).round() / scale - offset;
+        let outer = Rect::new(
+            snap(outer.x0, affine[4]),
+            snap(outer.y0, affine[5]),
+            snap(outer.x1, affine[4]),
+            snap(outer.y1, affine[5]),
+        );
+        Some((outer.inflate(-width / 2.0, -width / 2.0), width))
+    }
+
+    /// Move a horizontal or vertical hairline onto the middle of a row or
+    /// column of device pixels, so it covers exactly one instead of blurring
+    /// over two.
+    fn snap_hairline(&self, line: Line) -> Line {
+        let scale = self.scale;
+        let affine = self.cur_transform.as_coeffs();
+        let snap =
+            |v: f64, offset: f64| ((v + offset) * scale).floor() / scale + 0.5 / scale - offset;
+        let mut line = line;
+        if line.p0.y == line.p1.y {
+            line.p0.y = snap(line.p0.y, affine[5]);
+            line.p1.y = line.p0.y;
+        } else if line.p0.x == line.p1.x {
+            line.p0.x = snap(line.p0.x, affine[4]);
+            line.p1.x = line.p0.x;
+        }
+        line
+    }
+
+    fn check_tessellation<T>(
+        &mut self,
+        result: Result<T, tessellation::TessellationError>,
+    ) -> bool {
+        match result {
+            Ok(_) => true,
+            Err(e) => {
+                self.record_error(piet::Error::BackendError(
+                    format!("tessellation failed: {:?}", e).into(),
+                ));
+                false
+            }
+        }
     }
 
     pub fn draw_svg(&mut self, svg: &Svg, rect: Rect, override_color: Option<&Color>) {
         let view_box = svg.tree.svg_node().view_box;
         let view_rect = view_box.rect;
-        let scale =
-            (rect.width() / view_rect.width()).min(rect.height() / view_rect.height()) as f32;
+        let scale = (rect.width() / view_rect.width()).min(rect.height() / view_rect.height());
+        let transform = Affine::translate(Vec2::new(rect.x0, rect.y0)) * Affine::scale(scale);
+        let style = SvgStyle {
+            color: override_color.cloned(),
+            ..Default::default()
+        };
+        self.draw_svg_transformed(svg, transform, &style);
+    }
+
+    /// Draw `svg` into `rect`, sized and positioned according to `fit`.
+    pub fn draw_svg_fit(
+        &mut self,
+        svg: &Svg,
+        rect: Rect,
+        fit: SvgFit,
+        override_color: Option<&Color>,
+    ) {
+        let style = SvgStyle {
+            color: override_color.cloned(),
+            ..Default::default()
+        };
+        self.draw_svg_styled(svg, rect, fit, &style);
+    }
+
+    /// Draw `svg` into `rect` like [`draw_svg_fit`](Self::draw_svg_fit),
+    /// recolored and faded according to `style`.
+    pub fn draw_svg_styled(&mut self, svg: &Svg, rect: Rect, fit: SvgFit, style: &SvgStyle) {
+        let view_rect = svg.tree.svg_node().view_box.rect;
+        let view_rect = Rect::new(
+            view_rect.left(),
+            view_rect.top(),
+            view_rect.right(),
+            view_rect.bottom(),
+        );
+        let transform = fit.transform(view_rect, rect);
+        if fit == SvgFit::Cover {
+            let _ = self.save();
+            self.clip(rect);
+            self.draw_svg_transformed(svg, transform, style);
+            let _ = self.restore();
+        } else {
+            self.draw_svg_transformed(svg, transform, style);
+        }
+    }
 
-        let translate = [rect.x0 as f32, rect.y0 as f32];
-        let override_color = override_color.map(|c| format_color(c));
-        let svg_data = self.renderer.svg_store.get_svg_data(svg);
+    fn draw_svg_transformed(&mut self, svg: &Svg, transform: Affine, style: &SvgStyle) {
+        // Everything is baked into each path's primitive transform, so the
+        // vertices themselves aren't translated.
+        if self.renderer.is_none() {
+            self.record_error(piet::Error::NotSupported);
+            return;
+        }
+        let base = self.cur_transform * transform;
+        let scale = base.determinant().abs().sqrt() * self.scale;
+        let override_color = style.color.as_ref().map(format_color);
+        let svg_data = self.renderer().svg_store.get_svg_data(svg, scale);
+        let pending: Vec<(usize, u32, u32, Vec<u8>)> = svg_data
+            .images
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, image)| image.uploaded.is_none())
+            .map(|(i, image)| {
+                (
+                    i,
+                    image.width,
+                    image.height,
+                    std::mem::take(&mut image.pixels),
+                )
+            })
+            .collect();
+        for (i, width, height, pixels) in pending {
+            let image = self.upload_image(width, height, pixels);
+            self.renderer().svg_store.get_svg_data(svg, scale).images[i].uploaded = Some(image);
+        }
+        let svg_data = self.renderer().svg_store.get_svg_data(svg, scale);
         let transforms = svg_data.transforms.clone();
+        let gradients = svg_data.gradients.clone();
         let offset = self.geometry.vertices.len() as u32;
 
         let primitive_id = self.primitives.len() as u32;
-        for transform in transforms {
-            self.add_primitive();
-            let primitive = self.primitives.last_mut().unwrap();
-            primitive.transform_1[0] *= scale * transform[0];
-            primitive.transform_1[3] *= scale * transform[3];
-            primitive.transform_2[0] += scale * transform[4];
-            primitive.transform_2[1] += scale * transform[5];
+        for (transform, gradient) in transforms.into_iter().zip(gradients) {
+            let t = base * Affine::new(transform.map(|c| c as f64));
+            let [a, b, c, d, e, f] = t.as_coeffs().map(|c| c as f32);
+            let mut primitive = Primitive {
+                transform_1: [a, b, c, d],
+                transform_2: [e, f],
+                z: self.z,
+                alpha: self.alpha,
+                ..Default::default()
+            };
+            // A recolored SVG is drawn flat, gradients included.
+            if let (Some(gradient), None) = (gradient, override_color) {
+                gradient.apply(&mut primitive, &mut self.gradient_stops);
+            }
+            self.feather_clip(&mut primitive);
+            self.primitives.push(primitive);
         }
         self.add_primitive();
 
-        let svg_data = self.renderer.svg_store.get_svg_data(svg);
-        let mut vertices = svg_data
-            .geometry
-            .vertices
-            .iter()
-            .map(|v| {
-                let mut v = v.clone();
-                v.translate = translate;
-                v.primitive_id = primitive_id + v.primitive_id;
-                if let Some(c) = override_color.clone() {
-                    v.color = c;
-                }
-                v
-            })
-            .collect();
-        let mut indices = svg_data
+        let svg_data = self.renderer().svg_store.get_svg_data(svg, scale);
+        let mut vertices = style.vertices(svg_data);
+        for v in &mut vertices {
+            v.primitive_id += primitive_id;
+        }
+        let mut images = Vec::new();
+        for image in &svg_data.images {
+            let uploaded = match image.uploaded {
+                Some(ref uploaded) if !image.indices.is_empty() => uploaded,
+                _ => continue,
+            };
+            let uv = uploaded.uv;
+            for v in &mut vertices[image.vertices.clone()] {
+                v.tex_pos = [
+                    (uv.x0 + v.tex_pos[0] as f64 * uv.width()) as f32,
+                    (uv.y0 + v.tex_pos[1] as f64 * uv.height()) as f32,
+                ];
+            }
+            let bind_group = uploaded.texture.bind_group(image.interp).clone();
+            images.push((image.indices.start, bind_group));
+        }
+        let indices: Vec<u32> = svg_data
             .geometry
             .indices
             .iter()
             .map(|i| *i + offset)
             .collect();
+        let groups = svg_data.groups.clone();
         self.geometry.vertices.append(&mut vertices);
-        self.geometry.indices.append(&mut indices);
+
+        // Translucent groups are drawn to layers so overlapping paths in
+        // them don't show through each other.
+        let mut images = &images[..];
+        let mut next = 0;
+        let mut open_groups: Vec<usize> = Vec::new();
+        // Layer bounds are in user space with only the translation applied,
+        // but the SVG's paths are drawn with all of `base`.
+        let affine = self.cur_transform.as_coeffs();
+        let translation = Vec2::new(affine[4], affine[5]);
+        for (opacity, range, bounds) in groups {
+            while let Some(&end) = open_groups.last() {
+                if end > range.start {
+                    break;
+                }
+                self.push_svg_indices(&indices, &mut images, &mut next, end);
+                let _ = self.restore();
+                open_groups.pop();
+            }
+            self.push_svg_indices(&indices, &mut images, &mut next, range.start);
+            let bounds = base.transform_rect_bbox(bounds) - translation;
+            if self.push_layer(opacity, 0.0, Some(bounds)).is_ok() {
+                open_groups.push(range.end);
+            }
+        }
+        while let Some(end) = open_groups.pop() {
+            self.push_svg_indices(&indices, &mut images, &mut next, end);
+            let _ = self.restore();
+        }
+        self.push_svg_indices(&indices, &mut images, &mut next, indices.len());
+    }
+
+    /// Append an SVG's `indices` from `next` up to `until`, switching to
+    /// each of its `images` as the image's quad is reached.
+    fn push_svg_indices(
+        &mut self,
+        indices: &[u32],
+        images: &mut &[(usize, Rc<wgpu::BindGroup>)],
+        next: &mut usize,
+        until: usize,
+    ) {
+        while let Some((start, image)) = images.first().filter(|(start, _)| *start < until) {
+            self.geometry
+                .indices
+                .extend_from_slice(&indices[*next..*start]);
+            *next = *start;
+            self.set_image(image);
+            *images = &images[1..];
+        }
+        self.geometry
+            .indices
+            .extend_from_slice(&indices[*next..until]);
+        *next = until;
+    }
+}
+
+/// A shape the fragment shader draws from its distance field.
+struct SdfShape {
+    center: Point,
+    /// The unit vector the shape's x axis points along.
+    axis: Vec2,
+    /// The half size of the shape's box, or the radii of the ellipse or
+    /// pie slice.
+    size: [f32; 2],
+    /// The corner radii of the box: top left, top right, bottom right,
+    /// bottom left. For a pie slice, the sine and cosine of half its sweep.
+    radii: [f32; 4],
+    kind: SdfKind,
+}
+
+#[derive(Clone, Copy)]
+enum SdfKind {
+    RoundedBox,
+    Ellipse,
+    /// A slice of a circle, centered on the x axis.
+    Pie,
+}
+
+impl SdfKind {
+    /// The vertex `tex` the shader draws the shape for.
+    fn tex(self) -> f32 {
+        match self {
+            SdfKind::RoundedBox => 4.0,
+            SdfKind::Ellipse => 5.0,
+            SdfKind::Pie => 7.0,
+        }
+    }
+}
+
+impl SdfShape {
+    fn to_path(&self, tolerance: f64) -> BezPath {
+        let [w, h] = [self.size[0] as f64, self.size[1] as f64];
+        let rotation = self.axis.atan2();
+        match self.kind {
+            SdfKind::RoundedBox => {}
+            SdfKind::Ellipse => {
+                return Ellipse::new(self.center, (w, h), rotation).to_path(tolerance)
+            }
+            SdfKind::Pie => {
+                let half_sweep = (self.radii[0] as f64).atan2(self.radii[1] as f64);
+                return pie_path(
+                    &piet::kurbo::Arc {
+                        center: self.center,
+                        radii: Vec2::new(w, h),
+                        start_angle: rotation - half_sweep,
+                        sweep_angle: half_sweep * 2.0,
+                        x_rotation: 0.0,
+                    },
+                    tolerance,
+                );
+            }
+        }
+        let [tl, tr, br, bl] = self.radii.map(|radius| radius as f64);
+        let rect = Rect::from_center_size(Point::ZERO, (w * 2.0, h * 2.0));
+        Affine::translate(self.center.to_vec2())
+            * Affine::rotate(rotation)
+            * RoundedRect::from_rect(rect, (tl, tr, br, bl)).to_path(tolerance)
     }
 }
 
+/// How much a frame has recorded, for finding heavy frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    pub vertices: usize,
+    pub indices: usize,
+    pub primitives: usize,
+    /// Runs of indices drawn with one scissor rect, blend mode and image,
+    /// including those inside layers.
+    pub draw_batches: usize,
+    pub layers: usize,
+    pub custom_passes: usize,
+}
+
+/// How `blurred_rect` computes its blur.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlurQuality {
+    /// Evaluate the blur analytically in the shader. Cheap, but can band
+    /// with large radii.
+    #[default]
+    Fast,
+    /// Draw the rect offscreen and blur it with a separable Gaussian in two
+    /// passes. Costs two textures the size of the blurred rect and two
+    /// passes per blurred rounded rect; rects with sharp corners are blurred
+    /// exactly in the shader either way.
+    Gaussian,
+}
+
+/// Gradients made with [`RenderContext::gradient`] clamp to their end
+/// colors; use [`Brush::with_extend`] to repeat or reflect them instead.
 #[derive(Clone)]
 pub enum Brush {
     Solid(Color),
+    LinearGradient(piet::FixedLinearGradient, ExtendMode),
+    RadialGradient(piet::FixedRadialGradient, ExtendMode),
+    /// Fills with `image`, placed by `transform` from image pixels to the
+    /// coordinates of the shape being filled, and extended past its edges by
+    /// `extend`.
+    Pattern {
+        image: WgpuImage,
+        transform: Affine,
+        extend: ExtendMode,
+    },
+}
+
+impl Brush {
+    /// The brush with its gradient or pattern extended past its ends by
+    /// `extend`. Solid brushes are returned unchanged.
+    pub fn with_extend(self, extend: ExtendMode) -> Self {
+        match self {
+            Brush::Solid(color) => Brush::Solid(color),
+            Brush::LinearGradient(g, _) => Brush::LinearGradient(g, extend),
+            Brush::RadialGradient(g, _) => Brush::RadialGradient(g, extend),
+            Brush::Pattern {
+                image, transform, ..
+            } => Brush::Pattern {
+                image,
+                transform,
+                extend,
+            },
+        }
+    }
+
+    fn gradient(&self) -> Option<Gradient> {
+        match self {
+            Brush::Solid(_) | Brush::Pattern { .. } => None,
+            Brush::LinearGradient(g, extend) => {
+                Some(Gradient::linear(g.start, g.end, &piet_stops(&g.stops)).with_extend(*extend))
+            }
+            Brush::RadialGradient(g, extend) => Some(
+                Gradient::radial(g.center, g.radius, &piet_stops(&g.stops)).with_extend(*extend),
+            ),
+        }
+    }
+
+    /// A single color standing in for the brush where gradients and
+    /// patterns aren't supported yet: the first stop's, or nothing for
+    /// patterns.
+    fn solid_color(&self) -> Color {
+        let stops = match self {
+            Brush::Solid(color) => return color.clone(),
+            Brush::Pattern { .. } => return Color::TRANSPARENT,
+            Brush::LinearGradient(g, _) => &g.stops,
+            Brush::RadialGradient(g, _) => &g.stops,
+        };
+        stops
+            .first()
+            .map(|stop| stop.color.clone())
+            .unwrap_or(Color::TRANSPARENT)
+    }
+}
+
+#[derive(Clone)]
+pub struct WgpuImage {
+    width: u32,
+    height: u32,
+    pixels: Rc<Vec<u8>>,
+    texture: Rc<ImageTexture>,
+    /// The image's area of `texture`, in texture coordinates; less than the
+    /// whole of it for images packed into the image atlas.
+    uv: Rect,
+    /// Whether the texture holds premultiplied colors as the renderer drew
+    /// them, for images of a [`RenderTexture`].
+    premultiplied: bool,
 }
 
-pub struct WgpuImage {}
+impl WgpuImage {
+    const WHOLE_TEXTURE: Rect = Rect::new(0.0, 0.0, 1.0, 1.0);
+
+    /// The image's pixels, 4 bytes per pixel in row-major order without padding.
+    ///
+    /// Empty for images made with
+    /// [`image_from_texture`](WgpuRenderContext::image_from_texture), which
+    /// never leave the GPU.
+    pub fn raw_pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+}
 
 impl<'a> RenderContext for WgpuRenderContext<'a> {
     type Brush = Brush;
@@ -144,7 +2196,10 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
     type Image = WgpuImage;
 
     fn status(&mut self) -> Result<(), piet::Error> {
-        todo!()
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
     }
 
     fn solid_brush(&mut self, color: Color) -> Self::Brush {
@@ -155,153 +2210,48 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         &mut self,
         gradient: impl Into<piet::FixedGradient>,
     ) -> Result<Self::Brush, piet::Error> {
-        todo!()
+        match gradient.into() {
+            piet::FixedGradient::Linear(g) => Ok(Brush::LinearGradient(g, ExtendMode::default())),
+            piet::FixedGradient::Radial(g) => Ok(Brush::RadialGradient(g, ExtendMode::default())),
+        }
     }
 
-    fn clear(&mut self, region: impl Into<Option<Rect>>, color: Color) {}
+    fn clear(&mut self, _region: impl Into<Option<Rect>>, _color: Color) {}
 
+    /// Strokes with round caps and joins, like `stroke_styled` with those
+    /// set.
     fn stroke(&mut self, shape: impl Shape, brush: &impl piet::IntoBrush<Self>, width: f64) {
-        let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
-        let Brush::Solid(color) = brush;
-        let color = format_color(&color);
-        // let affine = self.cur_transform.as_coeffs();
-        // let translate = [affine[4] as f32, affine[5] as f32];
-        let primitive_id = self.primitives.len() as u32 - 1;
-
-        if let Some(rect) = shape.as_rect() {
-            self.stroke_tess.tessellate_rectangle(
-                &lyon::geom::Rect::new(
-                    lyon::geom::Point::new(rect.x0 as f32, rect.y0 as f32),
-                    lyon::geom::Size::new(rect.width() as f32, rect.height() as f32),
-                ),
-                &StrokeOptions::tolerance(0.02)
-                    .with_line_width(width as f32)
-                    .with_line_cap(tessellation::LineCap::Round)
-                    .with_line_join(tessellation::LineJoin::Round),
-                &mut BuffersBuilder::new(&mut self.geometry, |vertex: StrokeVertex| {
-                    let mut pos = vertex.position_on_path().to_array();
-                    let normal = vertex.normal().to_array();
-                    pos[0] += normal[0] * width as f32 / 2.0;
-                    pos[1] += normal[1] * width as f32 / 2.0;
-                    GpuVertex {
-                        pos,
-                        color,
-                        primitive_id,
-                        ..Default::default()
-                    }
-                }),
-            );
-        } else if let Some(line) = shape.as_line() {
-            let mut builder = lyon::path::Path::builder();
-            builder.begin(lyon::geom::point(line.p0.x as f32, line.p0.y as f32));
-            builder.line_to(lyon::geom::point(line.p1.x as f32, line.p1.y as f32));
-            builder.close();
-            let path = builder.build();
-            self.stroke_tess.tessellate_path(
-                &path,
-                &StrokeOptions::tolerance(0.02)
-                    .with_line_width(width as f32)
-                    .with_line_cap(tessellation::LineCap::Round)
-                    .with_line_join(tessellation::LineJoin::Round),
-                &mut BuffersBuilder::new(&mut self.geometry, |vertex: StrokeVertex| {
-                    let mut pos = vertex.position_on_path().to_array();
-                    let normal = vertex.normal().to_array();
-                    pos[0] += normal[0] * width as f32 / 2.0;
-                    pos[1] += normal[1] * width as f32 / 2.0;
-                    GpuVertex {
-                        pos,
-                        color,
-                        primitive_id,
-                        ..Default::default()
-                    }
-                }),
-            );
-        } else {
-            let mut builder = lyon::path::Path::builder();
-            let mut in_subpath = false;
-            for el in shape.path_elements(0.01) {
-                match el {
-                    piet::kurbo::PathEl::MoveTo(p) => {
-                        builder.begin(lyon::geom::point(p.x as f32, p.y as f32));
-                        in_subpath = true;
-                    }
-                    piet::kurbo::PathEl::LineTo(p) => {
-                        builder.line_to(lyon::geom::point(p.x as f32, p.y as f32));
-                    }
-                    piet::kurbo::PathEl::QuadTo(ctrl, to) => {
-                        builder.quadratic_bezier_to(
-                            lyon::geom::point(ctrl.x as f32, ctrl.y as f32),
-                            lyon::geom::point(to.x as f32, to.y as f32),
-                        );
-                    }
-                    piet::kurbo::PathEl::CurveTo(c1, c2, p) => {
-                        builder.cubic_bezier_to(
-                            lyon::geom::point(c1.x as f32, c1.y as f32),
-                            lyon::geom::point(c2.x as f32, c2.y as f32),
-                            lyon::geom::point(p.x as f32, p.y as f32),
-                        );
-                    }
-                    piet::kurbo::PathEl::ClosePath => {
-                        in_subpath = false;
-                        builder.close();
-                    }
-                }
-            }
-            if in_subpath {
-                builder.end(false);
-            }
-            let path = builder.build();
-            self.stroke_tess.tessellate_path(
-                &path,
-                &StrokeOptions::tolerance(0.02)
-                    .with_line_width(width as f32)
-                    .with_line_cap(tessellation::LineCap::Round)
-                    .with_line_join(tessellation::LineJoin::Round),
-                &mut BuffersBuilder::new(&mut self.geometry, |vertex: StrokeVertex| {
-                    let mut pos = vertex.position_on_path().to_array();
-                    let normal = vertex.normal().to_array();
-                    pos[0] += normal[0] * width as f32 / 2.0;
-                    pos[1] += normal[1] * width as f32 / 2.0;
-                    GpuVertex {
-                        pos,
-                        color,
-                        primitive_id,
-                        ..Default::default()
-                    }
-                }),
-            );
-        }
+        const ROUND: StrokeStyle = StrokeStyle::new()
+            .line_cap(LineCap::Round)
+            .line_join(LineJoin::Round);
+        self.stroke_styled(shape, brush, width, &ROUND);
     }
 
+    /// A `width` of 0 strokes a hairline one device pixel wide at any scale,
+    /// snapped to the pixel grid if it's a horizontal or vertical line.
     fn stroke_styled(
         &mut self,
-        shape: impl piet::kurbo::Shape,
+        shape: impl Shape,
         brush: &impl piet::IntoBrush<Self>,
         width: f64,
-        style: &piet::StrokeStyle,
+        style: &StrokeStyle,
     ) {
+        if width == 0.0 {
+            let width = 1.0 / self.scale;
+            match shape.as_line() {
+                Some(line) => self.stroke_styled(self.snap_hairline(line), brush, width, style),
+                None => self.stroke_styled(shape, brush, width, style),
+            }
+            return;
+        }
+        match self.snap_rect(&shape, width) {
+            Some((rect, width)) => self.stroke_shape(rect, brush, width, style),
+            None => self.stroke_shape(shape, brush, width, style),
+        }
     }
 
     fn fill(&mut self, shape: impl piet::kurbo::Shape, brush: &impl piet::IntoBrush<Self>) {
-        if let Some(rect) = shape.as_rect() {
-            let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
-            let Brush::Solid(color) = brush;
-            let color = format_color(&color);
-            let primitive_id = self.primitives.len() as u32 - 1;
-            self.fill_tess.tessellate_rectangle(
-                &lyon::geom::Rect::new(
-                    lyon::geom::Point::new(rect.x0 as f32, rect.y0 as f32),
-                    lyon::geom::Size::new(rect.width() as f32, rect.height() as f32),
-                ),
-                &FillOptions::tolerance(0.02).with_fill_rule(tessellation::FillRule::NonZero),
-                &mut BuffersBuilder::new(&mut self.geometry, |vertex: FillVertex| GpuVertex {
-                    pos: vertex.position().to_array(),
-                    color,
-                    primitive_id,
-                    ..Default::default()
-                }),
-            );
-        }
+        self.fill_with_rule(shape, brush, tessellation::FillRule::NonZero);
     }
 
     fn fill_even_odd(
@@ -309,18 +2259,11 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         shape: impl piet::kurbo::Shape,
         brush: &impl piet::IntoBrush<Self>,
     ) {
+        self.fill_with_rule(shape, brush, tessellation::FillRule::EvenOdd);
     }
 
     fn clip(&mut self, shape: impl Shape) {
-        if let Some(rect) = shape.as_rect() {
-            let affine = self.cur_transform.as_coeffs();
-            let rect = rect + Vec2::new(affine[4], affine[5]);
-            self.clip_stack.push(rect);
-            if let Some(state) = self.state_stack.last_mut() {
-                state.n_clip += 1;
-            }
-            self.add_primitive();
-        }
+        self.clip_feathered(shape, 0.0);
     }
 
     fn text(&mut self) -> &mut Self::Text {
@@ -329,8 +2272,7 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
 
     fn draw_text(&mut self, layout: &Self::TextLayout, pos: impl Into<piet::kurbo::Point>) {
         let point: Point = pos.into();
-        let translate = [point.x as f32, point.y as f32];
-        layout.draw_text(self, translate);
+        layout.draw_text(self, Affine::translate(point.to_vec2()));
     }
 
     fn save(&mut self) -> Result<(), piet::Error> {
@@ -338,6 +2280,11 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
             rel_transform: Affine::default(),
             transform: self.cur_transform,
             n_clip: 0,
+            layer: false,
+            blend: self.blend,
+            pixel_snap: self.pixel_snap,
+            z: self.z,
+            alpha: self.alpha,
         });
         Ok(())
     }
@@ -348,6 +2295,18 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
             for _ in 0..state.n_clip {
                 self.pop_clip();
             }
+            // Ending a layer starts its parent's next batch, so restoring a
+            // layer's blend mode mustn't start one inside the layer.
+            match state.layer {
+                true => self.blend = state.blend,
+                false => self.set_blend_mode(state.blend),
+            }
+            self.pixel_snap = state.pixel_snap;
+            self.z = state.z;
+            self.alpha = state.alpha;
+            if state.layer {
+                self.end_layer();
+            }
             self.add_primitive();
             Ok(())
         } else {
@@ -356,49 +2315,28 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
     }
 
     fn finish(&mut self) -> Result<(), piet::Error> {
-        self.renderer.ensure_encoder();
-        let mut encoder = self.renderer.take_encoder();
-
-        self.renderer.pipeline.upload_data(
-            &self.renderer.device,
-            &mut self.renderer.staging_belt.borrow_mut(),
-            &mut encoder,
-            &self.geometry,
-            &self.primitives,
-        );
-
-        let texture = self
-            .renderer
-            .surface
-            .get_current_texture()
-            .map_err(|e| piet::Error::NotSupported)?;
-        let view = texture
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
-
-        self.renderer.pipeline.draw(
-            &self.renderer.device,
-            &mut encoder,
-            &view,
-            &self.renderer.msaa,
-            &self.geometry,
-        );
-
-        self.renderer.staging_belt.borrow_mut().finish();
-        self.renderer.queue.submit(Some(encoder.finish()));
-        texture.present();
-
-        self.renderer
-            .local_pool
-            .spawner()
-            .spawn(self.renderer.staging_belt.borrow_mut().recall())
-            .expect("Recall staging belt");
-        self.renderer.local_pool.run_until_stalled();
-
-        Ok(())
+        self.finished = true;
+        while !self.layer_stack.is_empty() {
+            self.end_layer();
+        }
+        self.close_batch();
+        let renderer = match self.renderer.take() {
+            Some(renderer) => renderer,
+            None => return Ok(()),
+        };
+        let result = self.submit(renderer);
+        self.renderer = Some(renderer);
+        result
     }
 
+    /// Non-finite transforms (or ones whose product with the current
+    /// transform overflows) are ignored, keeping the current transform, and
+    /// reported by `status` as `InvalidInput`.
     fn transform(&mut self, transform: Affine) {
+        if !(self.cur_transform * transform).is_finite() {
+            self.record_error(piet::Error::InvalidInput);
+            return;
+        }
         if let Some(state) = self.state_stack.last_mut() {
             state.rel_transform *= transform;
         }
@@ -413,7 +2351,8 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         buf: &[u8],
         format: piet::ImageFormat,
     ) -> Result<Self::Image, piet::Error> {
-        todo!()
+        let pixels = to_rgba(width, height, buf, format)?;
+        Ok(self.upload_image(width as u32, height as u32, pixels))
     }
 
     fn draw_image(
@@ -422,7 +2361,7 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         dst_rect: impl Into<piet::kurbo::Rect>,
         interp: piet::InterpolationMode,
     ) {
-        todo!()
+        self.draw_image_area(image, image.size().to_rect(), dst_rect, interp);
     }
 
     fn draw_image_area(
@@ -432,14 +2371,66 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         dst_rect: impl Into<piet::kurbo::Rect>,
         interp: piet::InterpolationMode,
     ) {
-        todo!()
+        let (src, dst) = (src_rect.into(), dst_rect.into());
+        if image.width == 0 || image.height == 0 || dst.area() == 0.0 {
+            return;
+        }
+        self.set_image(image.texture.bind_group(interp));
+
+        let (width, height) = (image.width as f64, image.height as f64);
+        let uv = image.uv;
+        let primitive_id = self.primitives.len() as u32 - 1;
+        let offset = self.geometry.vertices.len() as u32;
+        let corners = [
+            (dst.x0, dst.y0, src.x0, src.y0),
+            (dst.x0, dst.y1, src.x0, src.y1),
+            (dst.x1, dst.y1, src.x1, src.y1),
+            (dst.x1, dst.y0, src.x1, src.y0),
+        ];
+        for (x, y, u, v) in corners {
+            self.geometry.vertices.push(GpuVertex {
+                pos: [x as f32, y as f32],
+                color: [1.0, 1.0, 1.0, 1.0],
+                tex: if image.premultiplied { 8.0 } else { 2.0 },
+                tex_pos: [
+                    (uv.x0 + u / width * uv.width()) as f32,
+                    (uv.y0 + v / height * uv.height()) as f32,
+                ],
+                primitive_id,
+                ..Default::default()
+            });
+        }
+        self.geometry
+            .indices
+            .extend([0, 1, 2, 0, 2, 3].iter().map(|i| i + offset));
     }
 
+    /// Read back an area of the frame as of the last `finish`, as an image
+    /// with straight alpha.
+    ///
+    /// Windowed renderers need
+    /// [frame capture](WgpuRenderer::set_frame_capture) or partial redraws
+    /// on to keep a readable frame; otherwise this returns `NotSupported`.
     fn capture_image_area(
         &mut self,
         src_rect: impl Into<piet::kurbo::Rect>,
     ) -> Result<Self::Image, piet::Error> {
-        todo!()
+        let renderer = self
+            .renderer
+            .as_deref_mut()
+            .ok_or(piet::Error::NotSupported)?;
+        let texture = renderer.readable_frame().ok_or(piet::Error::NotSupported)?;
+        let scale = self.scale;
+        let size = self.size;
+        let rect = src_rect
+            .into()
+            .scale_from_origin(scale)
+            .intersect(Rect::new(0.0, 0.0, size.width, size.height))
+            .round();
+        let (width, height) = (rect.width() as u32, rect.height() as u32);
+        let pixels =
+            renderer.read_texture(texture, [rect.x0 as u32, rect.y0 as u32, width, height])?;
+        Ok(self.upload_image(width, height, pixels))
     }
 
     fn blurred_rect(
@@ -448,37 +2439,7 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         blur_radius: f64,
         brush: &impl piet::IntoBrush<Self>,
     ) {
-        let rect = rect.inflate(3.0 * blur_radius, 3.0 * blur_radius);
-        let blur_rect = rect.inflate(-3.0 * blur_radius, -3.0 * blur_radius);
-        let brush = brush.make_brush(self, || rect).into_owned();
-        let Brush::Solid(color) = brush;
-        let color = format_color(&color);
-
-        self.add_primitive();
-        let primitive = self.primitives.last_mut().unwrap();
-        primitive.blur_radius = blur_radius as f32;
-        primitive.blur_rect = [
-            blur_rect.x0 as f32,
-            blur_rect.y0 as f32,
-            blur_rect.x1 as f32,
-            blur_rect.y1 as f32,
-        ];
-
-        let primitive_id = self.primitives.len() as u32 - 1;
-        self.fill_tess.tessellate_rectangle(
-            &lyon::geom::Rect::new(
-                lyon::geom::Point::new(rect.x0 as f32, rect.y0 as f32),
-                lyon::geom::Size::new(rect.width() as f32, rect.height() as f32),
-            ),
-            &FillOptions::tolerance(0.02).with_fill_rule(tessellation::FillRule::NonZero),
-            &mut BuffersBuilder::new(&mut self.geometry, |vertex: FillVertex| GpuVertex {
-                pos: vertex.position().to_array(),
-                color,
-                primitive_id,
-                ..Default::default()
-            }),
-        );
-        self.add_primitive();
+        self.blurred_rounded_rect(rect, 0.0, blur_radius, brush);
     }
 
     fn current_transform(&self) -> piet::kurbo::Affine {
@@ -495,11 +2456,24 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
     }
 }
 
+/// A context dropped without calling `finish`, e.g. on an early return,
+/// finishes the frame then so it's still submitted and presented, logging a
+/// warning. Errors are ignored; call `finish` to see them.
+impl<'a> Drop for WgpuRenderContext<'a> {
+    fn drop(&mut self) {
+        if self.finished || std::thread::panicking() {
+            return;
+        }
+        log::warn!("WgpuRenderContext dropped without calling finish");
+        let _ = self.finish();
+    }
+}
+
 impl<'a> IntoBrush<WgpuRenderContext<'a>> for Brush {
     fn make_brush<'b>(
         &'b self,
-        piet: &mut WgpuRenderContext,
-        bbox: impl FnOnce() -> piet::kurbo::Rect,
+        _piet: &mut WgpuRenderContext,
+        _bbox: impl FnOnce() -> piet::kurbo::Rect,
     ) -> std::borrow::Cow<'b, Brush> {
         Cow::Borrowed(self)
     }
@@ -507,11 +2481,73 @@ impl<'a> IntoBrush<WgpuRenderContext<'a>> for Brush {
 
 impl Image for WgpuImage {
     fn size(&self) -> piet::kurbo::Size {
-        todo!()
+        piet::kurbo::Size::new(self.width as f64, self.height as f64)
+    }
+}
+
+/// The closed slice of `arc`'s ellipse: from the center along the arc and
+/// back.
+fn pie_path(arc: &piet::kurbo::Arc, tolerance: f64) -> BezPath {
+    let mut path = BezPath::new();
+    path.move_to(arc.center);
+    for el in arc.path_elements(tolerance) {
+        match el {
+            PathEl::MoveTo(p) => path.line_to(p),
+            el => path.push(el),
+        }
+    }
+    path.close_path();
+    path
+}
+
+/// Convert `shape` for lyon. Only subpaths ending in `ClosePath` are closed,
+/// so stroking an open polyline doesn't join its ends.
+///
+/// Shapes that aren't Béziers, like circles, are approximated within
+/// `tolerance`; callers pass half of the tessellator's, so the two errors
+/// together stay within it.
+fn shape_to_path(shape: &impl Shape, tolerance: f64) -> lyon::path::Path {
+    let mut builder = lyon::path::Path::builder();
+    let mut in_subpath = false;
+    for el in shape.path_elements(tolerance) {
+        match el {
+            piet::kurbo::PathEl::MoveTo(p) => {
+                if in_subpath {
+                    builder.end(false);
+                }
+                builder.begin(lyon::geom::point(p.x as f32, p.y as f32));
+                in_subpath = true;
+            }
+            piet::kurbo::PathEl::LineTo(p) => {
+                builder.line_to(lyon::geom::point(p.x as f32, p.y as f32));
+            }
+            piet::kurbo::PathEl::QuadTo(ctrl, to) => {
+                builder.quadratic_bezier_to(
+                    lyon::geom::point(ctrl.x as f32, ctrl.y as f32),
+                    lyon::geom::point(to.x as f32, to.y as f32),
+                );
+            }
+            piet::kurbo::PathEl::CurveTo(c1, c2, p) => {
+                builder.cubic_bezier_to(
+                    lyon::geom::point(c1.x as f32, c1.y as f32),
+                    lyon::geom::point(c2.x as f32, c2.y as f32),
+                    lyon::geom::point(p.x as f32, p.y as f32),
+                );
+            }
+            piet::kurbo::PathEl::ClosePath => {
+                in_subpath = false;
+                builder.close();
+            }
+        }
+    }
+    if in_subpath {
+        builder.end(false);
     }
+    builder.build()
 }
 
-pub fn from_linear(x: f32) -> f32 {
+/// Decode an sRGB-encoded channel to linear light.
+pub fn srgb_to_linear(x: f32) -> f32 {
     if x <= 0.04045 {
         x * (1.0 / 12.92)
     } else {
@@ -519,12 +2555,257 @@ pub fn from_linear(x: f32) -> f32 {
     }
 }
 
+pub(crate) fn linear_to_srgb(x: f32) -> f32 {
+    if x <= 0.0031308 {
+        x * 12.92
+    } else {
+        x.powf(1.0 / 2.4) * 1.055 - 0.055
+    }
+}
+
+/// `color` as the shader takes it: linear RGB with straight alpha.
+///
+/// piet colors are sRGB-encoded, so RGB is decoded here. Alpha is opacity,
+/// which has no encoding, and passes through unchanged. Every color the
+/// renderer draws goes through here, whether it fills a shape, colors text,
+/// is a gradient stop or an SVG paint, so they all blend alike: the shader
+/// premultiplies and the target blends in the space it stores colors in,
+/// linear light for `*Srgb` formats and sRGB for the rest.
 pub fn format_color(color: &Color) -> [f32; 4] {
     let color = color.as_rgba();
     [
-        from_linear(color.0 as f32),
-        from_linear(color.1 as f32),
-        from_linear(color.2 as f32),
+        srgb_to_linear(color.0 as f32),
+        srgb_to_linear(color.1 as f32),
+        srgb_to_linear(color.2 as f32),
         color.3 as f32,
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_renderer;
+    use piet::{Text, TextLayoutBuilder};
+
+    #[test]
+    fn layer_is_composited_in_the_blend_mode_it_was_opened_in() {
+        let mut ctx = recording();
+        ctx.set_blend_mode(BlendMode::Multiply);
+        ctx.save_layer(1.0).unwrap();
+        ctx.fill(Rect::new(0.0, 0.0, 10.0, 10.0), &Color::WHITE);
+        ctx.restore().unwrap();
+
+        let composite = ctx.batches.iter().find(|b| b.layer.is_some()).unwrap();
+        assert_eq!(composite.blend, BlendMode::Multiply);
+        assert!(ctx.layers[0]
+            .batches
+            .iter()
+            .all(|b| b.blend == BlendMode::SourceOver));
+        ctx.finish().unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn captured_frame_has_straight_alpha() {
+        let mut renderer = test_renderer(4, 4);
+        renderer.set_clear_color(Color::TRANSPARENT);
+        let mut ctx = WgpuRenderContext::new(&mut renderer);
+        ctx.fill(Rect::new(0.0, 0.0, 2.0, 4.0), &Color::BLACK);
+        ctx.fill(Rect::new(0.0, 0.0, 4.0, 4.0), &Color::WHITE.with_alpha(0.5));
+        ctx.finish().unwrap();
+        drop(ctx);
+
+        let frame = renderer.capture_frame().unwrap();
+        // Over black, half of white's light, blended in linear light.
+        assert_pixel(&frame.pixels[..4], [188, 188, 188, 255]);
+        // Over nothing, white at half alpha rather than gray.
+        assert_pixel(&frame.pixels[12..16], [255, 255, 255, 128]);
+    }
+
+    #[test]
+    fn with_clip_restores_when_the_closure_fails() {
+        let mut ctx = recording();
+        let result = ctx.with_clip(Rect::new(0.0, 0.0, 10.0, 10.0), |ctx| {
+            ctx.with_save(|ctx| {
+                ctx.transform(Affine::translate((5.0, 5.0)));
+                ctx.clip(Rect::new(0.0, 0.0, 2.0, 2.0));
+                Err(piet::Error::NotSupported)
+            })?;
+            Ok(())
+        });
+        assert!(matches!(result, Err(piet::Error::NotSupported)));
+        assert_eq!(ctx.current_clip_rect(), None);
+        assert_eq!(ctx.current_transform(), Affine::IDENTITY);
+        assert!(ctx.state_stack.is_empty());
+        ctx.finish().unwrap();
+    }
+
+    #[test]
+    fn non_finite_transform_is_ignored_and_reported() {
+        let mut ctx = recording();
+        ctx.transform(Affine::translate((10.0, 0.0)));
+        ctx.transform(Affine::scale(f64::NAN));
+        assert_eq!(ctx.current_transform(), Affine::translate((10.0, 0.0)));
+        assert!(matches!(ctx.status(), Err(piet::Error::InvalidInput)));
+        ctx.finish().unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn dropped_context_finishes_the_frame() {
+        let mut renderer = test_renderer(4, 4);
+        renderer.set_clear_color(Color::TRANSPARENT);
+        let mut ctx = WgpuRenderContext::new(&mut renderer);
+        ctx.fill(Rect::new(0.0, 0.0, 4.0, 4.0), &Color::WHITE);
+        drop(ctx);
+
+        let frame = renderer.capture_frame().unwrap();
+        assert_pixel(&frame.pixels[..4], [255, 255, 255, 255]);
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn blurred_rounded_rect_has_rounded_corners() {
+        // The alpha of the corner and center pixels of a 32x32 shadow.
+        let alphas = |radius: f64| {
+            let mut renderer = test_renderer(32, 32);
+            renderer.set_clear_color(Color::TRANSPARENT);
+            let mut ctx = WgpuRenderContext::new(&mut renderer);
+            let rect = Rect::new(0.0, 0.0, 32.0, 32.0);
+            ctx.blurred_rounded_rect(rect, radius, 1.0, &Color::BLACK);
+            ctx.finish().unwrap();
+            drop(ctx);
+            let frame = renderer.capture_frame().unwrap();
+            let center = (16 * 32 + 16) * 4;
+            (frame.pixels[3], frame.pixels[center + 3])
+        };
+        let (sharp, rounded) = (alphas(0.0), alphas(12.0));
+        assert!(sharp.0 > 64, "{:?}", sharp);
+        assert!(rounded.0 < 8, "{:?}", rounded);
+        assert_eq!((sharp.1, rounded.1), (255, 255));
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn text_is_drawn() {
+        let mut renderer = test_renderer(64, 32);
+        renderer.set_clear_color(Color::TRANSPARENT);
+        let mut ctx = WgpuRenderContext::new(&mut renderer);
+        let layout = ctx
+            .text()
+            .new_text_layout("Hello")
+            .font(piet::FontFamily::SANS_SERIF, 20.0)
+            .text_color(Color::BLACK)
+            .build()
+            .unwrap();
+        ctx.draw_text(&layout, (2.0, 2.0));
+        ctx.finish().unwrap();
+        drop(ctx);
+
+        let frame = renderer.capture_frame().unwrap();
+        assert!(frame.pixels.chunks(4).any(|pixel| pixel[3] > 128));
+    }
+
+    #[test]
+    fn text_is_drawn_as_glyph_quads() {
+        let mut ctx = recording();
+        let layout = ctx
+            .text()
+            .new_text_layout("Hello")
+            .font(piet::FontFamily::SANS_SERIF, 20.0)
+            .text_color(Color::BLACK)
+            .build()
+            .unwrap();
+        ctx.draw_text(&layout, (2.0, 2.0));
+        let glyph_vertices: Vec<_> = ctx
+            .geometry
+            .vertices
+            .iter()
+            .filter(|v| v.tex == 1.0)
+            .collect();
+        // A quad for each glyph, sampling the atlas.
+        assert_eq!(glyph_vertices.len(), 4 * 5);
+        assert!(glyph_vertices
+            .iter()
+            .all(|v| v.color == [0.0, 0.0, 0.0, 1.0]));
+        assert!(glyph_vertices.iter().any(|v| v.tex_pos != [0.0, 0.0]));
+    }
+
+    #[test]
+    fn open_polyline_is_stroked_without_a_closing_segment() {
+        let mut polyline = BezPath::new();
+        polyline.move_to((0.0, 0.0));
+        polyline.line_to((100.0, 0.0));
+        polyline.line_to((100.0, 100.0));
+        let path = shape_to_path(&polyline, 0.05);
+        assert!(path
+            .iter()
+            .all(|event| !matches!(event, lyon::path::Event::End { close: true, .. })));
+
+        let mut geometry: VertexBuffers<[f32; 2], u32> = VertexBuffers::new();
+        StrokeTessellator::new()
+            .tessellate_path(
+                &path,
+                &StrokeOptions::default().with_line_width(2.0),
+                &mut BuffersBuilder::new(&mut geometry, |v: tessellation::StrokeVertex| {
+                    v.position().to_array()
+                }),
+            )
+            .unwrap();
+        // A closing segment would run back along the diagonal.
+        assert!(!covers(&geometry, [50.0, 50.0]));
+    }
+
+    #[test]
+    fn even_odd_fill_leaves_a_hole() {
+        // Two circles wound the same way, as a path since a bare circle is
+        // drawn without tessellating, so only even-odd leaves the inner one
+        // empty.
+        let mut donut = BezPath::new();
+        for radius in [30.0, 10.0] {
+            donut.extend(Circle::new((32.0, 32.0), radius).path_elements(0.1));
+        }
+        let mut ctx = recording();
+        ctx.fill_even_odd(&donut, &Color::BLACK);
+        assert!(covers(&positions(&ctx.geometry), [12.0, 32.0]));
+        assert!(!covers(&positions(&ctx.geometry), [32.0, 32.0]));
+
+        let mut ctx = recording();
+        ctx.fill(&donut, &Color::BLACK);
+        assert!(covers(&positions(&ctx.geometry), [32.0, 32.0]));
+    }
+
+    /// A context that only records, over a 64 by 64 target.
+    fn recording() -> WgpuRenderContext<'static> {
+        WgpuRenderContext::recording(WgpuText::measuring(), Size::new(64.0, 64.0), 1.0)
+    }
+
+    /// The positions of `geometry`'s vertices, with the same indices.
+    fn positions(geometry: &VertexBuffers<GpuVertex, u32>) -> VertexBuffers<[f32; 2], u32> {
+        VertexBuffers {
+            vertices: geometry.vertices.iter().map(|v| v.pos).collect(),
+            indices: geometry.indices.clone(),
+        }
+    }
+
+    /// Whether any triangle of `geometry` contains `point`.
+    fn covers(geometry: &VertexBuffers<[f32; 2], u32>, point: [f32; 2]) -> bool {
+        geometry.indices.chunks(3).any(|tri| {
+            let [a, b, c] = [0, 1, 2].map(|i| geometry.vertices[tri[i] as usize]);
+            let side = |p: [f32; 2], q: [f32; 2]| {
+                (q[0] - p[0]) * (point[1] - p[1]) - (q[1] - p[1]) * (point[0] - p[0])
+            };
+            let sides = [side(a, b), side(b, c), side(c, a)];
+            sides.iter().all(|s| *s >= 0.0) || sides.iter().all(|s| *s <= 0.0)
+        })
+    }
+
+    /// Check `pixel` is `expected`, give or take the GPU's rounding.
+    fn assert_pixel(pixel: &[u8], expected: [u8; 4]) {
+        let close = pixel
+            .iter()
+            .zip(expected)
+            .all(|(a, b)| (*a as i32 - b as i32).abs() <= 1);
+        assert!(close, "{:?} isn't {:?}", pixel, expected);
+    }
+}
@@ -1,11 +1,162 @@
-pub struct FontSource {
-    source: font_kit::source::SystemSource,
+use std::sync::Arc;
+
+use piet::{FontFamily, FontWeight};
+
+/// A font face, read from its file data with ttf-parser.
+#[derive(Clone)]
+pub(crate) struct Font {
+    data: Arc<Vec<u8>>,
+    index: u32,
+}
+
+/// A font's vertical metrics, in font units. `descent` is negative below the
+/// baseline.
+pub(crate) struct Metrics {
+    pub(crate) units_per_em: u32,
+    pub(crate) ascent: f32,
+    pub(crate) descent: f32,
+    pub(crate) line_gap: f32,
+}
+
+impl Font {
+    /// The face at `index` in font file `data`, if it parses.
+    pub(crate) fn from_bytes(data: Arc<Vec<u8>>, index: u32) -> Option<Self> {
+        ttf_parser::Face::from_slice(&data, index).ok()?;
+        Some(Self { data, index })
+    }
+
+    pub(crate) fn data(&self) -> &Arc<Vec<u8>> {
+        &self.data
+    }
+
+    /// The index of the face in its file, for font collections.
+    pub(crate) fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub(crate) fn face(&self) -> ttf_parser::Face<'_> {
+        ttf_parser::Face::from_slice(&self.data, self.index).expect("checked when loaded")
+    }
+
+    pub(crate) fn glyph_for_char(&self, c: char) -> Option<u32> {
+        self.face().glyph_index(c).map(|glyph| glyph.0 as u32)
+    }
+
+    /// The glyph's advance in font units.
+    pub(crate) fn advance(&self, glyph_id: u32) -> Option<u16> {
+        self.face()
+            .glyph_hor_advance(ttf_parser::GlyphId(glyph_id as u16))
+    }
+
+    pub(crate) fn metrics(&self) -> Metrics {
+        let face = self.face();
+        Metrics {
+            units_per_em: face.units_per_em().unwrap_or(1000) as u32,
+            ascent: face.ascender() as f32,
+            descent: face.descender() as f32,
+            line_gap: face.line_gap() as f32,
+        }
+    }
+
+    pub(crate) fn is_monospace(&self) -> bool {
+        self.face().is_monospaced()
+    }
+
+    pub(crate) fn weight(&self) -> u16 {
+        self.face().weight().to_number()
+    }
+
+    /// The typographic family name, or the legacy one for fonts without.
+    pub(crate) fn family_name(&self) -> Option<String> {
+        let face = self.face();
+        [
+            ttf_parser::name_id::TYPOGRAPHIC_FAMILY,
+            ttf_parser::name_id::FAMILY,
+        ]
+        .iter()
+        .find_map(|&id| {
+            face.names()
+                .filter(|name| name.name_id() == id)
+                .find_map(|name| name.to_string())
+        })
+    }
 }
 
-impl FontSource {
-    pub fn new() -> Self {
-        Self {
-            source: font_kit::source::SystemSource::new(),
+/// Finds the fonts installed on the system.
+///
+/// The system's font source isn't `Send` on every platform, so each thread
+/// looking fonts up gets its own, keeping the glyph cache `Send`.
+#[cfg(feature = "system-fonts")]
+pub(crate) struct SystemFonts;
+
+#[cfg(feature = "system-fonts")]
+thread_local! {
+    static SYSTEM_SOURCE: font_kit::source::SystemSource = font_kit::source::SystemSource::new();
+}
+
+#[cfg(feature = "system-fonts")]
+impl SystemFonts {
+    pub(crate) fn new() -> Self {
+        Self
+    }
+
+    /// The installed font closest to `family` and `weight`.
+    pub(crate) fn select(&self, family: &FontFamily, weight: FontWeight) -> Option<Font> {
+        use font_kit::family_name::FamilyName;
+
+        let family_name = match family.inner() {
+            piet::FontFamilyInner::Serif => FamilyName::Serif,
+            piet::FontFamilyInner::SansSerif => FamilyName::SansSerif,
+            piet::FontFamilyInner::Monospace => FamilyName::Monospace,
+            piet::FontFamilyInner::SystemUi => FamilyName::SansSerif,
+            piet::FontFamilyInner::Named(name) => FamilyName::Title(name.to_string()),
+            _ => FamilyName::SansSerif,
+        };
+        let mut properties = font_kit::properties::Properties::new();
+        properties.weight(font_kit::properties::Weight(weight.to_raw() as f32));
+        let handle = SYSTEM_SOURCE
+            .with(|source| source.select_best_match(&[family_name], &properties))
+            .ok()?;
+        Self::load(handle)
+    }
+
+    /// The first installed font of the named families.
+    pub(crate) fn select_by_name(&self, names: &[&str]) -> Option<Font> {
+        names.iter().find_map(|name| {
+            let family = SYSTEM_SOURCE.with(|source| source.select_family_by_name(name).ok())?;
+            Self::load(family.fonts().first()?.clone())
+        })
+    }
+
+    fn load(handle: font_kit::handle::Handle) -> Option<Font> {
+        match handle {
+            font_kit::handle::Handle::Path { path, font_index } => {
+                Font::from_bytes(Arc::new(std::fs::read(path).ok()?), font_index)
+            }
+            font_kit::handle::Handle::Memory { bytes, font_index } => {
+                Font::from_bytes(bytes, font_index)
+            }
         }
     }
 }
+
+/// Stands in for system font lookup when it's compiled out: nothing is
+/// installed, so only bundled fonts and those registered with `load_font`
+/// are drawn with.
+#[cfg(not(feature = "system-fonts"))]
+pub(crate) struct SystemFonts;
+
+#[cfg(not(feature = "system-fonts"))]
+impl SystemFonts {
+    pub(crate) fn new() -> Self {
+        Self
+    }
+
+    pub(crate) fn select(&self, _family: &FontFamily, _weight: FontWeight) -> Option<Font> {
+        None
+    }
+
+    pub(crate) fn select_by_name(&self, _names: &[&str]) -> Option<Font> {
+        None
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use hashbrown::HashMap;
+use lyon::lyon_tessellation::VertexBuffers;
+use piet::kurbo::{PathEl, Shape};
+
+use crate::pipeline::GpuVertex;
+
+/// Frames an entry may go unused before it's evicted.
+const MAX_AGE: u64 = 60;
+
+/// Bytes of geometry kept before the least recently used entries are
+/// evicted to make room.
+const MAX_BYTES: usize = 32 << 20;
+
+/// A shape and the parameters it's tessellated with, as the bytes they hash
+/// to, so a hash collision can't emit another shape's geometry.
+pub(crate) struct GeometryKey {
+    hash: u64,
+    source: Vec<u8>,
+}
+
+struct CachedGeometry {
+    geometry: VertexBuffers<GpuVertex, u32>,
+    source: Vec<u8>,
+    last_used: u64,
+}
+
+impl CachedGeometry {
+    fn bytes(&self) -> usize {
+        self.geometry.vertices.len() * std::mem::size_of::<GpuVertex>()
+            + self.geometry.indices.len() * std::mem::size_of::<u32>()
+            + self.source.len()
+    }
+}
+
+/// Tessellation output kept across frames, keyed by shape and tessellation
+/// parameters.
+///
+/// Entries only store positions and topology; the color and primitive id are
+/// filled in again every time an entry is emitted.
+#[derive(Default)]
+pub(crate) struct GeometryCache {
+    pub(crate) enabled: bool,
+    frame: u64,
+    entries: HashMap<u64, CachedGeometry>,
+    /// The total [`bytes`](CachedGeometry::bytes) of `entries`.
+    bytes: usize,
+}
+
+impl GeometryCache {
+    /// Append a cached entry to `geometry`, returning false on a miss.
+    pub(crate) fn emit(
+        &mut self,
+        key: &GeometryKey,
+        geometry: &mut VertexBuffers<GpuVertex, u32>,
+        color: [f32; 4],
+        primitive_id: u32,
+    ) -> bool {
+        let entry = match self.entries.get_mut(&key.hash) {
+            Some(entry) if entry.source == key.source => entry,
+            _ => return false,
+        };
+        entry.last_used = self.frame;
+
+        let offset = geometry.vertices.len() as u32;
+        geometry
+            .vertices
+            .extend(entry.geometry.vertices.iter().map(|v| GpuVertex {
+                color,
+                primitive_id,
+                ..*v
+            }));
+        geometry
+            .indices
+            .extend(entry.geometry.indices.iter().map(|i| *i + offset));
+        true
+    }
+
+    /// Store the geometry appended to `geometry` since `vertex_start` and
+    /// `index_start`, evicting the least recently used entries if it doesn't
+    /// fit in the budget. Geometry bigger than the whole budget isn't kept.
+    pub(crate) fn insert(
+        &mut self,
+        key: GeometryKey,
+        geometry: &VertexBuffers<GpuVertex, u32>,
+        vertex_start: usize,
+        index_start: usize,
+    ) {
+        let offset = vertex_start as u32;
+        let entry = CachedGeometry {
+            geometry: VertexBuffers {
+                vertices: geometry.vertices[vertex_start..].to_vec(),
+                indices: geometry.indices[index_start..]
+                    .iter()
+                    .map(|i| *i - offset)
+                    .collect(),
+            },
+            source: key.source,
+            last_used: self.frame,
+        };
+        let bytes = entry.bytes();
+        if bytes > MAX_BYTES {
+            return;
+        }
+        if let Some(old) = self.entries.remove(&key.hash) {
+            self.bytes -= old.bytes();
+        }
+        while self.bytes + bytes > MAX_BYTES {
+            let (&oldest, _) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .unwrap();
+            let old = self.entries.remove(&oldest).unwrap();
+            self.bytes -= old.bytes();
+        }
+        self.bytes += bytes;
+        self.entries.insert(key.hash, entry);
+    }
+
+    /// Advance the frame counter and drop entries that have gone stale.
+    pub(crate) fn end_frame(&mut self) {
+        let frame = self.frame;
+        let bytes = &mut self.bytes;
+        self.entries.retain(|_, entry| {
+            let keep = frame - entry.last_used < MAX_AGE;
+            if !keep {
+                *bytes -= entry.bytes();
+            }
+            keep
+        });
+        self.frame += 1;
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+}
+
+/// Collects the bytes hashed into it, to compare keys by.
+#[derive(Default)]
+struct Source(Vec<u8>);
+
+impl Hasher for Source {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(&self.0);
+        hasher.finish()
+    }
+}
+
+/// Key a shape's path together with the parameters it's tessellated with.
+pub(crate) fn geometry_key(shape: &impl Shape, params: impl Hash) -> GeometryKey {
+    let mut hasher = Source::default();
+    params.hash(&mut hasher);
+    for el in shape.path_elements(0.01) {
+        match el {
+            PathEl::MoveTo(p) => (0u8, [p.x, p.y].map(f64::to_bits)).hash(&mut hasher),
+            PathEl::LineTo(p) => (1u8, [p.x, p.y].map(f64::to_bits)).hash(&mut hasher),
+            PathEl::QuadTo(p1, p2) => {
+                (2u8, [p1.x, p1.y, p2.x, p2.y].map(f64::to_bits)).hash(&mut hasher)
+            }
+            PathEl::CurveTo(p1, p2, p3) => {
+                (3u8, [p1.x, p1.y, p2.x, p2.y, p3.x, p3.y].map(f64::to_bits)).hash(&mut hasher)
+            }
+            PathEl::ClosePath => 4u8.hash(&mut hasher),
+        }
+    }
+    GeometryKey {
+        hash: hasher.finish(),
+        source: hasher.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use piet::kurbo::Rect;
+
+    fn geometry() -> VertexBuffers<GpuVertex, u32> {
+        VertexBuffers {
+            vertices: vec![GpuVertex::default(); 3],
+            indices: vec![0, 1, 2],
+        }
+    }
+
+    fn cache() -> GeometryCache {
+        GeometryCache {
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn colliding_key_misses() {
+        let mut cache = cache();
+        let key = geometry_key(&Rect::new(0.0, 0.0, 10.0, 10.0), 1u32);
+        let hash = key.hash;
+        cache.insert(key, &geometry(), 0, 0);
+
+        let mut out = VertexBuffers::new();
+        let other = geometry_key(&Rect::new(0.0, 0.0, 20.0, 10.0), 1u32);
+        let collision = GeometryKey { hash, ..other };
+        assert!(!cache.emit(&collision, &mut out, [1.0; 4], 0));
+        let key = geometry_key(&Rect::new(0.0, 0.0, 10.0, 10.0), 1u32);
+        assert!(cache.emit(&key, &mut out, [1.0; 4], 0));
+        assert_eq!(out.indices.len(), 3);
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted_over_budget() {
+        let mut cache = cache();
+        let big = |hash, byte| GeometryKey {
+            hash,
+            source: vec![byte; MAX_BYTES / 2],
+        };
+        cache.insert(big(1, 1), &geometry(), 0, 0);
+        cache.end_frame();
+        cache.insert(big(2, 2), &geometry(), 0, 0);
+        assert_eq!(cache.entries.len(), 1);
+        assert!(cache.entries.contains_key(&2));
+        assert!(cache.bytes <= MAX_BYTES);
+    }
+}
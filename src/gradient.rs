@@ -0,0 +1,128 @@
+use piet::{kurbo::Point, GradientStop};
+
+use crate::{
+    context::format_color,
+    pipeline::{GpuGradientStop, Primitive},
+};
+
+const LINEAR: f32 = 1.0;
+const RADIAL: f32 = 2.0;
+
+/// What a gradient does past its first and last stops.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExtendMode {
+    /// Keep the end colors.
+    #[default]
+    Clamp,
+    /// Start over from the first stop.
+    Repeat,
+    /// Run back and forth between the stops, mirroring every other time.
+    Reflect,
+}
+
+impl ExtendMode {
+    pub(crate) fn to_f32(self) -> f32 {
+        match self {
+            ExtendMode::Clamp => 0.0,
+            ExtendMode::Repeat => 1.0,
+            ExtendMode::Reflect => 2.0,
+        }
+    }
+}
+
+/// A gradient as the shader evaluates it, in the coordinates of the shape
+/// it fills.
+///
+/// The color is interpolated linearly between the surrounding stops and
+/// extended past the first and last according to `extend`. At most
+/// [`MAX_GRADIENT_STOPS`] stops are used; past that, the stops in between are dropped
+/// from the end so the first and last are kept.
+#[derive(Clone)]
+pub(crate) struct Gradient {
+    kind: f32,
+    /// Start and end points for linear gradients; center, start radius and
+    /// end radius for radial ones, placed at the first and last stops.
+    params: [f32; 4],
+    /// Offsets from 0 at the first stop to 1 at the last, with linear colors.
+    stops: Vec<GpuGradientStop>,
+    extend: ExtendMode,
+}
+
+/// The most stops a gradient is drawn with.
+pub const MAX_GRADIENT_STOPS: usize = 16;
+
+impl Gradient {
+    /// `stops` are offsets along the gradient with linear colors.
+    pub(crate) fn linear(start: Point, end: Point, stops: &[(f32, [f32; 4])]) -> Self {
+        let (from, to, stops) = normalize(stops);
+        let p0 = start.lerp(end, from as f64);
+        let p1 = start.lerp(end, to as f64);
+        Self {
+            kind: LINEAR,
+            params: [p0.x as f32, p0.y as f32, p1.x as f32, p1.y as f32],
+            stops,
+            extend: ExtendMode::Clamp,
+        }
+    }
+
+    pub(crate) fn radial(center: Point, radius: f64, stops: &[(f32, [f32; 4])]) -> Self {
+        let (from, to, stops) = normalize(stops);
+        let radius = radius as f32;
+        Self {
+            kind: RADIAL,
+            params: [center.x as f32, center.y as f32, from * radius, to * radius],
+            stops,
+            extend: ExtendMode::Clamp,
+        }
+    }
+
+    pub(crate) fn with_extend(mut self, extend: ExtendMode) -> Self {
+        self.extend = extend;
+        self
+    }
+
+    /// Point `primitive` at the gradient, appending its stops to the
+    /// frame's.
+    pub(crate) fn apply(&self, primitive: &mut Primitive, stops: &mut Vec<GpuGradientStop>) {
+        primitive.gradient_kind = self.kind;
+        primitive.gradient = self.params;
+        primitive.gradient_stops = [stops.len() as f32, self.stops.len() as f32];
+        primitive.gradient_extend = self.extend.to_f32();
+        stops.extend_from_slice(&self.stops);
+    }
+}
+
+/// The offsets of the first and last stops, and the stops rescaled to run
+/// from 0 to 1 between them, in order and capped at [`MAX_GRADIENT_STOPS`].
+fn normalize(stops: &[(f32, [f32; 4])]) -> (f32, f32, Vec<GpuGradientStop>) {
+    let (first, last) = match (stops.first(), stops.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return (0.0, 1.0, vec![GpuGradientStop::new(0.0, [0.0; 4])]),
+    };
+    // Keep the gradient from degenerating when both ends share an offset.
+    let from = first.0;
+    let to = if last.0 > from {
+        last.0
+    } else {
+        from + f32::EPSILON
+    };
+    let kept = stops.len().min(MAX_GRADIENT_STOPS) - 1;
+    let mut offset = 0.0f32;
+    let normalized = stops[..kept]
+        .iter()
+        .chain(std::iter::once(last))
+        .map(|(pos, color)| {
+            // Stops out of order take the offset of the one before them.
+            offset = offset.max(((pos - from) / (to - from)).min(1.0));
+            GpuGradientStop::new(offset, *color)
+        })
+        .collect();
+    (from, to, normalized)
+}
+
+pub(crate) fn piet_stops(stops: &[GradientStop]) -> Vec<(f32, [f32; 4])> {
+    stops
+        .iter()
+        .map(|stop| (stop.pos, format_color(&stop.color)))
+        .collect()
+}
@@ -0,0 +1,363 @@
+use std::{rc::Rc, sync::Arc};
+
+use piet::{kurbo::Rect, ImageFormat, InterpolationMode};
+
+use crate::{blit::Blitter, pipeline::Pipeline};
+
+/// The format images uploaded from CPU pixels are stored in.
+pub(crate) const IMAGE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+/// A texture `draw_image` can sample, with a bind group for each
+/// interpolation mode.
+pub(crate) struct ImageTexture {
+    /// Keeps an imported texture alive for as long as any image uses it.
+    _texture: Option<Arc<wgpu::Texture>>,
+    nearest: Rc<wgpu::BindGroup>,
+    linear: Rc<wgpu::BindGroup>,
+}
+
+impl ImageTexture {
+    /// Upload tightly packed RGBA8 pixels with straight alpha, filling in a
+    /// full mip chain with `mipmaps` if given.
+    pub(crate) fn upload(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipeline: &Pipeline,
+        mipmaps: Option<&MipmapGenerator>,
+        width: u32,
+        height: u32,
+        rgba: &[u8],
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width: width.max(1),
+            height: height.max(1),
+            depth_or_array_layers: 1,
+        };
+        let mip_level_count = match mipmaps {
+            Some(_) => 32 - size.width.max(size.height).leading_zeros(),
+            None => 1,
+        };
+        let mut usage = wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST;
+        if mip_level_count > 1 {
+            usage |= wgpu::TextureUsages::RENDER_ATTACHMENT;
+        }
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("image texture"),
+            size,
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: IMAGE_FORMAT,
+            usage,
+        });
+        if width > 0 && height > 0 {
+            queue.write_texture(
+                texture.as_image_copy(),
+                rgba,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(width * 4),
+                    rows_per_image: std::num::NonZeroU32::new(height),
+                },
+                size,
+            );
+        }
+        if let Some(mipmaps) = mipmaps {
+            mipmaps.generate(device, queue, &texture, mip_level_count);
+        }
+        Self::new(device, pipeline, &texture, None)
+    }
+
+    /// An empty texture to copy pixels into later.
+    fn empty(
+        device: &wgpu::Device,
+        pipeline: &Pipeline,
+        width: u32,
+        height: u32,
+    ) -> (wgpu::Texture, Self) {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("image atlas texture"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: IMAGE_FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        });
+        let image = Self::new(device, pipeline, &texture, None);
+        (texture, image)
+    }
+
+    /// Wrap a texture created elsewhere without copying it.
+    pub(crate) fn import(
+        device: &wgpu::Device,
+        pipeline: &Pipeline,
+        texture: Arc<wgpu::Texture>,
+    ) -> Self {
+        Self::new(device, pipeline, &texture, Some(texture.clone()))
+    }
+
+    fn new(
+        device: &wgpu::Device,
+        pipeline: &Pipeline,
+        texture: &wgpu::Texture,
+        keep: Option<Arc<wgpu::Texture>>,
+    ) -> Self {
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        Self {
+            _texture: keep,
+            nearest: Rc::new(pipeline.image_bind_group(
+                device,
+                &view,
+                InterpolationMode::NearestNeighbor,
+            )),
+            linear: Rc::new(pipeline.image_bind_group(device, &view, InterpolationMode::Bilinear)),
+        }
+    }
+
+    pub(crate) fn bind_group(&self, interp: InterpolationMode) -> &Rc<wgpu::BindGroup> {
+        match interp {
+            InterpolationMode::NearestNeighbor => &self.nearest,
+            InterpolationMode::Bilinear => &self.linear,
+        }
+    }
+}
+
+/// Fills in the mip levels of image textures, each by downsampling the level
+/// above it, so minified images are sampled without aliasing.
+pub(crate) struct MipmapGenerator {
+    blitter: Blitter,
+}
+
+impl MipmapGenerator {
+    pub(crate) fn new(device: &wgpu::Device) -> Self {
+        Self {
+            blitter: Blitter::new(device, IMAGE_FORMAT),
+        }
+    }
+
+    /// Render levels 1 and up of `texture` from level 0.
+    fn generate(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+        mip_level_count: u32,
+    ) {
+        let views: Vec<_> = (0..mip_level_count)
+            .map(|level| {
+                texture.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some("mip level"),
+                    base_mip_level: level,
+                    mip_level_count: std::num::NonZeroU32::new(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("mipmap encoder"),
+        });
+        for pair in views.windows(2) {
+            self.blitter.blit(device, &mut encoder, &pair[0], &pair[1]);
+        }
+        queue.submit(Some(encoder.finish()));
+    }
+}
+
+/// Shared textures small images are packed into, so drawing many of them
+/// doesn't start a new batch for each.
+///
+/// Pages are filled in shelves of similar height. Space isn't freed image by
+/// image; a page is reused once every image packed into it is dropped.
+pub(crate) struct ImageAtlas {
+    pages: Vec<AtlasPage>,
+}
+
+struct AtlasPage {
+    texture: wgpu::Texture,
+    /// Shared with every image packed into the page, so it's unused once
+    /// this is the only reference left.
+    image: Rc<ImageTexture>,
+    shelves: Vec<Shelf>,
+}
+
+struct Shelf {
+    y: u32,
+    height: u32,
+    width: u32,
+}
+
+impl AtlasPage {
+    /// Room for a `width` x `height` area, on a shelf at most a quarter
+    /// taller or a new one.
+    fn allocate(&mut self, width: u32, height: u32) -> Option<[u32; 2]> {
+        let size = ImageAtlas::PAGE_SIZE;
+        let shelf = self.shelves.iter_mut().find(|shelf| {
+            shelf.height >= height
+                && shelf.height <= height + height / 4
+                && size - shelf.width >= width
+        });
+        let shelf = match shelf {
+            Some(shelf) => shelf,
+            None => {
+                let y = self
+                    .shelves
+                    .last()
+                    .map_or(0, |shelf| shelf.y + shelf.height);
+                if size - y < height {
+                    return None;
+                }
+                self.shelves.push(Shelf {
+                    y,
+                    height,
+                    width: 0,
+                });
+                self.shelves.last_mut().unwrap()
+            }
+        };
+        let offset = [shelf.width, shelf.y];
+        shelf.width += width;
+        Some(offset)
+    }
+}
+
+impl ImageAtlas {
+    const PAGE_SIZE: u32 = 1024;
+    const MAX_PAGES: usize = 4;
+    /// Images larger than this either way get a texture of their own.
+    pub(crate) const MAX_IMAGE_SIZE: u32 = 128;
+
+    pub(crate) fn new() -> Self {
+        Self { pages: Vec::new() }
+    }
+
+    /// Pack tightly packed RGBA8 pixels into a page, returning the page and
+    /// where the image is on it, in texture coordinates. `None` if the image
+    /// is too big or every page is full.
+    pub(crate) fn insert(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipeline: &Pipeline,
+        width: u32,
+        height: u32,
+        rgba: &[u8],
+    ) -> Option<(Rc<ImageTexture>, Rect)> {
+        if width == 0 || height == 0 || width.max(height) > Self::MAX_IMAGE_SIZE {
+            return None;
+        }
+        // A border of repeated edge pixels keeps filtering from picking up
+        // neighbouring images.
+        let (padded_width, padded_height) = (width + 2, height + 2);
+        let found = self
+            .pages
+            .iter_mut()
+            .enumerate()
+            .find_map(|(i, page)| Some((i, page.allocate(padded_width, padded_height)?)));
+        let (page, [x, y]) = match found {
+            Some(found) => found,
+            None => {
+                let unused = self
+                    .pages
+                    .iter()
+                    .position(|page| Rc::strong_count(&page.image) == 1);
+                let i = match unused {
+                    Some(i) => {
+                        self.pages[i].shelves.clear();
+                        i
+                    }
+                    None if self.pages.len() < Self::MAX_PAGES => {
+                        let (texture, image) =
+                            ImageTexture::empty(device, pipeline, Self::PAGE_SIZE, Self::PAGE_SIZE);
+                        self.pages.push(AtlasPage {
+                            texture,
+                            image: Rc::new(image),
+                            shelves: Vec::new(),
+                        });
+                        self.pages.len() - 1
+                    }
+                    None => return None,
+                };
+                (i, self.pages[i].allocate(padded_width, padded_height)?)
+            }
+        };
+        let page = &self.pages[page];
+
+        let (width, height) = (width as usize, height as usize);
+        let row_bytes = (width + 2) * 4;
+        let mut padded = Vec::with_capacity(row_bytes * (height + 2));
+        for row in 0..height + 2 {
+            let src_row = row.saturating_sub(1).min(height - 1);
+            let src = &rgba[src_row * width * 4..][..width * 4];
+            padded.extend_from_slice(&src[..4]);
+            padded.extend_from_slice(src);
+            padded.extend_from_slice(&src[src.len() - 4..]);
+        }
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &page.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d { x, y, z: 0 },
+                aspect: wgpu::TextureAspect::All,
+            },
+            &padded,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(padded_width * 4),
+                rows_per_image: std::num::NonZeroU32::new(padded_height),
+            },
+            wgpu::Extent3d {
+                width: padded_width,
+                height: padded_height,
+                depth_or_array_layers: 1,
+            },
+        );
+        let size = Self::PAGE_SIZE as f64;
+        let uv = Rect::new(
+            (x + 1) as f64 / size,
+            (y + 1) as f64 / size,
+            (x as usize + 1 + width) as f64 / size,
+            (y as usize + 1 + height) as f64 / size,
+        );
+        Some((page.image.clone(), uv))
+    }
+}
+
+/// Convert `buf` to tightly packed RGBA8 with straight alpha.
+pub(crate) fn to_rgba(
+    width: usize,
+    height: usize,
+    buf: &[u8],
+    format: ImageFormat,
+) -> Result<Vec<u8>, piet::Error> {
+    let pixels = width * height;
+    if buf.len() < pixels * format.bytes_per_pixel() {
+        return Err(piet::Error::InvalidInput);
+    }
+    let buf = &buf[..pixels * format.bytes_per_pixel()];
+    let rgba = match format {
+        ImageFormat::RgbaSeparate => buf.to_vec(),
+        ImageFormat::RgbaPremul => buf
+            .chunks_exact(4)
+            .flat_map(|p| {
+                let a = p[3];
+                let unpremul = |c: u8| match a {
+                    0 => 0,
+                    _ => ((c as u32 * 255 + a as u32 / 2) / a as u32).min(255) as u8,
+                };
+                [unpremul(p[0]), unpremul(p[1]), unpremul(p[2]), a]
+            })
+            .collect(),
+        ImageFormat::Rgb => buf
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        ImageFormat::Grayscale => buf.iter().flat_map(|&l| [l, l, l, 255]).collect(),
+        _ => return Err(piet::Error::NotSupported),
+    };
+    Ok(rgba)
+}
@@ -1 +1,458 @@
+use std::num::NonZeroU64;
 
+use piet::kurbo::Rect;
+
+use crate::pipeline::{BlendMode, DrawBatch};
+
+/// Draws recorded between `save_layer` and the matching `restore`.
+///
+/// The layer is rendered to its own texture and composited onto its parent
+/// with `alpha` where the parent's batches reference it.
+pub(crate) struct Layer {
+    pub(crate) batches: Vec<DrawBatch>,
+    pub(crate) alpha: f32,
+    /// Standard deviation of a Gaussian blur applied to the layer, in logical
+    /// pixels. 0 for none.
+    pub(crate) blur: f32,
+    /// The part of the frame the layer can draw to, in whole device pixels.
+    /// Its target only covers this much.
+    pub(crate) bounds: Rect,
+}
+
+struct LayerTarget {
+    view: wgpu::TextureView,
+    msaa: Option<wgpu::TextureView>,
+    depth: wgpu::TextureView,
+    /// The size of the textures, at least that of the layer's bounds.
+    size: (u32, u32),
+    params: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    /// The horizontally blurred layer, created the first time the layer is
+    /// blurred.
+    blur: Option<BlurTarget>,
+}
+
+struct BlurTarget {
+    view: wgpu::TextureView,
+    params: wgpu::Buffer,
+    /// Samples the layer for the horizontal pass.
+    bind_group: wgpu::BindGroup,
+    /// Samples `view` with the layer's params for the vertical pass while
+    /// compositing.
+    composite_bind_group: wgpu::BindGroup,
+}
+
+/// Offscreen targets for layers and the pipeline that blends them back.
+///
+/// Each layer's target covers only its bounds. Targets are kept across
+/// frames for the layers at the same index, and recreated when a layer
+/// needs a bigger one or uses much less than its target.
+pub(crate) struct Compositor {
+    /// One pipeline per blend mode a layer can be composited with, indexed
+    /// by `BlendMode as usize`.
+    pipelines: Vec<wgpu::RenderPipeline>,
+    /// Runs the horizontal blur pass into a single-sampled texture.
+    blur_pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    format: wgpu::TextureFormat,
+    sample_count: u32,
+    size: (u32, u32),
+    targets: Vec<LayerTarget>,
+}
+
+/// The destination of passes covering their whole target, in clip space.
+const FULL_TARGET: [f32; 4] = [-1.0, 1.0, 1.0, -1.0];
+
+const PARAMS_SIZE: u64 = std::mem::size_of::<[f32; 12]>() as u64;
+
+/// Target sizes are rounded up to this many pixels, so layers that move or
+/// grow a little from frame to frame keep their target.
+const SIZE_STEP: u32 = 64;
+
+impl Compositor {
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Self {
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("layer shader"),
+            source: wgpu::ShaderSource::Wgsl(std::borrow::Cow::Borrowed(include_str!(
+                "shader/layer.wgsl"
+            ))),
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("layer bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::NonFiltering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(PARAMS_SIZE),
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("layer pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        // Layers are composited in passes with the depth buffer attached,
+        // but aren't depth tested themselves; blurring has no depth buffer.
+        let create_pipeline = |sample_count, depth_stencil, blend| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("layer pipeline"),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_main",
+                    targets: &[wgpu::ColorTargetState {
+                        format,
+                        blend: Some(blend),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil,
+                multisample: wgpu::MultisampleState {
+                    count: sample_count,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                },
+                multiview: None,
+            })
+        };
+        let pipelines = BlendMode::ALL
+            .iter()
+            .map(|mode| {
+                create_pipeline(
+                    sample_count,
+                    Some(wgpu::DepthStencilState {
+                        format: crate::render_pass::DEPTH_FORMAT,
+                        depth_write_enabled: false,
+                        depth_compare: wgpu::CompareFunction::Always,
+                        stencil: wgpu::StencilState::default(),
+                        bias: wgpu::DepthBiasState::default(),
+                    }),
+                    mode.blend_state(),
+                )
+            })
+            .collect();
+        let blur_pipeline =
+            create_pipeline(1, None, wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING);
+
+        Self {
+            pipelines,
+            blur_pipeline,
+            bind_group_layout,
+            sampler,
+            format,
+            sample_count,
+            size: (0, 0),
+            targets: Vec::new(),
+        }
+    }
+
+    /// Make sure every layer has a big enough target, free those no layer
+    /// uses this frame, and upload the layers' alphas, blur radii and where
+    /// they go in their parents.
+    ///
+    /// `size` is the frame's, in device pixels, and `scale` converts blur
+    /// radii to them.
+    pub(crate) fn prepare(
+        &mut self,
+        device: &wgpu::Device,
+        staging_belt: &mut wgpu::util::StagingBelt,
+        encoder: &mut wgpu::CommandEncoder,
+        size: (u32, u32),
+        scale: f32,
+        layers: &[Layer],
+    ) {
+        if self.size != size {
+            self.size = size;
+            self.targets.clear();
+        }
+        self.targets.truncate(layers.len());
+        for (i, layer) in layers.iter().enumerate() {
+            let needed = self.texture_size_for(layer.bounds);
+            let fits = self.targets.get(i).is_some_and(|target| {
+                let (width, height) = target.size;
+                width >= needed.0 && height >= needed.1 && width * height <= 4 * needed.0 * needed.1
+            });
+            if !fits {
+                let target = self.create_target(device, needed);
+                match self.targets.get_mut(i) {
+                    Some(old) => *old = target,
+                    None => self.targets.push(target),
+                }
+            }
+        }
+
+        // Layers are composited where their parent's batches reference them.
+        let mut parents = vec![None; layers.len()];
+        for (i, layer) in layers.iter().enumerate() {
+            for child in layer.batches.iter().filter_map(|batch| batch.layer) {
+                parents[child] = Some(i);
+            }
+        }
+
+        for (i, layer) in layers.iter().enumerate() {
+            let radius = layer.blur * scale;
+            if radius > 0.0 && self.targets[i].blur.is_none() {
+                let blur = self.create_blur_target(device, &self.targets[i]);
+                self.targets[i].blur = Some(blur);
+            }
+            let (origin, (parent_width, parent_height)) = match parents[i] {
+                Some(parent) => (layers[parent].bounds.origin(), self.targets[parent].size),
+                None => (piet::kurbo::Point::ZERO, size),
+            };
+            let bounds = layer.bounds - origin.to_vec2();
+            let dest = [
+                (bounds.x0 / parent_width as f64 * 2.0 - 1.0) as f32,
+                (1.0 - bounds.y0 / parent_height as f64 * 2.0) as f32,
+                (bounds.x1 / parent_width as f64 * 2.0 - 1.0) as f32,
+                (1.0 - bounds.y1 / parent_height as f64 * 2.0) as f32,
+            ];
+            let target = &self.targets[i];
+            let uv_max = [
+                (layer.bounds.width() / target.size.0 as f64) as f32,
+                (layer.bounds.height() / target.size.1 as f64) as f32,
+            ];
+            let mut write =
+                |buffer: &wgpu::Buffer, params: [f32; 4], dest: [f32; 4], uv_max: [f32; 2]| {
+                    let params = [
+                        params[0], params[1], params[2], params[3], dest[0], dest[1], dest[2],
+                        dest[3], uv_max[0], uv_max[1], 0.0, 0.0,
+                    ];
+                    let mut view = staging_belt.write_buffer(
+                        encoder,
+                        buffer,
+                        0,
+                        NonZeroU64::new(PARAMS_SIZE).unwrap(),
+                        device,
+                    );
+                    view.copy_from_slice(bytemuck::cast_slice(&params));
+                };
+            // The horizontal pass blurs the whole target into another of the
+            // same size.
+            match target.blur {
+                Some(ref blur) if radius > 0.0 => {
+                    write(
+                        &blur.params,
+                        [1.0, radius, 1.0, 0.0],
+                        FULL_TARGET,
+                        [1.0, 1.0],
+                    );
+                    write(
+                        &target.params,
+                        [layer.alpha, radius, 0.0, 1.0],
+                        dest,
+                        uv_max,
+                    );
+                }
+                _ => write(&target.params, [layer.alpha, 0.0, 0.0, 0.0], dest, uv_max),
+            }
+        }
+    }
+
+    /// The size of target a layer covering `bounds` gets: rounded up to
+    /// `SIZE_STEP`, but never bigger than the frame, so layers covering the
+    /// whole frame get a target of exactly its size.
+    fn texture_size_for(&self, bounds: Rect) -> (u32, u32) {
+        let round = |pixels: f64, max: u32| {
+            let pixels = (pixels.max(1.0) as u32).div_ceil(SIZE_STEP) * SIZE_STEP;
+            pixels.min(max).max(1)
+        };
+        (
+            round(bounds.width(), self.size.0),
+            round(bounds.height(), self.size.1),
+        )
+    }
+
+    /// The size of layer `index`'s target, in device pixels.
+    pub(crate) fn texture_size(&self, index: usize) -> (u32, u32) {
+        self.targets[index].size
+    }
+
+    fn create_texture(
+        &self,
+        device: &wgpu::Device,
+        (width, height): (u32, u32),
+    ) -> wgpu::TextureView {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("layer texture"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        });
+        texture.create_view(&wgpu::TextureViewDescriptor::default())
+    }
+
+    fn create_params(&self, device: &wgpu::Device) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("layer params"),
+            size: PARAMS_SIZE,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_bind_group(
+        &self,
+        device: &wgpu::Device,
+        view: &wgpu::TextureView,
+        params: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("layer bind group"),
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Buffer(params.as_entire_buffer_binding()),
+                },
+            ],
+        })
+    }
+
+    fn create_target(&self, device: &wgpu::Device, size: (u32, u32)) -> LayerTarget {
+        let (width, height) = size;
+        let view = self.create_texture(device, size);
+        let msaa = crate::create_msaa(device, self.format, self.sample_count, width, height);
+        let depth = crate::render_pass::create_depth(device, self.sample_count, width, height);
+        let params = self.create_params(device);
+        let bind_group = self.create_bind_group(device, &view, &params);
+        LayerTarget {
+            view,
+            msaa,
+            depth,
+            size,
+            params,
+            bind_group,
+            blur: None,
+        }
+    }
+
+    fn create_blur_target(&self, device: &wgpu::Device, layer: &LayerTarget) -> BlurTarget {
+        let view = self.create_texture(device, layer.size);
+        let params = self.create_params(device);
+        let bind_group = self.create_bind_group(device, &layer.view, &params);
+        let composite_bind_group = self.create_bind_group(device, &view, &layer.params);
+        BlurTarget {
+            view,
+            params,
+            bind_group,
+            composite_bind_group,
+        }
+    }
+
+    /// The view to resolve layer `index` into, the multisampled view to
+    /// render it with if MSAA is on, and its depth buffer.
+    pub(crate) fn target(
+        &self,
+        index: usize,
+    ) -> (
+        &wgpu::TextureView,
+        Option<&wgpu::TextureView>,
+        &wgpu::TextureView,
+    ) {
+        let target = &self.targets[index];
+        (&target.view, target.msaa.as_ref(), &target.depth)
+    }
+
+    /// Run the horizontal blur pass for layer `index` once it's been drawn.
+    pub(crate) fn blur(&self, encoder: &mut wgpu::CommandEncoder, index: usize, layer: &Layer) {
+        let blur = match self.targets[index].blur {
+            Some(ref blur) if layer.blur > 0.0 => blur,
+            _ => return,
+        };
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: &blur.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.blur_pipeline);
+        pass.set_bind_group(0, &blur.bind_group, &[]);
+        pass.draw(0..6, 0..1);
+    }
+
+    /// Blend layer `index` with whatever `pass` has drawn so far in `blend`
+    /// mode, finishing its blur if it has one.
+    pub(crate) fn composite<'a>(
+        &'a self,
+        pass: &mut wgpu::RenderPass<'a>,
+        index: usize,
+        layer: &Layer,
+        blend: BlendMode,
+    ) {
+        let target = &self.targets[index];
+        let bind_group = match target.blur {
+            Some(ref blur) if layer.blur > 0.0 => &blur.composite_bind_group,
+            _ => &target.bind_group,
+        };
+        pass.set_pipeline(&self.pipelines[blend as usize]);
+        pass.set_bind_group(0, bind_group, &[]);
+        pass.draw(0..6, 0..1);
+    }
+}
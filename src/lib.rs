@@ -1,21 +1,50 @@
+mod blit;
 mod context;
 mod font;
+mod geometry;
+mod gradient;
+mod image;
 mod layer;
+#[cfg(feature = "debug-overdraw")]
+mod overdraw;
 mod pipeline;
+mod png;
+mod recording;
+mod render_pass;
 mod svg;
 mod text;
+#[cfg(feature = "timestamps")]
+mod timing;
 mod transformation;
+#[cfg(feature = "debug-wireframe")]
+mod wireframe;
 
 pub use piet::kurbo;
 use piet::kurbo::Size;
 pub use piet::*;
-pub use svg::Svg;
+pub use pipeline::{BlendMode, CustomShader};
+pub use recording::FrameRecording;
+pub use render_pass::{RenderPassCtx, DEPTH_FORMAT};
 use svg::SvgStore;
+pub use svg::{Svg, SvgFit, SvgStyle};
 
-use std::{cell::RefCell, marker::PhantomData, rc::Rc};
+use futures::task::LocalSpawnExt;
+use std::{
+    cell::Cell,
+    marker::PhantomData,
+    num::NonZeroU32,
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
 
+pub use context::{BlurQuality, FrameStats};
 use context::{WgpuImage, WgpuRenderContext};
+pub use gradient::{ExtendMode, MAX_GRADIENT_STOPS};
+use lyon::lyon_tessellation::VertexBuffers;
+use pipeline::{GpuVertex, Primitive};
 use text::{WgpuText, WgpuTextLayout, WgpuTextLayoutBuilder};
+#[cfg(feature = "timestamps")]
+pub use timing::FrameTimings;
 
 pub type Piet<'a> = WgpuRenderContext<'a>;
 
@@ -29,115 +58,797 @@ pub type PietTextLayoutBuilder = WgpuTextLayoutBuilder;
 
 pub type PietImage = WgpuImage;
 
+/// A whole frame read back with [`WgpuRenderer::capture_frame`].
+pub struct CapturedFrame {
+    pub width: u32,
+    pub height: u32,
+    /// Straight-alpha sRGB RGBA8 pixels in row-major order without padding,
+    /// the layout `image::RgbaImage::from_raw` expects.
+    pub pixels: Vec<u8>,
+}
+
+#[cfg(feature = "image")]
+impl CapturedFrame {
+    /// The frame as an `image` crate buffer, e.g. to save it as a PNG.
+    pub fn into_image(self) -> ::image::RgbaImage {
+        ::image::RgbaImage::from_raw(self.width, self.height, self.pixels)
+            .expect("captured frames hold width * height RGBA8 pixels")
+    }
+}
+
 pub struct WgpuRenderer {
-    instance: wgpu::Instance,
-    device: Rc<wgpu::Device>,
-    surface: wgpu::Surface,
+    adapter_info: wgpu::AdapterInfo,
+    device: Arc<wgpu::Device>,
+    surface: Option<wgpu::Surface>,
+    /// The texture frames are rendered into when there's no surface.
+    offscreen: Option<wgpu::Texture>,
     queue: wgpu::Queue,
     format: wgpu::TextureFormat,
-    staging_belt: Rc<RefCell<wgpu::util::StagingBelt>>,
+    present_mode: wgpu::PresentMode,
+    sample_count: u32,
+    staging_belt: Arc<Mutex<wgpu::util::StagingBelt>>,
     local_pool: futures::executor::LocalPool,
-    msaa: wgpu::TextureView,
+    /// The multisampled color target, absent when `sample_count` is 1.
+    msaa: Option<wgpu::TextureView>,
+    /// The depth buffer for `set_z` and custom render passes, created the
+    /// first time a frame is finished.
+    depth: Option<wgpu::TextureView>,
     size: Size,
     svg_store: SvgStore,
+    image_atlas: image::ImageAtlas,
+    /// Whether surface frames are drawn into `retained` first, so a later
+    /// frame can redraw only its damage.
+    partial_redraw: bool,
+    /// Whether surface frames are drawn into `retained` first, so
+    /// `capture_frame` can read them back.
+    frame_capture: bool,
+    /// The last frame drawn for the surface, with what copies it there.
+    retained: Option<(wgpu::Texture, blit::Blitter)>,
+    /// Whether the frame targets still hold the last frame drawn.
+    frame_kept: bool,
+    /// Created the first time an image with mipmaps is made.
+    mipmaps: Option<image::MipmapGenerator>,
+    geometry_cache: geometry::GeometryCache,
+    /// CPU-side geometry and primitives handed from frame to frame so their
+    /// allocations are reused.
+    frame_geometry: VertexBuffers<GpuVertex, u32>,
+    frame_primitives: Vec<Primitive>,
+    last_frame_stats: FrameStats,
+    record_frames: bool,
+    frame_recording: Option<FrameRecording>,
+    blocking_finish: bool,
+    frame_completion: FrameCompletion,
+    /// Whether a context has drawn into the frame begun last.
+    frame_drawn: bool,
+    #[cfg(feature = "timestamps")]
+    timer: Option<timing::GpuTimer>,
 
     text: WgpuText,
 
     pipeline: pipeline::Pipeline,
-    pub(crate) encoder: Rc<RefCell<Option<wgpu::CommandEncoder>>>,
+    pub(crate) encoder: Arc<Mutex<Option<wgpu::CommandEncoder>>>,
 }
 
-impl WgpuRenderer {
-    pub fn new<W: raw_window_handle::HasRawWindowHandle>(window: &W) -> Result<Self, piet::Error> {
+/// Whether the GPU is done with a finished frame's uploads, so the staging
+/// memory they went through can be reused; see
+/// [`WgpuRenderer::set_blocking_finish`].
+#[derive(Clone, Debug, Default)]
+pub struct FrameCompletion {
+    recalled: Rc<Cell<bool>>,
+}
+
+impl FrameCompletion {
+    /// Whether the frame's staging buffers have been recalled. It only
+    /// changes while the renderer finishes a frame or is
+    /// [polled](WgpuRenderer::poll).
+    pub fn is_complete(&self) -> bool {
+        self.recalled.get()
+    }
+}
+
+/// A frame acquired with [`WgpuRenderer::begin_frame`] that contexts made
+/// with [`WgpuRenderContext::new_for_frame`] draw into, until it's presented
+/// with [`WgpuRenderer::end_frame`].
+pub struct Frame {
+    /// `None` for headless renderers, whose frames are the offscreen texture.
+    texture: Option<wgpu::SurfaceTexture>,
+    view: wgpu::TextureView,
+}
+
+impl Frame {
+    /// The frame's texture, to render into between contexts.
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.view
+    }
+}
+
+/// A texture of the renderer's format and size to draw into with
+/// [`WgpuRenderContext::new_for_texture`] and then draw like an image, made
+/// with [`WgpuRenderer::create_render_texture`].
+///
+/// Images made from it with
+/// [`image_from_render_texture`](WgpuRenderContext::image_from_render_texture)
+/// sample the texture itself rather than a copy, and hold a clone of it, so
+/// it lives until this and every such image are dropped. Drawing it into
+/// itself is a wgpu validation error.
+pub struct RenderTexture {
+    texture: Arc<wgpu::Texture>,
+    view: wgpu::TextureView,
+    size: Size,
+}
+
+impl RenderTexture {
+    /// The texture's view, to render into between contexts.
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.view
+    }
+
+    /// The texture itself, shared with the images made from it.
+    pub fn texture(&self) -> &Arc<wgpu::Texture> {
+        &self.texture
+    }
+
+    /// The texture's size in physical pixels.
+    pub fn size(&self) -> Size {
+        self.size
+    }
+}
+
+/// Picks an adapter by its info; see [`WgpuRendererBuilder::adapter_filter`].
+#[cfg(not(target_arch = "wasm32"))]
+type AdapterFilter = Box<dyn Fn(&wgpu::AdapterInfo) -> bool>;
+
+/// Configures a [`WgpuRenderer`] before it's created.
+///
+/// The defaults match [`WgpuRenderer::new`]: `Fifo` presentation, the
+/// high-performance adapter, 4x MSAA and the surface's preferred format.
+pub struct WgpuRendererBuilder {
+    present_mode: wgpu::PresentMode,
+    power_preference: wgpu::PowerPreference,
+    sample_count: u32,
+    format: Option<wgpu::TextureFormat>,
+    glyph_atlas_size: u32,
+    features: wgpu::Features,
+    limits: Option<wgpu::Limits>,
+    #[cfg(not(target_arch = "wasm32"))]
+    adapter_filter: Option<AdapterFilter>,
+}
+
+impl Default for WgpuRendererBuilder {
+    fn default() -> Self {
+        Self {
+            present_mode: wgpu::PresentMode::Fifo,
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            sample_count: 4,
+            format: None,
+            glyph_atlas_size: 2000,
+            features: wgpu::Features::empty(),
+            limits: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            adapter_filter: None,
+        }
+    }
+}
+
+impl WgpuRendererBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn present_mode(mut self, present_mode: wgpu::PresentMode) -> Self {
+        self.present_mode = present_mode;
+        self
+    }
+
+    /// Which adapter to prefer when there's a choice, e.g. between the
+    /// integrated and discrete GPU of a laptop; `HighPerformance` by default.
+    pub fn power_preference(mut self, power_preference: wgpu::PowerPreference) -> Self {
+        self.power_preference = power_preference;
+        self
+    }
+
+    /// Pick the first adapter `filter` accepts, among those that can draw to
+    /// the window, instead of letting wgpu pick by the power preference.
+    /// Building fails if it accepts none.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn adapter_filter(mut self, filter: impl Fn(&wgpu::AdapterInfo) -> bool + 'static) -> Self {
+        self.adapter_filter = Some(Box::new(filter));
+        self
+    }
+
+    /// The MSAA sample count; 1 disables multisampling.
+    pub fn sample_count(mut self, sample_count: u32) -> Self {
+        self.sample_count = sample_count;
+        self
+    }
+
+    /// Override the color format instead of using the surface's preferred one.
+    ///
+    /// Opaque colors are drawn the same either way: on `*Srgb` formats the
+    /// hardware encodes them, on other formats the shader does. Translucent
+    /// ones blend in the space the target stores: linear light on `*Srgb`
+    /// formats, sRGB otherwise, which is what browsers do.
+    pub fn format(mut self, format: wgpu::TextureFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// The width and height of the glyph atlas texture, capped at the
+    /// device's maximum texture size.
+    ///
+    /// Once it's full, the rows of glyphs used least recently are evicted to
+    /// make room, and rasterized again when they're next needed.
+    pub fn glyph_atlas_size(mut self, size: u32) -> Self {
+        self.glyph_atlas_size = size;
+        self
+    }
+
+    /// Device features to enable on top of those the renderer uses itself,
+    /// for passes recorded on [`WgpuRenderer::device`]. Building fails if the
+    /// adapter lacks any of them.
+    pub fn features(mut self, features: wgpu::Features) -> Self {
+        self.features = features;
+        self
+    }
+
+    /// The limits to request the device with, instead of wgpu's defaults
+    /// (or WebGL2's, with the `webgl` feature). Building fails if the adapter
+    /// can't meet them.
+    pub fn limits(mut self, limits: wgpu::Limits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    pub fn build<W: raw_window_handle::HasRawWindowHandle>(
+        self,
+        window: &W,
+    ) -> Result<WgpuRenderer, piet::Error> {
         let instance = wgpu::Instance::new(wgpu::Backends::all());
         let surface = unsafe { instance.create_surface(window) };
-        let adapter =
-            futures::executor::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::HighPerformance,
-                compatible_surface: Some(&surface),
-                force_fallback_adapter: false,
-            }))
-            .ok_or(piet::Error::NotSupported)?;
-        let (device, queue) = futures::executor::block_on(
-            adapter.request_device(&wgpu::DeviceDescriptor::default(), None),
-        )
-        .map_err(|e| piet::Error::BackendError(Box::new(e)))?;
-
-        let format = surface
-            .get_preferred_format(&adapter)
-            .ok_or(piet::Error::MissingFeature("no supported texture format"))?;
+        let adapter = self.request_adapter(&instance, Some(&surface))?;
+
+        let format = match self.format {
+            Some(format) => format,
+            None => surface
+                .get_preferred_format(&adapter)
+                .ok_or(piet::Error::MissingFeature("no supported texture format"))?,
+        };
+
+        WgpuRenderer::with_adapter(self, adapter, Some(surface), format)
+    }
+
+    /// Build a renderer without a window; see [`WgpuRenderer::new_headless`].
+    pub fn build_headless(self) -> Result<WgpuRenderer, piet::Error> {
+        let instance = wgpu::Instance::new(wgpu::Backends::all());
+        let adapter = self.request_adapter(&instance, None)?;
+
+        let format = self.format.unwrap_or(wgpu::TextureFormat::Rgba8UnormSrgb);
+        WgpuRenderer::with_adapter(self, adapter, None, format)
+    }
+
+    fn request_adapter(
+        &self,
+        instance: &wgpu::Instance,
+        surface: Option<&wgpu::Surface>,
+    ) -> Result<wgpu::Adapter, piet::Error> {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(filter) = self.adapter_filter.as_ref() {
+            return instance
+                .enumerate_adapters(wgpu::Backends::all())
+                .filter(|adapter| match surface {
+                    Some(surface) => adapter.is_surface_supported(surface),
+                    None => true,
+                })
+                .find(|adapter| filter(&adapter.get_info()))
+                .ok_or(piet::Error::MissingFeature(
+                    "no adapter passed the adapter filter",
+                ));
+        }
+        futures::executor::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: self.power_preference,
+            compatible_surface: surface,
+            force_fallback_adapter: false,
+        }))
+        .ok_or(piet::Error::NotSupported)
+    }
+}
+
+impl WgpuRenderer {
+    pub fn new<W: raw_window_handle::HasRawWindowHandle>(window: &W) -> Result<Self, piet::Error> {
+        WgpuRendererBuilder::new().build(window)
+    }
+
+    /// Create a renderer without a window.
+    ///
+    /// Frames are resolved into an offscreen `Rgba8UnormSrgb` texture sized by
+    /// [`set_size`](Self::set_size) instead of being presented, and can be read
+    /// back with `capture_image_area`.
+    pub fn new_headless() -> Result<Self, piet::Error> {
+        WgpuRendererBuilder::new().build_headless()
+    }
+
+    pub fn builder() -> WgpuRendererBuilder {
+        WgpuRendererBuilder::new()
+    }
+
+    fn with_adapter(
+        builder: WgpuRendererBuilder,
+        adapter: wgpu::Adapter,
+        surface: Option<wgpu::Surface>,
+        format: wgpu::TextureFormat,
+    ) -> Result<Self, piet::Error> {
+        let missing = builder.features - adapter.features();
+        if !missing.is_empty() {
+            return Err(piet::Error::BackendError(
+                format!("adapter doesn't support features {:?}", missing).into(),
+            ));
+        }
+        #[cfg_attr(not(feature = "timestamps"), allow(unused_mut))]
+        let mut features = builder.features;
+        #[cfg(feature = "timestamps")]
+        {
+            features |= adapter.features() & wgpu::Features::TIMESTAMP_QUERY;
+        }
+        // Everything piet draws fits in WebGL2's limits; only raise the
+        // texture size to what the adapter allows.
+        #[cfg(feature = "webgl")]
+        let default_limits =
+            || wgpu::Limits::downlevel_webgl2_defaults().using_resolution(adapter.limits());
+        #[cfg(not(feature = "webgl"))]
+        let default_limits = wgpu::Limits::default;
+        let descriptor = wgpu::DeviceDescriptor {
+            label: None,
+            features,
+            limits: builder.limits.clone().unwrap_or_else(default_limits),
+        };
+        let (device, queue) =
+            futures::executor::block_on(adapter.request_device(&descriptor, None))
+                .map_err(|e| piet::Error::BackendError(Box::new(e)))?;
+        #[cfg(feature = "timestamps")]
+        let timer = timing::GpuTimer::new(&device, &queue);
 
         let staging_belt = wgpu::util::StagingBelt::new(1024);
         let local_pool = futures::executor::LocalPool::new();
 
-        let msaa_texture = device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("Multisampled frame descriptor"),
-            size: wgpu::Extent3d {
-                width: 1,
-                height: 1,
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 4,
-            dimension: wgpu::TextureDimension::D2,
-            format,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-        });
-        let msaa = msaa_texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sample_count = builder.sample_count;
+        let msaa = create_msaa(&device, format, sample_count, 1, 1);
 
-        let staging_belt = Rc::new(RefCell::new(staging_belt));
-        let encoder = Rc::new(RefCell::new(None));
-        let device = Rc::new(device);
-        let text = WgpuText::new(device.clone(), staging_belt.clone(), encoder.clone());
-        let pipeline = pipeline::Pipeline::new(&device, format, &text.cache.borrow());
+        let staging_belt = Arc::new(Mutex::new(staging_belt));
+        let encoder = Arc::new(Mutex::new(None));
+        let device = Arc::new(device);
+        let atlas_size = builder
+            .glyph_atlas_size
+            .clamp(1, device.limits().max_texture_dimension_2d);
+        let text = WgpuText::new(
+            device.clone(),
+            staging_belt.clone(),
+            encoder.clone(),
+            atlas_size,
+        );
+        let pipeline = pipeline::Pipeline::new(
+            &device,
+            format,
+            sample_count,
+            text.cache
+                .lock()
+                .unwrap()
+                .textures()
+                .expect("text made with a device has atlas textures"),
+        );
 
         Ok(Self {
-            instance,
+            adapter_info: adapter.get_info(),
             device,
             queue,
             surface,
+            offscreen: None,
             text,
             size: Size::ZERO,
             format,
+            present_mode: builder.present_mode,
+            sample_count,
             staging_belt,
             local_pool,
             msaa,
+            depth: None,
             pipeline,
             svg_store: SvgStore::new(),
+            image_atlas: image::ImageAtlas::new(),
+            partial_redraw: false,
+            frame_capture: false,
+            retained: None,
+            frame_kept: false,
+            mipmaps: None,
+            geometry_cache: geometry::GeometryCache::default(),
+            frame_geometry: VertexBuffers::new(),
+            frame_primitives: Vec::new(),
+            last_frame_stats: FrameStats::default(),
+            record_frames: false,
+            frame_recording: None,
+            blocking_finish: true,
+            frame_completion: FrameCompletion::default(),
+            frame_drawn: false,
+            #[cfg(feature = "timestamps")]
+            timer,
             encoder,
         })
     }
 
     pub fn set_size(&mut self, size: Size) {
         self.size = size;
-        let sc_desc = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-            format: self.format,
-            width: size.width as u32,
-            height: size.height as u32,
-            present_mode: wgpu::PresentMode::Fifo,
+        self.retained = None;
+        self.frame_kept = false;
+        if self.surface.is_some() {
+            self.configure_surface();
+        } else {
+            self.offscreen = Some(self.device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("Offscreen frame"),
+                size: wgpu::Extent3d {
+                    width: size.width as u32,
+                    height: size.height as u32,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: self.format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                    | wgpu::TextureUsages::COPY_SRC
+                    | wgpu::TextureUsages::TEXTURE_BINDING,
+            }));
+        }
+        self.depth = None;
+        self.msaa = create_msaa(
+            &self.device,
+            self.format,
+            self.sample_count,
+            size.width as u32,
+            size.height as u32,
+        );
+        self.pipeline.size = size;
+    }
+
+    fn configure_surface(&self) {
+        if let Some(surface) = self.surface.as_ref() {
+            let sc_desc = wgpu::SurfaceConfiguration {
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+                format: self.format,
+                width: self.size.width as u32,
+                height: self.size.height as u32,
+                present_mode: self.present_mode,
+            };
+            surface.configure(&self.device, &sc_desc);
+        }
+    }
+
+    /// Acquire the next surface texture.
+    ///
+    /// A `Lost` or `Outdated` surface (e.g. after a GPU switch or display
+    /// change) is reconfigured and acquisition retried once; any other error,
+    /// such as `OutOfMemory`, is returned to the caller.
+    pub(crate) fn acquire_surface_texture(
+        &self,
+    ) -> Option<Result<wgpu::SurfaceTexture, wgpu::SurfaceError>> {
+        let surface = self.surface.as_ref()?;
+        let texture = match surface.get_current_texture() {
+            Err(wgpu::SurfaceError::Lost) | Err(wgpu::SurfaceError::Outdated) => {
+                self.configure_surface();
+                surface.get_current_texture()
+            }
+            result => result,
+        };
+        Some(texture)
+    }
+
+    /// Acquire the next surface texture for several contexts, and anything
+    /// else, to draw into before it's presented with
+    /// [`end_frame`](Self::end_frame).
+    ///
+    /// The first context finished in the frame starts from the clear color
+    /// and later ones draw over it. Partial redraws don't apply to these
+    /// frames. With MSAA, later contexts start from the multisampled copy of
+    /// the frame, which misses anything rendered into [`Frame::view`]
+    /// outside of them; use a custom render pass for that instead.
+    pub fn begin_frame(&mut self) -> Result<Frame, piet::Error> {
+        let texture = match self.acquire_surface_texture() {
+            Some(texture) => Some(texture.map_err(|e| piet::Error::BackendError(Box::new(e)))?),
+            None => None,
         };
-        self.surface.configure(&self.device, &sc_desc);
-        let msaa_texture = self.device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("Multisampled frame descriptor"),
+        let view = match (&texture, &self.offscreen) {
+            (Some(texture), _) => &texture.texture,
+            (None, Some(offscreen)) => offscreen,
+            (None, None) => return Err(piet::Error::InvalidInput),
+        }
+        .create_view(&wgpu::TextureViewDescriptor::default());
+        self.frame_drawn = false;
+        Ok(Frame { texture, view })
+    }
+
+    /// Present a frame begun with [`begin_frame`](Self::begin_frame). Each
+    /// context submitted its drawing when it was finished.
+    pub fn end_frame(&mut self, frame: Frame) {
+        if let Some(texture) = frame.texture {
+            texture.present();
+        }
+    }
+
+    /// Create a texture to draw a context into instead of the surface, and
+    /// then draw as an image in later contexts; see [`RenderTexture`].
+    ///
+    /// It has the renderer's format and current physical size, so it has to
+    /// be created again after the renderer is resized.
+    pub fn create_render_texture(&self) -> RenderTexture {
+        let size = self.size;
+        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("render texture"),
             size: wgpu::Extent3d {
-                width: size.width as u32,
-                height: size.height as u32,
+                width: (size.width as u32).max(1),
+                height: (size.height as u32).max(1),
                 depth_or_array_layers: 1,
             },
             mip_level_count: 1,
-            sample_count: 4,
+            sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format: self.format,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
         });
-        self.msaa = msaa_texture.create_view(&wgpu::TextureViewDescriptor::default());
-        self.pipeline.size = size;
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        RenderTexture {
+            texture: Arc::new(texture),
+            view,
+            size,
+        }
+    }
+
+    /// Compile a custom fragment shader to fill and stroke with, via
+    /// [`WgpuRenderContext::fill_with_shader`] and
+    /// [`stroke_with_shader`](WgpuRenderContext::stroke_with_shader). See
+    /// [`CustomShader`] for what its WGSL has to define and what it's given.
+    ///
+    /// WGSL that doesn't compile is a `BackendError` with wgpu's message.
+    pub fn register_shader(&mut self, source: &str) -> Result<CustomShader, piet::Error> {
+        let index = self.pipeline.add_custom_shader(&self.device, source)?;
+        Ok(CustomShader { index })
     }
 
+    /// Reconfigure the surface for a new physical size and DPI scale.
+    ///
+    /// This reallocates every size-dependent target, so it should be called
+    /// from the window's resize handler before the next frame is drawn. Zero
+    /// sizes (e.g. a minimized window) are clamped to 1x1.
+    pub fn resize(&mut self, width: u32, height: u32, scale: f64) {
+        self.set_size(Size::new(width.max(1) as f64, height.max(1) as f64));
+        self.set_scale(scale);
+    }
+
+    /// The size of the surface or offscreen target in logical pixels, the
+    /// units drawing is done in: the physical size divided by the scale.
+    pub fn size(&self) -> Size {
+        let scale = self.scale();
+        Size::new(self.size.width / scale, self.size.height / scale)
+    }
+
+    /// The size of the surface or offscreen target in physical pixels, as
+    /// last set with [`set_size`](Self::set_size) or
+    /// [`resize`](Self::resize).
+    pub fn physical_size(&self) -> Size {
+        self.size
+    }
+
+    /// Physical pixels per logical pixel, which text is rasterized at.
+    pub fn scale(&self) -> f64 {
+        self.pipeline.scale
+    }
+
+    /// The color every frame starts from unless it only redraws damaged
+    /// areas; white by default. Clearing is free, so this is cheaper than
+    /// filling the window. Custom render passes draw over it.
+    pub fn set_clear_color(&mut self, color: Color) {
+        self.pipeline.set_clear_color(&color);
+    }
+
+    /// Set the DPI scale without resizing, e.g. when the window moves to a
+    /// monitor with a different one.
+    ///
+    /// Glyphs are rasterized again at the new density: existing text
+    /// layouts rebuild the next time they're drawn or measured.
     pub fn set_scale(&mut self, scale: f64) {
         self.pipeline.scale = scale;
-        self.text.cache.borrow_mut().scale = scale;
+        self.text.cache.lock().unwrap().set_scale(scale);
+    }
+
+    /// The fraction of the glyph atlas taken up by rasterized glyphs, to help
+    /// tune [`glyph_atlas_size`](WgpuRendererBuilder::glyph_atlas_size).
+    pub fn glyph_atlas_occupancy(&self) -> f64 {
+        self.text.cache.lock().unwrap().occupancy()
+    }
+
+    /// How long the GPU took on the most recent frame whose timestamps have
+    /// been read back, usually the one before the last.
+    ///
+    /// `None` until the first readback finishes, or if the adapter doesn't
+    /// support `TIMESTAMP_QUERY`.
+    #[cfg(feature = "timestamps")]
+    pub fn gpu_timings(&self) -> Option<&FrameTimings> {
+        self.timer.as_ref().and_then(|timer| timer.last())
+    }
+
+    /// Keep each frame drawn to the surface, so a context can redraw only
+    /// the areas it declares damaged with
+    /// [`add_damage`](WgpuRenderContext::add_damage).
+    ///
+    /// Frames are then drawn into a texture of their own and copied onto
+    /// the surface. Headless renderers keep their frames regardless.
+    pub fn set_partial_redraw(&mut self, enabled: bool) {
+        self.partial_redraw = enabled;
+        if !self.keeps_surface_frames() {
+            self.retained = None;
+        }
+    }
+
+    /// Keep each frame drawn to the surface so
+    /// [`capture_frame`](Self::capture_frame) and `capture_image_area` can
+    /// read it back, off by default. Like partial redraws, frames are then
+    /// drawn into a texture of their own and copied onto the surface.
+    /// Headless renderers can always be captured.
+    pub fn set_frame_capture(&mut self, enabled: bool) {
+        self.frame_capture = enabled;
+        if !self.keeps_surface_frames() {
+            self.retained = None;
+        }
+    }
+
+    /// Whether surface frames are drawn into a texture that's kept.
+    pub(crate) fn keeps_surface_frames(&self) -> bool {
+        self.partial_redraw || self.frame_capture
+    }
+
+    /// The texture holding the last frame drawn, if it can be read back.
+    pub(crate) fn readable_frame(&self) -> Option<&wgpu::Texture> {
+        self.offscreen
+            .as_ref()
+            .or_else(|| self.retained.as_ref().map(|(texture, _)| texture))
+    }
+
+    /// The texture surface frames are drawn into with partial redraws or
+    /// frame capture on, created the first time it's needed.
+    pub(crate) fn retained_view(&mut self) -> wgpu::TextureView {
+        let (device, format, size) = (&self.device, self.format, self.size);
+        let (texture, _) = self.retained.get_or_insert_with(|| {
+            let texture = device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("retained frame"),
+                size: wgpu::Extent3d {
+                    width: (size.width as u32).max(1),
+                    height: (size.height as u32).max(1),
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                    | wgpu::TextureUsages::TEXTURE_BINDING
+                    | wgpu::TextureUsages::COPY_SRC,
+            });
+            (texture, blit::Blitter::new(device, format))
+        });
+        texture.create_view(&wgpu::TextureViewDescriptor::default())
+    }
+
+    /// Whether frames are drawn with a heatmap over them of how many times
+    /// each pixel was drawn, from blue for once to red for 8 or more. Off by
+    /// default.
+    ///
+    /// Every fragment shaded counts, translucent or not, including the
+    /// transparent corners of quads. Layers count where their content is
+    /// drawn, but compositing them doesn't.
+    #[cfg(feature = "debug-overdraw")]
+    pub fn set_debug_overdraw(&mut self, enabled: bool) {
+        self.pipeline.debug_overdraw = enabled;
+    }
+
+    /// Whether finishing a frame also runs the staging belt's recall, and
+    /// any GPU readbacks, as far as they can get before returning. On by
+    /// default.
+    ///
+    /// Turn it off to return as soon as the frame is submitted and drive
+    /// them from the event loop with [`poll`](Self::poll) instead. Until a
+    /// frame's [completion](Self::frame_completion), uploads go through
+    /// newly allocated staging memory, so poll at least once a frame.
+    pub fn set_blocking_finish(&mut self, blocking: bool) {
+        self.blocking_finish = blocking;
+    }
+
+    /// The completion of the last finished frame.
+    pub fn frame_completion(&self) -> FrameCompletion {
+        self.frame_completion.clone()
+    }
+
+    /// Make what progress the GPU allows on finished frames without
+    /// waiting, and return whether the last one is complete.
+    pub fn poll(&mut self) -> bool {
+        self.device.poll(wgpu::Maintain::Poll);
+        self.local_pool.run_until_stalled();
+        self.frame_completion.is_complete()
+    }
+
+    pub(crate) fn start_recall(&mut self) {
+        let completion = FrameCompletion::default();
+        let recalled = completion.recalled.clone();
+        let recall = self.staging_belt.lock().unwrap().recall();
+        self.local_pool
+            .spawner()
+            .spawn_local(async move {
+                recall.await;
+                recalled.set(true);
+            })
+            .expect("Recall staging belt");
+        self.frame_completion = completion;
+    }
+
+    /// What the last finished frame recorded.
+    pub fn last_frame_stats(&self) -> FrameStats {
+        self.last_frame_stats
+    }
+
+    /// Whether finishing a frame records what it drew, for
+    /// [`take_frame_recording`](Self::take_frame_recording). Off by default,
+    /// since it copies the frame's geometry.
+    pub fn set_frame_recording(&mut self, enabled: bool) {
+        self.record_frames = enabled;
+        if !enabled {
+            self.frame_recording = None;
+        }
+    }
+
+    /// The last frame recorded since frame recording was turned on.
+    pub fn take_frame_recording(&mut self) -> Option<FrameRecording> {
+        self.frame_recording.take()
+    }
+
+    /// The number of vertices and indices the persistent GPU buffers can hold
+    /// before they have to be reallocated.
+    pub fn buffer_capacity(&self) -> (usize, usize) {
+        self.pipeline.capacity()
+    }
+
+    /// The device the renderer draws with, for creating resources that are
+    /// used alongside it.
+    pub fn device(&self) -> &Arc<wgpu::Device> {
+        &self.device
+    }
+
+    /// The adapter the renderer's device was created on: its name, vendor,
+    /// device type and backend, to log or put in bug reports.
+    pub fn adapter_info(&self) -> wgpu::AdapterInfo {
+        self.adapter_info.clone()
+    }
+
+    /// The queue the renderer submits to.
+    pub fn queue(&self) -> &wgpu::Queue {
+        &self.queue
+    }
+
+    /// Read back the whole frame drawn by the last `finish` as sRGB RGBA8
+    /// with straight alpha, e.g. for golden-image tests or screenshots.
+    ///
+    /// Windowed renderers need [frame capture](Self::set_frame_capture) or
+    /// partial redraws on to keep a readable frame; without them, and for
+    /// targets that aren't 8-bit RGBA or BGRA, this returns `NotSupported`.
+    pub fn capture_frame(&self) -> Result<CapturedFrame, piet::Error> {
+        let texture = self.readable_frame().ok_or(piet::Error::NotSupported)?;
+        let (width, height) = (self.size.width as u32, self.size.height as u32);
+        let pixels = self.read_texture(texture, [0, 0, width, height])?;
+        Ok(CapturedFrame {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    /// The texture format every target drawn by this renderer must use.
+    pub fn format(&self) -> wgpu::TextureFormat {
+        self.format
     }
 
     pub fn text(&self) -> WgpuText {
@@ -145,7 +856,7 @@ impl WgpuRenderer {
     }
 
     pub(crate) fn ensure_encoder(&mut self) {
-        let mut encoder = self.encoder.borrow_mut();
+        let mut encoder = self.encoder.lock().unwrap();
         if encoder.is_none() {
             *encoder = Some(
                 self.device
@@ -157,10 +868,139 @@ impl WgpuRenderer {
     }
 
     pub(crate) fn take_encoder(&mut self) -> wgpu::CommandEncoder {
-        self.encoder.take().unwrap()
+        self.encoder.lock().unwrap().take().unwrap()
+    }
+
+    /// Copy `rect` of `texture` back to the CPU as tightly packed 4-byte pixels.
+    pub(crate) fn read_texture(
+        &self,
+        texture: &wgpu::Texture,
+        rect: [u32; 4],
+    ) -> Result<Vec<u8>, piet::Error> {
+        let bgra = match self.format {
+            wgpu::TextureFormat::Rgba8UnormSrgb | wgpu::TextureFormat::Rgba8Unorm => false,
+            wgpu::TextureFormat::Bgra8UnormSrgb | wgpu::TextureFormat::Bgra8Unorm => true,
+            _ => return Err(piet::Error::NotSupported),
+        };
+        let [x, y, width, height] = rect;
+        if width == 0 || height == 0 {
+            return Ok(Vec::new());
+        }
+
+        let unpadded_bytes_per_row = width as usize * 4;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as usize;
+        let padded_bytes_per_row =
+            unpadded_bytes_per_row + (align - unpadded_bytes_per_row % align) % align;
+
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback buffer"),
+            size: (padded_bytes_per_row * height as usize) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("readback"),
+            });
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d { x, y, z: 0 },
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(padded_bytes_per_row as u32),
+                    rows_per_image: NonZeroU32::new(height),
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let mapping = slice.map_async(wgpu::MapMode::Read);
+        self.device.poll(wgpu::Maintain::Wait);
+        futures::executor::block_on(mapping).map_err(|e| piet::Error::BackendError(Box::new(e)))?;
+
+        let mut pixels = Vec::with_capacity(unpadded_bytes_per_row * height as usize);
+        {
+            let data = slice.get_mapped_range();
+            for row in data.chunks(padded_bytes_per_row) {
+                pixels.extend_from_slice(&row[..unpadded_bytes_per_row]);
+            }
+        }
+        buffer.unmap();
+        if bgra {
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+        for pixel in pixels.chunks_exact_mut(4) {
+            self.unpremultiply(pixel);
+        }
+        Ok(pixels)
+    }
+
+    /// Turn a pixel of a target, which holds premultiplied color, into
+    /// straight alpha.
+    ///
+    /// sRGB targets premultiply in linear light before encoding, so their
+    /// colors are decoded to divide by alpha; the others premultiply the
+    /// encoded color.
+    fn unpremultiply(&self, pixel: &mut [u8]) {
+        let alpha = pixel[3];
+        if alpha == 0 || alpha == 255 {
+            return;
+        }
+        let alpha = alpha as f32 / 255.0;
+        let srgb = self.format.describe().srgb;
+        for channel in &mut pixel[..3] {
+            let value = *channel as f32 / 255.0;
+            let value = match srgb {
+                true => context::linear_to_srgb((context::srgb_to_linear(value) / alpha).min(1.0)),
+                false => (value / alpha).min(1.0),
+            };
+            *channel = (value * 255.0).round() as u8;
+        }
     }
 }
 
+fn create_msaa(
+    device: &wgpu::Device,
+    format: wgpu::TextureFormat,
+    sample_count: u32,
+    width: u32,
+    height: u32,
+) -> Option<wgpu::TextureView> {
+    if sample_count <= 1 {
+        return None;
+    }
+    let msaa_texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Multisampled frame descriptor"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+    });
+    Some(msaa_texture.create_view(&wgpu::TextureViewDescriptor::default()))
+}
+
 pub struct Device {
     // Since not all backends can support `Device: Sync`, make it non-Sync here to, for fewer
     // portability surprises.
@@ -173,3 +1013,42 @@ unsafe impl Send for Device {}
 pub struct BitmapTarget<'a> {
     phantom: PhantomData<&'a ()>,
 }
+
+/// A headless renderer of `width` by `height` pixels at a scale of 1.
+///
+/// Tests that need one are `#[ignore]`d, since CI has no GPU adapter; run
+/// them with `cargo test -- --ignored` on a machine that has one.
+#[cfg(test)]
+pub(crate) fn test_renderer(width: u32, height: u32) -> WgpuRenderer {
+    let mut renderer = WgpuRenderer::new_headless().expect("no GPU adapter");
+    renderer.resize(width, height, 1.0);
+    renderer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use piet::{kurbo::Rect, Color, RenderContext};
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn frames_after_a_resize_cover_the_new_size() {
+        let mut renderer = test_renderer(4, 4);
+        renderer.set_clear_color(Color::TRANSPARENT);
+        let mut ctx = WgpuRenderContext::new(&mut renderer);
+        ctx.fill(Rect::new(0.0, 0.0, 4.0, 4.0), &Color::WHITE);
+        ctx.finish().unwrap();
+        drop(ctx);
+
+        renderer.resize(16, 12, 2.0);
+        assert_eq!(renderer.size(), Size::new(8.0, 6.0));
+        let mut ctx = WgpuRenderContext::new(&mut renderer);
+        ctx.fill(Rect::new(0.0, 0.0, 8.0, 6.0), &Color::WHITE);
+        ctx.finish().unwrap();
+        drop(ctx);
+
+        let frame = renderer.capture_frame().unwrap();
+        assert_eq!((frame.width, frame.height), (16, 12));
+        assert!(frame.pixels.iter().all(|&c| c == 255));
+    }
+}
@@ -0,0 +1,201 @@
+use piet::kurbo::Size;
+
+use crate::pipeline::{GpuVertex, VERTEX_ATTRIBUTES};
+
+/// Counts how many draws cover each pixel of a frame and shows the counts
+/// over it as a heatmap, to find overdraw.
+pub(crate) struct Overdraw {
+    count_pipeline: wgpu::RenderPipeline,
+    heatmap_pipeline: wgpu::RenderPipeline,
+    heatmap_layout: wgpu::BindGroupLayout,
+    /// The count target, for the size it was made at.
+    counts: Option<(Size, wgpu::TextureView, wgpu::BindGroup)>,
+}
+
+impl Overdraw {
+    const COUNT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R16Float;
+
+    /// Counts with the geometry pipeline's shader and layout, so every
+    /// fragment the frame shades is counted, translucent or not.
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        shader: &wgpu::ShaderModule,
+        layout: &wgpu::PipelineLayout,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Self {
+        let count_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("overdraw count pipeline"),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: std::mem::size_of::<GpuVertex>() as u64,
+                    step_mode: wgpu::VertexStepMode::Vertex,
+                    attributes: &VERTEX_ATTRIBUTES,
+                }],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: "fs_overdraw",
+                targets: &[wgpu::ColorTargetState {
+                    format: Self::COUNT_FORMAT,
+                    blend: Some(wgpu::BlendState {
+                        color: wgpu::BlendComponent {
+                            src_factor: wgpu::BlendFactor::One,
+                            dst_factor: wgpu::BlendFactor::One,
+                            operation: wgpu::BlendOperation::Add,
+                        },
+                        alpha: wgpu::BlendComponent::REPLACE,
+                    }),
+                    write_mask: wgpu::ColorWrites::RED,
+                }],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        let heatmap_shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("overdraw heatmap shader"),
+            source: wgpu::ShaderSource::Wgsl(std::borrow::Cow::Borrowed(include_str!(
+                "shader/overdraw.wgsl"
+            ))),
+        });
+        let heatmap_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("overdraw heatmap bind group layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            }],
+        });
+        let heatmap_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("overdraw heatmap pipeline layout"),
+                bind_group_layouts: &[&heatmap_layout],
+                push_constant_ranges: &[],
+            });
+        let heatmap_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("overdraw heatmap pipeline"),
+            layout: Some(&heatmap_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &heatmap_shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &heatmap_shader,
+                entry_point: "fs_main",
+                targets: &[wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                }],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        });
+
+        Self {
+            count_pipeline,
+            heatmap_pipeline,
+            heatmap_layout,
+            counts: None,
+        }
+    }
+
+    /// Make sure the count target is `size` physical pixels.
+    pub(crate) fn resize(&mut self, device: &wgpu::Device, size: Size) {
+        if matches!(self.counts, Some((counts_size, _, _)) if counts_size == size) {
+            return;
+        }
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("overdraw counts"),
+            size: wgpu::Extent3d {
+                width: (size.width as u32).max(1),
+                height: (size.height as u32).max(1),
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::COUNT_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("overdraw heatmap bind group"),
+            layout: &self.heatmap_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&view),
+            }],
+        });
+        self.counts = Some((size, view, bind_group));
+    }
+
+    /// A pass that adds one to the count of every pixel each draw covers,
+    /// with the count pipeline set. Call [`resize`](Self::resize) first.
+    pub(crate) fn count_pass<'a>(
+        &'a self,
+        encoder: &'a mut wgpu::CommandEncoder,
+    ) -> wgpu::RenderPass<'a> {
+        let (_, view, _) = self.counts.as_ref().expect("count target resized");
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("overdraw counts"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.count_pipeline);
+        pass
+    }
+
+    /// Draw the counted pixels over `view` as a heatmap.
+    pub(crate) fn show(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        msaa: Option<&wgpu::TextureView>,
+    ) {
+        let (_, _, bind_group) = match self.counts {
+            Some(ref counts) => counts,
+            None => return,
+        };
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("overdraw heatmap"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: msaa.unwrap_or(view),
+                resolve_target: msaa.map(|_| view),
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.heatmap_pipeline);
+        pass.set_bind_group(0, bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
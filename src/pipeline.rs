@@ -1,27 +1,23 @@
-use std::hash::BuildHasherDefault;
 use std::num::{NonZeroU32, NonZeroU64};
+use std::rc::Rc;
 use std::sync::Arc;
 
-use font_kit::canvas::{Canvas, Format, RasterizationOptions};
-use font_kit::family_name::FamilyName;
-use font_kit::font::Font;
-use font_kit::hinting::HintingOptions;
-use font_kit::loader::Loader;
-use font_kit::source::SystemSource;
 use hashbrown::HashMap;
 use include_dir::include_dir;
 use include_dir::Dir;
 use linked_hash_map::LinkedHashMap;
-use lyon::lyon_tessellation::{
-    BuffersBuilder, FillOptions, FillTessellator, FillVertex, FillVertexConstructor,
-    StrokeVertexConstructor, VertexBuffers,
-};
-use lyon::tessellation;
-use pathfinder_geometry::transform2d::Transform2F;
-use pathfinder_geometry::vector::{Vector2F, Vector2I};
-use piet::kurbo::{Affine, Point, Rect, Size};
-use piet::{Color, FontFamily, FontWeight};
-use wgpu::util::DeviceExt;
+use lyon::lyon_tessellation::VertexBuffers;
+use piet::kurbo::{Affine, BezPath, PathEl, Point, Rect, Size};
+use piet::{Color, FontFamily, FontWeight, InterpolationMode};
+
+use crate::context::format_color;
+use crate::font::{Font, SystemFonts};
+use crate::layer::{Compositor, Layer};
+#[cfg(feature = "debug-overdraw")]
+use crate::overdraw::Overdraw;
+use crate::render_pass::DEPTH_FORMAT;
+#[cfg(feature = "debug-wireframe")]
+use crate::wireframe::Wireframe;
 
 const FONTS_DIR: Dir = include_dir!("./fonts");
 const DEFAULT_FONT: &[u8] = include_bytes!("../fonts/CascadiaCode-Regular.otf");
@@ -31,7 +27,12 @@ const DEFAULT_FONT: &[u8] = include_bytes!("../fonts/CascadiaCode-Regular.otf");
 struct Globals {
     resolution: [f32; 2],
     scale: f32,
-    _pad: f32,
+    /// 1 when the target isn't sRGB, so the shader encodes colors itself.
+    encode_srgb: f32,
+    /// Where the target's top left corner is in the frame, in device
+    /// pixels. Layers only cover the part of the frame they draw to.
+    origin: [f32; 2],
+    _pad: [f32; 2],
 }
 
 unsafe impl bytemuck::Pod for Globals {}
@@ -43,11 +44,29 @@ pub struct Primitive {
     pub(crate) clip_rect: [f32; 4],
     pub(crate) transform_1: [f32; 4],
     pub(crate) blur_rect: [f32; 4],
+    pub(crate) gradient: [f32; 4],
     pub(crate) transform_2: [f32; 2],
     pub(crate) translate: [f32; 2],
     pub(crate) scale: [f32; 2],
+    /// The index of the gradient's first stop in the frame's stops, and how
+    /// many it has.
+    pub(crate) gradient_stops: [f32; 2],
     pub(crate) clip: f32,
+    /// The standard deviation of the blur of `blur_rect`, negated for inset
+    /// shadows. 0 for none.
     pub(crate) blur_radius: f32,
+    /// 0 for none, 1 for linear and 2 for radial.
+    pub(crate) gradient_kind: f32,
+    pub(crate) blur_corner_radius: f32,
+    /// The depth drawing is tested at, from 0 to 1.
+    pub(crate) z: f32,
+    /// 0 to clamp, 1 to repeat and 2 to reflect.
+    pub(crate) gradient_extend: f32,
+    /// Multiplied into the alpha of everything drawn with the primitive.
+    pub(crate) alpha: f32,
+    /// How far inside `clip_rect` its edges fade out, in logical pixels; 0
+    /// for a hard edge.
+    pub(crate) clip_feather: f32,
 }
 
 unsafe impl bytemuck::Pod for Primitive {}
@@ -64,6 +83,276 @@ impl Default for Primitive {
             transform_2: [0.0, 0.0],
             blur_rect: [0.0, 0.0, 0.0, 0.0],
             blur_radius: 0.0,
+            gradient: [0.0, 0.0, 0.0, 0.0],
+            gradient_stops: [0.0, 0.0],
+            gradient_kind: 0.0,
+            blur_corner_radius: 0.0,
+            z: 0.0,
+            gradient_extend: 0.0,
+            alpha: 1.0,
+            clip_feather: 0.0,
+        }
+    }
+}
+
+/// Per-frame data the shaders look up by index, kept in a float texture
+/// since WebGL2 has no storage buffers.
+///
+/// Items are a whole number of texels, packed along rows `ROW_TEXELS` wide.
+/// The texture's filled from a buffer so the upload is ordered with the rest
+/// of the frame's commands, and grows by powers of two like the other
+/// per-frame buffers.
+struct DataTexture {
+    label: &'static str,
+    buffer: wgpu::Buffer,
+    texture: wgpu::Texture,
+    capacity: usize,
+}
+
+impl DataTexture {
+    const ROW_TEXELS: u32 = 1024;
+    const TEXEL_SIZE: u32 = 16;
+    const BINDING_TYPE: wgpu::BindingType = wgpu::BindingType::Texture {
+        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+        view_dimension: wgpu::TextureViewDimension::D2,
+        multisampled: false,
+    };
+
+    /// Room for `rows` rows of `T`, which must be a multiple of 16 bytes.
+    fn new<T>(device: &wgpu::Device, label: &'static str, rows: u32) -> Self {
+        let item_size = std::mem::size_of::<T>() as u32;
+        debug_assert_eq!(item_size % Self::TEXEL_SIZE, 0);
+        let row_bytes = Self::ROW_TEXELS * Self::TEXEL_SIZE;
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: (row_bytes * rows) as u64,
+            usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width: Self::ROW_TEXELS,
+                height: rows,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        });
+        Self {
+            label,
+            buffer,
+            texture,
+            capacity: (row_bytes / item_size * rows) as usize,
+        }
+    }
+
+    /// Make room for `len` items of `T`, replacing the texture with one of
+    /// the next power-of-two row count if it's too small, as far as the
+    /// device's texture size limit allows. Returns whether it was replaced.
+    fn reserve<T>(&mut self, device: &wgpu::Device, len: usize) -> bool {
+        if len <= self.capacity {
+            return false;
+        }
+        let items_per_row =
+            (Self::ROW_TEXELS * Self::TEXEL_SIZE) as usize / std::mem::size_of::<T>();
+        let rows = len.div_ceil(items_per_row).next_power_of_two() as u32;
+        let rows = rows.min(device.limits().max_texture_dimension_2d);
+        if rows as usize * items_per_row <= self.capacity {
+            return false;
+        }
+        *self = Self::new::<T>(device, self.label, rows);
+        true
+    }
+
+    /// Upload `items`, dropping those past the texture's capacity with an
+    /// `InvalidInput` error if even `reserve` couldn't make room for them.
+    fn upload<T: bytemuck::Pod>(
+        &self,
+        device: &wgpu::Device,
+        staging_belt: &mut wgpu::util::StagingBelt,
+        encoder: &mut wgpu::CommandEncoder,
+        items: &[T],
+    ) -> Result<(), piet::Error> {
+        let result = if items.len() > self.capacity {
+            log::warn!(
+                "{} {} past the {} the device supports were dropped",
+                items.len() - self.capacity,
+                self.label,
+                self.capacity,
+            );
+            Err(piet::Error::InvalidInput)
+        } else {
+            Ok(())
+        };
+        let bytes: &[u8] = bytemuck::cast_slice(&items[..items.len().min(self.capacity)]);
+        let size = match NonZeroU64::new(bytes.len() as u64) {
+            Some(size) => size,
+            None => return result,
+        };
+        staging_belt
+            .write_buffer(encoder, &self.buffer, 0, size, device)
+            .copy_from_slice(bytes);
+        let row_bytes = Self::ROW_TEXELS * Self::TEXEL_SIZE;
+        let rows = (bytes.len() as u32).div_ceil(row_bytes);
+        encoder.copy_buffer_to_texture(
+            wgpu::ImageCopyBuffer {
+                buffer: &self.buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(row_bytes),
+                    rows_per_image: None,
+                },
+            },
+            self.texture.as_image_copy(),
+            wgpu::Extent3d {
+                width: Self::ROW_TEXELS,
+                height: rows,
+                depth_or_array_layers: 1,
+            },
+        );
+        result
+    }
+}
+
+/// A gradient stop as the shader reads it.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct GpuGradientStop {
+    color: [f32; 4],
+    offset: f32,
+    _pad: [f32; 3],
+}
+
+unsafe impl bytemuck::Pod for GpuGradientStop {}
+unsafe impl bytemuck::Zeroable for GpuGradientStop {}
+
+impl GpuGradientStop {
+    pub(crate) fn new(offset: f32, color: [f32; 4]) -> Self {
+        Self {
+            color,
+            offset,
+            _pad: [0.0; 3],
+        }
+    }
+}
+
+/// A run of indices drawn with the same scissor rect, or the point where a
+/// finished layer is composited if `layer` is set.
+///
+/// `scissor` is in logical coordinates; `None` draws unclipped.
+pub(crate) struct DrawBatch {
+    pub(crate) start: u32,
+    pub(crate) end: u32,
+    pub(crate) scissor: Option<Rect>,
+    pub(crate) blend: BlendMode,
+    pub(crate) layer: Option<usize>,
+    /// A custom render pass to record here instead of drawing.
+    pub(crate) custom: Option<usize>,
+    /// The image textured vertices in the batch sample.
+    pub(crate) image: Option<Rc<wgpu::BindGroup>>,
+    /// The custom shader the batch is drawn with, by its index.
+    pub(crate) shader: Option<usize>,
+}
+
+impl DrawBatch {
+    /// Whether the batch draws indices, rather than compositing a layer or
+    /// running a custom pass.
+    pub(crate) fn is_draw(&self) -> bool {
+        self.layer.is_none() && self.custom.is_none()
+    }
+}
+
+/// Records custom render pass `index` into a target and its multisampled view.
+pub(crate) type CustomPassFn<'f> = &'f mut dyn FnMut(
+    usize,
+    &mut wgpu::CommandEncoder,
+    &wgpu::TextureView,
+    Option<&wgpu::TextureView>,
+);
+
+/// A fragment shader registered with
+/// [`WgpuRenderer::register_shader`](crate::WgpuRenderer::register_shader),
+/// to fill and stroke with.
+///
+/// The shader's WGSL is compiled after the crate's own, so it can use its
+/// declarations, and must define
+///
+/// ```wgsl
+/// fn custom_color(input: VertexOutput, params: vec4<f32>) -> vec4<f32>
+/// ```
+///
+/// returning a straight alpha color in linear light. It's clipped, encoded
+/// for the target and premultiplied afterwards like any other fragment.
+/// `params` are the ones passed to the draw, and of `input`:
+///
+/// - `position` is the fragment's position in physical pixels of its
+///   target; add `globals.u_origin` for its position in the frame.
+/// - `pos` is its position in the shape's coordinates, in logical pixels.
+/// - `tex_pos` is its position across the shape's bounds, from 0 to 1.
+/// - `color` is white, at the context's alpha.
+/// - `primitive_id` is the draw's primitive, for `load_primitive`.
+///
+/// `globals` has the target's resolution, origin and DPI scale, and
+/// `srgb_to_linear` and `linear_to_srgb` are there for colors given in sRGB.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CustomShader {
+    pub(crate) index: usize,
+}
+
+/// Calls a custom shader's `custom_color` for every fragment.
+const CUSTOM_ENTRY_POINT: &str = "
+[[stage(fragment)]]
+fn fs_custom(input: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return target_color(input, custom_color(input, input.gradient));
+}
+";
+
+/// How drawn colors are combined with what's already in the target.
+///
+/// Colors are premultiplied when they're blended, so every mode is exact for
+/// translucent colors too.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlendMode {
+    /// Normal alpha compositing.
+    #[default]
+    SourceOver,
+    /// Add the color to the target, useful for glows and light effects.
+    Additive,
+    /// Multiply the target by the color, darkening it.
+    Multiply,
+    /// The inverse of multiplying the inverses, lightening the target.
+    Screen,
+}
+
+impl BlendMode {
+    pub(crate) const ALL: [BlendMode; 4] = [
+        BlendMode::SourceOver,
+        BlendMode::Additive,
+        BlendMode::Multiply,
+        BlendMode::Screen,
+    ];
+
+    pub(crate) fn blend_state(self) -> wgpu::BlendState {
+        let color = |src_factor, dst_factor| wgpu::BlendComponent {
+            src_factor,
+            dst_factor,
+            operation: wgpu::BlendOperation::Add,
+        };
+        let color = match self {
+            BlendMode::SourceOver => wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING.color,
+            BlendMode::Additive => color(wgpu::BlendFactor::One, wgpu::BlendFactor::One),
+            BlendMode::Multiply => {
+                color(wgpu::BlendFactor::Dst, wgpu::BlendFactor::OneMinusSrcAlpha)
+            }
+            BlendMode::Screen => color(wgpu::BlendFactor::One, wgpu::BlendFactor::OneMinusSrc),
+        };
+        wgpu::BlendState {
+            color,
+            alpha: wgpu::BlendComponent::OVER,
         }
     }
 }
@@ -77,6 +366,12 @@ pub struct GpuVertex {
     pub(crate) tex: f32,
     pub(crate) tex_pos: [f32; 2],
     pub(crate) primitive_id: u32,
+    /// The half size of the rounded box a quad with `tex` 4 covers, which
+    /// `tex_pos` is relative to the center of.
+    pub(crate) sdf_size: [f32; 2],
+    /// The box's corner radii: top left, top right, bottom right, bottom
+    /// left.
+    pub(crate) sdf_radii: [f32; 4],
 }
 
 unsafe impl bytemuck::Pod for GpuVertex {}
@@ -91,54 +386,104 @@ impl Default for GpuVertex {
             tex: 0.0,
             tex_pos: [0.0, 0.0],
             primitive_id: 0,
+            sdf_size: [0.0, 0.0],
+            sdf_radii: [0.0; 4],
         }
     }
 }
 
+/// Where each `GpuVertex` field is, in the order the shader declares them.
+pub(crate) const VERTEX_ATTRIBUTES: [wgpu::VertexAttribute; 8] = wgpu::vertex_attr_array!(
+    0 => Float32x2,
+    1 => Float32x2,
+    2 => Float32x4,
+    3 => Float32,
+    4 => Float32x2,
+    5 => Uint32,
+    6 => Float32x2,
+    7 => Float32x4,
+);
+
 pub struct Pipeline {
-    pub pipeline: wgpu::RenderPipeline,
+    /// One pipeline per blend mode, indexed by `BlendMode as usize`.
+    pipelines: Vec<wgpu::RenderPipeline>,
+    /// The pipelines of each registered custom shader, like `pipelines`.
+    custom_pipelines: Vec<Vec<wgpu::RenderPipeline>>,
+    pipeline_layout: wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    sample_count: u32,
+    bind_group_layout: wgpu::BindGroupLayout,
     bind_group: wgpu::BindGroup,
+    /// The textures bound besides the globals, to rebind them when the
+    /// globals grow: the glyph cache, primitives, color glyphs and gradient
+    /// stops.
+    bound_views: [wgpu::TextureView; 4],
+    sampler: wgpu::Sampler,
+    image_bind_group_layout: wgpu::BindGroupLayout,
+    /// Nearest and linear samplers for images.
+    image_samplers: [wgpu::Sampler; 2],
+    /// Bound for batches without an image.
+    blank_image: wgpu::BindGroup,
+    /// The globals of each target, `globals_stride` bytes apart: the frame's
+    /// first, then each layer's.
     globals: wgpu::Buffer,
-    primitives: wgpu::Buffer,
+    globals_stride: u64,
+    globals_capacity: usize,
+    primitives: DataTexture,
+    gradient_stops: DataTexture,
     vertices: wgpu::Buffer,
     indices: wgpu::Buffer,
-    supported_primitives: usize,
     supported_vertices: usize,
     supported_indices: usize,
+    compositor: Compositor,
+    /// Whether the target stores encoded values without converting them.
+    encode_srgb: bool,
+    /// What frames start from when they're drawn in full, premultiplied and
+    /// in the target's encoding.
+    clear_color: wgpu::Color,
+    #[cfg(feature = "debug-wireframe")]
+    wireframe: Wireframe,
+    /// Whether the frame's triangle edges are drawn over it.
+    #[cfg(feature = "debug-wireframe")]
+    pub(crate) debug_wireframe: bool,
+    #[cfg(feature = "debug-overdraw")]
+    overdraw: Overdraw,
+    /// Whether a heatmap of how often each pixel was drawn is shown over
+    /// the frame.
+    #[cfg(feature = "debug-overdraw")]
+    pub(crate) debug_overdraw: bool,
     pub(crate) size: Size,
     pub(crate) scale: f64,
 }
 
 impl Pipeline {
-    pub fn new(device: &wgpu::Device, format: wgpu::TextureFormat, cache: &Cache) -> Self {
+    const INITIAL_VERTICES: usize = 4096;
+    const INITIAL_INDICES: usize = 6144;
+
+    pub fn new(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+        atlas: &AtlasTextures,
+    ) -> Self {
         let globals_buffer_byte_size = std::mem::size_of::<Globals>() as u64;
-        let supported_primitives = 1000;
-        let primitives_buffer_byte_size =
-            std::mem::size_of::<Primitive>() as u64 * supported_primitives as u64;
-
-        let globals = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Globals ubo"),
-            size: globals_buffer_byte_size,
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
-        let primitives = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Pritives ubo"),
-            size: primitives_buffer_byte_size,
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+        let alignment = device.limits().min_uniform_buffer_offset_alignment as u64;
+        let globals_stride = globals_buffer_byte_size.div_ceil(alignment) * alignment;
+        let globals_capacity = 1;
+        let globals = create_globals(device, globals_stride, globals_capacity);
+        let primitives = DataTexture::new::<Primitive>(device, "primitives", 8);
+        let gradient_stops = DataTexture::new::<GpuGradientStop>(device, "gradient stops", 8);
 
         let vertices = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Globals ubo"),
-            size: std::mem::size_of::<GpuVertex>() as u64,
-            usage: wgpu::BufferUsages::VERTEX,
+            label: Some("vertices ubo"),
+            size: std::mem::size_of::<GpuVertex>() as u64 * Self::INITIAL_VERTICES as u64,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
         let indices = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Globals ubo"),
-            size: std::mem::size_of::<u32>() as u64,
-            usage: wgpu::BufferUsages::INDEX,
+            label: Some("indices ubo"),
+            size: std::mem::size_of::<u32>() as u64 * Self::INITIAL_INDICES as u64,
+            usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
 
@@ -165,10 +510,10 @@ impl Pipeline {
             entries: &[
                 wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
+                        has_dynamic_offset: true,
                         min_binding_size: wgpu::BufferSize::new(globals_buffer_byte_size),
                     },
                     count: None,
@@ -191,107 +536,189 @@ impl Pipeline {
                 },
                 wgpu::BindGroupLayoutEntry {
                     binding: 3,
-                    visibility: wgpu::ShaderStages::VERTEX,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: wgpu::BufferSize::new(primitives_buffer_byte_size),
-                    },
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: DataTexture::BINDING_TYPE,
                     count: None,
                 },
-            ],
-        });
-
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Bind group"),
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::Buffer(globals.as_entire_buffer_binding()),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&sampler),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: wgpu::BindingResource::TextureView(&cache.view),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
                 },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: wgpu::BindingResource::Buffer(primitives.as_entire_buffer_binding()),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: DataTexture::BINDING_TYPE,
+                    count: None,
                 },
             ],
         });
 
+        let view = |texture: &wgpu::Texture| texture.create_view(&Default::default());
+        let bound_views = [
+            view(&atlas.glyphs),
+            view(&primitives.texture),
+            view(&atlas.color),
+            view(&gradient_stops.texture),
+        ];
+        let bind_group =
+            create_bind_group(device, &bind_group_layout, &globals, &sampler, &bound_views);
+
+        let image_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("image bind group layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+        let image_sampler = |filter| {
+            device.create_sampler(&wgpu::SamplerDescriptor {
+                label: Some("image sampler"),
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: filter,
+                min_filter: filter,
+                mipmap_filter: filter,
+                ..Default::default()
+            })
+        };
+        let image_samplers = [
+            image_sampler(wgpu::FilterMode::Nearest),
+            image_sampler(wgpu::FilterMode::Linear),
+        ];
+
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-            bind_group_layouts: &[&bind_group_layout],
+            bind_group_layouts: &[&bind_group_layout, &image_bind_group_layout],
             push_constant_ranges: &[],
             label: Some("pipeline layout"),
         });
 
-        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("pipeline descriptor"),
-            layout: Some(&pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "vs_main",
-                buffers: &[wgpu::VertexBufferLayout {
-                    array_stride: std::mem::size_of::<GpuVertex>() as u64,
-                    step_mode: wgpu::VertexStepMode::Vertex,
-                    attributes: &wgpu::vertex_attr_array!(
-                        0 => Float32x2,
-                        1 => Float32x2,
-                        2 => Float32x4,
-                        3 => Float32,
-                        4 => Float32x2,
-                        5 => Uint32,
-                    ),
-                }],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fs_main",
-                targets: &[wgpu::ColorTargetState {
-                    format,
-                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
-                    write_mask: wgpu::ColorWrites::ALL,
-                }],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                polygon_mode: wgpu::PolygonMode::Fill,
-                front_face: wgpu::FrontFace::Ccw,
-                strip_index_format: None,
-                cull_mode: None,
-                unclipped_depth: false,
-                conservative: false,
-            },
-            depth_stencil: None,
-            multisample: wgpu::MultisampleState {
-                count: 4,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
+        let pipelines = create_pipelines(
+            device,
+            &pipeline_layout,
+            &shader,
+            "fs_main",
+            format,
+            sample_count,
+        );
+
+        let blank_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("blank image"),
+            size: wgpu::Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
             },
-            multiview: None,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: crate::image::IMAGE_FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING,
         });
+        let blank_image = create_image_bind_group(
+            device,
+            &image_bind_group_layout,
+            &image_samplers[0],
+            &blank_texture.create_view(&wgpu::TextureViewDescriptor::default()),
+        );
 
         Self {
-            pipeline,
+            pipelines,
+            custom_pipelines: Vec::new(),
+            format,
+            sample_count,
+            bind_group_layout,
             bind_group,
+            bound_views,
+            sampler,
+            image_bind_group_layout,
+            image_samplers,
+            blank_image,
             globals,
+            globals_stride,
+            globals_capacity,
             vertices,
             indices,
             primitives,
-            supported_vertices: 1,
-            supported_indices: 1,
-            supported_primitives,
+            gradient_stops,
+            supported_vertices: Self::INITIAL_VERTICES,
+            supported_indices: Self::INITIAL_INDICES,
+            compositor: Compositor::new(device, format, sample_count),
+            encode_srgb: !format.describe().srgb,
+            clear_color: wgpu::Color::WHITE,
+            #[cfg(feature = "debug-wireframe")]
+            wireframe: Wireframe::new(device, &shader, &pipeline_layout, format, sample_count),
+            #[cfg(feature = "debug-wireframe")]
+            debug_wireframe: false,
+            #[cfg(feature = "debug-overdraw")]
+            overdraw: Overdraw::new(device, &shader, &pipeline_layout, format, sample_count),
+            #[cfg(feature = "debug-overdraw")]
+            debug_overdraw: false,
             size: Size::ZERO,
             scale: 1.0,
+            pipeline_layout,
         }
     }
 
+    /// Compile a custom shader's WGSL into pipelines like the built-in ones
+    /// and return its index. Shaders that don't compile are a
+    /// `BackendError` with wgpu's validation error.
+    pub(crate) fn add_custom_shader(
+        &mut self,
+        device: &wgpu::Device,
+        source: &str,
+    ) -> Result<usize, piet::Error> {
+        let source = format!(
+            "{}\n{}\n{}",
+            include_str!("shader/geometry.wgsl"),
+            source,
+            CUSTOM_ENTRY_POINT
+        );
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("custom shader"),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+        let pipelines = create_pipelines(
+            device,
+            &self.pipeline_layout,
+            &shader,
+            "fs_custom",
+            self.format,
+            self.sample_count,
+        );
+        if let Some(e) = futures::executor::block_on(device.pop_error_scope()) {
+            return Err(piet::Error::BackendError(Box::new(e)));
+        }
+        self.custom_pipelines.push(pipelines);
+        Ok(self.custom_pipelines.len() - 1)
+    }
+
+    /// Upload a frame's geometry and the data its shaders look up, failing
+    /// with `InvalidInput` if there are more primitives or gradient stops
+    /// than the device's textures can hold; the rest are dropped.
+    #[allow(clippy::too_many_arguments)]
     pub fn upload_data(
         &mut self,
         device: &wgpu::Device,
@@ -299,9 +726,26 @@ impl Pipeline {
         encoder: &mut wgpu::CommandEncoder,
         geometry: &VertexBuffers<GpuVertex, u32>,
         primitives: &[Primitive],
-    ) {
+        gradient_stops: &[GpuGradientStop],
+        layers: &[Layer],
+    ) -> Result<(), piet::Error> {
+        let size = (
+            (self.size.width as u32).max(1),
+            (self.size.height as u32).max(1),
+        );
+        self.compositor.prepare(
+            device,
+            staging_belt,
+            encoder,
+            size,
+            self.scale as f32,
+            layers,
+        );
+
+        // Grow geometrically so a frame that's slightly bigger than the last
+        // doesn't reallocate every time.
         if geometry.vertices.len() > self.supported_vertices {
-            self.supported_vertices = geometry.vertices.len();
+            self.supported_vertices = geometry.vertices.len().next_power_of_two();
             let size = std::mem::size_of::<GpuVertex>() as u64 * self.supported_vertices as u64;
             self.vertices = device.create_buffer(&wgpu::BufferDescriptor {
                 label: Some("vertices ubo"),
@@ -311,7 +755,7 @@ impl Pipeline {
             });
         }
         if geometry.indices.len() > self.supported_indices {
-            self.supported_indices = geometry.indices.len();
+            self.supported_indices = geometry.indices.len().next_power_of_two();
             let size = std::mem::size_of::<u32>() as u64 * self.supported_indices as u64;
             self.indices = device.create_buffer(&wgpu::BufferDescriptor {
                 label: Some("indices ubo"),
@@ -321,100 +765,227 @@ impl Pipeline {
             });
         }
 
-        {
-            let vertices_bytes = bytemuck::cast_slice(&geometry.vertices);
-            let mut vertices = staging_belt.write_buffer(
-                encoder,
-                &self.vertices,
-                0,
-                unsafe { NonZeroU64::new_unchecked(vertices_bytes.len() as u64) },
-                device,
-            );
+        let vertices_bytes: &[u8] = bytemuck::cast_slice(&geometry.vertices);
+        if let Some(size) = NonZeroU64::new(vertices_bytes.len() as u64) {
+            let mut vertices = staging_belt.write_buffer(encoder, &self.vertices, 0, size, device);
             vertices.copy_from_slice(vertices_bytes);
         }
-        {
-            let indices_bytes = bytemuck::cast_slice(&geometry.indices);
-            let mut indices = staging_belt.write_buffer(
-                encoder,
-                &self.indices,
-                0,
-                unsafe { NonZeroU64::new_unchecked(indices_bytes.len() as u64) },
-                device,
-            );
+        let indices_bytes: &[u8] = bytemuck::cast_slice(&geometry.indices);
+        if let Some(size) = NonZeroU64::new(indices_bytes.len() as u64) {
+            let mut indices = staging_belt.write_buffer(encoder, &self.indices, 0, size, device);
             indices.copy_from_slice(indices_bytes);
         }
+        #[cfg(feature = "debug-wireframe")]
+        if self.debug_wireframe {
+            self.wireframe
+                .upload(device, staging_belt, encoder, geometry);
+        }
 
         {
-            let globals = vec![Globals {
+            let targets = layers.len() + 1;
+            let grown_primitives = self
+                .primitives
+                .reserve::<Primitive>(device, primitives.len());
+            let grown_stops = self
+                .gradient_stops
+                .reserve::<GpuGradientStop>(device, gradient_stops.len());
+            if grown_primitives {
+                self.bound_views[1] = self.primitives.texture.create_view(&Default::default());
+            }
+            if grown_stops {
+                self.bound_views[3] = self.gradient_stops.texture.create_view(&Default::default());
+            }
+            let grown_globals = targets > self.globals_capacity;
+            if grown_globals {
+                self.globals_capacity = targets.next_power_of_two();
+                self.globals = create_globals(device, self.globals_stride, self.globals_capacity);
+            }
+            if grown_globals || grown_primitives || grown_stops {
+                self.bind_group = create_bind_group(
+                    device,
+                    &self.bind_group_layout,
+                    &self.globals,
+                    &self.sampler,
+                    &self.bound_views,
+                );
+            }
+
+            let frame = Globals {
                 resolution: [self.size.width as f32, self.size.height as f32],
                 scale: self.scale as f32,
-                _pad: 0.0,
-            }];
+                encode_srgb: if self.encode_srgb { 1.0 } else { 0.0 },
+                origin: [0.0, 0.0],
+                _pad: [0.0, 0.0],
+            };
+            let layer_globals = layers.iter().enumerate().map(|(i, layer)| {
+                let (width, height) = self.compositor.texture_size(i);
+                Globals {
+                    resolution: [width as f32, height as f32],
+                    origin: [layer.bounds.x0 as f32, layer.bounds.y0 as f32],
+                    ..frame
+                }
+            });
 
-            let global_bytes = bytemuck::cast_slice(&globals);
+            let stride = self.globals_stride as usize;
+            let mut global_bytes = vec![0; stride * targets];
+            for (i, globals) in std::iter::once(frame).chain(layer_globals).enumerate() {
+                let bytes = bytemuck::bytes_of(&globals);
+                global_bytes[i * stride..i * stride + bytes.len()].copy_from_slice(bytes);
+            }
             let mut globals = staging_belt.write_buffer(
                 encoder,
                 &self.globals,
                 0,
-                unsafe { NonZeroU64::new_unchecked(global_bytes.len() as u64) },
+                NonZeroU64::new(global_bytes.len() as u64).unwrap(),
                 device,
             );
-            globals.copy_from_slice(global_bytes);
+            globals.copy_from_slice(&global_bytes);
         }
 
-        {
-            if primitives.len() > self.supported_primitives {
-                println!(
-                    "warning primities len {} more than supported",
-                    primitives.len()
-                );
+        let primitives = self
+            .primitives
+            .upload(device, staging_belt, encoder, primitives);
+        let gradient_stops =
+            self.gradient_stops
+                .upload(device, staging_belt, encoder, gradient_stops);
+        primitives.and(gradient_stops)
+    }
+
+    /// Start frames drawn in full from `color`.
+    pub(crate) fn set_clear_color(&mut self, color: &Color) {
+        let (r, g, b, a) = color.as_rgba();
+        let [r, g, b] = match self.encode_srgb {
+            true => [r, g, b],
+            false => {
+                let [r, g, b, _] = format_color(color);
+                [r as f64, g as f64, b as f64]
             }
+        };
+        self.clear_color = wgpu::Color {
+            r: r * a,
+            g: g * a,
+            b: b * a,
+            a,
+        };
+    }
 
-            let primitives_bytes = bytemuck::cast_slice(
-                &primitives[..primitives.len().min(self.supported_primitives)],
-            );
-            let mut primivites_buffer = staging_belt.write_buffer(
-                encoder,
-                &self.primitives,
-                0,
-                unsafe { NonZeroU64::new_unchecked(primitives_bytes.len() as u64) },
-                device,
-            );
-            primivites_buffer.copy_from_slice(primitives_bytes);
-        }
+    /// A bind group sampling `view` for `draw_image`.
+    pub(crate) fn image_bind_group(
+        &self,
+        device: &wgpu::Device,
+        view: &wgpu::TextureView,
+        interp: InterpolationMode,
+    ) -> wgpu::BindGroup {
+        let sampler = match interp {
+            InterpolationMode::NearestNeighbor => &self.image_samplers[0],
+            InterpolationMode::Bilinear => &self.image_samplers[1],
+        };
+        create_image_bind_group(device, &self.image_bind_group_layout, sampler, view)
+    }
+
+    /// The number of vertices and indices the GPU buffers currently hold.
+    pub(crate) fn capacity(&self) -> (usize, usize) {
+        (self.supported_vertices, self.supported_indices)
     }
 
+    /// Draw the frame into `view`.
+    ///
+    /// Without `damage` the frame starts from the clear color; with it, areas
+    /// in device pixels, only those are drawn and the rest of `view` is kept.
+    /// `custom` records custom render pass `index` into the given target and
+    /// its multisampled view. piet's own drawing is split into separate passes
+    /// around it, so everything is drawn in the order it was recorded.
+    #[allow(clippy::too_many_arguments)]
     pub fn draw(
         &mut self,
-        device: &wgpu::Device,
+        _device: &wgpu::Device,
         encoder: &mut wgpu::CommandEncoder,
         view: &wgpu::TextureView,
-        msaa: &wgpu::TextureView,
-        geometry: &VertexBuffers<GpuVertex, u32>,
+        msaa: Option<&wgpu::TextureView>,
+        depth: &wgpu::TextureView,
+        damage: Option<&[Rect]>,
+        batches: &[DrawBatch],
+        layers: &[Layer],
+        custom: CustomPassFn,
     ) {
-        let fill_range = 0..(geometry.indices.len() as u32);
+        // Layers are finished before any layer that contains them, so drawing
+        // them in order has every nested layer ready when it's composited.
+        for (i, layer) in layers.iter().enumerate() {
+            let (target, layer_msaa, layer_depth) = self.compositor.target(i);
+            self.draw_target(
+                encoder,
+                target,
+                layer_msaa,
+                layer_depth,
+                Target {
+                    bounds: layer.bounds,
+                    globals_offset: ((i + 1) as u64 * self.globals_stride) as u32,
+                },
+                wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                None,
+                &layer.batches,
+                layers,
+                custom,
+            );
+            self.compositor.blur(encoder, i, layer);
+        }
 
-        {
-            let _ = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: None,
-                color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
-                        store: true,
-                    },
-                }],
-                depth_stencil_attachment: None,
-            });
+        // The first pass clears the multisampled target too, and comes
+        // before any custom pass.
+        let load = match damage {
+            Some(_) => wgpu::LoadOp::Load,
+            None => wgpu::LoadOp::Clear(self.clear_color),
+        };
+        let frame = Target {
+            bounds: Rect::from_origin_size(Point::ZERO, self.size),
+            globals_offset: 0,
+        };
+        self.draw_target(
+            encoder, view, msaa, depth, frame, load, damage, batches, layers, custom,
+        );
+
+        // Layers are counted where their content is drawn, not where
+        // they're composited.
+        #[cfg(feature = "debug-overdraw")]
+        if self.debug_overdraw {
+            self.overdraw.resize(_device, self.size);
+            {
+                let mut pass = self.overdraw.count_pass(encoder);
+                pass.set_bind_group(0, &self.bind_group, &[0]);
+                pass.set_vertex_buffer(0, self.vertices.slice(..));
+                pass.set_index_buffer(self.indices.slice(..), wgpu::IndexFormat::Uint32);
+                let all = batches
+                    .iter()
+                    .chain(layers.iter().flat_map(|layer| layer.batches.iter()));
+                for batch in all.filter(|batch| batch.is_draw() && batch.start < batch.end) {
+                    let scissor = match self.scissor(batch, frame.bounds, frame.bounds) {
+                        Some(scissor) => scissor,
+                        None => continue,
+                    };
+                    pass.set_scissor_rect(
+                        scissor.x0 as u32,
+                        scissor.y0 as u32,
+                        scissor.width() as u32,
+                        scissor.height() as u32,
+                    );
+                    pass.set_bind_group(
+                        1,
+                        batch.image.as_deref().unwrap_or(&self.blank_image),
+                        &[],
+                    );
+                    pass.draw_indexed(batch.start..batch.end, 0, 0..1);
+                }
+            }
+            self.overdraw.show(encoder, view, msaa);
         }
 
-        {
+        #[cfg(feature = "debug-wireframe")]
+        if self.debug_wireframe {
             let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: None,
+                label: Some("wireframe"),
                 color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: msaa,
-                    resolve_target: Some(&view),
+                    view: msaa.unwrap_or(view),
+                    resolve_target: msaa.map(|_| view),
                     ops: wgpu::Operations {
                         load: wgpu::LoadOp::Load,
                         store: true,
@@ -422,119 +993,664 @@ impl Pipeline {
                 }],
                 depth_stencil_attachment: None,
             });
+            self.wireframe.draw(
+                &mut pass,
+                &self.bind_group,
+                &self.blank_image,
+                &self.vertices,
+            );
+        }
+    }
+
+    /// Draw `batches` into one target, ending and restarting the render pass
+    /// around every custom pass.
+    #[allow(clippy::too_many_arguments)]
+    fn draw_target(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        msaa: Option<&wgpu::TextureView>,
+        depth: &wgpu::TextureView,
+        target: Target,
+        mut load: wgpu::LoadOp<wgpu::Color>,
+        damage: Option<&[Rect]>,
+        mut batches: &[DrawBatch],
+        layers: &[Layer],
+        custom: CustomPassFn,
+    ) {
+        loop {
+            let split = batches.iter().position(|b| b.custom.is_some());
+            let drawn = &batches[..split.unwrap_or(batches.len())];
+            {
+                let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: None,
+                    color_attachments: &[wgpu::RenderPassColorAttachment {
+                        view: msaa.unwrap_or(view),
+                        resolve_target: msaa.map(|_| view),
+                        ops: wgpu::Operations { load, store: true },
+                    }],
+                    // Custom passes use the depth buffer too, so depth only
+                    // orders drawing between them.
+                    depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                        view: depth,
+                        depth_ops: Some(wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(0.0),
+                            store: false,
+                        }),
+                        stencil_ops: None,
+                    }),
+                });
+                self.draw_batches(&mut pass, target, drawn, layers, damage);
+            }
+            load = wgpu::LoadOp::Load;
+            match split {
+                Some(i) => {
+                    if let Some(index) = batches[i].custom {
+                        custom(index, encoder, view, msaa);
+                    }
+                    batches = &batches[i + 1..];
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// The pixels of a target covering `bounds` of the frame that `batch`
+    /// draws to within `area`, or `None` if that's less than a pixel. `area`
+    /// and `bounds` are in device pixels of the frame.
+    fn scissor(&self, batch: &DrawBatch, area: Rect, bounds: Rect) -> Option<Rect> {
+        let scissor = batch
+            .scissor
+            .map(|r| r.scale_from_origin(self.scale).intersect(bounds).expand())
+            .unwrap_or(bounds)
+            .intersect(bounds)
+            .intersect(area);
+        if scissor.width() < 1.0 || scissor.height() < 1.0 {
+            return None;
+        }
+        Some(scissor - bounds.origin().to_vec2())
+    }
+
+    fn draw_batches<'a>(
+        &'a self,
+        pass: &mut wgpu::RenderPass<'a>,
+        target: Target,
+        batches: &'a [DrawBatch],
+        layers: &[Layer],
+        damage: Option<&[Rect]>,
+    ) {
+        let whole = [target.bounds];
+        // Damaged areas don't overlap, so drawing everything once per area
+        // draws nothing twice.
+        let areas = damage.unwrap_or(&whole);
+        let batches = areas
+            .iter()
+            .flat_map(|area| batches.iter().map(move |batch| (area, batch)));
+        for (area, batch) in batches {
+            if batch.is_draw() && batch.start >= batch.end {
+                continue;
+            }
+            let scissor = match self.scissor(batch, *area, target.bounds) {
+                Some(scissor) => scissor,
+                None => continue,
+            };
+            pass.set_scissor_rect(
+                scissor.x0 as u32,
+                scissor.y0 as u32,
+                scissor.width() as u32,
+                scissor.height() as u32,
+            );
+            match batch.layer {
+                Some(layer) => self
+                    .compositor
+                    .composite(pass, layer, &layers[layer], batch.blend),
+                None => {
+                    let pipelines = match batch.shader {
+                        Some(shader) => &self.custom_pipelines[shader],
+                        None => &self.pipelines,
+                    };
+                    pass.set_pipeline(&pipelines[batch.blend as usize]);
+                    pass.set_bind_group(0, &self.bind_group, &[target.globals_offset]);
+                    pass.set_bind_group(
+                        1,
+                        batch.image.as_deref().unwrap_or(&self.blank_image),
+                        &[],
+                    );
+                    pass.set_vertex_buffer(0, self.vertices.slice(..));
+                    pass.set_index_buffer(self.indices.slice(..), wgpu::IndexFormat::Uint32);
+                    pass.draw_indexed(batch.start..batch.end, 0, 0..1);
+                }
+            }
+        }
+    }
+}
+
+/// Where a target is in the frame.
+#[derive(Clone, Copy)]
+struct Target {
+    /// The part of the frame drawn to it, in device pixels.
+    bounds: Rect,
+    /// The offset of its globals in the globals buffer.
+    globals_offset: u32,
+}
+
+/// One pipeline per blend mode, indexed by `BlendMode as usize`, drawing
+/// geometry with `shader`'s `fragment_entry`.
+fn create_pipelines(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    fragment_entry: &str,
+    format: wgpu::TextureFormat,
+    sample_count: u32,
+) -> Vec<wgpu::RenderPipeline> {
+    let create_pipeline = |blend| {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("pipeline descriptor"),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: std::mem::size_of::<GpuVertex>() as u64,
+                    step_mode: wgpu::VertexStepMode::Vertex,
+                    attributes: &VERTEX_ATTRIBUTES,
+                }],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: fragment_entry,
+                targets: &[wgpu::ColorTargetState {
+                    format,
+                    blend: Some(blend),
+                    write_mask: wgpu::ColorWrites::ALL,
+                }],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                front_face: wgpu::FrontFace::Ccw,
+                strip_index_format: None,
+                cull_mode: None,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::GreaterEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        })
+    };
+    BlendMode::ALL
+        .iter()
+        .map(|mode| create_pipeline(mode.blend_state()))
+        .collect()
+}
+
+fn create_image_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    sampler: &wgpu::Sampler,
+    view: &wgpu::TextureView,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("image bind group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(view),
+            },
+        ],
+    })
+}
+
+/// A buffer for the globals of `capacity` targets.
+fn create_globals(device: &wgpu::Device, stride: u64, capacity: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Globals ubo"),
+        size: stride * capacity as u64,
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+/// The bind group of the geometry shader, with one target's globals at a
+/// dynamic offset into `globals` and `views` bound after the sampler.
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    globals: &wgpu::Buffer,
+    sampler: &wgpu::Sampler,
+    views: &[wgpu::TextureView; 4],
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Bind group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: globals,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(std::mem::size_of::<Globals>() as u64),
+                }),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(&views[0]),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::TextureView(&views[1]),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::TextureView(&views[2]),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: wgpu::BindingResource::TextureView(&views[3]),
+            },
+        ],
+    })
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Default)]
+pub(crate) struct GlyphInfo {
+    font_id: usize,
+    glyph_id: u32,
+    font_size: u32,
+}
+
+/// A glyph picked by shaping a run of text.
+#[derive(Clone, Debug)]
+pub(crate) struct ShapedGlyph {
+    pub(crate) font_id: usize,
+    pub(crate) glyph_id: u32,
+    /// The byte offset in the shaped text of the first char the glyph was
+    /// made from.
+    pub(crate) cluster: usize,
+    /// The pen advance in ems, including kerning.
+    pub(crate) advance: f64,
+}
+
+#[derive(Default, Clone)]
+pub(crate) struct GlyphMetricInfo {
+    pub(crate) ascent: f64,
+    pub(crate) descent: f64,
+    pub(crate) line_gap: f64,
+    pub(crate) mono: bool,
+}
+
+#[derive(Default, Clone)]
+pub(crate) struct GlyphPosInfo {
+    pub(crate) metric: GlyphMetricInfo,
+    pub(crate) width: f64,
+    pub(crate) rect: Rect,
+    /// The glyph's inked bounds relative to the top left of `rect`, which
+    /// can reach past it; empty for glyphs that draw nothing.
+    pub(crate) ink: Rect,
+    pub(crate) cache_rect: Rect,
+    /// Whether the glyph is a color image in the color atlas rather than
+    /// coverage in the glyph atlas.
+    pub(crate) color: bool,
+    /// The glyph atlas row the glyph is in, if it's been rasterized there.
+    pub(crate) row: Option<usize>,
+    /// Whether the glyph is for a whitespace char, and the text index of the
+    /// char, set by the layout placing it.
+    pub(crate) whitespace: bool,
+    pub(crate) text_index: usize,
+}
+
+struct Row {
+    y: u32,
+    height: u32,
+    width: u32,
+    glyphs: Vec<GlyphPosInfo>,
+    /// The frame a glyph in the row was last used in.
+    last_used: u64,
+}
+
+/// A glyph's bitmap from a color font's bitmap tables, decoded to RGBA.
+struct ColorGlyphImage {
+    pixels: Vec<u8>,
+    size: [u32; 2],
+    /// From the glyph origin to the bitmap's bottom left corner, y up.
+    offset: [f32; 2],
+    /// The pixels per em the bitmap was drawn for.
+    ppem: f32,
+}
+
+/// Shelf-packed RGBA glyphs from color fonts, such as emoji.
+struct ColorAtlas {
+    size: u32,
+    x: u32,
+    y: u32,
+    row_height: u32,
+    glyphs: HashMap<GlyphInfo, GlyphPosInfo>,
+}
+
+impl ColorAtlas {
+    const SIZE: u32 = 1024;
+
+    fn new() -> Self {
+        Self {
+            size: Self::SIZE,
+            x: 0,
+            y: 0,
+            row_height: 0,
+            glyphs: HashMap::new(),
+        }
+    }
+
+    /// Drop every glyph, so the whole texture is free again.
+    fn clear(&mut self) {
+        self.x = 0;
+        self.y = 0;
+        self.row_height = 0;
+        self.glyphs.clear();
+    }
+
+    /// Find room for a `width` by `height` glyph, or `None` when full.
+    fn allocate(&mut self, width: u32, height: u32) -> Option<[u32; 2]> {
+        if self.x + width > self.size {
+            self.x = 0;
+            self.y += self.row_height;
+            self.row_height = 0;
+        }
+        if width > self.size || self.y + height > self.size {
+            return None;
+        }
+        let offset = [self.x, self.y];
+        self.x += width;
+        self.row_height = self.row_height.max(height);
+        Some(offset)
+    }
+}
+
+/// What new glyphs are uploaded into the atlas textures with.
+pub(crate) struct GlyphUpload<'a> {
+    pub(crate) device: &'a wgpu::Device,
+    pub(crate) staging_belt: &'a mut wgpu::util::StagingBelt,
+    pub(crate) encoder: &'a mut wgpu::CommandEncoder,
+}
+
+/// The textures glyphs are uploaded into, and the buffer they go through.
+pub(crate) struct AtlasTextures {
+    pub(crate) glyphs: wgpu::Texture,
+    pub(crate) color: wgpu::Texture,
+    upload_buffer: wgpu::Buffer,
+    upload_buffer_size: u64,
+}
+
+impl AtlasTextures {
+    const INITIAL_UPLOAD_BUFFER_SIZE: u64 = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as u64 * 100;
+
+    fn new(device: &wgpu::Device, width: u32, height: u32) -> Self {
+        let glyphs = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("glyph atlas"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Unorm,
+            usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
+            mip_level_count: 1,
+            sample_count: 1,
+        });
+
+        let color = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("color glyph atlas"),
+            size: wgpu::Extent3d {
+                width: ColorAtlas::SIZE,
+                height: ColorAtlas::SIZE,
+                depth_or_array_layers: 1,
+            },
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
+            mip_level_count: 1,
+            sample_count: 1,
+        });
 
-            pass.set_pipeline(&self.pipeline);
-            pass.set_bind_group(0, &self.bind_group, &[]);
-            pass.set_vertex_buffer(0, self.vertices.slice(..));
-            pass.set_index_buffer(self.indices.slice(..), wgpu::IndexFormat::Uint32);
+        let upload_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("glyph atlas upload buffer"),
+            size: Self::INITIAL_UPLOAD_BUFFER_SIZE,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
 
-            pass.draw_indexed(fill_range.clone(), 0, 0..1);
+        Self {
+            glyphs,
+            color,
+            upload_buffer,
+            upload_buffer_size: Self::INITIAL_UPLOAD_BUFFER_SIZE,
         }
     }
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, Default)]
-pub(crate) struct GlyphInfo {
-    font_id: usize,
-    glyph_id: u32,
-    font_size: u32,
-}
-
-#[derive(Default, Clone)]
-pub(crate) struct GlyphMetricInfo {
-    pub(crate) ascent: f64,
-    pub(crate) descent: f64,
-    pub(crate) line_gap: f64,
-    pub(crate) mono: bool,
-}
-
-#[derive(Default, Clone)]
-pub(crate) struct GlyphPosInfo {
-    pub(crate) info: GlyphInfo,
-    pub(crate) metric: GlyphMetricInfo,
-    pub(crate) width: f64,
-    pub(crate) rect: Rect,
-    pub(crate) cache_rect: Rect,
-}
-
-struct Row {
-    y: u32,
-    height: u32,
-    width: u32,
-    glyphs: Vec<GlyphPosInfo>,
-}
-
 pub struct Cache {
-    texture: wgpu::Texture,
-    pub(super) view: wgpu::TextureView,
-    upload_buffer: wgpu::Buffer,
-    upload_buffer_size: u64,
+    /// Made by [`create_textures`](Self::create_textures); `None` in caches
+    /// that only measure text.
+    textures: Option<AtlasTextures>,
+    color: ColorAtlas,
+    /// Fonts with color bitmap tables, by font id; `None` for fonts without
+    /// them.
+    color_fonts: HashMap<usize, Option<Font>>,
     width: u32,
     height: u32,
 
-    font_source: SystemSource,
+    system_fonts: SystemFonts,
     fonts: Vec<Font>,
     default_font: Font,
     fallback_fonts_range: std::ops::Range<usize>,
     fallback_fonts_loaded: bool,
     font_families: HashMap<(FontFamily, FontWeight), usize>,
+    /// Fonts registered with `load_font`, by family name. They take
+    /// precedence over system fonts of the same name.
+    loaded_fonts: HashMap<String, Vec<Font>>,
+    /// Variable font instances made by `font_variation`, by family name: the
+    /// family they vary and its axis values.
+    font_instances: HashMap<String, (FontFamily, Arc<[rustybuzz::Variation]>)>,
+    /// The axis values of fonts that are variable font instances, by font
+    /// id.
+    font_variations: HashMap<usize, Arc<[rustybuzz::Variation]>>,
 
     rows: LinkedHashMap<usize, Row>,
     glyphs: HashMap<GlyphInfo, (usize, usize)>,
     glyph_infos: HashMap<(char, FontFamily, FontWeight), (usize, u32)>,
-    pub(crate) scale: f64,
+    /// Glyph outlines by font and glyph id, in ems with y down and the origin
+    /// on the baseline. `None` for glyphs without one, like bitmap emoji.
+    outlines: HashMap<(usize, u32), Option<Arc<BezPath>>>,
+    scale: f64,
+    /// Bumped whenever glyph positions handed out before may no longer be
+    /// right, so text layouts know to rebuild.
+    generation: u64,
+    frame: u64,
 }
 
 fn get_fallback_fonts() -> Vec<Font> {
     let mut fonts = Vec::new();
     for file in FONTS_DIR.files() {
-        if let Ok(font) = Font::from_bytes(Arc::new(file.contents().to_vec()), 0) {
+        if let Some(font) = Font::from_bytes(Arc::new(file.contents().to_vec()), 0) {
             fonts.push(font);
         }
     }
     fonts
 }
 
-impl Cache {
-    const INITIAL_UPLOAD_BUFFER_SIZE: u64 = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as u64 * 100;
+/// A system font with color emoji, tried after the bundled fallbacks.
+fn get_emoji_font(system_fonts: &SystemFonts) -> Option<Font> {
+    system_fonts.select_by_name(&["Noto Color Emoji", "Apple Color Emoji", "Twemoji"])
+}
 
-    pub fn new(device: &wgpu::Device, width: u32, height: u32) -> Cache {
-        let texture = device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("wgpu_glyph::Cache"),
-            size: wgpu::Extent3d {
-                width,
-                height,
-                depth_or_array_layers: 1,
-            },
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::R8Unorm,
-            usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
-            mip_level_count: 1,
-            sample_count: 1,
-        });
+/// Rasterize `path`, in pixels, into a `width` x `height` A8 coverage mask.
+///
+/// Signed edge areas are accumulated along each row, so overlapping
+/// contours, common in variable fonts, are filled as with nonzero winding.
+/// Parts outside the mask are clamped to its edges.
+fn rasterize(path: &BezPath, width: usize, height: usize) -> Vec<u8> {
+    let mut area = vec![0.0f32; width * height + 2];
+    let max_x = width.saturating_sub(1) as f32;
+    let mut line = |p0: Point, p1: Point| {
+        let (p0, p1) = (
+            (p0.x.clamp(0.0, max_x as f64) as f32, p0.y as f32),
+            (p1.x.clamp(0.0, max_x as f64) as f32, p1.y as f32),
+        );
+        if (p0.1 - p1.1).abs() <= f32::EPSILON {
+            return;
+        }
+        let (dir, p0, p1) = if p0.1 < p1.1 {
+            (1.0, p0, p1)
+        } else {
+            (-1.0, p1, p0)
+        };
+        let dxdy = (p1.0 - p0.0) / (p1.1 - p0.1);
+        let mut x = p0.0 + (-p0.1).max(0.0) * dxdy;
+        for y in (p0.1.max(0.0) as usize)..height.min(p1.1.ceil() as usize) {
+            let row = y * width;
+            let dy = ((y + 1) as f32).min(p1.1) - (y as f32).max(p0.1);
+            let x_next = x + dxdy * dy;
+            let d = dy * dir;
+            let (x0, x1) = if x < x_next { (x, x_next) } else { (x_next, x) };
+            let x0_floor = x0.floor();
+            let x0i = x0_floor as usize;
+            let x1_ceil = x1.ceil();
+            let x1i = x1_ceil as usize;
+            if x1i <= x0i + 1 {
+                // The edge stays within one pixel on this row.
+                let mid = 0.5 * (x + x_next) - x0_floor;
+                area[row + x0i] += d - d * mid;
+                area[row + x0i + 1] += d * mid;
+            } else {
+                let s = (x1 - x0).recip();
+                let x0f = x0 - x0_floor;
+                let a0 = 0.5 * s * (1.0 - x0f) * (1.0 - x0f);
+                let x1f = x1 - x1_ceil + 1.0;
+                let am = 0.5 * s * x1f * x1f;
+                area[row + x0i] += d * a0;
+                if x1i == x0i + 2 {
+                    area[row + x0i + 1] += d * (1.0 - a0 - am);
+                } else {
+                    let a1 = s * (1.5 - x0f);
+                    area[row + x0i + 1] += d * (a1 - a0);
+                    for xi in x0i + 2..x1i - 1 {
+                        area[row + xi] += d * s;
+                    }
+                    let a2 = a1 + (x1i - x0i - 3) as f32 * s;
+                    area[row + x1i - 1] += d * (1.0 - a2 - am);
+                }
+                area[row + x1i] += d * am;
+            }
+            x = x_next;
+        }
+    };
 
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let (mut start, mut last) = (Point::ZERO, Point::ZERO);
+    path.flatten(0.1, |el| match el {
+        PathEl::MoveTo(p) => {
+            line(last, start);
+            start = p;
+            last = p;
+        }
+        PathEl::LineTo(p) => {
+            line(last, p);
+            last = p;
+        }
+        PathEl::ClosePath => {
+            line(last, start);
+            last = start;
+        }
+        _ => {}
+    });
+    line(last, start);
+
+    let mut coverage = 0.0;
+    area[..width * height]
+        .iter()
+        .map(|a| {
+            coverage += a;
+            (coverage.abs().min(1.0) * 255.0).round() as u8
+        })
+        .collect()
+}
 
-        let upload_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("wgpu_glyph::Cache upload buffer"),
-            size: Self::INITIAL_UPLOAD_BUFFER_SIZE,
-            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
-            mapped_at_creation: false,
-        });
+/// Scale an RGBA image by averaging the source pixels each destination pixel
+/// covers, in premultiplied space so transparent pixels don't bleed.
+fn resample_rgba(src: &[u8], src_size: [u32; 2], dst_size: [u32; 2]) -> Vec<u8> {
+    let [sw, sh] = src_size;
+    let [dw, dh] = dst_size;
+    let mut dst = vec![0; (dw * dh * 4) as usize];
+    let (fx, fy) = (sw as f32 / dw as f32, sh as f32 / dh as f32);
+    for y in 0..dh {
+        let y0 = (y as f32 * fy) as u32;
+        let y1 = (((y + 1) as f32 * fy).ceil() as u32).clamp(y0 + 1, sh);
+        for x in 0..dw {
+            let x0 = (x as f32 * fx) as u32;
+            let x1 = (((x + 1) as f32 * fx).ceil() as u32).clamp(x0 + 1, sw);
+            let mut sum = [0.0f32; 4];
+            for sy in y0..y1 {
+                for sx in x0..x1 {
+                    let p = &src[((sy * sw + sx) * 4) as usize..][..4];
+                    let a = p[3] as f32;
+                    sum[0] += p[0] as f32 * a;
+                    sum[1] += p[1] as f32 * a;
+                    sum[2] += p[2] as f32 * a;
+                    sum[3] += a;
+                }
+            }
+            let count = ((y1 - y0) * (x1 - x0)) as f32;
+            let d = &mut dst[((y * dw + x) * 4) as usize..][..4];
+            if sum[3] > 0.0 {
+                for c in 0..3 {
+                    d[c] = (sum[c] / sum[3]).round() as u8;
+                }
+                d[3] = (sum[3] / count).round() as u8;
+            }
+        }
+    }
+    dst
+}
 
+impl Cache {
+    /// A cache for a `width` by `height` glyph atlas, which can measure text
+    /// but not rasterize it until it has [textures](Self::create_textures).
+    pub fn new(width: u32, height: u32) -> Cache {
         let default_font = Font::from_bytes(Arc::new(DEFAULT_FONT.to_vec()), 0).unwrap();
 
         Cache {
-            texture,
-            view,
-            upload_buffer,
-            upload_buffer_size: Self::INITIAL_UPLOAD_BUFFER_SIZE,
+            textures: None,
+            color: ColorAtlas::new(),
+            color_fonts: HashMap::new(),
             width,
             height,
 
-            font_source: SystemSource::new(),
+            system_fonts: SystemFonts::new(),
 
             font_families: HashMap::new(),
+            loaded_fonts: HashMap::new(),
+            font_instances: HashMap::new(),
+            font_variations: HashMap::new(),
             fonts: Vec::new(),
             default_font,
             fallback_fonts_range: 0..0,
@@ -543,14 +1659,92 @@ impl Cache {
             rows: LinkedHashMap::new(),
             glyphs: HashMap::new(),
             glyph_infos: HashMap::new(),
+            outlines: HashMap::new(),
             scale: 1.0,
+            generation: 0,
+            frame: 0,
+        }
+    }
+
+    /// Create the atlas textures on `device`, so glyphs can be rasterized
+    /// into them.
+    pub(crate) fn create_textures(&mut self, device: &wgpu::Device) {
+        self.textures = Some(AtlasTextures::new(device, self.width, self.height));
+    }
+
+    pub(crate) fn textures(&self) -> Option<&AtlasTextures> {
+        self.textures.as_ref()
+    }
+
+    /// Start a new frame. Rows whose glyphs weren't used in the frame that
+    /// ended become candidates for eviction.
+    pub(crate) fn end_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    /// Keep `rows` from being evicted this frame, for layouts drawn from
+    /// glyphs they looked up in an earlier one.
+    pub(crate) fn touch_rows(&mut self, rows: &[usize]) {
+        for row in rows {
+            if let Some(row) = self.rows.get_mut(row) {
+                row.last_used = self.frame;
+            }
+        }
+    }
+
+    /// The fraction of the glyph atlas taken up by rasterized glyphs.
+    pub(crate) fn occupancy(&self) -> f64 {
+        let used: u64 = self
+            .rows
+            .values()
+            .map(|row| row.width as u64 * row.height as u64)
+            .sum();
+        used as f64 / (self.width as u64 * self.height as u64) as f64
+    }
+
+    /// Empty the least recently used row at least `height` tall that no
+    /// glyph drawn this frame is in, and return its number.
+    ///
+    /// Layouts holding glyphs from the row are rebuilt when next drawn, which
+    /// rasterizes the glyphs again.
+    fn evict_row(&mut self, height: u32) -> Option<usize> {
+        let frame = self.frame;
+        let (&number, _) = self
+            .rows
+            .iter()
+            .filter(|(_, row)| row.last_used < frame && row.height >= height)
+            .min_by_key(|(_, row)| (row.last_used, row.height))?;
+        let row = self.rows.get_mut(&number).unwrap();
+        row.width = 0;
+        row.glyphs.clear();
+        self.glyphs.retain(|_, (row, _)| *row != number);
+        self.generation += 1;
+        Some(number)
+    }
+
+    /// Glyphs are rasterized for the scale, so layouts built at another
+    /// scale have to be rebuilt. Glyphs rasterized for the old scale are
+    /// dropped from both atlases rather than left to be evicted, since the
+    /// color atlas never evicts.
+    pub(crate) fn set_scale(&mut self, scale: f64) {
+        if scale != self.scale {
+            self.scale = scale;
+            self.rows.clear();
+            self.glyphs.clear();
+            self.color.clear();
+            self.generation += 1;
         }
     }
 
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
     fn get_glyph_from_fallback_fonts(&mut self, c: char) -> Option<(usize, u32)> {
         if !self.fallback_fonts_loaded {
             self.fallback_fonts_loaded = true;
             let mut fallback_fonts = get_fallback_fonts();
+            fallback_fonts.extend(get_emoji_font(&self.system_fonts));
             let start = self.fonts.len();
             let end = start + fallback_fonts.len();
             self.fonts.append(&mut fallback_fonts);
@@ -600,145 +1794,506 @@ impl Cache {
         })
     }
 
+    /// The font and glyph id `c` is drawn with.
+    pub(crate) fn glyph_id(
+        &mut self,
+        c: char,
+        font_family: FontFamily,
+        font_weight: FontWeight,
+    ) -> Option<(usize, u32)> {
+        let glyph = self.get_glyph_info(c, font_family, font_weight, 0).ok()?;
+        Some((glyph.font_id, glyph.glyph_id))
+    }
+
+    /// The outline of a glyph at a font size of 1.
+    pub(crate) fn glyph_outline(&mut self, font_id: usize, glyph_id: u32) -> Option<Arc<BezPath>> {
+        if let Some(outline) = self.outlines.get(&(font_id, glyph_id)) {
+            return outline.clone();
+        }
+        let font = &self.fonts[font_id];
+        let mut sink = OutlineBuilder {
+            path: BezPath::new(),
+            units_per_em: font.metrics().units_per_em as f64,
+        };
+        let glyph = ttf_parser::GlyphId(glyph_id as u16);
+        let outline = if self.font_variations.contains_key(&font_id) {
+            self.with_varied_face(font_id, |face| face.outline_glyph(glyph, &mut sink))
+        } else {
+            font.face().outline_glyph(glyph, &mut sink)
+        }
+        .map(|_| Arc::new(sink.path));
+        self.outlines.insert((font_id, glyph_id), outline.clone());
+        outline
+    }
+
+    /// Run `f` with the face of `font_id` set to its axis values, if it's a
+    /// variable font instance.
+    fn with_varied_face<T>(
+        &self,
+        font_id: usize,
+        f: impl FnOnce(&ttf_parser::Face) -> Option<T>,
+    ) -> Option<T> {
+        let variations = self.font_variations.get(&font_id)?;
+        let mut face = self.fonts[font_id].face();
+        for variation in variations.iter() {
+            face.set_variation(variation.tag, variation.value);
+        }
+        f(&face)
+    }
+
+    /// Where `c` is in the atlas, rasterizing it into a free spot first if
+    /// it isn't there yet. Its pixels are uploaded with `upload`, if given.
     pub(crate) fn get_glyph_pos(
         &mut self,
         c: char,
         font_family: FontFamily,
         font_size: f32,
         font_weight: FontWeight,
-        device: &wgpu::Device,
-        staging_belt: &mut wgpu::util::StagingBelt,
-        encoder: &mut wgpu::CommandEncoder,
+        upload: Option<GlyphUpload>,
+    ) -> Result<&GlyphPosInfo, piet::Error> {
+        let font_size = (font_size as f64 * self.scale * 2.0).round() as u32;
+        let glyph = self.get_glyph_info(c, font_family, font_weight, font_size)?;
+        self.glyph_pos(glyph, upload)
+    }
+
+    /// Like [`get_glyph_pos`](Self::get_glyph_pos), for a glyph already
+    /// picked by shaping.
+    pub(crate) fn get_shaped_glyph_pos(
+        &mut self,
+        glyph: &ShapedGlyph,
+        font_size: f32,
+        upload: Option<GlyphUpload>,
     ) -> Result<&GlyphPosInfo, piet::Error> {
+        let glyph = GlyphInfo {
+            font_id: glyph.font_id,
+            glyph_id: glyph.glyph_id,
+            font_size: (font_size as f64 * self.scale * 2.0).round() as u32,
+        };
+        self.glyph_pos(glyph, upload)
+    }
+
+    /// Split `text` into the byte ranges that can be shaped with the font
+    /// for `font_family` and `font_weight`, and those laid out a char at a
+    /// time, each with whether it can be shaped.
+    ///
+    /// Control chars like newlines and tabs, which fonts rarely have glyphs
+    /// for, and chars the font doesn't cover, which come from fallback
+    /// fonts, can't be shaped; nor can anything in a monospace font, so
+    /// code keeps its grid.
+    pub(crate) fn shaping_runs(
+        &mut self,
+        text: &str,
+        font_family: FontFamily,
+        font_weight: FontWeight,
+    ) -> Vec<(std::ops::Range<usize>, bool)> {
+        let font_id = self.get_font_by_family(font_family, font_weight);
+        let font = &self.fonts[font_id];
+        if font.is_monospace() {
+            return vec![(0..text.len(), false)];
+        }
+        let face = font.face();
+        let mut runs: Vec<(std::ops::Range<usize>, bool)> = Vec::new();
+        for (i, c) in text.char_indices() {
+            let shaped = !c.is_control() && face.glyph_index(c).is_some();
+            let end = i + c.len_utf8();
+            match runs.last_mut() {
+                Some((run, run_shaped)) if *run_shaped == shaped => run.end = end,
+                _ => runs.push((i..end, shaped)),
+            }
+        }
+        runs
+    }
+
+    /// Shape `text`, one of the runs [`shaping_runs`](Self::shaping_runs)
+    /// says can be, with the font for `font_family` and `font_weight`,
+    /// applying its ligatures, contextual forms and kerning.
+    pub(crate) fn shape(
+        &mut self,
+        text: &str,
+        rtl: bool,
+        font_family: FontFamily,
+        font_weight: FontWeight,
+    ) -> Option<Vec<ShapedGlyph>> {
+        let font_id = self.get_font_by_family(font_family, font_weight);
+        let font = &self.fonts[font_id];
+        let mut face = rustybuzz::Face::from_slice(font.data(), font.index())?;
+        if let Some(variations) = self.font_variations.get(&font_id) {
+            face.set_variations(variations);
+        }
+        let units_per_em = font.metrics().units_per_em as f64;
+
+        let mut buffer = rustybuzz::UnicodeBuffer::new();
+        buffer.push_str(text);
+        buffer.set_direction(if rtl {
+            rustybuzz::Direction::RightToLeft
+        } else {
+            rustybuzz::Direction::LeftToRight
+        });
+        buffer.guess_segment_properties();
+        let output = rustybuzz::shape(&face, &[], buffer);
+        let glyphs = output
+            .glyph_infos()
+            .iter()
+            .zip(output.glyph_positions())
+            .map(|(info, pos)| ShapedGlyph {
+                font_id,
+                glyph_id: info.codepoint,
+                cluster: info.cluster as usize,
+                advance: pos.x_advance as f64 / units_per_em,
+            })
+            .collect();
+        Some(glyphs)
+    }
+
+    /// The position info `get_glyph_pos` would return for `c`, but from the
+    /// font's metrics alone, without rasterizing the glyph or touching the
+    /// atlas. `cache_rect` is empty for glyphs that aren't cached yet.
+    pub(crate) fn measure_glyph(
+        &mut self,
+        c: char,
+        font_family: FontFamily,
+        font_size: f32,
+        font_weight: FontWeight,
+    ) -> Result<GlyphPosInfo, piet::Error> {
+        let font_size = (font_size as f64 * self.scale * 2.0).round() as u32;
+        let glyph = self.get_glyph_info(c, font_family, font_weight, font_size)?;
+        Ok(self.measure(&glyph))
+    }
+
+    /// Like [`measure_glyph`](Self::measure_glyph), for a shaped glyph.
+    pub(crate) fn measure_shaped_glyph(
+        &mut self,
+        glyph: &ShapedGlyph,
+        font_size: f32,
+    ) -> GlyphPosInfo {
+        let glyph = GlyphInfo {
+            font_id: glyph.font_id,
+            glyph_id: glyph.glyph_id,
+            font_size: (font_size as f64 * self.scale * 2.0).round() as u32,
+        };
+        self.measure(&glyph)
+    }
+
+    fn measure(&self, glyph: &GlyphInfo) -> GlyphPosInfo {
+        if let Some((row, index)) = self.glyphs.get(glyph) {
+            return self.rows[row].glyphs[*index].clone();
+        }
+        if let Some(pos) = self.color.glyphs.get(glyph) {
+            return pos.clone();
+        }
+        let scale = self.scale * 2.0;
+        let (width, height, metric) = self.glyph_size(glyph);
+        let rect = Size::new(width as f64 / scale, height as f64 / scale).to_rect();
+        GlyphPosInfo {
+            width: width as f64 / scale,
+            ink: self.glyph_ink(glyph, &metric, rect),
+            metric,
+            rect,
+            cache_rect: Rect::ZERO,
+            color: false,
+            row: None,
+            whitespace: false,
+            text_index: 0,
+        }
+    }
+
+    /// The inked bounds of `glyph` in logical pixels, relative to the top
+    /// left of its `rect`: its outline's bounding box, or all of `rect` for
+    /// color bitmap glyphs.
+    fn glyph_ink(&self, glyph: &GlyphInfo, metric: &GlyphMetricInfo, rect: Rect) -> Rect {
+        let font = &self.fonts[glyph.font_id];
+        let face = font.face();
+        let id = ttf_parser::GlyphId(glyph.glyph_id as u16);
+        let bbox = self
+            .with_varied_face(glyph.font_id, |face| face.glyph_bounding_box(id))
+            .or_else(|| face.glyph_bounding_box(id));
+        let bbox = match bbox {
+            Some(bbox) => bbox,
+            None => {
+                let ppem = glyph.font_size.min(u16::MAX as u32) as u16;
+                return match face.glyph_raster_image(id, ppem) {
+                    Some(_) => rect.with_origin(Point::ZERO),
+                    None => Rect::ZERO,
+                };
+            }
+        };
+        let px = glyph.font_size as f64 / (font.metrics().units_per_em as f64 * self.scale * 2.0);
+        Rect::new(
+            bbox.x_min as f64 * px,
+            metric.ascent - bbox.y_max as f64 * px,
+            bbox.x_max as f64 * px,
+            metric.ascent - bbox.y_min as f64 * px,
+        )
+    }
+
+    /// The advance and line height of `glyph` in atlas pixels, and its
+    /// font's metrics in logical pixels.
+    fn glyph_size(&self, glyph: &GlyphInfo) -> (f32, f32, GlyphMetricInfo) {
         let scale = self.scale * 2.0;
+        let font_size = glyph.font_size as f32;
+        let font = &self.fonts[glyph.font_id];
+        let font_metrics = font.metrics();
+        let units_per_em = font_metrics.units_per_em as f32;
+        let varied_advance = self.with_varied_face(glyph.font_id, |face| {
+            face.glyph_hor_advance(ttf_parser::GlyphId(glyph.glyph_id as u16))
+        });
+        let advance = varied_advance
+            .or_else(|| font.advance(glyph.glyph_id))
+            .unwrap_or(0) as f32;
+        let width = advance / units_per_em * font_size;
+        let height = (font_metrics.ascent - font_metrics.descent + font_metrics.line_gap)
+            / units_per_em
+            * font_size;
+        let metric = GlyphMetricInfo {
+            ascent: (font_metrics.ascent / units_per_em * font_size) as f64 / scale,
+            descent: (font_metrics.descent / units_per_em * font_size) as f64 / scale,
+            line_gap: (font_metrics.line_gap / units_per_em * font_size) as f64 / scale,
+            mono: font.is_monospace(),
+        };
+        (width, height, metric)
+    }
 
-        let font_size = (font_size as f64 * scale).round() as u32;
-        let glyph = self.get_glyph_info(c, font_family.clone(), font_weight, font_size)?;
+    fn glyph_pos(
+        &mut self,
+        glyph: GlyphInfo,
+        upload: Option<GlyphUpload>,
+    ) -> Result<&GlyphPosInfo, piet::Error> {
+        let scale = self.scale * 2.0;
+        let font_size = glyph.font_size;
 
         if let Some((row, index)) = self.glyphs.get(&glyph) {
-            let row = self.rows.get(row).unwrap();
+            let row = self.rows.get_mut(row).unwrap();
+            row.last_used = self.frame;
             return Ok(&row.glyphs[*index]);
         }
+        if self.color.glyphs.contains_key(&glyph) {
+            return Ok(&self.color.glyphs[&glyph]);
+        }
 
+        let color_image = self.color_glyph_image(&glyph);
         let padding = 2.0;
+        let (glyph_real_width, glyph_real_height, glyph_metric) = self.glyph_size(&glyph);
         let font = &self.fonts[glyph.font_id];
         let font_metrics = font.metrics();
         let units_per_em = font_metrics.units_per_em as f32;
-        let glyph_real_width =
-            font.advance(glyph.glyph_id).unwrap().x() / units_per_em * font_size as f32;
-        let glyph_real_height =
-            (font_metrics.ascent - font_metrics.descent + font_metrics.line_gap) / units_per_em
-                * font_size as f32;
-        let glyph_metric = GlyphMetricInfo {
-            ascent: (font_metrics.ascent / units_per_em * font_size as f32) as f64 / scale,
-            descent: (font_metrics.descent / units_per_em * font_size as f32) as f64 / scale,
-            line_gap: (font_metrics.line_gap / units_per_em * font_size as f32) as f64 / scale,
-            mono: font.is_monospace(),
-        };
         let glyph_rect = Size::new(glyph_real_width as f64, glyph_real_height as f64).to_rect();
 
         let glyph_width = glyph_real_width.ceil() as u32 + padding as u32;
         let glyph_height = glyph_real_height.ceil() as u32 + padding as u32;
 
-        let mut canvas = Canvas::new(
-            Vector2I::new(glyph_width as i32, glyph_height as i32),
-            Format::A8,
+        if let Some(image) = color_image {
+            let ascent = font_metrics.ascent / units_per_em * font_size as f32;
+            let size = [glyph_width, glyph_height];
+            let pixels = Self::place_color_glyph(image, font_size as f32, ascent, padding, size);
+            let offset = self
+                .color
+                .allocate(glyph_width, glyph_height)
+                .ok_or(piet::Error::MissingFont)?;
+            let origin = Point::new(
+                offset[0] as f64 + padding as f64 / 2.0,
+                offset[1] as f64 + padding as f64 / 2.0,
+            );
+            let atlas_size = [self.color.size, self.color.size];
+            let mut glyph_pos =
+                glyph_rect_to_pos(glyph_rect, origin, &glyph_metric, scale, atlas_size);
+            glyph_pos.color = true;
+            glyph_pos.ink = glyph_pos.rect.with_origin(Point::ZERO);
+            self.color.glyphs.insert(glyph.clone(), glyph_pos);
+            self.upload(upload, true, offset, [glyph_width, glyph_height], &pixels);
+            return Ok(&self.color.glyphs[&glyph]);
+        }
+
+        let baseline = (
+            padding as f64 / 2.0,
+            (font_metrics.ascent / units_per_em * font_size as f32 + padding / 2.0) as f64,
+        );
+        let mut outline = self
+            .glyph_outline(glyph.font_id, glyph.glyph_id)
+            .map(|outline| (*outline).clone())
+            .unwrap_or_default();
+        outline.apply_affine(Affine::translate(baseline) * Affine::scale(font_size as f64));
+        let pixels = rasterize(&outline, glyph_width as usize, glyph_height as usize);
+        let ink = self.glyph_ink(
+            &glyph,
+            &glyph_metric,
+            glyph_rect.scale_from_origin(1.0 / scale),
         );
-        font.rasterize_glyph(
-            &mut canvas,
-            glyph.glyph_id,
-            font_size as f32,
-            Transform2F::from_translation(Vector2F::new(
-                padding / 2.0,
-                font_metrics.ascent / units_per_em * font_size as f32 + padding / 2.0,
-            )),
-            HintingOptions::None,
-            RasterizationOptions::GrayscaleAa,
-        )
-        .map_err(|_| piet::Error::MissingFont)?;
-
-        let mut offset = [0, 0];
-        let mut inserted = false;
-        for (row_number, row) in self.rows.iter_mut().rev() {
-            if row.height == glyph_height {
-                if self.width - row.width > glyph_width {
-                    let origin = Point::new(
-                        row.width as f64 + padding as f64 / 2.0,
-                        row.y as f64 + padding as f64 / 2.0,
-                    );
-                    let glyph_pos = glyph_rect_to_pos(
-                        glyph_rect,
-                        origin,
-                        &glyph,
-                        &glyph_metric,
-                        scale,
-                        [self.width, self.height],
-                    );
 
-                    row.glyphs.push(glyph_pos);
-                    offset[0] = row.width;
-                    offset[1] = row.y;
-                    row.width += glyph_width;
-                    self.glyphs
-                        .insert(glyph.clone(), (*row_number, row.glyphs.len() - 1));
-                    inserted = true;
-                    break;
+        let width = self.width;
+        let fitting_row = self
+            .rows
+            .iter()
+            .rev()
+            .find(|(_, row)| row.height == glyph_height && width - row.width > glyph_width)
+            .map(|(row_number, _)| *row_number);
+        let row_number = match fitting_row {
+            Some(row_number) => row_number,
+            None => {
+                let mut y = 0;
+                if !self.rows.is_empty() {
+                    let last_row = self.rows.get(&(self.rows.len() - 1)).unwrap();
+                    y = last_row.y + last_row.height;
+                }
+                if glyph_width >= self.width {
+                    return Err(piet::Error::MissingFont);
+                }
+                if self.height < y + glyph_height {
+                    self.evict_row(glyph_height)
+                        .ok_or(piet::Error::MissingFont)?
+                } else {
+                    let new_row = self.rows.len();
+                    let row = Row {
+                        y,
+                        height: glyph_height,
+                        width: 0,
+                        glyphs: Vec::new(),
+                        last_used: self.frame,
+                    };
+                    self.rows.insert(new_row, row);
+                    new_row
                 }
             }
-        }
+        };
 
-        if !inserted {
-            let mut y = 0;
-            if self.rows.len() > 0 {
-                let last_row = self.rows.get(&(self.rows.len() - 1)).unwrap();
-                y = last_row.y + last_row.height;
-            }
-            if self.height < y + glyph_height {
-                return Err(piet::Error::MissingFont);
-            }
+        let row = self.rows.get_mut(&row_number).unwrap();
+        let origin = Point::new(
+            row.width as f64 + padding as f64 / 2.0,
+            row.y as f64 + padding as f64 / 2.0,
+        );
+        let mut glyph_pos = glyph_rect_to_pos(
+            glyph_rect,
+            origin,
+            &glyph_metric,
+            scale,
+            [self.width, self.height],
+        );
+        glyph_pos.row = Some(row_number);
+        glyph_pos.ink = ink;
+        let offset = [row.width, row.y];
+        row.glyphs.push(glyph_pos);
+        row.width += glyph_width;
+        row.last_used = self.frame;
+        self.glyphs
+            .insert(glyph.clone(), (row_number, row.glyphs.len() - 1));
 
-            let origin = Point::new(0.0 + padding as f64 / 2.0, y as f64 + padding as f64 / 2.0);
-            let glyph_pos = glyph_rect_to_pos(
-                glyph_rect,
-                origin,
-                &glyph,
-                &glyph_metric,
-                scale,
-                [self.width, self.height],
-            );
+        self.upload(upload, false, offset, [glyph_width, glyph_height], &pixels);
 
-            offset[0] = 0;
-            offset[1] = y;
-            let new_row = self.rows.len();
-            let glyphs = vec![glyph_pos];
-            let row = Row {
-                y,
-                height: glyph_height,
-                width: glyph_width,
-                glyphs,
-            };
+        let (row, index) = self.glyphs.get(&glyph).unwrap();
+        let row = self.rows.get(row).unwrap();
+        Ok(&row.glyphs[*index])
+    }
+
+    /// The color bitmap of `glyph`, if its font has one.
+    fn color_glyph_image(&mut self, glyph: &GlyphInfo) -> Option<ColorGlyphImage> {
+        let fonts = &self.fonts;
+        let font = self.color_fonts.entry(glyph.font_id).or_insert_with(|| {
+            let face = fonts[glyph.font_id].face();
+            let has_bitmaps = face.has_table(ttf_parser::TableName::ColorBitmapData)
+                || face.has_table(ttf_parser::TableName::StandardBitmapGraphics);
+            has_bitmaps.then(|| fonts[glyph.font_id].clone())
+        });
+        let face = font.as_ref()?.face();
+        let ppem = glyph.font_size.min(u16::MAX as u32) as u16;
+        let image = face.glyph_raster_image(ttf_parser::GlyphId(glyph.glyph_id as u16), ppem)?;
+        let (width, height, pixels) = crate::png::decode(image.data)?;
+        Some(ColorGlyphImage {
+            pixels,
+            size: [width, height],
+            offset: [image.x as f32, image.y as f32],
+            ppem: image.pixels_per_em as f32,
+        })
+    }
 
-            self.rows.insert(new_row, row);
-            self.glyphs.insert(glyph.clone(), (new_row, 0));
+    /// Scale a color glyph's bitmap to `font_size` and draw it into a
+    /// `size` canvas laid out like a rasterized glyph, with the baseline
+    /// `ascent` below the top padding.
+    fn place_color_glyph(
+        image: ColorGlyphImage,
+        font_size: f32,
+        ascent: f32,
+        padding: f32,
+        size: [u32; 2],
+    ) -> Vec<u8> {
+        let [width, height] = image.size;
+        let [x, y] = image.offset;
+        let scale = font_size / image.ppem.max(1.0);
+        let scaled = [
+            ((width as f32 * scale).round() as u32).clamp(1, size[0]),
+            ((height as f32 * scale).round() as u32).clamp(1, size[1]),
+        ];
+        let scaled_pixels = resample_rgba(&image.pixels, [width, height], scaled);
+
+        let left = (padding / 2.0 + x * scale).round().max(0.0) as u32;
+        let top = (padding / 2.0 + ascent - y * scale - scaled[1] as f32)
+            .round()
+            .max(0.0) as u32;
+        let mut canvas = vec![0; (size[0] * size[1] * 4) as usize];
+        for row in 0..scaled[1].min(size[1].saturating_sub(top)) {
+            let columns = scaled[0].min(size[0].saturating_sub(left)) as usize * 4;
+            let src = (row * scaled[0] * 4) as usize;
+            let dst = (((top + row) * size[0] + left) * 4) as usize;
+            canvas[dst..dst + columns].copy_from_slice(&scaled_pixels[src..src + columns]);
         }
+        canvas
+    }
 
-        self.update(
-            device,
-            staging_belt,
-            encoder,
-            offset,
-            [glyph_width, glyph_height],
-            &canvas.pixels,
-        );
+    /// Register a font from its file contents, returning its family.
+    pub(crate) fn load_font(&mut self, data: &[u8]) -> Result<FontFamily, piet::Error> {
+        let font =
+            Font::from_bytes(Arc::new(data.to_vec()), 0).ok_or(piet::Error::FontLoadingFailed)?;
+        let name = font.family_name().unwrap_or_default();
+        // Lookups of this family made before may have resolved to another
+        // font; resolve them again.
+        self.font_families
+            .retain(|(family, _), _| family.name() != name);
+        self.glyph_infos
+            .retain(|(_, family, _), _| family.name() != name);
+        self.generation += 1;
+        self.loaded_fonts
+            .entry(name.clone())
+            .or_default()
+            .push(font);
+        Ok(FontFamily::new_unchecked(name))
+    }
 
-        let (row, index) = self.glyphs.get(&glyph).unwrap();
-        let row = self.rows.get(row).unwrap();
-        Ok(&row.glyphs[*index])
+    /// Make a family drawing `family` with its variation axes set to `axes`,
+    /// pairs of a four letter axis tag and a value.
+    pub(crate) fn font_variation(
+        &mut self,
+        family: &FontFamily,
+        axes: &[(&str, f32)],
+    ) -> Result<FontFamily, piet::Error> {
+        let variations = axes
+            .iter()
+            .map(|&(tag, value)| match tag.as_bytes() {
+                &[a, b, c, d] => Ok(rustybuzz::Variation {
+                    tag: rustybuzz::Tag::from_bytes(&[a, b, c, d]),
+                    value,
+                }),
+                _ => Err(piet::Error::InvalidInput),
+            })
+            .collect::<Result<Arc<[_]>, _>>()?;
+        let axes = axes
+            .iter()
+            .map(|(tag, value)| format!("{}={}", tag, value))
+            .collect::<Vec<_>>();
+        let name = format!("{} [{}]", family.name(), axes.join(","));
+        self.font_instances
+            .entry(name.clone())
+            .or_insert_with(|| (family.clone(), variations));
+        Ok(FontFamily::new_unchecked(name))
     }
 
     fn get_font_by_family(&mut self, family: FontFamily, weight: FontWeight) -> usize {
         if !self.font_families.contains_key(&(family.clone(), weight)) {
-            let font = self.get_new_font(&family, weight);
+            let font = match self.font_instances.get(family.name()).cloned() {
+                Some((base, variations)) => {
+                    let base_id = self.get_font_by_family(base, weight);
+                    self.font_variations.insert(self.fonts.len(), variations);
+                    self.fonts[base_id].clone()
+                }
+                None => self.get_new_font(&family, weight),
+            };
             let font_id = self.fonts.len();
             self.font_families.insert((family.clone(), weight), font_id);
             self.fonts.push(font);
@@ -749,44 +2304,38 @@ impl Cache {
     }
 
     fn get_new_font(&self, family: &FontFamily, weight: FontWeight) -> Font {
-        let family_name = match family.inner() {
-            piet::FontFamilyInner::Serif => FamilyName::Serif,
-            piet::FontFamilyInner::SansSerif => FamilyName::SansSerif,
-            piet::FontFamilyInner::Monospace => FamilyName::Monospace,
-            piet::FontFamilyInner::SystemUi => FamilyName::SansSerif,
-            piet::FontFamilyInner::Named(name) => {
-                font_kit::family_name::FamilyName::Title(name.to_string())
+        if let Some(fonts) = self.loaded_fonts.get(family.name()) {
+            let distance =
+                |font: &&Font| (font.weight() as i32 - weight.to_raw() as i32).unsigned_abs();
+            if let Some(font) = fonts.iter().min_by_key(distance) {
+                return font.clone();
             }
-            _ => FamilyName::SansSerif,
-        };
-        let font = self
-            .font_source
-            .select_best_match(
-                &[family_name],
-                &font_kit::properties::Properties::new()
-                    .weight(font_kit::properties::Weight(weight.to_raw() as f32)),
-            )
-            .ok()
-            .and_then(|h| h.load().ok())
-            .unwrap_or(self.default_font.clone());
-        font
-    }
-
-    pub fn update(
+        }
+        self.system_fonts
+            .select(family, weight)
+            .unwrap_or_else(|| self.default_font.clone())
+    }
+
+    /// Copy a glyph's pixels into the glyph atlas, or the color atlas if
+    /// `color` is set.
+    fn upload(
         &mut self,
-        device: &wgpu::Device,
-        staging_belt: &mut wgpu::util::StagingBelt,
-        encoder: &mut wgpu::CommandEncoder,
+        upload: Option<GlyphUpload>,
+        color: bool,
         offset: [u32; 2],
         size: [u32; 2],
         data: &[u8],
     ) {
-        let width = size[0] as usize;
+        let bytes_per_pixel = if color { 4 } else { 1 };
+        let width = size[0] as usize * bytes_per_pixel;
         let height = size[1] as usize;
 
-        if width == 0 || height == 0 {
-            return;
-        }
+        let (textures, device, staging_belt, encoder) = match (self.textures.as_mut(), upload) {
+            (Some(textures), Some(upload)) if width > 0 && height > 0 => {
+                (textures, upload.device, upload.staging_belt, upload.encoder)
+            }
+            _ => return,
+        };
 
         // It is a webgpu requirement that:
         //  BufferCopyView.layout.bytes_per_row % wgpu::COPY_BYTES_PER_ROW_ALIGNMENT == 0
@@ -798,20 +2347,20 @@ impl Cache {
 
         let padded_data_size = (padded_width * height) as u64;
 
-        if self.upload_buffer_size < padded_data_size {
-            self.upload_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-                label: Some("wgpu_glyph::Cache upload buffer"),
+        if textures.upload_buffer_size < padded_data_size {
+            textures.upload_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("glyph atlas upload buffer"),
                 size: padded_data_size,
                 usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
                 mapped_at_creation: false,
             });
 
-            self.upload_buffer_size = padded_data_size;
+            textures.upload_buffer_size = padded_data_size;
         }
 
         let mut padded_data = staging_belt.write_buffer(
             encoder,
-            &self.upload_buffer,
+            &textures.upload_buffer,
             0,
             NonZeroU64::new(padded_data_size).unwrap(),
             device,
@@ -825,7 +2374,7 @@ impl Cache {
         // TODO: Move to use Queue for less buffer usage
         encoder.copy_buffer_to_texture(
             wgpu::ImageCopyBuffer {
-                buffer: &self.upload_buffer,
+                buffer: &textures.upload_buffer,
                 layout: wgpu::ImageDataLayout {
                     offset: 0,
                     bytes_per_row: NonZeroU32::new(padded_width as u32),
@@ -833,47 +2382,90 @@ impl Cache {
                 },
             },
             wgpu::ImageCopyTexture {
-                texture: &self.texture,
+                texture: if color {
+                    &textures.color
+                } else {
+                    &textures.glyphs
+                },
                 mip_level: 0,
                 origin: wgpu::Origin3d {
-                    x: u32::from(offset[0]),
-                    y: u32::from(offset[1]),
+                    x: offset[0],
+                    y: offset[1],
                     z: 0,
                 },
                 aspect: wgpu::TextureAspect::All,
             },
             wgpu::Extent3d {
-                width: size[0] as u32,
-                height: size[1] as u32,
+                width: size[0],
+                height: size[1],
                 depth_or_array_layers: 1,
             },
         );
     }
 }
 
+/// Collects a glyph outline from font units into ems, flipping y to point
+/// down.
+struct OutlineBuilder {
+    path: BezPath,
+    units_per_em: f64,
+}
+
+impl OutlineBuilder {
+    fn point(&self, x: f32, y: f32) -> Point {
+        Point::new(x as f64 / self.units_per_em, -y as f64 / self.units_per_em)
+    }
+}
+
+impl ttf_parser::OutlineBuilder for OutlineBuilder {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.path.move_to(self.point(x, y));
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        self.path.line_to(self.point(x, y));
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        self.path.quad_to(self.point(x1, y1), self.point(x, y));
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        self.path
+            .curve_to(self.point(x1, y1), self.point(x2, y2), self.point(x, y));
+    }
+
+    fn close(&mut self) {
+        self.path.close_path();
+    }
+}
+
 fn glyph_rect_to_pos(
     glyph_rect: Rect,
     origin: Point,
-    glyph: &GlyphInfo,
     glyph_metric: &GlyphMetricInfo,
     scale: f64,
     size: [u32; 2],
 ) -> GlyphPosInfo {
     let glyph_rect = glyph_rect.with_origin(origin);
-    let mut cache_rect = glyph_rect.clone();
+    let mut cache_rect = glyph_rect;
     cache_rect.x0 /= size[0] as f64;
     cache_rect.x1 /= size[0] as f64;
     cache_rect.y0 /= size[1] as f64;
     cache_rect.y1 /= size[1] as f64;
-    let glyph_pos = GlyphPosInfo {
-        info: glyph.clone(),
+
+    GlyphPosInfo {
         rect: glyph_rect.with_size(Size::new(
             glyph_rect.size().width / scale,
             glyph_rect.size().height / scale,
         )),
         width: glyph_rect.size().width / scale,
         metric: glyph_metric.clone(),
+        ink: Rect::ZERO,
         cache_rect,
-    };
-    glyph_pos
+        color: false,
+        row: None,
+        whitespace: false,
+        text_index: 0,
+    }
 }
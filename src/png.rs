@@ -0,0 +1,118 @@
+use std::convert::TryInto;
+
+/// Decode the PNGs color glyph tables and SVGs embed into straight-alpha
+/// RGBA8.
+///
+/// Only what glyph images use is supported: 8-bit gray, gray-alpha, RGB,
+/// RGBA and palette images (palette also at lower bit depths), without
+/// interlacing.
+pub(crate) fn decode(data: &[u8]) -> Option<(u32, u32, Vec<u8>)> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    let mut rest = data.strip_prefix(SIGNATURE)?;
+
+    let mut header = None;
+    let mut palette: &[u8] = &[];
+    let mut transparency: &[u8] = &[];
+    let mut compressed = Vec::new();
+    while rest.len() >= 12 {
+        let len = u32::from_be_bytes(rest[..4].try_into().ok()?) as usize;
+        let kind = &rest[4..8];
+        let body = rest.get(8..8 + len)?;
+        match kind {
+            b"IHDR" if len >= 13 => header = Some(body),
+            b"PLTE" => palette = body,
+            b"tRNS" => transparency = body,
+            b"IDAT" => compressed.extend_from_slice(body),
+            b"IEND" => break,
+            _ => {}
+        }
+        rest = rest.get(12 + len..)?;
+    }
+
+    let header = header?;
+    let width = u32::from_be_bytes(header[..4].try_into().ok()?);
+    let height = u32::from_be_bytes(header[4..8].try_into().ok()?);
+    let (depth, color_type, interlace) = (header[8], header[9], header[12]);
+    let channels = match (color_type, depth) {
+        (3, 1) | (3, 2) | (3, 4) | (3, 8) | (0, 8) => 1,
+        (4, 8) => 2,
+        (2, 8) => 3,
+        (6, 8) => 4,
+        _ => return None,
+    };
+    if interlace != 0 {
+        return None;
+    }
+
+    let raw = miniz_oxide::inflate::decompress_to_vec_zlib(&compressed).ok()?;
+    let stride = (width as usize * channels * depth as usize).div_ceil(8);
+    let bpp = (channels * depth as usize / 8).max(1);
+    let mut scanlines = vec![0; stride * height as usize];
+    let mut previous = vec![0; stride];
+    for (row, line) in raw.chunks(stride + 1).take(height as usize).enumerate() {
+        let (&filter, line) = line.split_first()?;
+        if line.len() < stride {
+            return None;
+        }
+        let current = &mut scanlines[row * stride..(row + 1) * stride];
+        for i in 0..stride {
+            let a = if i >= bpp { current[i - bpp] } else { 0 };
+            let b = previous[i];
+            let c = if i >= bpp { previous[i - bpp] } else { 0 };
+            let predicted = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                _ => return None,
+            };
+            current[i] = line[i].wrapping_add(predicted);
+        }
+        previous.copy_from_slice(current);
+    }
+
+    let mut rgba = Vec::with_capacity(width as usize * height as usize * 4);
+    for line in scanlines.chunks(stride) {
+        for x in 0..width as usize {
+            let pixel = match color_type {
+                3 => {
+                    let per_byte = 8 / depth as usize;
+                    let byte = line[x / per_byte];
+                    let shift = 8 - depth as usize * (x % per_byte + 1);
+                    let index = ((byte >> shift) & ((1u16 << depth) - 1) as u8) as usize;
+                    let rgb = palette.get(index * 3..index * 3 + 3)?;
+                    let alpha = transparency.get(index).copied().unwrap_or(255);
+                    [rgb[0], rgb[1], rgb[2], alpha]
+                }
+                0 => [line[x], line[x], line[x], 255],
+                4 => [line[x * 2], line[x * 2], line[x * 2], line[x * 2 + 1]],
+                2 => [line[x * 3], line[x * 3 + 1], line[x * 3 + 2], 255],
+                _ => [
+                    line[x * 4],
+                    line[x * 4 + 1],
+                    line[x * 4 + 2],
+                    line[x * 4 + 3],
+                ],
+            };
+            rgba.extend_from_slice(&pixel);
+        }
+    }
+    Some((width, height, rgba))
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
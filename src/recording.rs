@@ -0,0 +1,199 @@
+use lyon::lyon_tessellation::VertexBuffers;
+use piet::kurbo::{Rect, Size};
+
+use crate::pipeline::{BlendMode, DrawBatch, GpuGradientStop, GpuVertex, Primitive};
+
+/// The geometry and primitives a frame uploaded, recorded with
+/// [`WgpuRenderer::set_frame_recording`](crate::WgpuRenderer::set_frame_recording)
+/// and drawn again with the render context's `replay`.
+///
+/// Only what's drawn straight to the target is recorded. Layers, custom
+/// render passes and draws with custom shaders are left out, and textures
+/// aren't saved: replayed glyphs sample the replaying renderer's glyph
+/// atlas, and images are blank.
+#[derive(Clone, Default)]
+pub struct FrameRecording {
+    size: Size,
+    pub(crate) vertices: Vec<GpuVertex>,
+    pub(crate) indices: Vec<u32>,
+    pub(crate) primitives: Vec<Primitive>,
+    pub(crate) gradient_stops: Vec<GpuGradientStop>,
+    batches: Vec<RecordedBatch>,
+}
+
+/// A draw batch without its image, as it's saved.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct RecordedBatch {
+    start: u32,
+    end: u32,
+    blend: u32,
+    /// 1 if `scissor` applies.
+    clipped: u32,
+    scissor: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for RecordedBatch {}
+unsafe impl bytemuck::Zeroable for RecordedBatch {}
+
+impl FrameRecording {
+    const MAGIC: &'static [u8; 4] = b"PWFR";
+    const VERSION: u32 = 1;
+
+    pub(crate) fn new(
+        size: Size,
+        geometry: &VertexBuffers<GpuVertex, u32>,
+        primitives: &[Primitive],
+        gradient_stops: &[GpuGradientStop],
+        batches: &[DrawBatch],
+    ) -> Self {
+        let batches = batches
+            .iter()
+            .filter(|batch| batch.is_draw() && batch.shader.is_none() && batch.start < batch.end)
+            .map(|batch| {
+                let scissor = batch.scissor.unwrap_or(Rect::ZERO);
+                RecordedBatch {
+                    start: batch.start,
+                    end: batch.end,
+                    blend: batch.blend as u32,
+                    clipped: batch.scissor.is_some() as u32,
+                    scissor: [
+                        scissor.x0 as f32,
+                        scissor.y0 as f32,
+                        scissor.x1 as f32,
+                        scissor.y1 as f32,
+                    ],
+                }
+            })
+            .collect();
+        Self {
+            size,
+            vertices: geometry.vertices.clone(),
+            indices: geometry.indices.clone(),
+            primitives: primitives.to_vec(),
+            gradient_stops: gradient_stops.to_vec(),
+            batches,
+        }
+    }
+
+    /// The logical size of the frame that was recorded.
+    pub fn size(&self) -> Size {
+        self.size
+    }
+
+    /// The recording as bytes to save, e.g. to attach to a bug report.
+    ///
+    /// Numbers are stored in the machine's byte order, so recordings only
+    /// read back on machines with the same one.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Self::MAGIC.to_vec();
+        let header = [
+            Self::VERSION,
+            (self.size.width as f32).to_bits(),
+            (self.size.height as f32).to_bits(),
+            self.vertices.len() as u32,
+            self.indices.len() as u32,
+            self.primitives.len() as u32,
+            self.gradient_stops.len() as u32,
+            self.batches.len() as u32,
+        ];
+        bytes.extend_from_slice(bytemuck::cast_slice(&header));
+        bytes.extend_from_slice(bytemuck::cast_slice(&self.vertices));
+        bytes.extend_from_slice(bytemuck::cast_slice(&self.indices));
+        bytes.extend_from_slice(bytemuck::cast_slice(&self.primitives));
+        bytes.extend_from_slice(bytemuck::cast_slice(&self.gradient_stops));
+        bytes.extend_from_slice(bytemuck::cast_slice(&self.batches));
+        bytes
+    }
+
+    /// Read a recording saved with [`to_bytes`](Self::to_bytes).
+    ///
+    /// Anything that isn't one, or that draws outside its own data, is
+    /// `InvalidInput`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, piet::Error> {
+        let bytes = bytes
+            .strip_prefix(&Self::MAGIC[..])
+            .ok_or(piet::Error::InvalidInput)?;
+        let mut reader = Reader { bytes };
+        let header: Vec<u32> = reader.take(8)?;
+        if header[0] != Self::VERSION {
+            return Err(piet::Error::InvalidInput);
+        }
+        let counts: Vec<usize> = header[3..].iter().map(|&n| n as usize).collect();
+        let recording = Self {
+            size: Size::new(
+                f32::from_bits(header[1]) as f64,
+                f32::from_bits(header[2]) as f64,
+            ),
+            vertices: reader.take(counts[0])?,
+            indices: reader.take(counts[1])?,
+            primitives: reader.take(counts[2])?,
+            gradient_stops: reader.take(counts[3])?,
+            batches: reader.take(counts[4])?,
+        };
+        if !recording.is_consistent() {
+            return Err(piet::Error::InvalidInput);
+        }
+        Ok(recording)
+    }
+
+    /// Whether every index, primitive and batch refers to data that's there,
+    /// so replaying can't read past the GPU buffers.
+    fn is_consistent(&self) -> bool {
+        let vertices = self.vertices.len() as u32;
+        let primitives = self.primitives.len() as u32;
+        let indices = self.indices.len() as u32;
+        self.indices.iter().all(|&i| i < vertices)
+            && self.vertices.iter().all(|v| v.primitive_id < primitives)
+            && self.batches.iter().all(|batch| {
+                batch.start <= batch.end
+                    && batch.end <= indices
+                    && (batch.blend as usize) < BlendMode::ALL.len()
+            })
+    }
+
+    /// The recorded batches, ready to draw.
+    pub(crate) fn draw_batches(&self) -> Vec<DrawBatch> {
+        self.batches
+            .iter()
+            .map(|batch| DrawBatch {
+                start: batch.start,
+                end: batch.end,
+                scissor: match batch.clipped {
+                    0 => None,
+                    _ => {
+                        let [x0, y0, x1, y1] = batch.scissor;
+                        Some(Rect::new(x0 as f64, y0 as f64, x1 as f64, y1 as f64))
+                    }
+                },
+                blend: BlendMode::ALL[batch.blend as usize],
+                layer: None,
+                custom: None,
+                image: None,
+                shader: None,
+            })
+            .collect()
+    }
+}
+
+/// Reads consecutive arrays out of a recording's bytes, which may not be
+/// aligned for them.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take<T: bytemuck::Pod>(&mut self, count: usize) -> Result<Vec<T>, piet::Error> {
+        let size = std::mem::size_of::<T>();
+        let len = count.checked_mul(size).ok_or(piet::Error::InvalidInput)?;
+        if self.bytes.len() < len {
+            return Err(piet::Error::InvalidInput);
+        }
+        let (head, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(head
+            .chunks_exact(size)
+            .map(bytemuck::pod_read_unaligned)
+            .collect())
+    }
+}
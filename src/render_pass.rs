@@ -0,0 +1,133 @@
+use piet::kurbo::Size;
+
+/// The format of the depth buffer custom render passes can attach.
+pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+/// A custom render pass recorded with
+/// [`custom_render_pass`](crate::Piet::custom_render_pass).
+pub(crate) type CustomPass<'a> = Box<dyn FnOnce(&mut RenderPassCtx) + 'a>;
+
+/// What a custom render pass gets to draw into the frame with.
+///
+/// Passes begun here target the same texture piet draws into, multisampled
+/// with the renderer's sample count, so pipelines used in them must be
+/// created with the renderer's [`format`](crate::WgpuRenderer::format) and
+/// [`sample_count`](Self::sample_count).
+///
+/// # Ordering
+///
+/// Text, shapes, images and custom passes all go through the same stream of
+/// draw batches, so they composite in the order they were recorded. piet's
+/// draws are split into separate render passes around each custom pass and
+/// everything lands in one command buffer: what was drawn before
+/// `custom_render_pass` is underneath, what's drawn after it is on top. To
+/// put text over custom content draw it after recording the pass, and
+/// before to put it underneath.
+pub struct RenderPassCtx<'a> {
+    pub device: &'a wgpu::Device,
+    pub queue: &'a wgpu::Queue,
+    pub encoder: &'a mut wgpu::CommandEncoder,
+    pub(crate) view: &'a wgpu::TextureView,
+    pub(crate) msaa: Option<&'a wgpu::TextureView>,
+    pub(crate) depth: &'a wgpu::TextureView,
+    pub(crate) format: wgpu::TextureFormat,
+    pub(crate) sample_count: u32,
+    pub(crate) size: Size,
+    pub(crate) load: wgpu::LoadOp<wgpu::Color>,
+    pub(crate) depth_ops: wgpu::Operations<f32>,
+}
+
+impl<'a> RenderPassCtx<'a> {
+    pub fn format(&self) -> wgpu::TextureFormat {
+        self.format
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    /// The target's size in physical pixels.
+    pub fn size(&self) -> Size {
+        self.size
+    }
+
+    /// Begin a pass that draws over what piet has drawn so far, unless
+    /// [`set_load_op`](Self::set_load_op) asked for a clear.
+    pub fn render_pass(&mut self) -> wgpu::RenderPass<'_> {
+        self.begin(None)
+    }
+
+    /// Set how passes begun afterwards load the color target.
+    ///
+    /// The default, `LoadOp::Load`, keeps what piet has drawn so far;
+    /// `LoadOp::Clear` replaces it, including anything drawn before this
+    /// custom pass in the frame.
+    pub fn set_load_op(&mut self, load: wgpu::LoadOp<wgpu::Color>) {
+        self.load = load;
+    }
+
+    /// Set how [`render_pass_with_depth`](Self::render_pass_with_depth)
+    /// loads and stores the depth buffer.
+    ///
+    /// The default clears it to 1.0 and discards it afterwards. The depth
+    /// buffer is shared by every custom pass in the frame, so storing it lets
+    /// a later pass keep testing against it.
+    pub fn set_depth_ops(&mut self, ops: wgpu::Operations<f32>) {
+        self.depth_ops = ops;
+    }
+
+    /// Begin a pass like [`render_pass`](Self::render_pass) with a depth
+    /// buffer of [`DEPTH_FORMAT`] attached.
+    pub fn render_pass_with_depth(&mut self) -> wgpu::RenderPass<'_> {
+        let depth = self.depth;
+        let size = self.size;
+        let depth_ops = self.depth_ops;
+        let mut pass = self.begin(Some(wgpu::RenderPassDepthStencilAttachment {
+            view: depth,
+            depth_ops: Some(depth_ops),
+            stencil_ops: None,
+        }));
+        pass.set_viewport(0.0, 0.0, size.width as f32, size.height as f32, 0.0, 1.0);
+        pass
+    }
+
+    fn begin(
+        &mut self,
+        depth_stencil_attachment: Option<wgpu::RenderPassDepthStencilAttachment<'a>>,
+    ) -> wgpu::RenderPass<'_> {
+        self.encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("custom render pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: self.msaa.unwrap_or(self.view),
+                resolve_target: self.msaa.map(|_| self.view),
+                ops: wgpu::Operations {
+                    load: self.load,
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment,
+        })
+    }
+}
+
+pub(crate) fn create_depth(
+    device: &wgpu::Device,
+    sample_count: u32,
+    width: u32,
+    height: u32,
+) -> wgpu::TextureView {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Custom pass depth"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count,
+        dimension: wgpu::TextureDimension::D2,
+        format: DEPTH_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+    });
+    texture.create_view(&wgpu::TextureViewDescriptor::default())
+}
@@ -0,0 +1,26 @@
+[[group(0), binding(0)]] var blit_sampler: sampler;
+[[group(0), binding(1)]] var blit_tex: texture_2d<f32>;
+
+struct VertexOutput {
+    [[builtin(position)]] position: vec4<f32>;
+    [[location(0)]] uv: vec2<f32>;
+};
+
+// A single triangle covering the whole target.
+[[stage(vertex)]]
+fn vs_main([[builtin(vertex_index)]] index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+
+    var out: VertexOutput;
+    out.position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+// At the same size this copies pixels exactly; at half size, for mipmaps,
+// each pixel lands between 2x2 pixels of the source, so a linear sample
+// averages them.
+[[stage(fragment)]]
+fn fs_main(input: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return textureSample(blit_tex, blit_sampler, input.uv);
+}
@@ -2,26 +2,44 @@ struct Primitive {
     u_clip_rect: vec4<f32>;
     u_transform_1: vec4<f32>;
     u_blur_rect: vec4<f32>;
+    u_gradient: vec4<f32>;
     u_transform_2: vec2<f32>;
     u_translate: vec2<f32>;
     u_scale: vec2<f32>;
+    u_gradient_stops: vec2<f32>;
     u_clip: f32;
     u_blur_radius: f32;
+    u_gradient_kind: f32;
+    u_blur_corner_radius: f32;
+    u_z: f32;
+    u_gradient_extend: f32;
+    u_alpha: f32;
+    u_clip_feather: f32;
+};
+
+struct GradientStop {
+    color: vec4<f32>;
+    offset: f32;
 };
 
 struct Globals {
     u_resolution: vec2<f32>;
     u_scale: f32;
-};
-
-struct Primitives {
-    data: array<Primitive>;
+    u_encode_srgb: f32;
+    // The target's top left corner in the frame, in device pixels.
+    u_origin: vec2<f32>;
 };
 
 [[group(0), binding(0)]] var<uniform> globals: Globals;
 [[group(0), binding(1)]] var font_sampler: sampler;
 [[group(0), binding(2)]] var font_tex: texture_2d<f32>;
-[[group(0), binding(3)]] var<storage> primitives: Primitives;
+// Primitives and gradient stops are packed into rows of 1024 texels, 8 per
+// primitive and 2 per stop, in the order of their fields.
+[[group(0), binding(3)]] var primitives: texture_2d<f32>;
+[[group(0), binding(4)]] var color_glyph_tex: texture_2d<f32>;
+[[group(0), binding(5)]] var gradient_stops: texture_2d<f32>;
+[[group(1), binding(0)]] var image_sampler: sampler;
+[[group(1), binding(1)]] var image_tex: texture_2d<f32>;
     
 struct VertexInput {
     [[location(0)]] v_pos: vec2<f32>;
@@ -30,6 +48,8 @@ struct VertexInput {
     [[location(3)]] v_tex: f32;
     [[location(4)]] v_tex_pos: vec2<f32>;
     [[location(5)]] v_primitive_id: u32;
+    [[location(6)]] v_sdf_size: vec2<f32>;
+    [[location(7)]] v_sdf_radii: vec4<f32>;
 };
 
 struct VertexOutput {
@@ -42,11 +62,56 @@ struct VertexOutput {
     [[location(5)]] tex_pos: vec2<f32>;
     [[location(6)]] clip: f32;
     [[location(7)]] clip_rect: vec4<f32>;
+    [[location(8)]] gradient: vec4<f32>;
+    [[location(9), interpolate(flat)]] gradient_stops: vec2<f32>;
+    [[location(10)]] gradient_kind: f32;
+    [[location(11)]] blur_corner_radius: f32;
+    [[location(12)]] sdf_size: vec2<f32>;
+    [[location(13)]] sdf_radii: vec4<f32>;
+    [[location(14)]] gradient_extend: f32;
+    [[location(15)]] clip_feather: f32;
+    [[location(16), interpolate(flat)]] primitive_id: u32;
 };
 
+fn load_primitive(id: u32) -> Primitive {
+    let x = i32(id % 128u) * 8;
+    let y = i32(id / 128u);
+    let t4 = textureLoad(primitives, vec2<i32>(x + 4, y), 0);
+    let t5 = textureLoad(primitives, vec2<i32>(x + 5, y), 0);
+    let t6 = textureLoad(primitives, vec2<i32>(x + 6, y), 0);
+    let t7 = textureLoad(primitives, vec2<i32>(x + 7, y), 0);
+    var primitive: Primitive;
+    primitive.u_clip_rect = textureLoad(primitives, vec2<i32>(x, y), 0);
+    primitive.u_transform_1 = textureLoad(primitives, vec2<i32>(x + 1, y), 0);
+    primitive.u_blur_rect = textureLoad(primitives, vec2<i32>(x + 2, y), 0);
+    primitive.u_gradient = textureLoad(primitives, vec2<i32>(x + 3, y), 0);
+    primitive.u_transform_2 = t4.xy;
+    primitive.u_translate = t4.zw;
+    primitive.u_scale = t5.xy;
+    primitive.u_gradient_stops = t5.zw;
+    primitive.u_clip = t6.x;
+    primitive.u_blur_radius = t6.y;
+    primitive.u_gradient_kind = t6.z;
+    primitive.u_blur_corner_radius = t6.w;
+    primitive.u_z = t7.x;
+    primitive.u_gradient_extend = t7.y;
+    primitive.u_alpha = t7.z;
+    primitive.u_clip_feather = t7.w;
+    return primitive;
+}
+
+fn load_gradient_stop(index: u32) -> GradientStop {
+    let x = i32(index % 512u) * 2;
+    let y = i32(index / 512u);
+    var stop: GradientStop;
+    stop.color = textureLoad(gradient_stops, vec2<i32>(x, y), 0);
+    stop.offset = textureLoad(gradient_stops, vec2<i32>(x + 1, y), 0).x;
+    return stop;
+}
+
 [[stage(vertex)]]
 fn vs_main(input: VertexInput) -> VertexOutput {
-    let primitive = primitives.data[input.v_primitive_id];
+    let primitive = load_primitive(input.v_primitive_id);
 
     var out: VertexOutput;
     
@@ -64,10 +129,10 @@ fn vs_main(input: VertexInput) -> VertexOutput {
     
     var translated_pos: vec2<f32> = (v_pos * primitive.u_scale + primitive.u_translate + input.v_translate) * globals.u_scale;
     
-    var pos: vec2<f32> = (translated_pos / globals.u_resolution * 2.0 - vec2<f32>(1.0, 1.0)) * invert_y;
+    var pos: vec2<f32> = ((translated_pos - globals.u_origin) / globals.u_resolution * 2.0 - vec2<f32>(1.0, 1.0)) * invert_y;
     
-    out.position = vec4<f32>(pos, 0.0, 1.0);
-    out.color = input.v_color;
+    out.position = vec4<f32>(pos, primitive.u_z, 1.0);
+    out.color = vec4<f32>(input.v_color.rgb, input.v_color.a * primitive.u_alpha);
     out.blur_radius = primitive.u_blur_radius;
     out.rect = primitive.u_blur_rect;
     out.pos = input.v_pos;
@@ -75,13 +140,22 @@ fn vs_main(input: VertexInput) -> VertexOutput {
     out.tex_pos = input.v_tex_pos;
     out.clip = primitive.u_clip;
     out.clip_rect = primitive.u_clip_rect;
+    out.clip_feather = primitive.u_clip_feather * globals.u_scale;
+    out.primitive_id = input.v_primitive_id;
+    out.gradient = primitive.u_gradient;
+    out.gradient_stops = primitive.u_gradient_stops;
+    out.gradient_kind = primitive.u_gradient_kind;
+    out.gradient_extend = primitive.u_gradient_extend;
+    out.blur_corner_radius = primitive.u_blur_corner_radius;
+    out.sdf_size = input.v_sdf_size;
+    out.sdf_radii = input.v_sdf_radii;
     
     if (out.clip > 0.0) {
         var left_top = vec2<f32>(primitive.u_clip_rect.x, primitive.u_clip_rect.y);
-        var left_top = left_top * globals.u_scale;
+        var left_top = left_top * globals.u_scale - globals.u_origin;
         
         var right_bottom = vec2<f32>(primitive.u_clip_rect.z, primitive.u_clip_rect.w);
-        var right_bottom = right_bottom * globals.u_scale;
+        var right_bottom = right_bottom * globals.u_scale - globals.u_origin;
         out.clip_rect = vec4<f32>(left_top, right_bottom);
     }
     
@@ -102,24 +176,257 @@ fn box_shadow(lower: vec2<f32>, upper: vec2<f32>, point: vec2<f32>, radius: f32)
     return (integral.z - integral.x) * (integral.w - integral.y);
 }
 
+fn erf2(x: vec2<f32>) -> vec2<f32> {
+    var s: vec2<f32> = sign(x);
+    var a: vec2<f32> = abs(x);
+    var r: vec2<f32> = 1.0 + (0.278393 + (0.230389 + 0.078108 * (a * a)) * a) * a;
+    r = r * r;
+    return s - s / (r * r);
+}
+
+fn gaussian(x: f32, sigma: f32) -> f32 {
+    return exp(-(x * x) / (2.0 * sigma * sigma)) / (2.5066283 * sigma);
+}
+
+// The blurred coverage of one horizontal slice of a rounded box.
+fn rounded_box_shadow_x(x: f32, y: f32, sigma: f32, corner: f32, half_size: vec2<f32>) -> f32 {
+    let delta = min(half_size.y - corner - abs(y), 0.0);
+    let curved = half_size.x - corner + sqrt(max(0.0, corner * corner - delta * delta));
+    let integral = 0.5 + 0.5 * erf2((x + vec2<f32>(-curved, curved)) * (sqrt(0.5) / sigma));
+    return integral.y - integral.x;
+}
+
+// Integrates the horizontal slices vertically with a few samples.
+fn rounded_box_shadow(lower: vec2<f32>, upper: vec2<f32>, point: vec2<f32>, sigma: f32, corner: f32) -> f32 {
+    let center = (lower + upper) * 0.5;
+    let half_size = (upper - lower) * 0.5;
+    let p = point - center;
+
+    let low = p.y - half_size.y;
+    let high = p.y + half_size.y;
+    let start = clamp(-3.0 * sigma, low, high);
+    let end = clamp(3.0 * sigma, low, high);
+
+    let step = (end - start) / 4.0;
+    var y = start + step * 0.5;
+    var value = 0.0;
+    for (var i: i32 = 0; i < 4; i = i + 1) {
+        value = value + rounded_box_shadow_x(p.x, p.y - y, sigma, corner, half_size) * gaussian(y, sigma) * step;
+        y = y + step;
+    }
+    return value;
+}
+
+// Wrap `t` past 0 and 1: 0 clamps, 1 repeats and 2 reflects.
+fn extend(t: vec2<f32>, mode: f32) -> vec2<f32> {
+    if (mode > 1.5) {
+        return 1.0 - abs(fract(t * 0.5) * 2.0 - 1.0);
+    }
+    if (mode > 0.5) {
+        return fract(t);
+    }
+    return clamp(t, vec2<f32>(0.0), vec2<f32>(1.0));
+}
+
+fn gradient_color(input: VertexOutput) -> vec4<f32> {
+    var t: f32;
+    if (input.gradient_kind < 1.5) {
+        let start = input.gradient.xy;
+        let dir = input.gradient.zw - start;
+        t = dot(input.pos - start, dir) / dot(dir, dir);
+    } else {
+        t = (length(input.pos - input.gradient.xy) - input.gradient.z) / (input.gradient.w - input.gradient.z);
+    }
+    t = extend(vec2<f32>(t), input.gradient_extend).x;
+    // Interpolate premultiplied so a stop fading out doesn't drag its
+    // neighbour's color towards black. Each stop past `t` leaves the color
+    // at the one before it.
+    let first = u32(input.gradient_stops.x);
+    var prev = load_gradient_stop(first);
+    var mixed = vec4<f32>(prev.color.rgb * prev.color.a, prev.color.a);
+    for (var i: u32 = 1u; i < u32(input.gradient_stops.y); i = i + 1u) {
+        let next = load_gradient_stop(first + i);
+        if (t > prev.offset) {
+            let f = clamp((t - prev.offset) / max(next.offset - prev.offset, 0.00001), 0.0, 1.0);
+            mixed = mix(
+                vec4<f32>(prev.color.rgb * prev.color.a, prev.color.a),
+                vec4<f32>(next.color.rgb * next.color.a, next.color.a),
+                f,
+            );
+        }
+        prev = next;
+    }
+    return vec4<f32>(mixed.rgb / max(mixed.a, 0.0001), mixed.a);
+}
+
+// The signed distance from `p` to a box centered at the origin with half size
+// `size` and corner radii `radii`, clockwise from the top left.
+fn rounded_box_sdf(p: vec2<f32>, size: vec2<f32>, radii: vec4<f32>) -> f32 {
+    let top = select(radii.x, radii.y, p.x > 0.0);
+    let bottom = select(radii.w, radii.z, p.x > 0.0);
+    let radius = select(top, bottom, p.y > 0.0);
+    let q = abs(p) - size + radius;
+    return min(max(q.x, q.y), 0.0) + length(max(q, vec2<f32>(0.0))) - radius;
+}
+
+// The approximate signed distance from `p` to an ellipse centered at the origin
+// with radii `radii`: its implicit function divided by its gradient.
+fn ellipse_sdf(p: vec2<f32>, radii: vec2<f32>) -> f32 {
+    let k = length(p / radii);
+    let gradient = length(p / (radii * radii));
+    if (gradient < 0.000001) {
+        return -min(radii.x, radii.y);
+    }
+    return (k - 1.0) * k / gradient;
+}
+
+// The signed distance from `p` to a slice of a circle centered at the origin
+// with radius `radius`, which opens along the x axis by the angle whose sine
+// and cosine are `half_sweep` on either side.
+fn pie_sdf(p: vec2<f32>, radius: f32, half_sweep: vec2<f32>) -> f32 {
+    let q = vec2<f32>(abs(p.y), p.x);
+    let outside_circle = length(q) - radius;
+    let to_side = length(q - half_sweep * clamp(dot(q, half_sweep), 0.0, radius));
+    return max(outside_circle, to_side * sign(half_sweep.y * q.x - half_sweep.x * q.y));
+}
+
+fn srgb_to_linear(c: vec3<f32>) -> vec3<f32> {
+    let low = c / 12.92;
+    let high = pow((c + 0.055) / 1.055, vec3<f32>(2.4));
+    return select(high, low, c <= vec3<f32>(0.04045));
+}
+
+fn linear_to_srgb(c: vec3<f32>) -> vec3<f32> {
+    let low = c * 12.92;
+    let high = pow(c, vec3<f32>(1.0 / 2.4)) * 1.055 - 0.055;
+    return select(high, low, c <= vec3<f32>(0.0031308));
+}
+
+// Clip a fragment's straight, linear `color` and turn it into what the
+// target blends. Custom shaders' colors go through here too.
+fn target_color(input: VertexOutput, straight: vec4<f32>) -> vec4<f32> {
+    var color: vec4<f32> = straight;
+
+    if (input.clip > 0.0) {
+        if (input.position.x < input.clip_rect.x || input.position.x > input.clip_rect.z || input.position.y < input.clip_rect.y || input.position.y > input.clip_rect.w) {
+            discard;
+        }
+        // Feathered clips fade out towards their edges, along each axis.
+        if (input.clip_feather > 0.0) {
+            let inside = min(
+                input.position.xy - input.clip_rect.xy,
+                input.clip_rect.zw - input.position.xy
+            );
+            let coverage = clamp(inside / input.clip_feather, vec2<f32>(0.0), vec2<f32>(1.0));
+            color.w = color.w * coverage.x * coverage.y;
+        }
+    }
+    
+    // Colors are linear up to here. sRGB targets encode them in hardware;
+    // other targets store what's written, so encode it here.
+    if (globals.u_encode_srgb > 0.5) {
+        color = vec4<f32>(linear_to_srgb(max(color.rgb, vec3<f32>(0.0))), color.a);
+    }
+
+    // Fully transparent parts, like the corners of rounded boxes, mustn't
+    // write depth and hide what's drawn behind them later.
+    if (color.a <= 0.0) {
+        discard;
+    }
+
+    // Colors are straight alpha up to here; the target blends premultiplied.
+    return vec4<f32>(color.rgb * color.a, color.a);
+}
+
 [[stage(fragment)]]
 fn fs_main(input: VertexOutput) -> [[location(0)]] vec4<f32> {
     var color: vec4<f32> = input.color;
+
+    if (input.gradient_kind > 0.0) {
+        color = color * gradient_color(input);
+    }
     
-    if (input.blur_radius > 0.0) {
-        color.w = color.w * box_shadow(
-           vec2<f32>(input.rect.x, input.rect.y),
-           vec2<f32>(input.rect.z, input.rect.w),
-           vec2<f32>(input.pos.x, input.pos.y),
-           input.blur_radius
-        );
+    // Negative blur radii are inset shadows, which darken everything but
+    // the blurred rect instead.
+    if (input.blur_radius != 0.0) {
+        let sigma = abs(input.blur_radius);
+        var shadow: f32;
+        if (input.blur_corner_radius > 0.0) {
+            shadow = rounded_box_shadow(
+               vec2<f32>(input.rect.x, input.rect.y),
+               vec2<f32>(input.rect.z, input.rect.w),
+               vec2<f32>(input.pos.x, input.pos.y),
+               sigma,
+               input.blur_corner_radius
+            );
+        } else {
+            shadow = box_shadow(
+               vec2<f32>(input.rect.x, input.rect.y),
+               vec2<f32>(input.rect.z, input.rect.w),
+               vec2<f32>(input.pos.x, input.pos.y),
+               sigma
+            );
+        }
+        if (input.blur_radius < 0.0) {
+            shadow = 1.0 - shadow;
+        }
+        color.w = color.w * shadow;
     }
 
+    // `tex` is 1 for glyphs sampled from the font cache, 2 for images, 3 for
+    // color glyphs, which keep their own colors, and 4 for rounded boxes and
+    // 5 for ellipses whose edges are anti-aliased from their distance field,
+    // 6 for patterns, 7 for pie slices, anti-aliased like ellipses, and 8 for
+    // render textures, which hold colors as this shader writes them.
     var alpha: f32 = textureSample(font_tex, font_sampler, input.tex_pos).r;
-    if (input.tex > 0.0) {
+    let image = textureSample(image_tex, image_sampler, input.tex_pos);
+    // Patterns count images across and down in `tex_pos`, wrapped into the
+    // image's area of its texture, which the primitive's `gradient` holds.
+    // The gradients of the unwrapped position keep the seams from picking
+    // the smallest mip level.
+    let pattern_size = input.gradient.zw - input.gradient.xy;
+    let pattern = textureSampleGrad(
+        image_tex,
+        image_sampler,
+        input.gradient.xy + extend(input.tex_pos, input.gradient_extend) * pattern_size,
+        dpdx(input.tex_pos) * pattern_size,
+        dpdy(input.tex_pos) * pattern_size
+    );
+    let color_glyph = textureSample(color_glyph_tex, font_sampler, input.tex_pos);
+    let distance = select(
+        select(
+            rounded_box_sdf(input.tex_pos, input.sdf_size, input.sdf_radii),
+            ellipse_sdf(input.tex_pos, input.sdf_size),
+            input.tex > 4.5,
+        ),
+        pie_sdf(input.tex_pos, input.sdf_size.x, input.sdf_radii.xy),
+        input.tex > 6.5,
+    );
+    let distance_per_pixel = max(fwidth(distance), 0.0001);
+    if (input.tex > 7.5) {
+        var drawn: vec4<f32> = image;
+        if (drawn.a > 0.0) {
+            drawn = vec4<f32>(drawn.rgb / drawn.a, drawn.a);
+        }
+        if (globals.u_encode_srgb > 0.5) {
+            drawn = vec4<f32>(srgb_to_linear(drawn.rgb), drawn.a);
+        }
+        color = color * drawn;
+    } else if (input.tex > 5.5 && input.tex < 6.5) {
+        color = color * pattern;
+    } else if (input.tex > 3.5) {
+        color.w = color.w * clamp(0.5 - distance / distance_per_pixel, 0.0, 1.0);
+    } else if (input.tex > 2.5) {
+        color = vec4<f32>(srgb_to_linear(color_glyph.rgb), color_glyph.a * color.a);
+    } else if (input.tex > 1.5) {
+        color = color * image;
+    } else if (input.tex > 0.0) {
         if (alpha <= 0.0) {
             discard;
         }
+        // Glyph coverage is thickened on the way to alpha, so text blended in
+        // linear light doesn't come out thinner than it's hinted to be. The
+        // text's color and opacity are used as they are, like any fill's.
         if (alpha <= 1.0) {
             if (alpha <= 0.0031308) {
                 alpha = alpha * 12.92;
@@ -133,11 +440,19 @@ fn fs_main(input: VertexOutput) -> [[location(0)]] vec4<f32> {
         color.w = color.w * alpha;
     }
     
-    if (input.clip > 0.0) {
-        if (input.position.x < input.clip_rect.x || input.position.x > input.clip_rect.z || input.position.y < input.clip_rect.y || input.position.y > input.clip_rect.w) {
-            discard;
-        }
-    }
-    
-    return color;
+    return target_color(input, color);
+}
+
+// Triangle edges for the debug wireframe, in a color that stands out on most
+// content.
+[[stage(fragment)]]
+fn fs_wireframe(input: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return vec4<f32>(1.0, 0.0, 1.0, 1.0);
+}
+
+// One more draw over each fragment for the debug overdraw heatmap, added up
+// in a single channel.
+[[stage(fragment)]]
+fn fs_overdraw(input: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return vec4<f32>(1.0, 0.0, 0.0, 0.0);
 }
@@ -0,0 +1,60 @@
+struct Layer {
+    alpha: f32;
+    // Standard deviation of the blur in pixels, 0 for none.
+    radius: f32;
+    direction: vec2<f32>;
+    // Where the layer goes in the target, in clip space: left, top, right,
+    // bottom.
+    dest: vec4<f32>;
+    // How much of the layer's texture it covers; the rest is unused.
+    uv_max: vec2<f32>;
+};
+
+[[group(0), binding(0)]] var layer_sampler: sampler;
+[[group(0), binding(1)]] var layer_tex: texture_2d<f32>;
+[[group(0), binding(2)]] var<uniform> layer: Layer;
+
+struct VertexOutput {
+    [[builtin(position)]] position: vec4<f32>;
+    [[location(0)]] uv: vec2<f32>;
+};
+
+// Two triangles covering the layer's destination.
+[[stage(vertex)]]
+fn vs_main([[builtin(vertex_index)]] index: u32) -> VertexOutput {
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(0.0, 0.0),
+        vec2<f32>(1.0, 0.0),
+        vec2<f32>(0.0, 1.0),
+        vec2<f32>(0.0, 1.0),
+        vec2<f32>(1.0, 0.0),
+        vec2<f32>(1.0, 1.0),
+    );
+    let corner = corners[index];
+
+    var out: VertexOutput;
+    out.position = vec4<f32>(mix(layer.dest.xy, layer.dest.zw, corner), 0.0, 1.0);
+    out.uv = corner * layer.uv_max;
+    return out;
+}
+
+// Layer textures hold premultiplied color, so the group alpha scales every channel.
+[[stage(fragment)]]
+fn fs_main(input: VertexOutput) -> [[location(0)]] vec4<f32> {
+    if (layer.radius <= 0.0) {
+        return textureSampleLevel(layer_tex, layer_sampler, input.uv, 0.0) * layer.alpha;
+    }
+
+    // One direction of a separable Gaussian blur.
+    let step = layer.direction / vec2<f32>(textureDimensions(layer_tex));
+    let taps = i32(min(ceil(layer.radius * 3.0), 64.0));
+    var sum = vec4<f32>(0.0, 0.0, 0.0, 0.0);
+    var total = 0.0;
+    for (var i: i32 = -taps; i <= taps; i = i + 1) {
+        let x = f32(i);
+        let weight = exp(-x * x / (2.0 * layer.radius * layer.radius));
+        sum = sum + textureSampleLevel(layer_tex, layer_sampler, input.uv + step * x, 0.0) * weight;
+        total = total + weight;
+    }
+    return sum / total * layer.alpha;
+}
@@ -0,0 +1,33 @@
+[[group(0), binding(0)]] var counts: texture_2d<f32>;
+
+struct VertexOutput {
+    [[builtin(position)]] position: vec4<f32>;
+};
+
+// A single triangle covering the whole target.
+[[stage(vertex)]]
+fn vs_main([[builtin(vertex_index)]] index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+
+    var out: VertexOutput;
+    out.position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    return out;
+}
+
+// Pixels drawn once are blue, turning green, yellow and then red at 8 or
+// more draws. Pixels nothing drew over are left alone.
+[[stage(fragment)]]
+fn fs_main(input: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let count = textureLoad(counts, vec2<i32>(input.position.xy), 0).r;
+    if (count < 0.5) {
+        discard;
+    }
+    let t = clamp((count - 1.0) / 7.0, 0.0, 1.0) * 3.0;
+    var color = mix(vec3<f32>(0.0, 0.2, 1.0), vec3<f32>(0.0, 1.0, 0.2), clamp(t, 0.0, 1.0));
+    color = mix(color, vec3<f32>(1.0, 1.0, 0.0), clamp(t - 1.0, 0.0, 1.0));
+    color = mix(color, vec3<f32>(1.0, 0.0, 0.0), clamp(t - 2.0, 0.0, 1.0));
+
+    // Premultiplied, over the frame.
+    let alpha = 0.7;
+    return vec4<f32>(color * alpha, alpha);
+}
@@ -1,18 +1,27 @@
-use std::{collections::HashMap, f64::NAN, str::FromStr};
+use std::{collections::HashMap, ops::Range, str::FromStr};
 
+use linked_hash_map::LinkedHashMap;
 use lyon::{
     lyon_tessellation::{
         BuffersBuilder, FillOptions, FillTessellator, FillVertex, StrokeOptions, StrokeTessellator,
         StrokeVertex, VertexBuffers,
     },
     math::{point, Point},
-    path::PathEvent,
+    path::{iterator::PathIterator, PathEvent},
     tessellation,
 };
+use piet::{
+    kurbo::{Affine, Point as KurboPoint, Rect, Size, Vec2},
+    Color, InterpolationMode,
+};
 use sha2::{Digest, Sha256};
 use usvg::NodeExt;
 
-use crate::{context::from_linear, pipeline::GpuVertex};
+use crate::{
+    context::{format_color, srgb_to_linear, WgpuImage},
+    gradient::{ExtendMode, Gradient},
+    pipeline::GpuVertex,
+};
 
 #[derive(Clone)]
 pub struct Svg {
@@ -45,13 +54,199 @@ impl FromStr for Svg {
     }
 }
 
+/// How an SVG's view box is fitted into the rect it's drawn in, like SVG's
+/// `preserveAspectRatio`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SvgFit {
+    /// Scale uniformly to fit inside the rect, centered.
+    Contain,
+    /// Scale uniformly to cover the whole rect, centered and clipped to it.
+    Cover,
+    /// Scale each axis independently to fill the rect exactly.
+    Stretch,
+    /// Keep the view box's size, centered in the rect.
+    Center,
+}
+
+impl SvgFit {
+    /// The transform from view box coordinates into `rect`.
+    pub(crate) fn transform(self, view_rect: Rect, rect: Rect) -> Affine {
+        let sx = rect.width() / view_rect.width();
+        let sy = rect.height() / view_rect.height();
+        let (sx, sy) = match self {
+            SvgFit::Contain => (sx.min(sy), sx.min(sy)),
+            SvgFit::Cover => (sx.max(sy), sx.max(sy)),
+            SvgFit::Stretch => (sx, sy),
+            SvgFit::Center => (1.0, 1.0),
+        };
+        let size = Vec2::new(view_rect.width() * sx, view_rect.height() * sy);
+        let origin = rect.center() - size / 2.0;
+        Affine::translate(origin.to_vec2())
+            * Affine::scale_non_uniform(sx, sy)
+            * Affine::translate(Vec2::new(-view_rect.x0, -view_rect.y0))
+    }
+}
+
+/// Colors and opacity applied to an SVG as it's drawn.
+#[derive(Clone, Debug)]
+pub struct SvgStyle {
+    pub(crate) color: Option<Color>,
+    pub(crate) opacity: f64,
+    pub(crate) path_colors: HashMap<String, Color>,
+}
+
+impl Default for SvgStyle {
+    fn default() -> Self {
+        Self {
+            color: None,
+            opacity: 1.0,
+            path_colors: HashMap::new(),
+        }
+    }
+}
+
+impl SvgStyle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Recolor every path. The color's alpha is multiplied with each path's
+    /// own opacity.
+    pub fn color(mut self, color: Color) -> Self {
+        self.color = Some(color);
+        self
+    }
+
+    /// Multiply the opacity of the whole SVG.
+    pub fn opacity(mut self, opacity: f64) -> Self {
+        self.opacity = opacity;
+        self
+    }
+
+    /// Recolor only the path with the given `id`, taking precedence over
+    /// [`color`](Self::color).
+    pub fn path_color(mut self, id: impl Into<String>, color: Color) -> Self {
+        self.path_colors.insert(id.into(), color);
+        self
+    }
+
+    /// The vertices of `data` recolored and faded by the style.
+    pub(crate) fn vertices(&self, data: &SvgData) -> Vec<GpuVertex> {
+        let recolor = |v: &mut GpuVertex, c: [f32; 4]| {
+            v.color = [c[0], c[1], c[2], v.color[3] * c[3]];
+        };
+        let override_color = self.color.as_ref().map(format_color);
+        let mut vertices: Vec<GpuVertex> = data
+            .geometry
+            .vertices
+            .iter()
+            .map(|v| {
+                let mut v = *v;
+                // Embedded images keep their own colors.
+                if let (Some(c), 0.0) = (override_color, v.tex) {
+                    recolor(&mut v, c);
+                }
+                v
+            })
+            .collect();
+        for (id, range) in &data.paths {
+            if let Some(color) = self.path_colors.get(id) {
+                let c = format_color(color);
+                for (v, original) in vertices[range.clone()]
+                    .iter_mut()
+                    .zip(&data.geometry.vertices[range.clone()])
+                {
+                    v.color = original.color;
+                    recolor(v, c);
+                }
+            }
+        }
+        let opacity = self.opacity as f32;
+        for v in &mut vertices {
+            v.color[3] *= opacity;
+        }
+        vertices
+    }
+}
+
 pub(crate) struct SvgData {
     pub(crate) geometry: VertexBuffers<GpuVertex, u32>,
     pub(crate) transforms: Vec<[f32; 6]>,
+    /// The gradient filling each entry of `transforms`, if any.
+    pub(crate) gradients: Vec<Option<Gradient>>,
+    /// The vertices of each path that has an id.
+    pub(crate) paths: Vec<(String, Range<usize>)>,
+    /// The opacity of each translucent group, the indices it covers and
+    /// their bounding box, outer groups before the ones inside them.
+    pub(crate) groups: Vec<(f32, Range<usize>, Rect)>,
+    /// Embedded raster images, in drawing order.
+    pub(crate) images: Vec<SvgImage>,
 }
 
+/// A raster image embedded in an SVG, drawn as a textured quad in its
+/// geometry.
+pub(crate) struct SvgImage {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    /// Straight-alpha RGBA8, taken once the image is uploaded.
+    pub(crate) pixels: Vec<u8>,
+    pub(crate) interp: InterpolationMode,
+    /// The quad's vertices, whose `tex_pos` runs from 0 to 1 across the
+    /// image rather than across its texture.
+    pub(crate) vertices: Range<usize>,
+    pub(crate) indices: Range<usize>,
+    pub(crate) uploaded: Option<WgpuImage>,
+}
+
+/// A translucent group whose content is still being tessellated.
+struct OpenGroup {
+    node: usvg::Node,
+    opacity: f32,
+    /// Where the group's entry in [`SvgData::groups`] is.
+    entry: usize,
+    vertex_start: usize,
+    /// The bounding boxes of the paths and images drawn in it, while they
+    /// don't overlap.
+    shapes: Vec<Rect>,
+    /// Whether anything drawn in it can overlap: shapes whose bounding
+    /// boxes do, strokes, which overlap their fills and themselves, or
+    /// translucent groups drawn to layers.
+    overlaps: bool,
+}
+
+impl OpenGroup {
+    /// Shapes past which overlap isn't checked for, to keep it cheap.
+    const MAX_SHAPES: usize = 64;
+
+    fn add_shape(&mut self, bounds: Rect, stroked: bool) {
+        self.overlaps |= stroked
+            || self.shapes.len() >= Self::MAX_SHAPES
+            || self
+                .shapes
+                .iter()
+                .any(|shape| shape.intersect(bounds).area() > 0.0);
+        self.shapes.push(bounds);
+    }
+
+    fn bounds(&self) -> Rect {
+        self.shapes
+            .iter()
+            .copied()
+            .reduce(|a, b| a.union(b))
+            .unwrap_or(Rect::ZERO)
+    }
+}
+
+/// Tessellations kept before the least recently used one is dropped.
+const MAX_SVG_DATA: usize = 64;
+
+/// Tolerance, in device pixels, that curves are flattened to.
+const TOLERANCE: f64 = 0.1;
+
 pub(crate) struct SvgStore {
-    svgs: HashMap<Vec<u8>, SvgData>,
+    /// Tessellations keyed by SVG and the power of two its scale rounds to,
+    /// in least recently used order.
+    svgs: LinkedHashMap<(Vec<u8>, i32), SvgData>,
     fill_tess: FillTessellator,
     stroke_tess: StrokeTessellator,
 }
@@ -59,59 +254,105 @@ pub(crate) struct SvgStore {
 impl SvgStore {
     pub(crate) fn new() -> Self {
         Self {
-            svgs: HashMap::new(),
+            svgs: LinkedHashMap::new(),
             fill_tess: FillTessellator::new(),
             stroke_tess: StrokeTessellator::new(),
         }
     }
 
-    pub(crate) fn get_svg_data(&mut self, svg: &Svg) -> &SvgData {
-        if !self.svgs.contains_key(&svg.hash) {
-            let data = self.new_svg_data(svg);
-            self.svgs.insert(svg.hash.clone(), data);
+    /// Get `svg` tessellated finely enough to be drawn at `scale` device
+    /// pixels per view box unit.
+    pub(crate) fn get_svg_data(&mut self, svg: &Svg, scale: f64) -> &mut SvgData {
+        let level = if scale.is_finite() && scale > 0.0 {
+            scale.log2().round().clamp(-16.0, 16.0) as i32
+        } else {
+            0
+        };
+        let key = (svg.hash.clone(), level);
+        if self.svgs.get_refresh(&key).is_none() {
+            let tolerance = TOLERANCE / 2f64.powi(level);
+            let data = self.new_svg_data(svg, tolerance as f32);
+            self.svgs.insert(key.clone(), data);
+            while self.svgs.len() > MAX_SVG_DATA {
+                self.svgs.pop_front();
+            }
         }
-        self.svgs.get(&svg.hash).unwrap()
+        self.svgs.get_mut(&key).unwrap()
     }
 
-    fn new_svg_data(&mut self, svg: &Svg) -> SvgData {
-        let mut prev_transform = usvg::Transform {
-            a: 1.0,
-            b: 0.0,
-            c: 0.0,
-            d: 1.0,
-            e: 0.0,
-            f: 0.0,
-        };
+    fn new_svg_data(&mut self, svg: &Svg, tolerance: f32) -> SvgData {
         let mut geometry: VertexBuffers<GpuVertex, u32> = VertexBuffers::new();
         let mut transforms = Vec::new();
         transforms.push([1.0, 0.0, 0.0, 1.0, 0.0, 0.0]);
+        let mut gradients = vec![None];
+        let mut paths = Vec::new();
+        let mut groups = Vec::new();
+        let mut images = Vec::new();
+        let mut open_groups: Vec<OpenGroup> = Vec::new();
         for node in svg.tree.root().descendants() {
-            if let usvg::NodeKind::Path(ref p) = *node.borrow() {
-                let t = node.transform();
-                if t != prev_transform {
-                    transforms.push([
-                        t.a as f32, t.b as f32, t.c as f32, t.d as f32, t.e as f32, t.f as f32,
-                    ]);
-                    prev_transform = t;
+            // Clip paths and other definitions are only drawn through what
+            // refers to them.
+            if svg.tree.is_in_defs(&node) {
+                continue;
+            }
+            while let Some(group) = open_groups.last() {
+                if node.ancestors().any(|n| n == group.node) {
+                    break;
                 }
+                let group = open_groups.pop().unwrap();
+                close_group(group, &mut geometry, &mut groups, &mut open_groups);
+            }
+            if let usvg::NodeKind::Group(ref g) = *node.borrow() {
+                if g.opacity.value() < 1.0 {
+                    open_groups.push(OpenGroup {
+                        node: node.clone(),
+                        opacity: g.opacity.value() as f32,
+                        entry: groups.len(),
+                        vertex_start: geometry.vertices.len(),
+                        shapes: Vec::new(),
+                        overlaps: false,
+                    });
+                    groups.push(Some((
+                        g.opacity.value() as f32,
+                        geometry.indices.len()..0,
+                        Rect::ZERO,
+                    )));
+                }
+            }
+            if let usvg::NodeKind::Path(ref p) = *node.borrow() {
+                // Groups that clip or fade their content keep their own
+                // transform, so the path's is relative to theirs.
+                let t = affine(node.abs_transform()) * affine(node.transform());
+                let t_coeffs = t.as_coeffs().map(|c| c as f32);
+                let primitive_id = solid_entry(&mut transforms, &mut gradients, t_coeffs);
+                let vertex_start = geometry.vertices.len();
+                let index_start = geometry.indices.len();
                 if let Some(ref fill) = p.fill {
-                    let color = match fill.paint {
-                        usvg::Paint::Color(c) => c,
-                        _ => FALLBACK_COLOR,
+                    let gradient = match fill.paint {
+                        usvg::Paint::Link(ref id) => svg_gradient(&svg.tree, id, p),
+                        usvg::Paint::Color(_) => None,
+                    };
+                    let (color, primitive_id) = match (gradient, &fill.paint) {
+                        (Some(gradient), _) => {
+                            transforms.push(t_coeffs);
+                            gradients.push(Some(gradient));
+                            ([1.0, 1.0, 1.0], transforms.len() as u32 - 1)
+                        }
+                        (None, usvg::Paint::Color(c)) => (linear_rgb(*c), primitive_id),
+                        (None, _) => (linear_rgb(FALLBACK_COLOR), primitive_id),
+                    };
+                    let color = [color[0], color[1], color[2], fill.opacity.value() as f32];
+                    let fill_rule = match fill.rule {
+                        usvg::FillRule::NonZero => tessellation::FillRule::NonZero,
+                        usvg::FillRule::EvenOdd => tessellation::FillRule::EvenOdd,
                     };
-                    let color = [
-                        from_linear(color.red as f32 / 255.0),
-                        from_linear(color.green as f32 / 255.0),
-                        from_linear(color.blue as f32 / 255.0),
-                        fill.opacity.value() as f32,
-                    ];
-                    self.fill_tess.tessellate(
+                    let _ = self.fill_tess.tessellate(
                         convert_path(p),
-                        &FillOptions::tolerance(0.01),
+                        &FillOptions::tolerance(tolerance).with_fill_rule(fill_rule),
                         &mut BuffersBuilder::new(&mut geometry, |vertex: FillVertex| GpuVertex {
                             pos: vertex.position().to_array(),
                             color,
-                            primitive_id: transforms.len() as u32 - 1,
+                            primitive_id,
                             ..Default::default()
                         }),
                     );
@@ -119,28 +360,160 @@ impl SvgStore {
 
                 if let Some(ref stroke) = p.stroke {
                     let (stroke_color, stroke_opacity, stroke_opts) = convert_stroke(stroke);
-                    let color = [
-                        from_linear(stroke_color.red as f32 / 255.0),
-                        from_linear(stroke_color.green as f32 / 255.0),
-                        from_linear(stroke_color.blue as f32 / 255.0),
-                        stroke_opacity.value() as f32,
-                    ];
-                    let _ = self.stroke_tess.tessellate(
-                        convert_path(p),
-                        &stroke_opts.with_tolerance(0.01),
-                        &mut BuffersBuilder::new(&mut geometry, |vertex: StrokeVertex| GpuVertex {
+                    let gradient = match stroke.paint {
+                        usvg::Paint::Link(ref id) => svg_gradient(&svg.tree, id, p),
+                        usvg::Paint::Color(_) => None,
+                    };
+                    let (color, primitive_id) = match gradient {
+                        Some(gradient) => {
+                            transforms.push(t_coeffs);
+                            gradients.push(Some(gradient));
+                            ([1.0, 1.0, 1.0], transforms.len() as u32 - 1)
+                        }
+                        None => (linear_rgb(stroke_color), primitive_id),
+                    };
+                    let color = [color[0], color[1], color[2], stroke_opacity.value() as f32];
+                    let options = stroke_opts.with_tolerance(tolerance);
+                    let mut builder =
+                        BuffersBuilder::new(&mut geometry, |vertex: StrokeVertex| GpuVertex {
                             pos: vertex.position().to_array(),
                             color,
-                            primitive_id: transforms.len() as u32 - 1,
+                            primitive_id,
                             ..Default::default()
-                        }),
-                    );
+                        });
+                    let _ = match stroke.dasharray {
+                        Some(ref dashes) => self.stroke_tess.tessellate_path(
+                            &dash_path(
+                                convert_path(p),
+                                dashes,
+                                stroke.dashoffset as f64,
+                                tolerance,
+                            ),
+                            &options,
+                            &mut builder,
+                        ),
+                        None => {
+                            self.stroke_tess
+                                .tessellate(convert_path(p), &options, &mut builder)
+                        }
+                    };
+                }
+                clip_to_groups(&svg.tree, &node, t, tolerance, &mut geometry, index_start);
+                if !p.id.is_empty() {
+                    paths.push((p.id.clone(), vertex_start..geometry.vertices.len()));
+                }
+                if let Some(bbox) = p.data.bbox() {
+                    let bbox = Rect::new(bbox.x(), bbox.y(), bbox.right(), bbox.bottom());
+                    // Miter joins reach out the furthest from the path.
+                    let reach = p.stroke.as_ref().map_or(0.0, |stroke| {
+                        stroke.width.value() * stroke.miterlimit.value().max(1.0) / 2.0
+                    });
+                    let bounds = t.transform_rect_bbox(bbox.inflate(reach, reach));
+                    for group in &mut open_groups {
+                        group.add_shape(bounds, p.stroke.is_some());
+                    }
+                }
+            }
+            if let usvg::NodeKind::Image(ref image) = *node.borrow() {
+                // Only PNGs can be decoded; JPEGs and nested SVGs are
+                // skipped.
+                let (width, height, pixels) = match image.kind {
+                    usvg::ImageKind::PNG(ref data) => match crate::png::decode(data) {
+                        Some(decoded) => decoded,
+                        None => continue,
+                    },
+                    _ => continue,
+                };
+                let t = affine(node.abs_transform()) * affine(node.transform());
+                let primitive_id = solid_entry(
+                    &mut transforms,
+                    &mut gradients,
+                    t.as_coeffs().map(|c| c as f32),
+                );
+                let rect = image_rect(&image.view_box, width, height);
+                let vertex_start = geometry.vertices.len();
+                let index_start = geometry.indices.len();
+                let corners = [
+                    (rect.x0, rect.y0, 0.0, 0.0),
+                    (rect.x0, rect.y1, 0.0, 1.0),
+                    (rect.x1, rect.y1, 1.0, 1.0),
+                    (rect.x1, rect.y0, 1.0, 0.0),
+                ];
+                for (x, y, u, v) in corners {
+                    geometry.vertices.push(GpuVertex {
+                        pos: [x as f32, y as f32],
+                        color: [1.0, 1.0, 1.0, 1.0],
+                        tex: 2.0,
+                        tex_pos: [u, v],
+                        primitive_id,
+                        ..Default::default()
+                    });
+                }
+                geometry
+                    .indices
+                    .extend([0, 1, 2, 0, 2, 3].iter().map(|i| i + vertex_start as u32));
+                // A sliced image overflows its viewport, which clips it.
+                if image.view_box.aspect.slice {
+                    let r = image.view_box.rect;
+                    let viewport = Rect::new(r.x(), r.y(), r.right(), r.bottom());
+                    clip_geometry(&mut geometry, index_start, &rect_polygon(viewport));
+                }
+                clip_to_groups(&svg.tree, &node, t, tolerance, &mut geometry, index_start);
+                images.push(SvgImage {
+                    width,
+                    height,
+                    pixels,
+                    interp: match image.rendering_mode {
+                        usvg::ImageRendering::OptimizeQuality => InterpolationMode::Bilinear,
+                        usvg::ImageRendering::OptimizeSpeed => InterpolationMode::NearestNeighbor,
+                    },
+                    vertices: vertex_start..geometry.vertices.len(),
+                    indices: index_start..geometry.indices.len(),
+                    uploaded: None,
+                });
+                for group in &mut open_groups {
+                    group.add_shape(t.transform_rect_bbox(rect), false);
                 }
             }
         }
+        while let Some(group) = open_groups.pop() {
+            close_group(group, &mut geometry, &mut groups, &mut open_groups);
+        }
         SvgData {
             geometry,
             transforms,
+            gradients,
+            paths,
+            groups: groups.into_iter().flatten().collect(),
+            images,
+        }
+    }
+}
+
+/// End `group`'s entry in `groups` at the last index tessellated, with its
+/// bounds.
+///
+/// A fill's triangles never overlap, nor do an image's, so a group of fills
+/// and images that don't overlap each other takes its opacity in its
+/// vertices instead and its entry is dropped. Otherwise it's drawn to a
+/// layer, which the groups around it can't fold into their vertices.
+fn close_group(
+    group: OpenGroup,
+    geometry: &mut VertexBuffers<GpuVertex, u32>,
+    groups: &mut [Option<(f32, Range<usize>, Rect)>],
+    outer: &mut [OpenGroup],
+) {
+    let entry = &mut groups[group.entry];
+    if !group.overlaps {
+        for v in &mut geometry.vertices[group.vertex_start..] {
+            v.color[3] *= group.opacity;
+        }
+        *entry = None;
+    } else if let Some((_, indices, bounds)) = entry {
+        indices.end = geometry.indices.len();
+        *bounds = group.bounds();
+        for group in outer {
+            group.overlaps = true;
         }
     }
 }
@@ -183,6 +556,7 @@ impl<'l> Iterator for PathConvIter<'l> {
                     })
                 } else {
                     self.first = point(*x as f32, *y as f32);
+                    self.prev = self.first;
                     self.needs_end = true;
                     Some(PathEvent::Begin { at: self.first })
                 }
@@ -241,7 +615,7 @@ impl<'l> Iterator for PathConvIter<'l> {
     }
 }
 
-pub fn convert_path(p: &usvg::Path) -> PathConvIter {
+pub fn convert_path(p: &usvg::Path) -> PathConvIter<'_> {
     PathConvIter {
         iter: p.data.iter(),
         first: Point::new(0.0, 0.0),
@@ -251,6 +625,474 @@ pub fn convert_path(p: &usvg::Path) -> PathConvIter {
     }
 }
 
+/// Split `path` into the dashes described by an SVG `stroke-dasharray` and
+/// `stroke-dashoffset`.
+pub(crate) fn dash_path(
+    path: impl Iterator<Item = PathEvent>,
+    dashes: &[f64],
+    offset: f64,
+    tolerance: f32,
+) -> lyon::path::Path {
+    // An odd number of dashes is repeated to make an even one.
+    let dashes: Vec<f32> = dashes
+        .iter()
+        .chain(dashes.iter().take(dashes.len() % 2 * dashes.len()))
+        .map(|d| *d as f32)
+        .collect();
+    let total: f32 = dashes.iter().sum();
+    let mut builder = lyon::path::Path::builder();
+    if dashes.is_empty() || total <= 0.0 || dashes.iter().any(|d| *d < 0.0) {
+        for event in path {
+            builder.path_event(event);
+        }
+        return builder.build();
+    }
+
+    // Where the pattern starts on every subpath.
+    let mut start_index = 0;
+    let mut start_offset = (offset as f32).rem_euclid(total);
+    while start_offset >= dashes[start_index] {
+        start_offset -= dashes[start_index];
+        start_index = (start_index + 1) % dashes.len();
+    }
+
+    let mut dasher = Dasher {
+        builder,
+        dashes: &dashes,
+        index: start_index,
+        remaining: 0.0,
+        on: false,
+    };
+    for event in path.flattened(tolerance) {
+        match event {
+            PathEvent::Begin { at } => {
+                dasher.index = start_index;
+                dasher.remaining = dashes[start_index] - start_offset;
+                dasher.on = start_index % 2 == 0;
+                if dasher.on {
+                    dasher.builder.begin(at);
+                }
+            }
+            PathEvent::Line { from, to } => dasher.line(from, to),
+            PathEvent::End { last, first, close } => {
+                if close {
+                    dasher.line(last, first);
+                }
+                if dasher.on {
+                    dasher.builder.end(false);
+                }
+            }
+            _ => {}
+        }
+    }
+    dasher.builder.build()
+}
+
+struct Dasher<'a> {
+    builder: lyon::path::path::Builder,
+    dashes: &'a [f32],
+    index: usize,
+    /// Length left in the current dash or gap.
+    remaining: f32,
+    /// Whether the current entry is a dash rather than a gap.
+    on: bool,
+}
+
+impl Dasher<'_> {
+    fn line(&mut self, mut from: Point, to: Point) {
+        let mut len = (to - from).length();
+        while len > self.remaining {
+            let p = from + (to - from) * (self.remaining / len);
+            if self.on {
+                self.builder.line_to(p);
+                self.builder.end(false);
+            } else {
+                self.builder.begin(p);
+            }
+            self.on = !self.on;
+            len -= self.remaining;
+            from = p;
+            self.index = (self.index + 1) % self.dashes.len();
+            self.remaining = self.dashes[self.index];
+        }
+        self.remaining -= len;
+        if self.on {
+            self.builder.line_to(to);
+        }
+    }
+}
+
+/// The entry of `transforms` for solid paths and images drawn with `t`.
+///
+/// They share one until the transform changes; gradients always get their
+/// own.
+fn solid_entry(
+    transforms: &mut Vec<[f32; 6]>,
+    gradients: &mut Vec<Option<Gradient>>,
+    t: [f32; 6],
+) -> u32 {
+    if transforms.last() != Some(&t) || gradients.last().unwrap().is_some() {
+        transforms.push(t);
+        gradients.push(None);
+    }
+    transforms.len() as u32 - 1
+}
+
+/// Where an image `width` by `height` pixels is drawn in its view box,
+/// following its `preserveAspectRatio`.
+fn image_rect(view_box: &usvg::ViewBox, width: u32, height: u32) -> Rect {
+    let r = view_box.rect;
+    let rect = Rect::new(r.x(), r.y(), r.right(), r.bottom());
+    let (sx, sy) = (rect.width() / width as f64, rect.height() / height as f64);
+    let scale = match (view_box.aspect.align, view_box.aspect.slice) {
+        (usvg::Align::None, _) => return rect,
+        (_, false) => sx.min(sy),
+        (_, true) => sx.max(sy),
+    };
+    let (ax, ay) = match view_box.aspect.align {
+        usvg::Align::XMinYMin => (0.0, 0.0),
+        usvg::Align::XMidYMin => (0.5, 0.0),
+        usvg::Align::XMaxYMin => (1.0, 0.0),
+        usvg::Align::XMinYMid => (0.0, 0.5),
+        usvg::Align::XMaxYMid => (1.0, 0.5),
+        usvg::Align::XMinYMax => (0.0, 1.0),
+        usvg::Align::XMidYMax => (0.5, 1.0),
+        usvg::Align::XMaxYMax => (1.0, 1.0),
+        _ => (0.5, 0.5),
+    };
+    let size = Size::new(width as f64 * scale, height as f64 * scale);
+    let origin = KurboPoint::new(
+        rect.x0 + (rect.width() - size.width) * ax,
+        rect.y0 + (rect.height() - size.height) * ay,
+    );
+    Rect::from_origin_size(origin, size)
+}
+
+/// Clip the triangles from `first_index` on, drawn for `node` with the
+/// transform `t`, to the clip paths on the groups around it.
+fn clip_to_groups(
+    tree: &usvg::Tree,
+    node: &usvg::Node,
+    t: Affine,
+    tolerance: f32,
+    geometry: &mut VertexBuffers<GpuVertex, u32>,
+    first_index: usize,
+) {
+    if let Some(clip) = path_clip(tree, node, tolerance) {
+        let local = if t.determinant() == 0.0 {
+            Vec::new()
+        } else {
+            convex_polygon(clip.iter().map(|p| t.inverse() * *p).collect())
+        };
+        clip_geometry(geometry, first_index, &local);
+    }
+}
+
+fn affine(t: usvg::Transform) -> Affine {
+    Affine::new([t.a, t.b, t.c, t.d, t.e, t.f])
+}
+
+/// The transform from `node`'s coordinates into those of its ancestor
+/// `root`'s children.
+fn transform_within(node: &usvg::Node, root: &usvg::Node) -> Affine {
+    node.ancestors()
+        .take_while(|n| n != root)
+        .fold(Affine::default(), |t, n| affine(n.transform()) * t)
+}
+
+/// The region every clip path on the groups around `node` leaves visible,
+/// as a convex polygon in the SVG's coordinates, or `None` if it isn't
+/// clipped.
+fn path_clip(tree: &usvg::Tree, node: &usvg::Node, tolerance: f32) -> Option<Vec<KurboPoint>> {
+    let mut region: Option<Vec<KurboPoint>> = None;
+    for ancestor in node.ancestors().skip(1) {
+        let clip = match *ancestor.borrow() {
+            usvg::NodeKind::Group(ref g) => g.clip_path.clone(),
+            _ => None,
+        };
+        let clip = match clip.and_then(|id| clip_region(tree, &id, &ancestor, tolerance)) {
+            Some(clip) => clip,
+            None => continue,
+        };
+        region = Some(match region {
+            Some(region) => clip_polygon(&region, &clip),
+            None => clip,
+        });
+    }
+    region
+}
+
+/// The region the clip path `id` leaves visible on `group`.
+///
+/// Clip paths made of one convex outline, like rects and circles, are
+/// exact; anything else clips to its bounding box.
+fn clip_region(
+    tree: &usvg::Tree,
+    id: &str,
+    group: &usvg::Node,
+    tolerance: f32,
+) -> Option<Vec<KurboPoint>> {
+    let node = tree.defs_by_id(id)?;
+    let clip = match *node.borrow() {
+        usvg::NodeKind::ClipPath(ref clip) => clip.clone(),
+        _ => return None,
+    };
+    let units = match clip.units {
+        usvg::Units::UserSpaceOnUse => Affine::default(),
+        usvg::Units::ObjectBoundingBox => {
+            let bbox = group_bbox(group)?;
+            Affine::new([bbox.width(), 0.0, 0.0, bbox.height(), bbox.x0, bbox.y0])
+        }
+    };
+    let transform =
+        affine(group.abs_transform()) * affine(group.transform()) * affine(clip.transform) * units;
+
+    let mut outlines: Vec<Vec<KurboPoint>> = Vec::new();
+    for child in node.descendants() {
+        if let usvg::NodeKind::Path(ref p) = *child.borrow() {
+            let t = transform * transform_within(&child, &node);
+            // Flattened before it's transformed, so finer by its scale.
+            let scale = t.determinant().abs().sqrt().max(1e-6) as f32;
+            for event in convert_path(p).flattened(tolerance / scale) {
+                match event {
+                    PathEvent::Begin { at } => outlines.push(vec![t * to_kurbo(at)]),
+                    PathEvent::Line { to, .. } => {
+                        outlines.last_mut().unwrap().push(t * to_kurbo(to))
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    let mut region = match outlines.len() {
+        1 if is_convex(&outlines[0]) => convex_polygon(outlines.pop().unwrap()),
+        _ => {
+            let mut points = outlines.into_iter().flatten();
+            match points.next() {
+                Some(first) => {
+                    let bbox =
+                        points.fold(Rect::from_points(first, first), |bbox, p| bbox.union_pt(p));
+                    rect_polygon(bbox)
+                }
+                None => Vec::new(),
+            }
+        }
+    };
+    if let Some(ref id) = clip.clip_path {
+        if let Some(clip) = clip_region(tree, id, group, tolerance) {
+            region = clip_polygon(&region, &clip);
+        }
+    }
+    Some(region)
+}
+
+/// The bounding box of the paths in `group`, in the coordinates of its
+/// children.
+fn group_bbox(group: &usvg::Node) -> Option<Rect> {
+    group
+        .descendants()
+        .filter_map(|node| match *node.borrow() {
+            usvg::NodeKind::Path(ref p) => {
+                let bbox = p.data.bbox()?;
+                let bbox = Rect::new(bbox.x(), bbox.y(), bbox.right(), bbox.bottom());
+                Some(transform_within(&node, group).transform_rect_bbox(bbox))
+            }
+            _ => None,
+        })
+        .reduce(|a, b| a.union(b))
+}
+
+fn to_kurbo(p: Point) -> KurboPoint {
+    KurboPoint::new(p.x as f64, p.y as f64)
+}
+
+/// Twice the signed area of `polygon`, positive when it winds clockwise
+/// in y-down coordinates.
+fn signed_area(polygon: &[KurboPoint]) -> f64 {
+    polygon
+        .iter()
+        .zip(polygon.iter().cycle().skip(1))
+        .map(|(a, b)| a.to_vec2().cross(b.to_vec2()))
+        .sum()
+}
+
+fn is_convex(polygon: &[KurboPoint]) -> bool {
+    let n = polygon.len();
+    let turns = (0..n).map(|i| {
+        let (a, b, c) = (polygon[i], polygon[(i + 1) % n], polygon[(i + 2) % n]);
+        (b - a).cross(c - b)
+    });
+    let (mut left, mut right) = (false, false);
+    for turn in turns {
+        left |= turn > 1e-9;
+        right |= turn < -1e-9;
+    }
+    n >= 3 && !(left && right)
+}
+
+fn rect_polygon(rect: Rect) -> Vec<KurboPoint> {
+    convex_polygon(vec![
+        KurboPoint::new(rect.x0, rect.y0),
+        KurboPoint::new(rect.x1, rect.y0),
+        KurboPoint::new(rect.x1, rect.y1),
+        KurboPoint::new(rect.x0, rect.y1),
+    ])
+}
+
+/// `polygon` wound the way [`clip_polygon`] expects its clip, or empty if
+/// it has no area.
+fn convex_polygon(mut polygon: Vec<KurboPoint>) -> Vec<KurboPoint> {
+    let area = signed_area(&polygon);
+    if area < 0.0 {
+        polygon.reverse();
+    }
+    if area == 0.0 {
+        polygon.clear();
+    }
+    polygon
+}
+
+/// The part of `subject` inside the convex polygon `clip`, by clipping it
+/// against each of `clip`'s edges in turn.
+fn clip_polygon(subject: &[KurboPoint], clip: &[KurboPoint]) -> Vec<KurboPoint> {
+    let mut output = subject.to_vec();
+    for (&a, &b) in clip.iter().zip(clip.iter().cycle().skip(1)) {
+        if output.is_empty() {
+            break;
+        }
+        let side = |p: KurboPoint| (b - a).cross(p - a);
+        let input = std::mem::take(&mut output);
+        for (&p, &q) in input.iter().zip(input.iter().cycle().skip(1)) {
+            let (sp, sq) = (side(p), side(q));
+            if sp >= 0.0 {
+                output.push(p);
+            }
+            if (sp >= 0.0) != (sq >= 0.0) {
+                output.push(p.lerp(q, sp / (sp - sq)));
+            }
+        }
+    }
+    if clip.is_empty() {
+        output.clear();
+    }
+    output
+}
+
+/// Clip the triangles from `first_index` on to the convex polygon `clip`,
+/// splitting those that cross its edges.
+fn clip_geometry(
+    geometry: &mut VertexBuffers<GpuVertex, u32>,
+    first_index: usize,
+    clip: &[KurboPoint],
+) {
+    let indices = geometry.indices.split_off(first_index);
+    for triangle in indices.chunks_exact(3) {
+        let points: Vec<KurboPoint> = triangle
+            .iter()
+            .map(|&i| {
+                let [x, y] = geometry.vertices[i as usize].pos;
+                KurboPoint::new(x as f64, y as f64)
+            })
+            .collect();
+        let polygon = clip_polygon(&points, clip);
+        if polygon == points {
+            geometry.indices.extend_from_slice(triangle);
+            continue;
+        }
+        if polygon.len() < 3 {
+            continue;
+        }
+        // Only the position and texture position vary across a path's fill
+        // or stroke, or an image's quad.
+        let vertices =
+            [triangle[0], triangle[1], triangle[2]].map(|i| geometry.vertices[i as usize]);
+        let (a, b, c) = (points[0], points[1], points[2]);
+        let area = (b - a).cross(c - a);
+        let first = geometry.vertices.len() as u32;
+        geometry.vertices.extend(polygon.iter().map(|&p| {
+            let (wb, wc) = if area == 0.0 {
+                (0.0, 0.0)
+            } else {
+                ((p - a).cross(c - a) / area, (b - a).cross(p - a) / area)
+            };
+            let weights = [1.0 - wb - wc, wb, wc];
+            let mut tex_pos = [0.0; 2];
+            for (v, w) in vertices.iter().zip(weights) {
+                tex_pos[0] += v.tex_pos[0] * w as f32;
+                tex_pos[1] += v.tex_pos[1] * w as f32;
+            }
+            GpuVertex {
+                pos: [p.x as f32, p.y as f32],
+                tex_pos,
+                ..vertices[0]
+            }
+        }));
+        for i in 1..polygon.len() as u32 - 1 {
+            geometry
+                .indices
+                .extend_from_slice(&[first, first + i, first + i + 1]);
+        }
+    }
+}
+
+fn linear_rgb(color: usvg::Color) -> [f32; 3] {
+    [
+        srgb_to_linear(color.red as f32 / 255.0),
+        srgb_to_linear(color.green as f32 / 255.0),
+        srgb_to_linear(color.blue as f32 / 255.0),
+    ]
+}
+
+/// Resolve the gradient paint server `id` into the coordinates of `path`.
+fn svg_gradient(tree: &usvg::Tree, id: &str, path: &usvg::Path) -> Option<Gradient> {
+    let node = tree.defs_by_id(id)?;
+    let kind = node.borrow();
+    let base = match *kind {
+        usvg::NodeKind::LinearGradient(ref g) => &g.base,
+        usvg::NodeKind::RadialGradient(ref g) => &g.base,
+        _ => return None,
+    };
+    let stops: Vec<(f32, [f32; 4])> = base
+        .stops
+        .iter()
+        .map(|stop| {
+            let [r, g, b] = linear_rgb(stop.color);
+            (
+                stop.offset.value() as f32,
+                [r, g, b, stop.opacity.value() as f32],
+            )
+        })
+        .collect();
+    let units = match base.units {
+        usvg::Units::UserSpaceOnUse => Affine::default(),
+        usvg::Units::ObjectBoundingBox => {
+            let bbox = path.data.bbox()?;
+            Affine::new([bbox.width(), 0.0, 0.0, bbox.height(), bbox.x(), bbox.y()])
+        }
+    };
+    let t = base.transform;
+    let transform = units * Affine::new([t.a, t.b, t.c, t.d, t.e, t.f]);
+    let extend = match base.spread_method {
+        usvg::SpreadMethod::Pad => ExtendMode::Clamp,
+        usvg::SpreadMethod::Repeat => ExtendMode::Repeat,
+        usvg::SpreadMethod::Reflect => ExtendMode::Reflect,
+    };
+    let gradient = match *kind {
+        usvg::NodeKind::LinearGradient(ref g) => Gradient::linear(
+            transform * KurboPoint::new(g.x1, g.y1),
+            transform * KurboPoint::new(g.x2, g.y2),
+            &stops,
+        ),
+        usvg::NodeKind::RadialGradient(ref g) => Gradient::radial(
+            transform * KurboPoint::new(g.cx, g.cy),
+            g.r.value() * transform.determinant().abs().sqrt(),
+            &stops,
+        ),
+        _ => return None,
+    };
+    Some(gradient.with_extend(extend))
+}
+
 pub fn convert_stroke(s: &usvg::Stroke) -> (usvg::Color, usvg::Opacity, StrokeOptions) {
     let color = match s.paint {
         usvg::Paint::Color(c) => c,
@@ -269,8 +1111,73 @@ pub fn convert_stroke(s: &usvg::Stroke) -> (usvg::Color, usvg::Opacity, StrokeOp
 
     let opt = StrokeOptions::tolerance(0.01)
         .with_line_width(s.width.value() as f32)
+        .with_miter_limit((s.miterlimit.value() as f32).max(StrokeOptions::MINIMUM_MITER_LIMIT))
         .with_line_cap(linecap)
         .with_line_join(linejoin);
 
     (color, s.opacity, opt)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn svg_data(source: &str) -> SvgData {
+        let svg: Svg = source.parse().unwrap();
+        SvgStore::new().new_svg_data(&svg, 0.1)
+    }
+
+    #[test]
+    fn translucent_group_of_disjoint_fills_is_folded() {
+        let data = svg_data(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="40" height="10">
+                <g opacity="0.5">
+                    <rect width="10" height="10" fill="red"/>
+                    <rect x="20" width="10" height="10" fill="blue"/>
+                </g>
+            </svg>"#,
+        );
+        assert!(data.groups.is_empty());
+        assert!(data.geometry.vertices.iter().all(|v| v.color[3] == 0.5));
+    }
+
+    #[test]
+    fn translucent_group_of_overlapping_fills_is_bounded() {
+        let data = svg_data(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="40" height="40">
+                <g opacity="0.5">
+                    <rect x="5" y="5" width="10" height="10" fill="red"/>
+                    <rect x="10" y="10" width="10" height="10" fill="blue"/>
+                </g>
+            </svg>"#,
+        );
+        assert_eq!(data.groups.len(), 1);
+        let (opacity, _, bounds) = data.groups[0];
+        assert_eq!(opacity, 0.5);
+        assert_eq!(bounds, Rect::new(5.0, 5.0, 20.0, 20.0));
+        assert!(data.geometry.vertices.iter().all(|v| v.color[3] == 1.0));
+    }
+
+    #[test]
+    fn path_color_recolors_only_its_path() {
+        let data = svg_data(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="40" height="10">
+                <rect id="base" width="10" height="10" fill="red"/>
+                <rect id="accent" x="20" width="10" height="10" fill="red"/>
+            </svg>"#,
+        );
+        let style = SvgStyle::new()
+            .path_color("accent", Color::rgb8(0, 0, 255))
+            .opacity(0.5);
+        let vertices = style.vertices(&data);
+        let range = |id: &str| data.paths.iter().find(|(p, _)| p == id).unwrap().1.clone();
+        let red = data.geometry.vertices[range("base").start].color;
+        let blue = format_color(&Color::rgb8(0, 0, 255));
+        assert!(vertices[range("base")]
+            .iter()
+            .all(|v| v.color == [red[0], red[1], red[2], 0.5]));
+        assert!(vertices[range("accent")]
+            .iter()
+            .all(|v| v.color == [blue[0], blue[1], blue[2], 0.5]));
+    }
+}
@@ -1,49 +1,80 @@
-use std::{cell::RefCell, collections::HashMap, ops::Range, rc::Rc};
-
-use font_kit::source::SystemSource;
-use lyon::lyon_tessellation::{
-    BuffersBuilder, FillOptions, FillTessellator, FillVertex, StrokeOptions, StrokeTessellator,
-    StrokeVertex, VertexBuffers,
+use std::{
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
 };
-use piet::kurbo::Line;
+
+use lyon::lyon_tessellation::VertexBuffers;
+use piet::kurbo::{Affine, BezPath, Line, Rect};
 use piet::Color;
 use piet::{
     kurbo::{Point, Size},
-    FontFamily, FontStyle, FontWeight, HitTestPoint, HitTestPosition, LineMetric, Text,
-    TextAttribute, TextLayout, TextLayoutBuilder, TextStorage,
+    FontFamily, FontWeight, HitTestPoint, HitTestPosition, LineMetric, Text, TextAttribute,
+    TextLayout, TextLayoutBuilder, TextStorage,
 };
+use unicode_bidi::BidiInfo;
 use unicode_width::UnicodeWidthChar;
 
-use crate::context::{format_color, from_linear, WgpuRenderContext};
-use crate::pipeline::{Cache, GlyphMetricInfo, GlyphPosInfo, GpuVertex};
+use crate::context::{format_color, WgpuRenderContext};
+use crate::pipeline::{Cache, GlyphPosInfo, GlyphUpload, GpuVertex, ShapedGlyph};
 
+/// The text factory, sharing the renderer's glyph cache.
+///
+/// This and the layouts it builds are `Send`, so layouts can be built and
+/// measured off the renderer's thread. The glyph cache is shared with the
+/// renderer behind a lock, and layouts built for drawing rasterize their
+/// glyphs into it, to be uploaded with the renderer's next frame.
 #[derive(Clone)]
 pub struct WgpuText {
-    source: Rc<RefCell<SystemSource>>,
-    glyphs: Rc<RefCell<HashMap<FontFamily, HashMap<char, Rc<(Vec<[f32; 2]>, Vec<u32>)>>>>>,
-    pub(crate) cache: Rc<RefCell<Cache>>,
-    device: Rc<wgpu::Device>,
-    staging_belt: Rc<RefCell<wgpu::util::StagingBelt>>,
-    encoder: Rc<RefCell<Option<wgpu::CommandEncoder>>>,
-    fill_tess: Rc<RefCell<FillTessellator>>,
-    stroke_tess: Rc<RefCell<StrokeTessellator>>,
+    pub(crate) cache: Arc<Mutex<Cache>>,
+    /// What new glyphs are uploaded with; `None` for text that's only
+    /// measured.
+    gpu: Option<TextGpu>,
+}
+
+#[derive(Clone)]
+struct TextGpu {
+    device: Arc<wgpu::Device>,
+    staging_belt: Arc<Mutex<wgpu::util::StagingBelt>>,
+    encoder: Arc<Mutex<Option<wgpu::CommandEncoder>>>,
+}
+
+fn _assert_send<T: Send>() {}
+
+fn _assert_text_send() {
+    _assert_send::<WgpuText>();
+    _assert_send::<WgpuTextLayout>();
 }
 
 impl WgpuText {
     pub(crate) fn new(
-        device: Rc<wgpu::Device>,
-        staging_belt: Rc<RefCell<wgpu::util::StagingBelt>>,
-        encoder: Rc<RefCell<Option<wgpu::CommandEncoder>>>,
+        device: Arc<wgpu::Device>,
+        staging_belt: Arc<Mutex<wgpu::util::StagingBelt>>,
+        encoder: Arc<Mutex<Option<wgpu::CommandEncoder>>>,
+        atlas_size: u32,
     ) -> Self {
+        let mut cache = Cache::new(atlas_size, atlas_size);
+        cache.create_textures(&device);
         Self {
-            source: Rc::new(RefCell::new(SystemSource::new())),
-            glyphs: Rc::new(RefCell::new(HashMap::new())),
-            cache: Rc::new(RefCell::new(Cache::new(&device, 2000, 2000))),
-            device,
-            staging_belt,
-            encoder,
-            fill_tess: Rc::new(RefCell::new(FillTessellator::new())),
-            stroke_tess: Rc::new(RefCell::new(StrokeTessellator::new())),
+            cache: Arc::new(Mutex::new(cache)),
+            gpu: Some(TextGpu {
+                device,
+                staging_belt,
+                encoder,
+            }),
+        }
+    }
+
+    /// Text without a device, for tests, which can't count on a GPU to make
+    /// a renderer with. Its layouts place glyphs in the atlas like any
+    /// others, but nothing is uploaded, so drawing them shows nothing.
+    #[cfg(test)]
+    pub(crate) fn measuring() -> Self {
+        Self {
+            cache: Arc::new(Mutex::new(Cache::new(1024, 1024))),
+            gpu: None,
         }
     }
 
@@ -54,28 +85,155 @@ impl WgpuText {
         font_size: f32,
         font_weight: FontWeight,
     ) -> Result<GlyphPosInfo, piet::Error> {
-        let mut encoder = self.encoder.borrow_mut();
+        self.with_cache(|cache, upload| {
+            cache
+                .get_glyph_pos(c, font_family, font_size, font_weight, upload)
+                .cloned()
+        })
+    }
+
+    pub(crate) fn get_shaped_glyph_pos(
+        &self,
+        glyph: &ShapedGlyph,
+        font_size: f32,
+    ) -> Result<GlyphPosInfo, piet::Error> {
+        self.with_cache(|cache, upload| {
+            cache
+                .get_shaped_glyph_pos(glyph, font_size, upload)
+                .cloned()
+        })
+    }
+
+    pub(crate) fn measure_glyph(
+        &self,
+        c: char,
+        font_family: FontFamily,
+        font_size: f32,
+        font_weight: FontWeight,
+    ) -> Result<GlyphPosInfo, piet::Error> {
+        self.cache
+            .lock()
+            .unwrap()
+            .measure_glyph(c, font_family, font_size, font_weight)
+    }
+
+    pub(crate) fn measure_shaped_glyph(&self, glyph: &ShapedGlyph, font_size: f32) -> GlyphPosInfo {
+        self.cache
+            .lock()
+            .unwrap()
+            .measure_shaped_glyph(glyph, font_size)
+    }
+
+    /// Read a font file and register it like `load_font`, returning its
+    /// family. A file that can't be read is a `BackendError` wrapping the IO
+    /// error.
+    pub fn load_font_file(
+        &mut self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<FontFamily, piet::Error> {
+        let data = std::fs::read(path).map_err(|e| piet::Error::BackendError(Box::new(e)))?;
+        self.load_font(&data)
+    }
+
+    /// A family drawing `family`, a variable font, with its axes set to
+    /// `axes`, like `[("wght", 437.0), ("opsz", 14.0)]`. Axes the font
+    /// doesn't have are ignored.
+    ///
+    /// Each distinct set of values gets its own glyphs in the atlas, so
+    /// animating an axis is fine, but round values to steps that are
+    /// visibly different. Tags that aren't four letters are `InvalidInput`.
+    pub fn font_variation(
+        &mut self,
+        family: &FontFamily,
+        axes: &[(&str, f32)],
+    ) -> Result<FontFamily, piet::Error> {
+        self.cache.lock().unwrap().font_variation(family, axes)
+    }
+
+    /// Rasterize the glyphs `text` needs in `font` at `font_size` into the
+    /// glyph atlas now, e.g. behind a splash screen, so the first frame that
+    /// draws them doesn't stall on it.
+    ///
+    /// The glyphs are looked up like a layout of `text` at the default
+    /// weight would, fallback fonts included, and uploaded with the next
+    /// frame. Like any glyph they're rasterized again if the atlas evicts
+    /// them to make room or the scale changes. Fails if laying `text` out
+    /// would.
+    pub fn prewarm(&self, text: &str, font: FontFamily, font_size: f64) -> Result<(), piet::Error> {
+        WgpuTextLayoutBuilder::new(text.to_string(), self.clone())
+            .font(font, font_size)
+            .build()
+            .map(|_| ())
+    }
+
+    /// The outline of `glyph` at `font_size`, with its origin at `origin` on
+    /// the baseline.
+    fn glyph_outline(&self, glyph: &OutlineGlyph) -> Option<BezPath> {
+        let outline = self
+            .cache
+            .lock()
+            .unwrap()
+            .glyph_outline(glyph.font_id, glyph.glyph_id)?;
+        let mut outline = (*outline).clone();
+        outline.apply_affine(
+            Affine::translate(glyph.origin.to_vec2()) * Affine::scale(glyph.font_size),
+        );
+        Some(outline)
+    }
+
+    pub(crate) fn shaping_runs(
+        &self,
+        text: &str,
+        font_family: FontFamily,
+        font_weight: FontWeight,
+    ) -> Vec<(Range<usize>, bool)> {
+        self.cache
+            .lock()
+            .unwrap()
+            .shaping_runs(text, font_family, font_weight)
+    }
+
+    pub(crate) fn shape(
+        &self,
+        text: &str,
+        rtl: bool,
+        font_family: FontFamily,
+        font_weight: FontWeight,
+    ) -> Option<Vec<ShapedGlyph>> {
+        self.cache
+            .lock()
+            .unwrap()
+            .shape(text, rtl, font_family, font_weight)
+    }
+
+    /// Run `f` with the glyph cache and what it needs to upload new glyphs,
+    /// which text without a device doesn't have.
+    fn with_cache<T>(
+        &self,
+        f: impl FnOnce(&mut Cache, Option<GlyphUpload>) -> Result<T, piet::Error>,
+    ) -> Result<T, piet::Error> {
+        let gpu = match self.gpu.as_ref() {
+            Some(gpu) => gpu,
+            None => return f(&mut self.cache.lock().unwrap(), None),
+        };
+        let mut encoder = gpu.encoder.lock().unwrap();
         if encoder.is_none() {
             *encoder = Some(
-                self.device
+                gpu.device
                     .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                         label: Some("render"),
                     }),
             );
         }
 
-        let mut cache = self.cache.borrow_mut();
-        cache
-            .get_glyph_pos(
-                c,
-                font_family,
-                font_size,
-                font_weight,
-                &self.device,
-                &mut self.staging_belt.borrow_mut(),
-                encoder.as_mut().unwrap(),
-            )
-            .map(|p| p.clone())
+        f(
+            &mut self.cache.lock().unwrap(),
+            Some(GlyphUpload {
+                device: &gpu.device,
+                staging_belt: &mut gpu.staging_belt.lock().unwrap(),
+                encoder: encoder.as_mut().unwrap(),
+            }),
+        )
     }
 }
 
@@ -84,10 +242,26 @@ pub struct WgpuTextLayout {
     state: WgpuText,
     text: String,
     width: f64,
-    attrs: Rc<Attributes>,
-    ref_glyph: Rc<RefCell<GlyphPosInfo>>,
-    glyphs: Rc<RefCell<Vec<GlyphPosInfo>>>,
-    geometry: Rc<RefCell<VertexBuffers<GpuVertex, u32>>>,
+    attrs: Arc<Mutex<Attributes>>,
+    ref_glyph: Arc<Mutex<GlyphPosInfo>>,
+    glyphs: Arc<Mutex<Vec<GlyphPosInfo>>>,
+    geometry: Arc<Mutex<VertexBuffers<GpuVertex, u32>>>,
+    /// The text index of the glyph each quad in `geometry` was built from.
+    quad_text_index: Arc<Mutex<Vec<usize>>>,
+    /// Set when the layout was only measured, so `geometry` is empty and
+    /// has to be built before the layout is drawn.
+    measured: Arc<AtomicBool>,
+    /// Set by [`mark_dirty`](Self::mark_dirty) to rebuild before the next
+    /// draw.
+    dirty: Arc<AtomicBool>,
+    /// The glyph cache generation the layout was built against.
+    generation: Arc<AtomicU64>,
+    bounds: Arc<Mutex<Option<[f64; 2]>>>,
+    /// The glyph atlas rows `geometry` samples, kept from eviction while
+    /// the layout is drawn.
+    atlas_rows: Arc<Mutex<Vec<usize>>>,
+    /// The glyphs `geometry` was built from, to outline them.
+    outline_glyphs: Arc<Mutex<Vec<OutlineGlyph>>>,
 }
 
 impl WgpuTextLayout {
@@ -99,13 +273,20 @@ impl WgpuTextLayout {
             state,
             text,
             width: f64::MAX,
-            attrs: Rc::new(Attributes::default()),
-            glyphs: Rc::new(RefCell::new(Vec::new())),
-            ref_glyph: Rc::new(RefCell::new(GlyphPosInfo::default())),
-            geometry: Rc::new(RefCell::new(VertexBuffers::with_capacity(
+            attrs: Arc::new(Mutex::new(Attributes::default())),
+            glyphs: Arc::new(Mutex::new(Vec::new())),
+            ref_glyph: Arc::new(Mutex::new(GlyphPosInfo::default())),
+            geometry: Arc::new(Mutex::new(VertexBuffers::with_capacity(
                 num_vertices,
                 num_indices,
             ))),
+            quad_text_index: Arc::new(Mutex::new(Vec::with_capacity(char_number))),
+            measured: Arc::new(AtomicBool::new(false)),
+            dirty: Arc::new(AtomicBool::new(false)),
+            generation: Arc::new(AtomicU64::new(0)),
+            bounds: Arc::new(Mutex::new(None)),
+            atlas_rows: Arc::new(Mutex::new(Vec::new())),
+            outline_glyphs: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
@@ -114,57 +295,169 @@ impl WgpuTextLayout {
     }
 
     fn set_attrs(&mut self, attrs: Attributes) {
-        self.attrs = Rc::new(attrs);
+        self.attrs = Arc::new(Mutex::new(attrs));
+    }
+
+    /// Set the opacity of the glyphs in `range`, multiplied into their color's
+    /// alpha when the layout is drawn.
+    ///
+    /// This doesn't rebuild the layout, so it's cheap enough to animate.
+    pub fn set_range_opacity(&self, range: impl std::ops::RangeBounds<usize>, opacity: f64) {
+        let range = piet::util::resolve_range(range, self.text.len());
+        self.attrs.lock().unwrap().set_opacity(range, opacity);
+    }
+
+    /// Paint `color` behind the glyphs in `range`, or stop painting behind
+    /// them with `None`. The background covers each glyph's advance and the
+    /// full line height.
+    ///
+    /// Like [`set_range_opacity`](Self::set_range_opacity), this doesn't
+    /// rebuild the layout.
+    pub fn set_range_background(
+        &self,
+        range: impl std::ops::RangeBounds<usize>,
+        color: Option<Color>,
+    ) {
+        let range = piet::util::resolve_range(range, self.text.len());
+        self.attrs.lock().unwrap().set_background(range, color);
     }
 
     pub(crate) fn rebuild(&self, bounds: Option<[f64; 2]>) {
-        let font_family = self.attrs.defaults.font.clone();
-        let font_size = self.attrs.defaults.font_size;
-        let font_weight = self.attrs.defaults.weight;
-        if let Ok(glyph_pos) =
+        self.layout(bounds, false);
+    }
+
+    /// Lay out the glyphs from font metrics alone, without rasterizing them
+    /// into the glyph atlas or building geometry. The geometry is built the
+    /// first time the layout is drawn.
+    pub(crate) fn measure(&self) {
+        self.layout(None, true);
+    }
+
+    /// Make the next draw rebuild the layout.
+    ///
+    /// Built layouts keep their glyph geometry, so drawing one again, at any
+    /// origin, only re-queues it. It's rebuilt by itself when the glyph cache
+    /// changes under it, e.g. on a scale change; this is for anything else
+    /// that should be picked up.
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    fn needs_rebuild(&self) -> bool {
+        self.measured.load(Ordering::Relaxed)
+            || self.dirty.load(Ordering::Relaxed)
+            || self.generation.load(Ordering::Relaxed)
+                != self.state.cache.lock().unwrap().generation()
+    }
+
+    fn layout(&self, bounds: Option<[f64; 2]>, measure_only: bool) {
+        self.measured.store(measure_only, Ordering::Relaxed);
+        self.dirty.store(false, Ordering::Relaxed);
+        *self.bounds.lock().unwrap() = bounds;
+        self.layout_glyphs(bounds, measure_only);
+
+        let mut atlas_rows = self.atlas_rows.lock().unwrap();
+        atlas_rows.sort_unstable();
+        atlas_rows.dedup();
+        // Read after laying out: glyphs it looked up are safe from the
+        // evictions it may have caused.
+        self.generation.store(
+            self.state.cache.lock().unwrap().generation(),
+            Ordering::Relaxed,
+        );
+    }
+
+    fn layout_glyphs(&self, bounds: Option<[f64; 2]>, measure_only: bool) {
+        let attrs = self.attrs.lock().unwrap();
+        let font_family = attrs.defaults.font.clone();
+        let font_size = attrs.defaults.font_size;
+        let font_weight = attrs.defaults.weight;
+        let ref_glyph = if measure_only {
+            self.state
+                .measure_glyph('W', font_family, font_size as f32, font_weight)
+        } else {
             self.state
-                .get_glyph_pos('W', font_family.clone(), font_size as f32, font_weight)
-        {
-            *self.ref_glyph.borrow_mut() = glyph_pos.clone();
+                .get_glyph_pos('W', font_family, font_size as f32, font_weight)
+        };
+        if let Ok(glyph_pos) = ref_glyph {
+            *self.ref_glyph.lock().unwrap() = glyph_pos.clone();
         }
 
-        let is_mono = self.ref_glyph.borrow().metric.mono;
-        let mono_width = self.ref_glyph.borrow().rect.width();
+        let is_mono = self.ref_glyph.lock().unwrap().metric.mono;
+        let mono_width = self.ref_glyph.lock().unwrap().rect.width();
 
         let len = self.text.chars().count();
 
-        let mut glyphs = self.glyphs.borrow_mut();
+        let mut glyphs = self.glyphs.lock().unwrap();
         glyphs.clear();
         glyphs.reserve(len);
-        let mut geometry = self.geometry.borrow_mut();
+        let mut geometry = self.geometry.lock().unwrap();
         geometry.vertices.clear();
         geometry.indices.clear();
         geometry.vertices.reserve(4 * len);
         geometry.indices.reserve(6 * len);
-
-        let tab_width = 8;
+        let mut quad_text_index = self.quad_text_index.lock().unwrap();
+        quad_text_index.clear();
+        let mut atlas_rows = self.atlas_rows.lock().unwrap();
+        atlas_rows.clear();
+        let mut outline_glyphs = self.outline_glyphs.lock().unwrap();
+        outline_glyphs.clear();
+
+        let tab_width = attrs.tab_width();
+        let tab_columns = (tab_width.round() as usize).max(1);
         let mut x = 0.0;
         let mut y = 0.0;
         let mut max_height = 0.0;
-        let mut index = 0;
         let mut mono_char_widths = 0;
-        for c in self.text.chars() {
-            let font_family = self.attrs.font(index);
-            let font_size = self.attrs.size(index) as f32;
-            let font_weight = self.attrs.font_weight(index);
-            let color = self.attrs.color(index);
-            index += c.len_utf8();
-
-            let color = format_color(&color);
-            if let Ok(glyph_pos) = self
-                .state
-                .get_glyph_pos(c, font_family, font_size, font_weight)
-            {
-                let mut glyph_pos = glyph_pos.clone();
-
+        // Where the layout could be cut off for an ellipsis, at the start of
+        // each glyph, when ellipsizing.
+        let mut cut_points: Vec<CutPoint> = Vec::new();
+        let mut ellipsized = false;
+        // Glyphs are laid out, and kept in `glyphs`, in display order.
+        let mut items = self.layout_items(&attrs);
+        let mut next = 0;
+        while next < items.len() {
+            let item = &items[next];
+            next += 1;
+            let (text_index, c) = (item.text_index, item.c);
+            let font_family = attrs.font(text_index);
+            let font_size = attrs.size(text_index) as f32;
+            let font_weight = attrs.font_weight(text_index);
+            let color = attrs.color(text_index);
+
+            let color = format_color(color);
+            let glyph_pos = match item.shaped {
+                // Tabs are measured as spaces, which their stops are counted
+                // in, whether or not they were shaped, since fonts rarely
+                // have a glyph for them, or give it no advance.
+                _ if c == '\t' => {
+                    self.state
+                        .measure_glyph(' ', font_family, font_size, font_weight)
+                }
+                Some(ref glyph) => {
+                    let pos = if measure_only {
+                        Ok(self.state.measure_shaped_glyph(glyph, font_size))
+                    } else {
+                        self.state.get_shaped_glyph_pos(glyph, font_size)
+                    };
+                    pos.map(|mut pos| {
+                        pos.width = glyph.advance * font_size as f64;
+                        pos
+                    })
+                }
+                None if measure_only => {
+                    self.state
+                        .measure_glyph(c, font_family, font_size, font_weight)
+                }
+                None => self
+                    .state
+                    .get_glyph_pos(c, font_family, font_size, font_weight),
+            };
+            if let Ok(mut glyph_pos) = glyph_pos {
+                let columns_before = mono_char_widths;
                 let width = if is_mono {
                     let char_width = if c == '\t' {
-                        tab_width - mono_char_widths % tab_width
+                        tab_columns - mono_char_widths % tab_columns
                     } else {
                         UnicodeWidthChar::width(c).unwrap_or(1)
                     };
@@ -172,18 +465,88 @@ impl WgpuTextLayout {
                     let width = char_width as f32 * mono_width as f32;
                     glyph_pos.width = width as f64;
                     width
+                } else if c == '\t' {
+                    // Up to the next multiple of the tab width from the start
+                    // of the line.
+                    let stop = (glyph_pos.width * tab_width) as f32;
+                    let width = match stop > 0.0 {
+                        true => ((x / stop).floor() + 1.0) * stop - x,
+                        false => 0.0,
+                    };
+                    glyph_pos.width = width as f64;
+                    width
                 } else {
-                    glyph_pos.rect.width() as f32
+                    glyph_pos.width as f32
                 };
 
-                if (x + width) as f64 > self.width {
+                if attrs.ellipsis && !ellipsized {
+                    cut_points.push(CutPoint {
+                        x,
+                        text_index,
+                        mono_char_widths: columns_before,
+                        glyphs: glyphs.len(),
+                        vertices: geometry.vertices.len(),
+                        indices: geometry.indices.len(),
+                        quads: quad_text_index.len(),
+                        outlines: outline_glyphs.len(),
+                    });
+                    if (x + width) as f64 > self.width {
+                        // Cut back to the last point the ellipsis fits after,
+                        // skipping trailing spaces, and lay it out in place
+                        // of the rest of the glyphs.
+                        let ellipsis_width = |point: &CutPoint| match is_mono {
+                            true => mono_width,
+                            false => self
+                                .state
+                                .measure_glyph(
+                                    '\u{2026}',
+                                    attrs.font(point.text_index),
+                                    attrs.size(point.text_index) as f32,
+                                    attrs.font_weight(point.text_index),
+                                )
+                                .map_or(0.0, |glyph| glyph.width),
+                        };
+                        let point = cut_points
+                            .iter()
+                            .rev()
+                            .find(|point| {
+                                let after_space =
+                                    point.glyphs > 0 && glyphs[point.glyphs - 1].whitespace;
+                                !after_space && point.x as f64 + ellipsis_width(point) <= self.width
+                            })
+                            .unwrap_or(&cut_points[0]);
+                        glyphs.truncate(point.glyphs);
+                        geometry.vertices.truncate(point.vertices);
+                        geometry.indices.truncate(point.indices);
+                        quad_text_index.truncate(point.quads);
+                        outline_glyphs.truncate(point.outlines);
+                        x = point.x;
+                        mono_char_widths = point.mono_char_widths;
+                        let text_index = point.text_index;
+                        items.truncate(next - 1);
+                        items.push(LayoutItem {
+                            text_index,
+                            c: '\u{2026}',
+                            shaped: None,
+                        });
+                        next -= 1;
+                        ellipsized = true;
+                        continue;
+                    }
+                } else if !attrs.ellipsis && (x + width) as f64 > self.width {
                     x = 0.0;
-                    y += max_height;
+                    y += max_height * attrs.line_height() as f32;
                 }
 
                 glyph_pos.rect = glyph_pos.rect.with_origin((x as f64, y as f64));
+                glyph_pos.whitespace = c.is_whitespace();
+                glyph_pos.text_index = text_index;
 
-                let new_x = x + width;
+                let mut spacing = attrs.letter_spacing;
+                if c == ' ' {
+                    spacing += attrs.word_spacing;
+                }
+                let new_x = x + width + spacing as f32;
 
                 let height = glyph_pos.rect.height() as f32;
                 if height > max_height {
@@ -201,7 +564,7 @@ impl WgpuTextLayout {
                     }
                 }
 
-                if c == ' ' || c == '\n' || c == '\t' {
+                if measure_only || c == ' ' || c == '\n' || c == '\t' {
                     x = new_x;
                     glyphs.push(glyph_pos);
                     continue;
@@ -209,31 +572,32 @@ impl WgpuTextLayout {
 
                 let rect = &glyph_pos.rect;
                 let cache_rect = &glyph_pos.cache_rect;
+                let tex = if glyph_pos.color { 3.0 } else { 1.0 };
                 let mut vertices = vec![
                     GpuVertex {
                         pos: [rect.x0 as f32, rect.y0 as f32],
-                        tex: 1.0,
+                        tex,
                         tex_pos: [cache_rect.x0 as f32, cache_rect.y0 as f32],
                         color,
                         ..Default::default()
                     },
                     GpuVertex {
                         pos: [rect.x0 as f32, rect.y1 as f32],
-                        tex: 1.0,
+                        tex,
                         tex_pos: [cache_rect.x0 as f32, cache_rect.y1 as f32],
                         color,
                         ..Default::default()
                     },
                     GpuVertex {
                         pos: [rect.x1 as f32, rect.y1 as f32],
-                        tex: 1.0,
+                        tex,
                         tex_pos: [cache_rect.x1 as f32, cache_rect.y1 as f32],
                         color,
                         ..Default::default()
                     },
                     GpuVertex {
                         pos: [rect.x1 as f32, rect.y0 as f32],
-                        tex: 1.0,
+                        tex,
                         tex_pos: [cache_rect.x1 as f32, cache_rect.y0 as f32],
                         color,
                         ..Default::default()
@@ -241,16 +605,34 @@ impl WgpuTextLayout {
                 ];
                 let offset = geometry.vertices.len() as u32;
                 let mut indices = vec![
-                    offset + 0,
+                    offset,
                     offset + 1,
                     offset + 2,
-                    offset + 0,
+                    offset,
                     offset + 2,
                     offset + 3,
                 ];
 
                 geometry.vertices.append(&mut vertices);
                 geometry.indices.append(&mut indices);
+                quad_text_index.push(text_index);
+                atlas_rows.extend(glyph_pos.row);
+                let glyph_id = match item.shaped {
+                    Some(ref glyph) => Some((glyph.font_id, glyph.glyph_id)),
+                    None => self.state.cache.lock().unwrap().glyph_id(
+                        c,
+                        attrs.font(text_index),
+                        attrs.font_weight(text_index),
+                    ),
+                };
+                if let Some((font_id, glyph_id)) = glyph_id {
+                    outline_glyphs.push(OutlineGlyph {
+                        font_id,
+                        glyph_id,
+                        font_size: font_size as f64,
+                        origin: Point::new(rect.x0, rect.y0 + glyph_pos.metric.ascent),
+                    });
+                }
 
                 x = new_x;
                 glyphs.push(glyph_pos);
@@ -258,21 +640,151 @@ impl WgpuTextLayout {
         }
     }
 
-    pub(crate) fn draw_text(&self, ctx: &mut WgpuRenderContext, translate: [f32; 2]) {
-        let geometry = self.geometry.borrow();
-        if geometry.vertices.len() == 0 {
+    /// The glyphs to lay out in display order: each bidi run is shaped a
+    /// font at a time where the font allows, and split into chars where it
+    /// doesn't, such as at control chars and chars from fallback fonts.
+    fn layout_items(&self, attrs: &Attributes) -> Vec<LayoutItem> {
+        let mut items = Vec::with_capacity(self.text.len());
+        for (run, rtl) in visual_runs(&self.text) {
+            let mut segments = Vec::new();
+            let mut start = run.start;
+            for (i, _) in self.text[run.clone()].char_indices().skip(1) {
+                let i = run.start + i;
+                if attrs.font(i) != attrs.font(start)
+                    || attrs.font_weight(i) != attrs.font_weight(start)
+                {
+                    segments.push(start..i);
+                    start = i;
+                }
+            }
+            segments.push(start..run.end);
+            if rtl {
+                segments.reverse();
+            }
+
+            for segment in segments {
+                let font_family = attrs.font(segment.start);
+                let font_weight = attrs.font_weight(segment.start);
+                let mut runs = self.state.shaping_runs(
+                    &self.text[segment.clone()],
+                    font_family.clone(),
+                    font_weight,
+                );
+                if rtl {
+                    runs.reverse();
+                }
+                for (run, shaped) in runs {
+                    let start = segment.start + run.start;
+                    let text = &self.text[start..segment.start + run.end];
+                    let glyphs = match shaped {
+                        true => self
+                            .state
+                            .shape(text, rtl, font_family.clone(), font_weight),
+                        false => None,
+                    };
+                    match glyphs {
+                        Some(glyphs) => items.extend(glyphs.into_iter().map(|glyph| {
+                            let text_index = start + glyph.cluster;
+                            LayoutItem {
+                                text_index,
+                                c: self.text[text_index..].chars().next().unwrap_or(' '),
+                                shaped: Some(glyph),
+                            }
+                        })),
+                        None => {
+                            let chars = text.char_indices().map(|(i, c)| LayoutItem {
+                                text_index: start + i,
+                                c,
+                                shaped: None,
+                            });
+                            if rtl {
+                                items.extend(chars.rev());
+                            } else {
+                                items.extend(chars);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        items
+    }
+
+    /// Add the layout's geometry to `ctx`, placed by `transform`.
+    ///
+    /// Only the translation is applied here, to each vertex; any rotation or
+    /// scale has to be in the transform of the context's current primitive.
+    pub(crate) fn draw_text(&self, ctx: &mut WgpuRenderContext, transform: Affine) {
+        if self.needs_rebuild() {
+            let bounds = *self.bounds.lock().unwrap();
+            self.rebuild(bounds);
+        }
+        // The batch's scissor clips text like everything else, but a layout
+        // wholly outside the clip, like a row scrolled out of a list, needs
+        // no geometry at all.
+        if let Some(clip) = ctx.current_clip_rect() {
+            let bounds = Rect::new(
+                0.0,
+                0.0,
+                self.trailing_whitespace_width(),
+                self.size().height,
+            )
+            .union(self.image_bounds());
+            if clip
+                .intersect(transform.transform_rect_bbox(bounds))
+                .is_empty()
+            {
+                return;
+            }
+        }
+        let affine = transform.as_coeffs();
+        let translate = [affine[4] as f32, affine[5] as f32];
+        self.state
+            .cache
+            .lock()
+            .unwrap()
+            .touch_rows(&self.atlas_rows.lock().unwrap());
+        let primivite_id = (ctx.primitives.len() - 1) as u32;
+        for (rect, color) in self.background_rects() {
+            let offset = ctx.geometry.vertices.len() as u32;
+            ctx.geometry.vertices.extend(
+                [
+                    [rect.x0, rect.y0],
+                    [rect.x0, rect.y1],
+                    [rect.x1, rect.y1],
+                    [rect.x1, rect.y0],
+                ]
+                .map(|[x, y]| GpuVertex {
+                    pos: [x as f32, y as f32],
+                    translate,
+                    color,
+                    primitive_id: primivite_id,
+                    ..Default::default()
+                }),
+            );
+            ctx.geometry
+                .indices
+                .extend([0, 1, 2, 0, 2, 3].map(|i| offset + i));
+        }
+        let geometry = self.geometry.lock().unwrap();
+        if geometry.vertices.is_empty() {
             return;
         }
 
         let offset = ctx.geometry.vertices.len() as u32;
-        let primivite_id = (ctx.primitives.len() - 1) as u32;
+        let attrs = self.attrs.lock().unwrap();
+        let quad_text_index = self.quad_text_index.lock().unwrap();
         let mut vertices = geometry
             .vertices
             .iter()
-            .map(|v| {
-                let mut v = v.clone();
+            .enumerate()
+            .map(|(i, v)| {
+                let mut v = *v;
                 v.translate = translate;
                 v.primitive_id = primivite_id;
+                if !attrs.opacity.is_empty() {
+                    v.color[3] *= attrs.opacity(quad_text_index[i / 4]) as f32;
+                }
                 v
             })
             .collect();
@@ -281,6 +793,122 @@ impl WgpuTextLayout {
         ctx.geometry.indices.append(&mut indices);
     }
 
+    /// The rects to paint behind glyphs with a background, and their colors.
+    fn background_rects(&self) -> Vec<(Rect, [f32; 4])> {
+        let attrs = self.attrs.lock().unwrap();
+        if attrs.background.is_empty() {
+            return Vec::new();
+        }
+        self.glyph_runs(attrs.line_height(), |text_index| {
+            attrs.background(text_index).map(format_color)
+        })
+    }
+
+    /// The rects covering runs of glyphs `key` gives the same value, one for
+    /// each run on a line, along with the value. Each spans its glyphs'
+    /// advances and the full height of lines `line_height` apart; glyphs
+    /// `key` gives `None` are left out.
+    fn glyph_runs<K: PartialEq>(
+        &self,
+        line_height: f64,
+        mut key: impl FnMut(usize) -> Option<K>,
+    ) -> Vec<(Rect, K)> {
+        let mut runs: Vec<(Rect, K)> = Vec::new();
+        let mut in_run = false;
+        for glyph in self.glyphs.lock().unwrap().iter() {
+            let value = match key(glyph.text_index) {
+                Some(value) => value,
+                None => {
+                    in_run = false;
+                    continue;
+                }
+            };
+            let rect = Rect::new(
+                glyph.rect.x0,
+                glyph.rect.y0,
+                glyph.rect.x0 + glyph.width,
+                glyph.rect.y0 + glyph.rect.height() * line_height,
+            );
+            match runs.last_mut() {
+                Some((last, last_value))
+                    if in_run && *last_value == value && last.y0 == rect.y0 =>
+                {
+                    *last = last.union(rect);
+                }
+                _ => runs.push((rect, value)),
+            }
+            in_run = true;
+        }
+        runs
+    }
+
+    /// The caret at text index `idx`, as a zero-width rect from the top to
+    /// the bottom of its line's text, and the distance from the top to the
+    /// baseline.
+    ///
+    /// It's at the leading edge of the first glyph at or after `idx`, or the
+    /// trailing edge of the last glyph for indices past it.
+    fn caret(&self, idx: usize) -> (Rect, f64) {
+        let glyphs = self.glyphs.lock().unwrap();
+        let (x, glyph) = match glyphs.iter().find(|glyph| glyph.text_index >= idx) {
+            Some(glyph) => (glyph.rect.x0, glyph),
+            None => match glyphs.last() {
+                Some(glyph) => (glyph.rect.x0 + glyph.width, glyph),
+                None => {
+                    let glyph = self.ref_glyph.lock().unwrap();
+                    return (
+                        Rect::new(0.0, 0.0, 0.0, glyph.rect.height()),
+                        glyph.metric.ascent,
+                    );
+                }
+            },
+        };
+        (
+            Rect::new(x, glyph.rect.y0, x, glyph.rect.y1),
+            glyph.metric.ascent,
+        )
+    }
+
+    /// The caret at text index `idx`, as a zero-width rect from the top to
+    /// the bottom of the line's text, where [`draw_text`] places the glyphs
+    /// around it.
+    ///
+    /// [`draw_text`]: piet::RenderContext::draw_text
+    pub fn caret_rect(&self, idx: usize) -> Rect {
+        self.caret(idx).0
+    }
+
+    /// The rects covering the glyphs of the text in `range`, one for each run
+    /// of them on a line, spanning the full line height, to draw a selection
+    /// with.
+    pub fn rects_for_range(&self, range: impl std::ops::RangeBounds<usize>) -> Vec<Rect> {
+        let range = piet::util::resolve_range(range, self.text.len());
+        let line_height = self.attrs.lock().unwrap().line_height();
+        self.glyph_runs(line_height, |text_index| {
+            range.contains(&text_index).then_some(())
+        })
+        .into_iter()
+        .map(|(rect, ())| rect)
+        .collect()
+    }
+
+    /// The outlines of the layout's glyphs, relative to the layout's origin.
+    ///
+    /// Color glyphs like emoji have no outline and are left out.
+    pub fn outline(&self) -> BezPath {
+        if self.needs_rebuild() {
+            let bounds = *self.bounds.lock().unwrap();
+            self.rebuild(bounds);
+        }
+        let mut path = BezPath::new();
+        for glyph in self.outline_glyphs.lock().unwrap().iter() {
+            if let Some(outline) = self.state.glyph_outline(glyph) {
+                path.extend(outline);
+            }
+        }
+        path
+    }
+
     pub fn cursor_line_for_text_position(&self, text_pos: usize) -> Line {
         let pos = self.hit_test_text_position(text_pos);
         let line_metric = self.line_metric(0).unwrap();
@@ -311,6 +939,76 @@ impl WgpuTextLayoutBuilder {
         self.attrs.add(range, attr);
     }
 
+    /// Paint `color` behind the glyphs in `range`, e.g. to highlight search
+    /// matches; see [`WgpuTextLayout::set_range_background`].
+    pub fn range_background(
+        mut self,
+        range: impl std::ops::RangeBounds<usize>,
+        color: Color,
+    ) -> Self {
+        let range = piet::util::resolve_range(range, self.text.len());
+        self.attrs.set_background(range, Some(color));
+        self
+    }
+
+    /// Set the opacity of the glyphs in `range`; see
+    /// [`WgpuTextLayout::set_range_opacity`].
+    pub fn range_opacity(mut self, range: impl std::ops::RangeBounds<usize>, opacity: f64) -> Self {
+        let range = piet::util::resolve_range(range, self.text.len());
+        self.attrs.set_opacity(range, opacity);
+        self
+    }
+
+    /// Add `spacing` after every glyph, loosening the tracking, or tightening
+    /// it if negative.
+    pub fn letter_spacing(mut self, spacing: f64) -> Self {
+        self.attrs.letter_spacing = spacing;
+        self
+    }
+
+    /// Add `spacing` after every space, on top of any letter spacing.
+    pub fn word_spacing(mut self, spacing: f64) -> Self {
+        self.attrs.word_spacing = spacing;
+        self
+    }
+
+    /// Scale the distance between lines by `factor`, e.g. 1.5 for airy body
+    /// text. The extra space goes below each line; 1.0 is the font's natural
+    /// line height.
+    pub fn line_height(mut self, factor: f64) -> Self {
+        self.attrs.line_height = Some(factor);
+        self
+    }
+
+    /// Set the distance between tab stops, in widths of a space in the tab's
+    /// font; 4 by default. Tabs advance to the next stop from the start of
+    /// their line, so tab separated columns line up.
+    pub fn tab_width(mut self, spaces: f64) -> Self {
+        self.attrs.tab_width = Some(spaces.max(0.0));
+        self
+    }
+
+    /// Keep the text on one line, cutting it off with an ellipsis, "…", in
+    /// the font of the text it replaces, if it's wider than the max width,
+    /// rather than wrapping it.
+    pub fn ellipsis(mut self, ellipsis: bool) -> Self {
+        self.attrs.ellipsis = ellipsis;
+        self
+    }
+
+    /// Build a layout for measuring: its size, line metrics and hit testing
+    /// come from font metrics alone, without touching the GPU or taking up
+    /// glyph atlas space. It can still be drawn, which rasterizes its glyphs
+    /// then.
+    pub fn build_measured(self) -> WgpuTextLayout {
+        let state = self.state.clone();
+        let mut text_layout = WgpuTextLayout::new(self.text, state);
+        text_layout.set_attrs(self.attrs);
+        text_layout.set_width(self.width);
+        text_layout.measure();
+        text_layout
+    }
+
     pub fn build_with_bounds(self, bounds: [f64; 2]) -> WgpuTextLayout {
         let state = self.state.clone();
         let mut text_layout = WgpuTextLayout::new(self.text, state);
@@ -324,12 +1022,12 @@ impl Text for WgpuText {
     type TextLayoutBuilder = WgpuTextLayoutBuilder;
     type TextLayout = WgpuTextLayout;
 
-    fn font_family(&mut self, family_name: &str) -> Option<FontFamily> {
-        todo!()
+    fn font_family(&mut self, _family_name: &str) -> Option<FontFamily> {
+        None
     }
 
     fn load_font(&mut self, data: &[u8]) -> Result<piet::FontFamily, piet::Error> {
-        todo!()
+        self.cache.lock().unwrap().load_font(data)
     }
 
     fn new_text_layout(&mut self, text: impl piet::TextStorage) -> Self::TextLayoutBuilder {
@@ -346,7 +1044,7 @@ impl TextLayoutBuilder for WgpuTextLayoutBuilder {
         self
     }
 
-    fn alignment(self, alignment: piet::TextAlignment) -> Self {
+    fn alignment(self, _alignment: piet::TextAlignment) -> Self {
         self
     }
 
@@ -379,36 +1077,62 @@ impl TextLayoutBuilder for WgpuTextLayoutBuilder {
 
 impl TextLayout for WgpuTextLayout {
     fn size(&self) -> Size {
-        if self.glyphs.borrow().len() == 0 {
-            let ref_glyph = self.ref_glyph.borrow();
-            Size::new(0.0, ref_glyph.rect.height())
+        let line_height = self.attrs.lock().unwrap().line_height();
+        if self.glyphs.lock().unwrap().is_empty() {
+            let ref_glyph = self.ref_glyph.lock().unwrap();
+            Size::new(0.0, ref_glyph.rect.height() * line_height)
         } else {
-            let glyphs = self.glyphs.borrow();
-
-            let last_glyph = &glyphs[glyphs.len() - 1];
-            let width = last_glyph.rect.x0 + last_glyph.width;
-            let height = last_glyph.rect.y1;
-            Size::new(width as f64, height as f64)
+            // The far edge of every line, not just the last one laid out,
+            // leaving out trailing whitespace as piet does.
+            self.glyphs
+                .lock()
+                .unwrap()
+                .iter()
+                .fold(Size::ZERO, |size, glyph| {
+                    let right = match glyph.whitespace {
+                        true => 0.0,
+                        false => glyph.rect.x0 + glyph.width,
+                    };
+                    Size::new(
+                        size.width.max(right),
+                        size.height
+                            .max(glyph.rect.y0 + glyph.rect.height() * line_height),
+                    )
+                })
         }
     }
 
+    /// The width of the layout with trailing whitespace, for placing a caret
+    /// after it.
     fn trailing_whitespace_width(&self) -> f64 {
-        0.0
+        self.glyphs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|glyph| glyph.rect.x0 + glyph.width)
+            .fold(0.0, f64::max)
     }
 
-    fn image_bounds(&self) -> piet::kurbo::Rect {
-        Size::ZERO.to_rect()
+    fn image_bounds(&self) -> Rect {
+        self.glyphs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|glyph| glyph.ink.area() > 0.0)
+            .map(|glyph| glyph.ink + glyph.rect.origin().to_vec2())
+            .reduce(|bounds, ink| bounds.union(ink))
+            .unwrap_or_default()
     }
 
     fn text(&self) -> &str {
         &self.text
     }
 
-    fn line_text(&self, line_number: usize) -> Option<&str> {
+    fn line_text(&self, _line_number: usize) -> Option<&str> {
         Some(&self.text)
     }
 
-    fn line_metric(&self, line_number: usize) -> Option<LineMetric> {
+    fn line_metric(&self, _line_number: usize) -> Option<LineMetric> {
         let mut metric = LineMetric {
             start_offset: 0,
             end_offset: self.text.len(),
@@ -417,9 +1141,10 @@ impl TextLayout for WgpuTextLayout {
             height: 0.0,
             y_offset: 0.0,
         };
-        let glyph = &self.ref_glyph.borrow();
+        let glyph = &self.ref_glyph.lock().unwrap();
         metric.baseline = glyph.metric.ascent;
-        metric.height = glyph.metric.ascent - glyph.metric.descent + glyph.metric.line_gap;
+        metric.height = (glyph.metric.ascent - glyph.metric.descent + glyph.metric.line_gap)
+            * self.attrs.lock().unwrap().line_height();
         Some(metric)
     }
 
@@ -427,27 +1152,65 @@ impl TextLayout for WgpuTextLayout {
         0
     }
 
-    fn hit_test_point(&self, point: Point) -> HitTestPoint {
+    fn hit_test_point(&self, _point: Point) -> HitTestPoint {
         HitTestPoint::default()
     }
 
     fn hit_test_text_position(&self, idx: usize) -> HitTestPosition {
-        if self.glyphs.borrow().len() == 0 {
-            return HitTestPosition::default();
-        }
+        let (caret, ascent) = self.caret(idx);
+        let mut pos = HitTestPosition::default();
+        pos.point = Point::new(caret.x0, caret.y0 + ascent);
+        pos
+    }
+}
 
-        let glyphs = self.glyphs.borrow();
+/// A glyph placed in a layout, for [`WgpuTextLayout::outline`].
+struct OutlineGlyph {
+    font_id: usize,
+    glyph_id: u32,
+    font_size: f64,
+    /// Where the glyph's origin is on the baseline.
+    origin: Point,
+}
 
-        let cur_glyph = &glyphs[idx.min(glyphs.len() - 1)];
-        let mut x = cur_glyph.rect.x0;
-        if idx >= glyphs.len() {
-            x = cur_glyph.rect.x1;
-        }
+/// A glyph to lay out, from shaping or a char looked up on its own.
+struct LayoutItem {
+    text_index: usize,
+    c: char,
+    shaped: Option<ShapedGlyph>,
+}
 
-        let mut pos = HitTestPosition::default();
-        pos.point = Point::new(x as f64, 0.0);
-        pos
+/// The state of a layout at the start of a glyph, to cut it back to for an
+/// ellipsis.
+struct CutPoint {
+    x: f32,
+    text_index: usize,
+    mono_char_widths: usize,
+    glyphs: usize,
+    vertices: usize,
+    indices: usize,
+    quads: usize,
+    outlines: usize,
+}
+
+/// The runs of `text` in display order by the Unicode bidi algorithm, and
+/// whether each is right-to-left.
+///
+/// Each paragraph is reordered as a single line.
+fn visual_runs(text: &str) -> Vec<(Range<usize>, bool)> {
+    let info = BidiInfo::new(text, None);
+    if !info.has_rtl() {
+        return vec![(0..text.len(), false)];
     }
+    let mut visual = Vec::new();
+    for para in &info.paragraphs {
+        let (levels, runs) = info.visual_runs(para, para.range.clone());
+        visual.extend(runs.into_iter().map(|run| {
+            let rtl = levels[run.start].is_rtl();
+            (run, rtl)
+        }));
+    }
+    visual
 }
 
 #[derive(Default)]
@@ -457,7 +1220,13 @@ struct Attributes {
     font: Vec<Span<FontFamily>>,
     size: Vec<Span<f64>>,
     weight: Vec<Span<FontWeight>>,
-    style: Option<Span<FontStyle>>,
+    opacity: Vec<Span<f64>>,
+    background: Vec<Span<Option<Color>>>,
+    letter_spacing: f64,
+    word_spacing: f64,
+    line_height: Option<f64>,
+    tab_width: Option<f64>,
+    ellipsis: bool,
 }
 
 /// during construction, `Span`s represent font attributes that have been applied
@@ -472,10 +1241,6 @@ impl<T> Span<T> {
     fn new(payload: T, range: Range<usize>) -> Self {
         Span { payload, range }
     }
-
-    fn range_end(&self) -> usize {
-        self.range.end
-    }
 }
 
 impl Attributes {
@@ -487,6 +1252,41 @@ impl Attributes {
         }
     }
 
+    fn line_height(&self) -> f64 {
+        self.line_height.unwrap_or(1.0)
+    }
+
+    fn tab_width(&self) -> f64 {
+        self.tab_width.unwrap_or(4.0)
+    }
+
+    fn set_opacity(&mut self, range: Range<usize>, opacity: f64) {
+        self.opacity.retain(|r| r.range != range);
+        self.opacity.push(Span::new(opacity, range));
+    }
+
+    fn set_background(&mut self, range: Range<usize>, color: Option<Color>) {
+        self.background.retain(|r| r.range != range);
+        self.background.push(Span::new(color, range));
+    }
+
+    fn background(&self, index: usize) -> Option<&Color> {
+        self.background
+            .iter()
+            .rev()
+            .find(|r| r.range.contains(&index))
+            .and_then(|r| r.payload.as_ref())
+    }
+
+    fn opacity(&self, index: usize) -> f64 {
+        for r in self.opacity.iter().rev() {
+            if r.range.contains(&index) {
+                return r.payload;
+            }
+        }
+        1.0
+    }
+
     fn color(&self, index: usize) -> &Color {
         for r in &self.color {
             if r.range.contains(&index) {
@@ -505,16 +1305,6 @@ impl Attributes {
         self.defaults.font_size
     }
 
-    fn italic(&self) -> bool {
-        matches!(
-            self.style
-                .as_ref()
-                .map(|t| t.payload)
-                .unwrap_or(self.defaults.style),
-            FontStyle::Italic
-        )
-    }
-
     fn font(&self, index: usize) -> FontFamily {
         for r in &self.font {
             if r.range.contains(&index) {
@@ -533,3 +1323,76 @@ impl Attributes {
         self.defaults.weight
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measure(text: &str) -> WgpuTextLayout {
+        WgpuText::measuring()
+            .new_text_layout(text.to_string())
+            .font(FontFamily::SANS_SERIF, 16.0)
+            .build_measured()
+    }
+
+    #[test]
+    fn trailing_whitespace_is_only_in_its_own_width() {
+        let trimmed = measure("abc");
+        let spaced = measure("abc   ");
+        assert!(spaced.trailing_whitespace_width() > trimmed.trailing_whitespace_width());
+        assert_eq!(spaced.size().width, trimmed.size().width);
+        assert!(trimmed.size().width > 0.0);
+    }
+
+    #[test]
+    fn bounds_include_descenders() {
+        let layout = measure("gjpqy");
+        let baseline = layout.line_metric(0).unwrap().baseline;
+        let ink = layout.image_bounds();
+        assert!(ink.y1 > baseline);
+        assert!(ink.y1 > measure("aceo").image_bounds().y1);
+        assert!(layout.size().height >= ink.y1);
+    }
+
+    #[test]
+    fn tabs_advance_to_stops_in_spaces() {
+        let space = measure(" ").trailing_whitespace_width();
+        for text in ["a\tb", "fit\tb"] {
+            let layout = measure(text);
+            let b = layout.hit_test_text_position(text.len() - 1).point.x;
+            assert!(
+                (b - 4.0 * space).abs() < 1e-6,
+                "{:?}: {} != {}",
+                text,
+                b,
+                4.0 * space
+            );
+        }
+    }
+
+    #[test]
+    fn text_around_control_chars_is_shaped() {
+        let layout = measure("fit\tin\nplace");
+        let items = layout.layout_items(&layout.attrs.lock().unwrap());
+        for item in items {
+            assert_eq!(item.shaped.is_some(), !item.c.is_control(), "{:?}", item.c);
+        }
+    }
+
+    #[test]
+    fn prewarm_fills_the_glyph_atlas() {
+        let text = WgpuText::measuring();
+        let occupancy = || text.cache.lock().unwrap().occupancy();
+        assert_eq!(occupancy(), 0.0);
+        text.prewarm("Hello", FontFamily::SANS_SERIF, 16.0).unwrap();
+        let prewarmed = occupancy();
+        assert!(prewarmed > 0.0);
+        // Laying the text out again finds every glyph already there.
+        text.clone()
+            .new_text_layout("Hello")
+            .font(FontFamily::SANS_SERIF, 16.0)
+            .build()
+            .unwrap();
+        assert_eq!(occupancy(), prewarmed);
+    }
+}
@@ -0,0 +1,151 @@
+use std::{cell::Cell, rc::Rc};
+
+use futures::task::LocalSpawnExt;
+
+/// How long the GPU spent on a frame, read back from timestamp queries.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrameTimings {
+    /// From the start of the frame's first pass to the end of its last.
+    pub total_ns: u64,
+    /// Each custom render pass, in the order they were recorded. Passes past
+    /// the first 31 in a frame aren't timed.
+    pub custom_pass_ns: Vec<u64>,
+}
+
+impl FrameTimings {
+    /// Everything but the custom passes: shapes, text, images and layers,
+    /// which all go through piet's own passes.
+    pub fn draw_ns(&self) -> u64 {
+        self.total_ns
+            .saturating_sub(self.custom_pass_ns.iter().sum::<u64>())
+    }
+}
+
+/// Writes timestamps around a frame's passes and reads them back without
+/// stalling: a frame only writes them once the previous readback is done, so
+/// results arrive a frame or more late.
+pub(crate) struct GpuTimer {
+    query_set: wgpu::QuerySet,
+    readback: wgpu::Buffer,
+    /// Nanoseconds per timestamp tick.
+    period: f32,
+    /// Whether the frame being recorded writes timestamps.
+    writing: bool,
+    /// The custom passes timed in the frame being recorded.
+    custom_passes: u32,
+    /// The custom passes timed in the frame being read back, if any.
+    in_flight: Option<u32>,
+    mapped: Rc<Cell<bool>>,
+    last: Option<FrameTimings>,
+}
+
+impl GpuTimer {
+    const MAX_CUSTOM_PASSES: u32 = 31;
+    const QUERY_COUNT: u32 = 2 + 2 * Self::MAX_CUSTOM_PASSES;
+
+    /// A timer, if the device was created with `TIMESTAMP_QUERY`.
+    pub(crate) fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Option<Self> {
+        if !device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+            return None;
+        }
+        let size = Self::QUERY_COUNT as u64 * std::mem::size_of::<u64>() as u64;
+        Some(Self {
+            query_set: device.create_query_set(&wgpu::QuerySetDescriptor {
+                label: Some("frame timestamps"),
+                ty: wgpu::QueryType::Timestamp,
+                count: Self::QUERY_COUNT,
+            }),
+            readback: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("frame timestamps readback"),
+                size,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            period: queue.get_timestamp_period(),
+            writing: false,
+            custom_passes: 0,
+            in_flight: None,
+            mapped: Rc::new(Cell::new(false)),
+            last: None,
+        })
+    }
+
+    /// The most recent timings read back.
+    pub(crate) fn last(&self) -> Option<&FrameTimings> {
+        self.last.as_ref()
+    }
+
+    pub(crate) fn begin_frame(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        self.writing = self.in_flight.is_none();
+        self.custom_passes = 0;
+        if self.writing {
+            encoder.write_timestamp(&self.query_set, 0);
+        }
+    }
+
+    fn times_custom_pass(&self) -> bool {
+        self.writing && self.custom_passes < Self::MAX_CUSTOM_PASSES
+    }
+
+    pub(crate) fn begin_custom_pass(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        if self.times_custom_pass() {
+            encoder.write_timestamp(&self.query_set, 2 + 2 * self.custom_passes);
+        }
+    }
+
+    pub(crate) fn end_custom_pass(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        if self.times_custom_pass() {
+            encoder.write_timestamp(&self.query_set, 3 + 2 * self.custom_passes);
+            self.custom_passes += 1;
+        }
+    }
+
+    pub(crate) fn end_frame(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        if !self.writing {
+            return;
+        }
+        encoder.write_timestamp(&self.query_set, 1);
+        let count = 2 + 2 * self.custom_passes;
+        encoder.resolve_query_set(&self.query_set, 0..count, &self.readback, 0);
+    }
+
+    /// Start reading back the frame's timestamps once it's been submitted.
+    pub(crate) fn after_submit(&mut self, spawner: &futures::executor::LocalSpawner) {
+        if !self.writing {
+            return;
+        }
+        self.writing = false;
+        self.in_flight = Some(self.custom_passes);
+        let mapping = self.readback.slice(..).map_async(wgpu::MapMode::Read);
+        let mapped = self.mapped.clone();
+        let _ = spawner.spawn_local(async move {
+            if mapping.await.is_ok() {
+                mapped.set(true);
+            }
+        });
+    }
+
+    /// Pick up a finished readback, if there is one.
+    pub(crate) fn collect(&mut self) {
+        let custom_passes = match self.in_flight {
+            Some(custom_passes) if self.mapped.get() => custom_passes,
+            _ => return,
+        };
+        {
+            let data = self.readback.slice(..).get_mapped_range();
+            let ticks: &[u64] = bytemuck::cast_slice(&data);
+            let ns = |start: usize, end: usize| {
+                (ticks[end].saturating_sub(ticks[start]) as f64 * self.period as f64) as u64
+            };
+            self.last = Some(FrameTimings {
+                total_ns: ns(0, 1),
+                custom_pass_ns: (0..custom_passes as usize)
+                    .map(|i| ns(2 + 2 * i, 3 + 2 * i))
+                    .collect(),
+            });
+        }
+        self.readback.unmap();
+        self.mapped.set(false);
+        self.in_flight = None;
+    }
+}
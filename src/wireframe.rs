@@ -0,0 +1,122 @@
+use std::num::NonZeroU64;
+
+use lyon::lyon_tessellation::VertexBuffers;
+
+use crate::pipeline::{GpuVertex, VERTEX_ATTRIBUTES};
+
+/// Draws the edges of every triangle in a frame over it, to debug
+/// tessellation.
+pub(crate) struct Wireframe {
+    pipeline: wgpu::RenderPipeline,
+    indices: wgpu::Buffer,
+    supported_indices: usize,
+    /// The line indices of the frame being drawn, two per edge.
+    lines: Vec<u32>,
+}
+
+impl Wireframe {
+    const INITIAL_INDICES: usize = 12288;
+
+    /// Shares the geometry pipeline's shader and layout, so the edges land
+    /// exactly where the triangles were drawn.
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        shader: &wgpu::ShaderModule,
+        layout: &wgpu::PipelineLayout,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Self {
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("wireframe pipeline"),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: std::mem::size_of::<GpuVertex>() as u64,
+                    step_mode: wgpu::VertexStepMode::Vertex,
+                    attributes: &VERTEX_ATTRIBUTES,
+                }],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: "fs_wireframe",
+                targets: &[wgpu::ColorTargetState {
+                    format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                }],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::LineList,
+                ..Default::default()
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        });
+        Self {
+            pipeline,
+            indices: Self::create_indices(device, Self::INITIAL_INDICES),
+            supported_indices: Self::INITIAL_INDICES,
+            lines: Vec::new(),
+        }
+    }
+
+    fn create_indices(device: &wgpu::Device, len: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("wireframe indices"),
+            size: std::mem::size_of::<u32>() as u64 * len as u64,
+            usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Turn the frame's triangles into lines along their edges.
+    pub(crate) fn upload(
+        &mut self,
+        device: &wgpu::Device,
+        staging_belt: &mut wgpu::util::StagingBelt,
+        encoder: &mut wgpu::CommandEncoder,
+        geometry: &VertexBuffers<GpuVertex, u32>,
+    ) {
+        self.lines.clear();
+        for triangle in geometry.indices.chunks_exact(3) {
+            let [a, b, c] = [triangle[0], triangle[1], triangle[2]];
+            self.lines.extend_from_slice(&[a, b, b, c, c, a]);
+        }
+        if self.lines.len() > self.supported_indices {
+            self.supported_indices = self.lines.len().next_power_of_two();
+            self.indices = Self::create_indices(device, self.supported_indices);
+        }
+        let bytes: &[u8] = bytemuck::cast_slice(&self.lines);
+        if let Some(size) = NonZeroU64::new(bytes.len() as u64) {
+            staging_belt
+                .write_buffer(encoder, &self.indices, 0, size, device)
+                .copy_from_slice(bytes);
+        }
+    }
+
+    /// Draw the uploaded edges over everything in `pass`, unclipped.
+    pub(crate) fn draw<'a>(
+        &'a self,
+        pass: &mut wgpu::RenderPass<'a>,
+        bind_group: &'a wgpu::BindGroup,
+        blank_image: &'a wgpu::BindGroup,
+        vertices: &'a wgpu::Buffer,
+    ) {
+        if self.lines.is_empty() {
+            return;
+        }
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, bind_group, &[0]);
+        pass.set_bind_group(1, blank_image, &[]);
+        pass.set_vertex_buffer(0, vertices.slice(..));
+        pass.set_index_buffer(self.indices.slice(..), wgpu::IndexFormat::Uint32);
+        pass.draw_indexed(0..self.lines.len() as u32, 0, 0..1);
+    }
+}